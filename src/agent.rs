@@ -0,0 +1,2025 @@
+//! Prompting the model for summaries of diffs.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::Path,
+    process::Stdio,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::StreamExt;
+use rig::{
+    OneOrMany,
+    agent::Agent,
+    client::{CompletionClient, Nothing},
+    completion::{CompletionError, CompletionModel, GetTokenUsage, Prompt, PromptError},
+    message::{DocumentMediaType, Message, UserContent},
+    providers::{anthropic, ollama, openai},
+    streaming::{StreamedAssistantContent, StreamingCompletion},
+};
+use tokio::sync::watch;
+use tracing::Instrument;
+
+use crate::{
+    config::{AgentConfig, Provider, ResponseMode, RetryConfig},
+    git::{DiffMetadata, changed_paths},
+    metrics,
+    presence::line_vars,
+    usage,
+};
+
+/// Anything that can write a summary of a diff. The main loop only talks to
+/// the model through this so that it can be run against a fake one.
+#[async_trait]
+pub trait Summarizer: Send + Sync {
+    /// Summarizes `diff` as asked by `prompt`, with `preamble` as the system
+    /// prompt.
+    async fn summarize(
+        &self,
+        preamble: &str,
+        prompt: &str,
+        diff: &str,
+    ) -> Result<String, ModelError>;
+
+    /// Like [Summarizer::summarize], but sends the response so far to
+    /// `partial` as it's generated. Summarizers that can't stream answer all
+    /// at once.
+    async fn summarize_streaming(
+        &self,
+        preamble: &str,
+        prompt: &str,
+        diff: &str,
+        _partial: &watch::Sender<String>,
+    ) -> Result<String, ModelError> {
+        self.summarize(preamble, prompt, diff).await
+    }
+}
+
+/// The configured models in order of preference, each with its own prebuilt
+/// [Summarizer], falling back to the next when one fails.
+pub struct ModelChain {
+    models: Vec<(String, Box<dyn Summarizer>)>,
+
+    /// The model that answered the last prompt and how long it took, until
+    /// it's [taken](ModelChain::take_last_prompt).
+    last_prompt: Mutex<Option<(String, Duration)>>,
+
+    /// The response so far of a [streaming](ModelChain::prompt_streaming)
+    /// prompt.
+    partial: watch::Sender<String>,
+
+    /// The model being prompted, or that was prompted last.
+    answering: Mutex<String>,
+
+    /// The most tokens to use in a day before refusing to prompt.
+    budget: Option<u64>,
+}
+
+impl ModelChain {
+    pub fn new(config: &AgentConfig) -> anyhow::Result<Self> {
+        let models = config
+            .model
+            .iter()
+            .map(|model| {
+                let agent = SummaryAgent::new(config, model)
+                    .with_context(|| format!("failed to create agent for {model}"))?;
+
+                Ok((model.clone(), Box::new(agent) as Box<dyn Summarizer>))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            budget: config.daily_token_budget,
+            ..Self::with_summarizers(models)
+        })
+    }
+
+    /// A chain of arbitrary summarizers, named like the models they stand in
+    /// for.
+    pub fn with_summarizers(models: Vec<(String, Box<dyn Summarizer>)>) -> Self {
+        Self {
+            models,
+            last_prompt: Mutex::new(None),
+            partial: watch::Sender::new(String::new()),
+            answering: Mutex::new(String::new()),
+            budget: None,
+        }
+    }
+
+    /// The model that answered the last prompt and how long it took, if
+    /// there's been one since this was last called.
+    pub fn take_last_prompt(&self) -> Option<(String, Duration)> {
+        self.last_prompt
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    /// Watches the responses of [streaming](ModelChain::prompt_streaming)
+    /// prompts as they're generated.
+    pub fn subscribe_partial(&self) -> watch::Receiver<String> {
+        self.partial.subscribe()
+    }
+
+    /// The model being prompted, which is the one writing the
+    /// [partial](ModelChain::subscribe_partial) response, or the one that
+    /// was prompted last.
+    pub fn answering(&self) -> String {
+        self.answering
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Prompts each model in turn with [prompt_with_retry] until one answers
+    /// within `timeout`, returning the name of the model that did along with
+    /// its response. Fails with the last model's error if none of them do.
+    pub async fn prompt(
+        &self,
+        preamble: &str,
+        prompt: &str,
+        diff: &str,
+        retry: &RetryConfig,
+        timeout: Duration,
+    ) -> Result<(&str, String), ModelError> {
+        self.prompt_models(preamble, prompt, diff, retry, timeout, None)
+            .await
+    }
+
+    /// Like [ModelChain::prompt], but sends the response so far to
+    /// [ModelChain::subscribe_partial]'s receivers as it's generated. It
+    /// starts over whenever a model is retried or fallen back from.
+    pub async fn prompt_streaming(
+        &self,
+        preamble: &str,
+        prompt: &str,
+        diff: &str,
+        retry: &RetryConfig,
+        timeout: Duration,
+    ) -> Result<(&str, String), ModelError> {
+        self.prompt_models(preamble, prompt, diff, retry, timeout, Some(&self.partial))
+            .await
+    }
+
+    async fn prompt_models(
+        &self,
+        preamble: &str,
+        prompt: &str,
+        diff: &str,
+        retry: &RetryConfig,
+        timeout: Duration,
+        partial: Option<&watch::Sender<String>>,
+    ) -> Result<(&str, String), ModelError> {
+        if let Some(budget) = self.budget
+            && usage::over_budget(budget)
+        {
+            return Err(ModelError::OverBudget(budget));
+        }
+
+        let mut last_err = None;
+
+        for (index, (model, summarizer)) in self.models.iter().enumerate() {
+            model.clone_into(
+                &mut self
+                    .answering
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+
+            let attempt =
+                prompt_with_retry(summarizer.as_ref(), preamble, prompt, diff, retry, partial);
+
+            // everything logged while prompting says which model it's about
+            let span = tracing::debug_span!("prompt", model = model.as_str());
+            let started = Instant::now();
+
+            let result = tokio::time::timeout(timeout, attempt)
+                .instrument(span.clone())
+                .await;
+
+            let elapsed = started.elapsed();
+
+            span.in_scope(|| {
+                tracing::debug!(
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    ok = matches!(result, Ok(Ok(_))),
+                    "prompt finished"
+                );
+            });
+
+            let err = match result {
+                Ok(Ok(response)) => {
+                    *self
+                        .last_prompt
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner) = Some((model.clone(), elapsed));
+
+                    return Ok((model, response));
+                }
+                Ok(Err(err)) => err,
+                Err(_) => ModelError::TimedOut(timeout),
+            };
+
+            if let Some((next, _)) = self.models.get(index + 1) {
+                tracing::warn!(
+                    "{model} failed, falling back to {next}: {}",
+                    crate::error_chain(&err)
+                );
+            }
+
+            last_err = Some(err);
+        }
+
+        match last_err {
+            Some(err) if self.models.len() > 1 => Err(ModelError::AllFailed {
+                models: self.models.len(),
+                last: Box::new(err),
+            }),
+            Some(err) => Err(err),
+            None => Err(ModelError::NoModels),
+        }
+    }
+}
+
+/// A prebuilt agent for one model of whichever provider is configured, so
+/// that the main loop doesn't have to care which one is live.
+pub enum SummaryAgent {
+    Ollama(Agent<ollama::CompletionModel>),
+    OpenAI(Agent<openai::responses_api::ResponsesCompletionModel>),
+    OpenAICompatible(Agent<openai::CompletionModel>),
+    Anthropic(Agent<anthropic::completion::CompletionModel>),
+}
+
+impl SummaryAgent {
+    pub fn new(config: &AgentConfig, model: &str) -> anyhow::Result<Self> {
+        let base_url = config.base_url()?;
+
+        match config.provider {
+            Provider::Ollama => {
+                let http_client = reqwest::Client::builder()
+                    .default_headers(config.auth_headers()?)
+                    .build()
+                    .context("failed to create HTTP client")?;
+
+                let mut builder = ollama::Client::<reqwest::Client>::builder()
+                    .api_key(Nothing)
+                    .http_client(http_client);
+
+                if let Some(base_url) = &base_url {
+                    builder = builder.base_url(base_url.as_str().trim_end_matches('/'));
+                }
+
+                let client = builder.build().context("failed to create Ollama client")?;
+                Ok(Self::Ollama(build_agent(&client, config, model)))
+            }
+            Provider::OpenAI => {
+                let api_key = config
+                    .api_key()
+                    .context("the openai provider needs agent.api_key or OPENAI_API_KEY")?;
+
+                let mut builder = openai::Client::builder().api_key(api_key);
+
+                if let Some(base_url) = &base_url {
+                    builder = builder.base_url(base_url.as_str().trim_end_matches('/'));
+                }
+
+                let client = builder.build().context("failed to create OpenAI client")?;
+                Ok(Self::OpenAI(build_agent(&client, config, model)))
+            }
+            Provider::OpenAICompatible => {
+                let base_url =
+                    base_url.context("the openai-compatible provider needs agent.base_url")?;
+
+                // local servers often don't check the key at all
+                let api_key = config.api_key().unwrap_or_default();
+
+                let client = openai::CompletionsClient::builder()
+                    .api_key(api_key)
+                    .base_url(base_url.as_str().trim_end_matches('/'))
+                    .build()
+                    .context("failed to create OpenAI-compatible client")?;
+
+                Ok(Self::OpenAICompatible(build_agent(&client, config, model)))
+            }
+            Provider::Anthropic => {
+                let api_key = config
+                    .api_key()
+                    .context("the anthropic provider needs agent.api_key or ANTHROPIC_API_KEY")?;
+
+                let mut builder = anthropic::Client::builder().api_key(api_key);
+
+                if let Some(base_url) = &base_url {
+                    builder = builder.base_url(base_url.as_str().trim_end_matches('/'));
+                }
+
+                let client = builder
+                    .build()
+                    .context("failed to create Anthropic client")?;
+
+                Ok(Self::Anthropic(build_agent(&client, config, model)))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Summarizer for SummaryAgent {
+    async fn summarize(
+        &self,
+        preamble: &str,
+        prompt: &str,
+        diff: &str,
+    ) -> Result<String, ModelError> {
+        let response = match self {
+            Self::Ollama(agent) => {
+                let prompt = diff_prompt(diff, prompt);
+                prompt_response(&with_preamble(agent, preamble), &agent.model.model, prompt).await
+            }
+            Self::OpenAI(agent) => {
+                let prompt = diff_prompt(diff, prompt);
+                prompt_response(&with_preamble(agent, preamble), &agent.model.model, prompt).await
+            }
+            Self::OpenAICompatible(agent) => {
+                let prompt = diff_prompt(diff, prompt);
+                prompt_response(&with_preamble(agent, preamble), &agent.model.model, prompt).await
+            }
+            // Anthropic only takes PDF documents
+            Self::Anthropic(agent) => {
+                let prompt = diff_text_prompt(diff, prompt);
+                prompt_response(&with_preamble(agent, preamble), &agent.model.model, prompt).await
+            }
+        };
+
+        response.map_err(ModelError::from)
+    }
+
+    async fn summarize_streaming(
+        &self,
+        preamble: &str,
+        prompt: &str,
+        diff: &str,
+        partial: &watch::Sender<String>,
+    ) -> Result<String, ModelError> {
+        let response = match self {
+            Self::Ollama(agent) => {
+                let prompt = diff_prompt(diff, prompt);
+                let model = &agent.model.model;
+                stream_response(&with_preamble(agent, preamble), model, prompt, partial).await
+            }
+            Self::OpenAI(agent) => {
+                let prompt = diff_prompt(diff, prompt);
+                let model = &agent.model.model;
+                stream_response(&with_preamble(agent, preamble), model, prompt, partial).await
+            }
+            Self::OpenAICompatible(agent) => {
+                let prompt = diff_prompt(diff, prompt);
+                let model = &agent.model.model;
+                stream_response(&with_preamble(agent, preamble), model, prompt, partial).await
+            }
+            Self::Anthropic(agent) => {
+                let prompt = diff_text_prompt(diff, prompt);
+                let model = &agent.model.model;
+                stream_response(&with_preamble(agent, preamble), model, prompt, partial).await
+            }
+        };
+
+        response.map_err(ModelError::from)
+    }
+}
+
+/// Prompts `agent`, counting the tokens it used towards `model`'s
+/// [usage](crate::usage).
+async fn prompt_response<M: CompletionModel>(
+    agent: &Agent<M>,
+    model: &str,
+    prompt: Message,
+) -> Result<String, PromptError> {
+    let response = agent.prompt(prompt).extended_details().await?;
+    usage::record(model, response.total_usage);
+    Ok(response.output)
+}
+
+/// Prompts `agent` with a streamed response, sending all of its text so far
+/// to `partial` as each piece comes in.
+async fn stream_response<M: CompletionModel>(
+    agent: &Agent<M>,
+    model: &str,
+    prompt: Message,
+    partial: &watch::Sender<String>,
+) -> Result<String, PromptError> {
+    let mut stream = agent
+        .stream_completion(prompt, Vec::new())
+        .await?
+        .stream()
+        .await?;
+
+    let mut response = String::new();
+    partial.send_replace(String::new());
+
+    while let Some(item) = stream.next().await {
+        match item? {
+            StreamedAssistantContent::Text(text) => {
+                response.push_str(&text.text);
+                partial.send_replace(response.clone());
+            }
+            StreamedAssistantContent::Final(end) => {
+                if let Some(tokens) = end.token_usage() {
+                    usage::record(model, tokens);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(response)
+}
+
+/// Copies an agent with its system prompt swapped out, which is cheap since
+/// the model itself is shared.
+fn with_preamble<M: CompletionModel>(agent: &Agent<M>, preamble: &str) -> Agent<M> {
+    let mut agent = agent.clone();
+    agent.preamble = Some(preamble.to_string());
+    agent
+}
+
+/// Prompts the summarizer, retrying transient failures with exponential
+/// backoff. The response is streamed to `partial` when it's given.
+pub async fn prompt_with_retry(
+    summarizer: &dyn Summarizer,
+    preamble: &str,
+    prompt: &str,
+    diff: &str,
+    retry: &RetryConfig,
+    partial: Option<&watch::Sender<String>>,
+) -> Result<String, ModelError> {
+    let mut delay = retry.base_delay;
+    let mut attempt = 1;
+
+    loop {
+        let started = Instant::now();
+
+        let result = match partial {
+            Some(partial) => {
+                summarizer
+                    .summarize_streaming(preamble, prompt, diff, partial)
+                    .await
+            }
+            None => summarizer.summarize(preamble, prompt, diff).await,
+        };
+
+        metrics::PROMPTS.inc();
+        metrics::PROMPT_DURATION.observe(started.elapsed());
+
+        if result.is_err() {
+            metrics::PROMPT_ERRORS.inc();
+        }
+
+        match result {
+            Ok(response) => return Ok(response),
+            Err(err) if attempt < retry.max_attempts && err.is_transient() => {
+                tracing::warn!(
+                    "prompt attempt {attempt} failed, retrying in {}: {}",
+                    humantime::format_duration(delay),
+                    crate::error_chain(&err)
+                );
+
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The text up to and including the first `.`, `!`, `?`, or `…` that's
+/// followed by whitespace or the end, which leaves alone the dots in things
+/// like `v1.2` and `config.rs`. All of it without one.
+fn first_sentence(text: &str) -> &str {
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        let ends = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+
+        if matches!(c, '.' | '!' | '?' | '…') && ends {
+            return &text[..index + c.len_utf8()];
+        }
+    }
+
+    text
+}
+
+/// An error from prompting the models, which says whether it's worth trying
+/// again.
+#[derive(Debug)]
+pub enum ModelError {
+    /// The provider rejected the API key, with its status code.
+    Unauthorized(u16, PromptError),
+
+    /// The provider is rate limiting requests.
+    RateLimited(PromptError),
+
+    /// Any other failure of the model or the request for it.
+    Prompt(PromptError),
+
+    /// The prompt didn't finish in time, retries included.
+    TimedOut(Duration),
+
+    /// None of the models answered, and this is how the last one failed.
+    AllFailed {
+        models: usize,
+        last: Box<ModelError>,
+    },
+
+    /// There are no models to prompt.
+    NoModels,
+
+    /// Today's [daily_token_budget](AgentConfig::daily_token_budget) is
+    /// used up.
+    OverBudget(u64),
+}
+
+impl ModelError {
+    /// Whether the prompt is worth retrying, i.e. it looks like a network
+    /// hiccup or an overloaded server rather than a configuration problem.
+    pub fn is_transient(&self) -> bool {
+        use rig::http_client::Error as HttpError;
+
+        match self {
+            ModelError::RateLimited(_) => true,
+            ModelError::Prompt(PromptError::CompletionError(CompletionError::HttpError(err))) => {
+                match err {
+                    HttpError::Instance(_) | HttpError::StreamEnded => true,
+                    HttpError::InvalidStatusCode(status)
+                    | HttpError::InvalidStatusCodeWithMessage(status, _) => {
+                        status.is_server_error()
+                    }
+                    _ => false,
+                }
+            }
+            ModelError::AllFailed { last, .. } => last.is_transient(),
+            ModelError::Unauthorized(..)
+            | ModelError::Prompt(_)
+            | ModelError::TimedOut(_)
+            | ModelError::NoModels
+            | ModelError::OverBudget(_) => false,
+        }
+    }
+}
+
+/// Sorts out rejected API keys and rate limits, which otherwise only read as
+/// a status code.
+impl From<PromptError> for ModelError {
+    fn from(err: PromptError) -> Self {
+        use rig::http_client::Error as HttpError;
+
+        let status = match &err {
+            PromptError::CompletionError(CompletionError::HttpError(
+                HttpError::InvalidStatusCode(status)
+                | HttpError::InvalidStatusCodeWithMessage(status, _),
+            )) => Some(status.as_u16()),
+            _ => None,
+        };
+
+        match status {
+            Some(status @ (401 | 403)) => ModelError::Unauthorized(status, err),
+            Some(429) => ModelError::RateLimited(err),
+            _ => ModelError::Prompt(err),
+        }
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Unauthorized(status, _) => {
+                write!(f, "the provider rejected the API key ({status})")
+            }
+            ModelError::RateLimited(_) => write!(f, "the provider is rate limiting requests (429)"),
+            ModelError::Prompt(err) => write!(f, "{err}"),
+            ModelError::TimedOut(timeout) => write!(
+                f,
+                "prompt timed out after {}",
+                humantime::format_duration(*timeout)
+            ),
+            ModelError::AllFailed { models, .. } => write!(f, "all {models} models failed"),
+            ModelError::NoModels => write!(f, "no models are configured"),
+            ModelError::OverBudget(budget) => write!(
+                f,
+                "the daily budget of {budget} tokens is used up until tomorrow"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ModelError::Unauthorized(_, err) | ModelError::RateLimited(err) => Some(err),
+            // what rig failed with is already the message
+            ModelError::Prompt(err) => err.source(),
+            ModelError::AllFailed { last, .. } => Some(last.as_ref()),
+            ModelError::TimedOut(_) | ModelError::NoModels | ModelError::OverBudget(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// How many agents [build_agent] has built on this thread.
+    static AGENTS_BUILT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn build_agent<C: CompletionClient>(
+    client: &C,
+    config: &AgentConfig,
+    model: &str,
+) -> Agent<C::CompletionModel> {
+    #[cfg(test)]
+    AGENTS_BUILT.with(|built| built.set(built.get() + 1));
+
+    let params = &config.params;
+
+    for name in params.unsupported.keys() {
+        tracing::warn!("ignoring unsupported model parameter agent.params.{name}");
+    }
+
+    let mut builder = client.agent(model).preamble(&config.preamble);
+
+    if let Some(temperature) = params.temperature {
+        builder = builder.temperature(temperature);
+    }
+
+    // the Responses API is the only one rig passes `max_tokens` through to,
+    // besides Anthropic's, which needs it
+    match (config.provider, params.max_tokens) {
+        (Provider::OpenAI, Some(max_tokens)) => builder = builder.max_tokens(max_tokens),
+        (Provider::Anthropic, max_tokens) => {
+            builder = builder.max_tokens(max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS));
+        }
+        _ => {}
+    }
+
+    if let Some(additional) = params.additional_params(config.provider) {
+        builder = builder.additional_params(additional);
+    }
+
+    builder.build()
+}
+
+/// The most tokens asked of Anthropic's models without
+/// [ModelParams::max_tokens], which it requires. Summaries and commit messages
+/// are far shorter.
+///
+/// [ModelParams::max_tokens]: crate::config::ModelParams::max_tokens
+const ANTHROPIC_MAX_TOKENS: u64 = 1024;
+
+/// The throwaway prompt [warm_up] sends, kept tiny so that it's over as soon
+/// as the model is loaded.
+const WARMUP_PROMPT: &str = "Reply with one word.";
+
+/// Sends the first model a throwaway prompt and discards the answer, so that
+/// a local model is loaded into memory by the time the first real prompt
+/// comes in, rather than making the first summary wait for it.
+pub async fn warm_up(config: AgentConfig) {
+    let Some(model) = config.model.first() else {
+        return;
+    };
+
+    if config.daily_token_budget.is_some_and(usage::over_budget) {
+        return;
+    }
+
+    let agent = match SummaryAgent::new(&config, model) {
+        Ok(agent) => agent,
+        Err(err) => {
+            tracing::warn!("failed to warm up {model}: {err:#}");
+            return;
+        }
+    };
+
+    let started = Instant::now();
+    let warmup = agent.summarize("", WARMUP_PROMPT, "");
+
+    match tokio::time::timeout(config.prompt_timeout, warmup).await {
+        Ok(Ok(_)) => tracing::info!(
+            model,
+            latency_ms = started.elapsed().as_millis() as u64,
+            "warmed up the model"
+        ),
+        Ok(Err(err)) => {
+            tracing::warn!("failed to warm up {model}: {}", crate::error_chain(&err));
+        }
+        Err(_) => tracing::warn!(
+            "timed out warming up {model} after {:?}",
+            config.prompt_timeout
+        ),
+    }
+}
+
+/// How long to wait for Ollama to list models.
+const OLLAMA_LIST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lists the models Ollama has pulled, from its `/api/tags` endpoint.
+pub async fn pulled_models(config: &AgentConfig) -> anyhow::Result<Vec<String>> {
+    ollama_models(config, "tags").await
+}
+
+/// Lists the models Ollama has loaded into memory, from its `/api/ps`
+/// endpoint. They're named like [pulled_models]' are.
+pub async fn loaded_models(config: &AgentConfig) -> anyhow::Result<Vec<String>> {
+    ollama_models(config, "ps").await
+}
+
+/// Lists the models from one of Ollama's endpoints, which all answer alike.
+async fn ollama_models(config: &AgentConfig, endpoint: &str) -> anyhow::Result<Vec<String>> {
+    let base_url = config.base_url()?.map_or_else(
+        || "http://localhost:11434".to_string(),
+        |url| url.to_string(),
+    );
+
+    let client = reqwest::Client::builder()
+        .timeout(OLLAMA_LIST_TIMEOUT)
+        .default_headers(config.auth_headers()?)
+        .build()
+        .context("failed to create HTTP client")?;
+
+    let response = client
+        .get(format!("{}/api/{endpoint}", base_url.trim_end_matches('/')))
+        .send()
+        .await?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        anyhow::bail!("Ollama answered with {status}");
+    }
+
+    let tags: serde_json::Value = serde_json::from_str(&response.text().await?)
+        .context("Ollama's list of models isn't valid JSON")?;
+
+    Ok(tags["models"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|tag| tag["name"].as_str())
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+/// Whether `model` is one of the `pulled` ones. Models without a tag are the
+/// same as `:latest`.
+pub fn is_pulled(pulled: &[String], model: &str) -> bool {
+    pulled
+        .iter()
+        .any(|name| name == model || name.strip_suffix(":latest") == Some(model))
+}
+
+/// With Ollama, leaves out the models that haven't been pulled, warning about
+/// each one, so that they don't fail every prompt before falling back. All of
+/// them are kept if none have been pulled or Ollama can't be asked, since
+/// they might be by the time they're prompted.
+pub async fn skip_missing_models(config: &mut AgentConfig) {
+    let Provider::Ollama = config.provider else {
+        return;
+    };
+
+    let pulled = match pulled_models(config).await {
+        Ok(pulled) => pulled,
+        Err(err) => {
+            tracing::warn!("couldn't check which models Ollama has: {err:#}");
+            return;
+        }
+    };
+
+    let (found, missing): (Vec<_>, Vec<_>) = config
+        .model
+        .iter()
+        .cloned()
+        .partition(|model| is_pulled(&pulled, model));
+
+    for model in &missing {
+        tracing::warn!("model '{model}' not found; run `ollama pull {model}`");
+    }
+
+    if found.is_empty() || missing.is_empty() {
+        return;
+    }
+
+    tracing::warn!("summarizing with {} instead", found.join(", "));
+    config.model = found;
+}
+
+/// Identifies a summary in the [ResponseCache](crate::cache::ResponseCache)
+/// by everything that goes into the prompt, with the preamble and prompt
+/// already filled in, and the output of [AgentConfig::context_command] if
+/// there is one. The model's parameters and how much of the diff it's given
+/// count too, since they change the answer.
+pub fn cache_key(
+    diff: &str,
+    command_output: Option<&str>,
+    agent: &AgentConfig,
+    preamble: &str,
+    prompt: &str,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    diff.hash(&mut hasher);
+    command_output.hash(&mut hasher);
+    agent.provider.hash(&mut hasher);
+    agent.base_url.hash(&mut hasher);
+    agent.model.hash(&mut hasher);
+    agent.summary_source.hash(&mut hasher);
+    agent.max_diff_bytes.hash(&mut hasher);
+    // the parameters have floats, which don't hash
+    serde_json::to_string(&agent.params)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    preamble.hash(&mut hasher);
+    prompt.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Cleans up model responses so that only the answer itself is shown.
+pub struct ResponseFilter {
+    patterns: Vec<regex::Regex>,
+    code_fences: bool,
+    separator: String,
+    mode: ResponseMode,
+}
+
+impl ResponseFilter {
+    pub fn new(config: &AgentConfig) -> anyhow::Result<Self> {
+        let patterns = config
+            .strip_patterns
+            .iter()
+            .map(|pattern| {
+                regex::Regex::new(pattern)
+                    .with_context(|| format!("invalid strip pattern {pattern:?}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            patterns,
+            code_fences: config.strip_code_fences,
+            separator: config.line_separator.clone(),
+            mode: config.response_mode,
+        })
+    }
+
+    /// Only removes everything matching the strip patterns and unwraps a
+    /// fenced code block, keeping the response's lines as they are.
+    pub fn strip(&self, response: &str) -> String {
+        let mut response = response.to_string();
+
+        for pattern in &self.patterns {
+            response = pattern.replace_all(&response, "").into_owned();
+        }
+
+        if self.code_fences {
+            response = strip_code_fence(&response).to_string();
+        }
+
+        response
+    }
+
+    /// Removes everything matching the strip patterns and collapses the rest
+    /// onto a single line, since Discord only shows one. Each line loses its
+    /// list marker, a label like "Summary:", and extra whitespace, and the
+    /// lines are joined with the configured separator, unless the
+    /// [ResponseMode] keeps less. Quotes around the whole answer go too.
+    pub fn filter(&self, response: &str) -> String {
+        let mut response = self.strip(response);
+
+        // markdown isn't rendered, so `code` would keep its backticks
+        if self.code_fences {
+            response.retain(|c| c != '`');
+        }
+
+        let mut lines = response
+            .lines()
+            .map(|line| {
+                strip_label(strip_list_marker(line))
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|line| !line.is_empty());
+
+        let response = match self.mode {
+            ResponseMode::Full => lines.collect::<Vec<_>>().join(&self.separator),
+            ResponseMode::FirstLine => lines.next().unwrap_or_default(),
+            ResponseMode::FirstSentence => {
+                // a sentence can carry on over a line break
+                let text = lines.collect::<Vec<_>>().join(" ");
+                first_sentence(&text).to_string()
+            }
+        };
+
+        strip_quotes(&response).to_string()
+    }
+}
+
+/// What models like to start their answer with, before the answer itself.
+const LABELS: &[&str] = &["summary", "answer", "response", "status", "update"];
+
+/// Trims a label like "Summary:" or "**Summary:**" off the start of a line.
+fn strip_label(line: &str) -> &str {
+    let unbolded = line.trim_start_matches('*');
+
+    let Some((label, rest)) = unbolded.split_once(':') else {
+        return line;
+    };
+
+    if !LABELS
+        .iter()
+        .any(|known| label.trim().eq_ignore_ascii_case(known))
+    {
+        return line;
+    }
+
+    rest.trim_start_matches('*').trim_start()
+}
+
+/// Unwraps `response` from the quotes around it, when it's only one quote.
+fn strip_quotes(response: &str) -> &str {
+    for (open, close) in [('"', '"'), ('\'', '\''), ('“', '”'), ('‘', '’')] {
+        let Some(inner) = response
+            .strip_prefix(open)
+            .and_then(|rest| rest.strip_suffix(close))
+        else {
+            continue;
+        };
+
+        // "fix" and "test" are two quotes, not one
+        if !inner.contains([open, close]) {
+            return inner.trim();
+        }
+    }
+
+    response
+}
+
+/// Unwraps `response` from a markdown code block fenced with ```, dropping
+/// the language after the opening fence, like ```text. A fence left on only
+/// one end is trimmed off too.
+fn strip_code_fence(response: &str) -> &str {
+    let response = response.trim();
+
+    let response = match response.strip_prefix("```") {
+        // the opening fence's line only has the language on it
+        Some(rest) => match rest.split_once('\n') {
+            Some((language, body)) if !language.trim().contains(' ') => body,
+            _ => rest,
+        },
+        None => response,
+    };
+
+    let response = response.trim_end();
+    response.strip_suffix("```").unwrap_or(response).trim()
+}
+
+/// Trims a leading bullet like `-`, `*`, or `•`, or a number like `1.` or
+/// `2)`, off a line of a list.
+fn strip_list_marker(line: &str) -> &str {
+    let line = line.trim_start();
+
+    let rest = match line.strip_prefix(['-', '*', '+', '•']) {
+        Some(rest) => rest,
+        None => {
+            let digits = line.trim_start_matches(|c: char| c.is_ascii_digit());
+
+            match digits.strip_prefix(['.', ')']) {
+                Some(rest) if digits.len() < line.len() => rest,
+                _ => return line,
+            }
+        }
+    };
+
+    // a marker is only one when it's followed by a space, unlike `-5%` or `*.rs`
+    if rest.starts_with(char::is_whitespace) {
+        rest
+    } else {
+        line
+    }
+}
+
+/// What files with these extensions are, for [file_type_hint], on top of
+/// [AgentConfig::file_types].
+const FILE_TYPES: &[(&str, &str)] = &[
+    ("c", "C source files"),
+    ("cpp", "C++ source files"),
+    ("cs", "C# source files"),
+    ("css", "stylesheets"),
+    ("go", "Go source files"),
+    ("h", "C headers"),
+    ("hpp", "C++ headers"),
+    ("html", "HTML pages"),
+    ("java", "Java source files"),
+    ("js", "JavaScript source files"),
+    ("json", "JSON data"),
+    ("jsx", "JavaScript source files"),
+    ("kt", "Kotlin source files"),
+    ("lua", "Lua scripts"),
+    ("md", "documentation"),
+    ("nix", "Nix expressions"),
+    ("php", "PHP source files"),
+    ("py", "Python source files"),
+    ("rb", "Ruby source files"),
+    ("rs", "Rust source files"),
+    ("rst", "documentation"),
+    ("sh", "shell scripts"),
+    ("sql", "SQL"),
+    ("swift", "Swift source files"),
+    ("toml", "configuration"),
+    ("ts", "TypeScript source files"),
+    ("tsx", "TypeScript source files"),
+    ("yaml", "configuration"),
+    ("yml", "configuration"),
+    ("zig", "Zig source files"),
+];
+
+/// A command that runs `command` with the platform's shell, `sh -c` or
+/// `cmd /C`.
+pub fn shell_command(command: &str) -> tokio::process::Command {
+    let mut process = if cfg!(windows) {
+        let mut process = tokio::process::Command::new("cmd");
+        process.arg("/C");
+        process
+    } else {
+        let mut process = tokio::process::Command::new("sh");
+        process.arg("-c");
+        process
+    };
+
+    process.arg(command);
+    process
+}
+
+/// Runs [AgentConfig::context_command] in `repo`, or the current directory
+/// without one, for what it prints to stdout and then stderr. Output past
+/// [AgentConfig::context_max_bytes] is cut from the front, since builds and
+/// test runs end with their summaries. `None` without a command, or with a
+/// warning if it can't be run or doesn't finish in time.
+pub async fn run_context_command(config: &AgentConfig, repo: Option<&Path>) -> Option<String> {
+    let command = config.context_command.as_deref()?;
+
+    let mut process = shell_command(command);
+
+    process
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    if let Some(repo) = repo {
+        process.current_dir(repo);
+    }
+
+    let output = match process.spawn() {
+        Ok(child) => tokio::time::timeout(config.context_timeout, child.wait_with_output()).await,
+        Err(err) => {
+            tracing::warn!("failed to run agent.context_command: {err}");
+            return None;
+        }
+    };
+
+    let output = match output {
+        Ok(Ok(output)) => output,
+        Ok(Err(err)) => {
+            tracing::warn!("failed to run agent.context_command: {err}");
+            return None;
+        }
+        Err(_) => {
+            tracing::warn!(
+                "agent.context_command didn't finish within {}, leaving it out",
+                humantime::format_duration(config.context_timeout)
+            );
+            return None;
+        }
+    };
+
+    // failing builds and tests are exactly what's worth knowing about, so
+    // their output is kept too, along with the exit status
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    let text = text.trim();
+
+    if text.is_empty() {
+        return None;
+    }
+
+    let mut start = text.len().saturating_sub(config.context_max_bytes);
+
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+
+    let mut out = format!("Output of `{command}` ({}):\n", output.status);
+
+    if start > 0 {
+        out.push_str("...\n");
+    }
+
+    out.push_str(&text[start..]);
+    Some(out)
+}
+
+/// A sentence telling the model what kinds of files `diff` mostly changes,
+/// like "This is a change to Rust source files.", or `None` when no kind of
+/// file makes up at least a third of them.
+pub fn file_type_hint(diff: &str, config: &AgentConfig) -> Option<String> {
+    if !config.file_type_hints {
+        return None;
+    }
+
+    let paths = changed_paths(diff);
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+
+    for path in &paths {
+        let Some(extension) = Path::new(path).extension().and_then(OsStr::to_str) else {
+            continue;
+        };
+
+        let extension = extension.to_lowercase();
+
+        let kind = match config.file_types.get(&extension) {
+            Some(kind) => kind.as_str(),
+            None => match FILE_TYPES.iter().find(|(ext, _)| *ext == extension) {
+                Some((_, kind)) => kind,
+                None => continue,
+            },
+        };
+
+        *counts.entry(kind).or_default() += 1;
+    }
+
+    let mut dominant: Vec<(&str, usize)> = counts
+        .into_iter()
+        .filter(|(kind, count)| !kind.is_empty() && count * 3 >= paths.len())
+        .collect();
+
+    // the most common first, and ties in alphabetical order
+    dominant.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    let kinds: Vec<&str> = dominant.into_iter().take(2).map(|(kind, _)| kind).collect();
+
+    (!kinds.is_empty()).then(|| format!("This is a change to {}.", kinds.join(" and ")))
+}
+
+/// The placeholders available in the prompt and preamble, which are those of
+/// the presence's lines along with `{file_list}`, the changed files one per
+/// line.
+pub fn prompt_vars(metadata: &DiffMetadata) -> Vec<(&'static str, String)> {
+    let mut vars = line_vars(metadata).to_vec();
+    vars.push(("file_list", metadata.file_list.clone()));
+    vars
+}
+
+/// Builds the per-tick prompt message, attaching the diff as a document ahead
+/// of the configured prompt so that the agent itself never has to be rebuilt.
+pub fn diff_prompt(diff: &str, prompt: &str) -> Message {
+    let mut content = OneOrMany::one(UserContent::document(diff, Some(DocumentMediaType::TXT)));
+    content.push(UserContent::text(prompt));
+    Message::User { content }
+}
+
+/// Builds the per-tick prompt message like [diff_prompt], but with the diff
+/// as plain text in `<diff>` tags, for providers that don't take text
+/// documents.
+pub fn diff_text_prompt(diff: &str, prompt: &str) -> Message {
+    let mut content = OneOrMany::one(UserContent::text(format!("<diff>\n{diff}\n</diff>")));
+    content.push(UserContent::text(prompt));
+    Message::User { content }
+}
+
+/// Puts together what the model is given about a diff: the lines of
+/// `header`, like the size of the change, then the diff, or only its `stat`
+/// when `stat_only`, then `command_output`. All of it comes to at most
+/// `max_bytes`. The diff is cut down with [cap_diff] to make room for the
+/// rest, unless the rest would take more than half, in which case the
+/// command's output and then the header are left out instead.
+pub fn diff_context(
+    diff: &str,
+    stat: &str,
+    stat_only: bool,
+    header: &[String],
+    command_output: Option<&str>,
+    max_bytes: usize,
+) -> String {
+    let mut header = if header.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n\n", header.join("\n"))
+    };
+
+    let mut output = command_output
+        .map(|output| format!("\n\n{output}"))
+        .unwrap_or_default();
+
+    // without a stat, like for a diff piped in, there's only the diff to go on
+    let stat_only = stat_only && !stat.trim().is_empty();
+
+    let body = if stat_only {
+        format!("NOTE: only the stat of this diff is given, not its contents.\n\n{stat}")
+    } else {
+        diff.to_string()
+    };
+
+    if header.len() + body.len() + output.len() <= max_bytes {
+        return format!("{header}{body}{output}");
+    }
+
+    if header.len() + output.len() > max_bytes / 2 {
+        output.clear();
+    }
+
+    if header.len() > max_bytes / 2 {
+        header.clear();
+    }
+
+    let room = max_bytes - header.len() - output.len();
+
+    let body = if body.len() <= room {
+        body
+    } else if stat_only {
+        whole_lines(&body, room).to_string()
+    } else {
+        cap_diff(diff, stat, room)
+    };
+
+    format!("{header}{body}{output}")
+}
+
+/// The lines at the start of `text` that fit in `max_bytes`.
+fn whole_lines(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+
+    let end = (0..=max_bytes)
+        .rev()
+        .find(|&end| text.is_char_boundary(end))
+        .unwrap_or(0);
+
+    let end = text[..end].rfind('\n').map_or(0, |newline| newline + 1);
+    &text[..end]
+}
+
+/// Cuts an oversized diff down to at most `max_bytes`, keeping the `--stat`
+/// summary and as many whole leading hunks as fit. The result opens with a
+/// note so the model knows it isn't seeing the complete change, and is empty
+/// if not even that fits.
+pub fn cap_diff(diff: &str, stat: &str, max_bytes: usize) -> String {
+    let mut out =
+        format!("NOTE: this diff was truncated to fit in {max_bytes} bytes and is incomplete.\n\n");
+
+    if out.len() > max_bytes {
+        return String::new();
+    }
+
+    for line in stat.split_inclusive('\n') {
+        if out.len() + line.len() > max_bytes {
+            return out;
+        }
+
+        out.push_str(line);
+    }
+
+    if out.len() + 1 > max_bytes {
+        return out;
+    }
+
+    out.push('\n');
+
+    // split into file headers and hunks, each of which is kept or dropped whole
+    let mut pieces: Vec<&str> = Vec::new();
+    let mut start = 0;
+
+    for (idx, _) in diff.match_indices('\n') {
+        let next = idx + 1;
+        let rest = &diff[next..];
+
+        if rest.starts_with("diff --git ") || rest.starts_with("@@") {
+            pieces.push(&diff[start..next]);
+            start = next;
+        }
+    }
+
+    pieces.push(&diff[start..]);
+
+    let mut pending_header: Option<&str> = None;
+
+    for piece in pieces {
+        if piece.starts_with("@@") {
+            let header = pending_header.unwrap_or("");
+
+            if out.len() + header.len() + piece.len() > max_bytes {
+                break;
+            }
+
+            out.push_str(header);
+            out.push_str(piece);
+            pending_header = None;
+        } else {
+            pending_header = Some(piece);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use super::*;
+    use crate::config::tests::default_config;
+
+    /// A [Summarizer] that gives canned responses in order, after `delay`,
+    /// keeping track of what it was asked.
+    pub struct MockSummarizer {
+        responses: Mutex<VecDeque<Result<String, ModelError>>>,
+
+        /// What to answer once the responses run out.
+        then: Option<String>,
+
+        delay: Duration,
+        calls: Arc<AtomicUsize>,
+        diffs: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockSummarizer {
+        pub fn new(responses: impl IntoIterator<Item = Result<String, ModelError>>) -> Self {
+            Self {
+                responses: Mutex::new(responses.into_iter().collect()),
+                then: None,
+                delay: Duration::ZERO,
+                calls: Arc::default(),
+                diffs: Arc::default(),
+            }
+        }
+
+        /// Answers `response` every time.
+        pub fn answering(response: &str) -> Self {
+            Self {
+                then: Some(response.to_string()),
+                ..Self::new([])
+            }
+        }
+
+        /// Takes `delay` over each response.
+        pub fn delayed(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+
+        /// Counts the prompts, even once this is boxed up in a [ModelChain].
+        pub fn calls(&self) -> Arc<AtomicUsize> {
+            self.calls.clone()
+        }
+
+        /// Keeps the diffs of the prompts in the same way.
+        pub fn diffs(&self) -> Arc<Mutex<Vec<String>>> {
+            self.diffs.clone()
+        }
+
+        /// Puts this in a chain of its own, as `model`.
+        pub fn chain(self, model: &str) -> ModelChain {
+            ModelChain::with_summarizers(vec![(model.to_string(), Box::new(self))])
+        }
+    }
+
+    #[async_trait]
+    impl Summarizer for MockSummarizer {
+        async fn summarize(
+            &self,
+            _preamble: &str,
+            _prompt: &str,
+            diff: &str,
+        ) -> Result<String, ModelError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.diffs.lock().unwrap().push(diff.to_string());
+            tokio::time::sleep(self.delay).await;
+
+            let response = self.responses.lock().unwrap().pop_front();
+
+            match (response, &self.then) {
+                (Some(response), _) => response,
+                (None, Some(then)) => Ok(then.clone()),
+                (None, None) => Err(fatal("out of responses")),
+            }
+        }
+    }
+
+    /// An error that's worth retrying.
+    pub fn transient() -> ModelError {
+        ModelError::RateLimited(PromptError::CompletionError(
+            CompletionError::ProviderError("slow down".into()),
+        ))
+    }
+
+    /// An error that isn't.
+    pub fn fatal(message: &str) -> ModelError {
+        ModelError::Prompt(PromptError::CompletionError(
+            CompletionError::ProviderError(message.into()),
+        ))
+    }
+
+    fn retry(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let mock = MockSummarizer::new([Err(transient()), Err(transient()), Ok("Fixing".into())]);
+        let calls = mock.calls();
+        let chain = mock.chain("model");
+
+        let (model, response) = chain
+            .prompt("", "", "diff", &retry(3), TIMEOUT)
+            .await
+            .unwrap();
+
+        assert_eq!((model, response.as_str()), ("model", "Fixing"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let mock = MockSummarizer::new([Err(transient()), Err(transient()), Ok("Fixing".into())]);
+        let calls = mock.calls();
+        let chain = mock.chain("model");
+
+        let result = chain.prompt("", "", "diff", &retry(2), TIMEOUT).await;
+
+        assert!(matches!(result, Err(ModelError::RateLimited(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_other_errors() {
+        let mock = MockSummarizer::new([Err(fatal("no such model")), Ok("Fixing".into())]);
+        let calls = mock.calls();
+        let chain = mock.chain("model");
+
+        let result = chain.prompt("", "", "diff", &retry(3), TIMEOUT).await;
+
+        assert!(matches!(result, Err(ModelError::Prompt(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn times_out_a_stuck_model() {
+        let chain = MockSummarizer::answering("Fixing")
+            .delayed(Duration::from_secs(60))
+            .chain("model");
+
+        let timeout = Duration::from_millis(20);
+        let result = chain.prompt("", "", "diff", &retry(3), timeout).await;
+
+        assert!(matches!(result, Err(ModelError::TimedOut(t)) if t == timeout));
+        assert!(!result.unwrap_err().is_transient());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_model() {
+        let primary = MockSummarizer::new([Err(fatal("no such model"))]);
+        let fallback = MockSummarizer::answering("Fixing");
+        let fallback_calls = fallback.calls();
+
+        let chain = ModelChain::with_summarizers(vec![
+            ("primary".into(), Box::new(primary)),
+            ("fallback".into(), Box::new(fallback)),
+        ]);
+
+        let (model, response) = chain
+            .prompt("", "", "diff", &retry(3), TIMEOUT)
+            .await
+            .unwrap();
+
+        assert_eq!((model, response.as_str()), ("fallback", "Fixing"));
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            chain.take_last_prompt().map(|(model, _)| model).as_deref(),
+            Some("fallback")
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_from_a_model_that_times_out() {
+        let primary = MockSummarizer::answering("Too late").delayed(Duration::from_secs(60));
+        let fallback = MockSummarizer::answering("Fixing");
+
+        let chain = ModelChain::with_summarizers(vec![
+            ("primary".into(), Box::new(primary)),
+            ("fallback".into(), Box::new(fallback)),
+        ]);
+
+        let timeout = Duration::from_millis(20);
+        let (model, _) = chain
+            .prompt("", "", "diff", &retry(1), timeout)
+            .await
+            .unwrap();
+        assert_eq!(model, "fallback");
+    }
+
+    #[tokio::test]
+    async fn fails_when_every_model_does() {
+        let chain = ModelChain::with_summarizers(vec![
+            (
+                "primary".into(),
+                Box::new(MockSummarizer::new([Err(fatal("down"))])),
+            ),
+            (
+                "fallback".into(),
+                Box::new(MockSummarizer::new([Err(transient())])),
+            ),
+        ]);
+
+        let result = chain.prompt("", "", "diff", &retry(1), TIMEOUT).await;
+
+        let Err(ModelError::AllFailed { models, last }) = result else {
+            panic!("expected every model to fail, got {result:?}");
+        };
+
+        assert_eq!(models, 2);
+        assert!(matches!(*last, ModelError::RateLimited(_)));
+        assert!(chain.take_last_prompt().is_none());
+    }
+
+    #[test]
+    fn errors_say_what_caused_them() {
+        let err = ModelError::AllFailed {
+            models: 2,
+            last: Box::new(ModelError::TimedOut(Duration::from_secs(30))),
+        };
+
+        let last = std::error::Error::source(&err).unwrap();
+        assert_eq!(last.to_string(), "prompt timed out after 30s");
+
+        assert_eq!(
+            crate::error_chain(&err),
+            "all 2 models failed: prompt timed out after 30s"
+        );
+    }
+
+    #[tokio::test]
+    async fn builds_each_agent_once_for_every_prompt() {
+        let mut config = default_config().agent;
+        config.model = vec!["primary".into(), "fallback".into()];
+
+        // nothing listens there, so every prompt fails straight away
+        config.base_url = Some("http://127.0.0.1:9".into());
+
+        let built = || AGENTS_BUILT.with(std::cell::Cell::get);
+        let before = built();
+
+        let chain = ModelChain::new(&config).unwrap();
+        assert_eq!(built() - before, 2);
+
+        for _ in 0..5 {
+            let result = chain.prompt("", "", "diff", &retry(1), TIMEOUT).await;
+            assert!(result.is_err());
+        }
+
+        assert_eq!(built() - before, 2);
+    }
+
+    /// The request line of the first request made to `listener`, which is
+    /// hung up on without an answer.
+    async fn request_line(listener: tokio::net::TcpListener) -> String {
+        use tokio::io::AsyncBufReadExt;
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut line = String::new();
+        tokio::io::BufReader::new(stream)
+            .read_line(&mut line)
+            .await
+            .unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn agents_target_the_base_url_without_its_trailing_slash() {
+        for (provider, path, expected) in [
+            (
+                Provider::OpenAICompatible,
+                "/v1/",
+                "POST /v1/chat/completions ",
+            ),
+            (Provider::Ollama, "/", "POST /api/chat "),
+        ] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let mut config = default_config().agent;
+            config.provider = provider;
+            config.base_url = Some(format!("http://{addr}{path}"));
+
+            let agent = SummaryAgent::new(&config, "model").unwrap();
+            let prompting = agent.summarize("", "prompt", "diff");
+            let (line, result) = tokio::join!(request_line(listener), prompting);
+
+            assert!(
+                line.starts_with(expected),
+                "{provider:?} requested {line:?}"
+            );
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn agents_reject_malformed_base_urls() {
+        let mut config = default_config().agent;
+        config.base_url = Some("localhost:11434".into());
+
+        let err = SummaryAgent::new(&config, "model").err().unwrap();
+        let err = format!("{err:#}");
+        assert!(err.starts_with("agent.base_url: \"localhost:11434\" must be an http://"));
+
+        config.base_url = Some("http://".into());
+
+        let err = SummaryAgent::new(&config, "model").err().unwrap();
+        let err = format!("{err:#}");
+        assert!(err.starts_with("agent.base_url: \"http://\" is not a valid URL: "));
+    }
+
+    #[test]
+    fn prompts_fill_in_what_the_lines_do() {
+        let metadata = DiffMetadata {
+            repo: "samwise".into(),
+            files: 3,
+            file_list: "modified src/lib.rs".into(),
+            ..DiffMetadata::default()
+        };
+
+        let vars = prompt_vars(&metadata);
+
+        for var in line_vars(&metadata) {
+            assert!(vars.contains(&var), "{} isn't in the prompt", var.0);
+        }
+
+        let rendered = crate::template::render_template("{repo}: {files}\n{file_list}", &vars);
+        assert_eq!(rendered.unwrap(), "samwise: 3\nmodified src/lib.rs");
+    }
+
+    #[test]
+    fn openai_compatible_agents_need_a_base_url() {
+        let mut config = default_config().agent;
+        config.provider = Provider::OpenAICompatible;
+        config.base_url = None;
+
+        let err = SummaryAgent::new(&config, "model").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the openai-compatible provider needs agent.base_url"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_without_models() {
+        let chain = ModelChain::with_summarizers(Vec::new());
+        let result = chain.prompt("", "", "diff", &retry(1), TIMEOUT).await;
+        assert!(matches!(result, Err(ModelError::NoModels)));
+    }
+
+    fn filter() -> ResponseFilter {
+        ResponseFilter::new(&default_config().agent).unwrap()
+    }
+
+    #[test]
+    fn filter_cleans_up_responses() {
+        let cases = [
+            // already clean
+            ("Fixing the parser", "Fixing the parser"),
+            ("  Fixing the parser \n", "Fixing the parser"),
+            // fenced
+            ("```\nFixing the parser\n```", "Fixing the parser"),
+            ("```text\nFixing the parser\n```", "Fixing the parser"),
+            ("Fixing the parser```", "Fixing the parser"),
+            ("Fixing the `Parser` type", "Fixing the Parser type"),
+            // quoted
+            ("\"Fixing the parser\"", "Fixing the parser"),
+            ("'Fixing the parser'", "Fixing the parser"),
+            ("“Fixing the parser”", "Fixing the parser"),
+            ("\"Fixing\" the \"parser\"", "\"Fixing\" the \"parser\""),
+            // labelled
+            ("Summary: Fixing the parser", "Fixing the parser"),
+            ("**Summary:** Fixing the parser", "Fixing the parser"),
+            ("summary:\nFixing the parser", "Fixing the parser"),
+            ("Summary: \"Fixing the parser\"", "Fixing the parser"),
+            (
+                "Fixing the summary: no longer empty",
+                "Fixing the summary: no longer empty",
+            ),
+            // reasoning
+            (
+                "<think>The diff changes parse().</think>\nFixing the parser",
+                "Fixing the parser",
+            ),
+            (
+                "It's about parse().</thinking>Fixing the parser",
+                "Fixing the parser",
+            ),
+            ("<think>Only thinking</think>", ""),
+            // lists
+            (
+                "- Fixing the parser\n* Adding tests\n1. Cleaning up",
+                "Fixing the parser Adding tests Cleaning up",
+            ),
+            ("-5% memory use", "-5% memory use"),
+        ];
+
+        let filter = filter();
+
+        for (response, filtered) in cases {
+            assert_eq!(filter.filter(response), filtered, "filtering {response:?}");
+        }
+    }
+
+    #[test]
+    fn filter_keeps_what_the_mode_asks_for() {
+        let mut config = default_config().agent;
+        let response = "Summary: Fixing the parser. Adding tests.\nCleaning up";
+
+        config.line_separator = " · ".into();
+        let full = ResponseFilter::new(&config).unwrap();
+        assert_eq!(
+            full.filter(response),
+            "Fixing the parser. Adding tests. · Cleaning up"
+        );
+
+        config.response_mode = ResponseMode::FirstLine;
+        let first_line = ResponseFilter::new(&config).unwrap();
+        assert_eq!(
+            first_line.filter(response),
+            "Fixing the parser. Adding tests."
+        );
+
+        config.response_mode = ResponseMode::FirstSentence;
+        let first_sentence = ResponseFilter::new(&config).unwrap();
+        assert_eq!(first_sentence.filter(response), "Fixing the parser.");
+    }
+
+    #[test]
+    fn strip_keeps_lines_and_inline_code() {
+        let response = "<think>hmm</think>```\nfix(parser): handle `None`\n\nMore detail.\n```";
+        assert_eq!(
+            filter().strip(response),
+            "fix(parser): handle `None`\n\nMore detail."
+        );
+    }
+
+    #[test]
+    fn strip_code_fence_cases() {
+        let cases = [
+            ("```\nfix\n```", "fix"),
+            ("```rust\nfn main() {}\n```", "fn main() {}"),
+            ("```fix the parser```", "fix the parser"),
+            ("```\nfix", "fix"),
+            ("fix\n```", "fix"),
+            ("fix", "fix"),
+        ];
+
+        for (response, stripped) in cases {
+            assert_eq!(
+                strip_code_fence(response),
+                stripped,
+                "stripping {response:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn strip_list_marker_cases() {
+        let cases = [
+            ("- fix", "fix"),
+            ("  * fix", "fix"),
+            ("• fix", "fix"),
+            ("12) fix", "fix"),
+            ("3. fix", "fix"),
+            ("*.rs files", "*.rs files"),
+            ("2.0 release", "2.0 release"),
+        ];
+
+        for (line, stripped) in cases {
+            assert_eq!(
+                strip_list_marker(line).trim_start(),
+                stripped,
+                "stripping {line:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn cache_key_changes_with_what_changes_the_answer() {
+        let config = default_config().agent;
+        let key = |agent: &AgentConfig| cache_key("+new\n", None, agent, "preamble", "prompt");
+        let base = key(&config);
+
+        assert_eq!(key(&config), base);
+        assert_ne!(
+            cache_key("+old\n", None, &config, "preamble", "prompt"),
+            base
+        );
+        assert_ne!(
+            cache_key("+new\n", Some("ok"), &config, "preamble", "prompt"),
+            base
+        );
+        assert_ne!(cache_key("+new\n", None, &config, "other", "prompt"), base);
+
+        let mut changed = config.clone();
+        changed.params.temperature = Some(0.2);
+        assert_ne!(key(&changed), base);
+
+        let mut changed = config.clone();
+        changed.max_diff_bytes /= 2;
+        assert_ne!(key(&changed), base);
+
+        let mut changed = config.clone();
+        changed.model = vec!["other-model".into()];
+        assert_ne!(key(&changed), base);
+    }
+
+    fn big_diff(files: usize) -> String {
+        (0..files)
+            .map(|file| {
+                format!(
+                    "diff --git a/{file}.rs b/{file}.rs\n--- a/{file}.rs\n+++ b/{file}.rs\n\
+                     @@ -1 +1 @@\n-old line of {file}\n+new line of {file}\n"
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_context_of_a_small_diff() {
+        let header = ["1 file changed".to_string()];
+        let context = diff_context("+new\n", "", false, &header, Some("ok"), 1000);
+        assert_eq!(context, "1 file changed\n\n+new\n\n\nok");
+    }
+
+    #[test]
+    fn diff_context_fits_in_the_cap() {
+        let diff = big_diff(50);
+        let stat = " 0.rs | 2 +-\n 1.rs | 2 +-\n";
+        let header = [
+            "50 files changed".to_string(),
+            "Changed files:\n...".to_string(),
+        ];
+        let output = "Output of `cargo check` (exit status: 0):\nFinished";
+
+        for max_bytes in (0..diff.len() + 200).step_by(7) {
+            for stat_only in [false, true] {
+                let context =
+                    diff_context(&diff, stat, stat_only, &header, Some(output), max_bytes);
+                assert!(
+                    context.len() <= max_bytes,
+                    "{} > {max_bytes}",
+                    context.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn diff_context_cuts_the_diff_before_the_rest() {
+        let diff = big_diff(50);
+        let header = ["50 files changed".to_string()];
+        let output = "Output of `cargo test`:\nok";
+
+        let context = diff_context(&diff, "", false, &header, Some(output), 1000);
+
+        assert!(context.starts_with("50 files changed\n\nNOTE: this diff was truncated"));
+        assert!(context.ends_with(output));
+        assert!(context.contains("+new line of 0\n"));
+        assert!(!context.contains("+new line of 49\n"));
+    }
+
+    #[test]
+    fn diff_context_leaves_out_the_rest_without_room() {
+        let diff = big_diff(10);
+        let header = ["x".repeat(100)];
+        let output = "%".repeat(100);
+
+        let context = diff_context(&diff, "", false, &header, Some(&output), 300);
+
+        assert!(!context.contains('%'));
+        assert!(context.starts_with(&header[0]));
+
+        let context = diff_context(&diff, "", false, &header, Some(&output), 150);
+        assert!(context.starts_with("NOTE: this diff was truncated"));
+    }
+
+    #[test]
+    fn cap_diff_keeps_whole_hunks() {
+        let diff = big_diff(10);
+        let capped = cap_diff(&diff, " 10 files changed\n", 300);
+
+        assert!(capped.len() <= 300);
+        assert!(capped.contains(" 10 files changed\n\ndiff --git a/0.rs b/0.rs\n"));
+        assert!(capped.ends_with("+new line of 1\n"));
+    }
+
+    #[test]
+    fn cap_diff_without_room_for_its_note() {
+        assert_eq!(cap_diff(&big_diff(1), "", 10), "");
+    }
+
+    fn diff_of(paths: &[&str]) -> String {
+        paths
+            .iter()
+            .map(|path| format!("diff --git a/{path} b/{path}\n+change\n"))
+            .collect()
+    }
+
+    #[test]
+    fn file_type_hint_of_one_kind() {
+        let config = default_config().agent;
+        let diff = diff_of(&["src/main.rs", "src/lib.rs"]);
+
+        assert_eq!(
+            file_type_hint(&diff, &config).as_deref(),
+            Some("This is a change to Rust source files.")
+        );
+    }
+
+    #[test]
+    fn file_type_hint_picks_the_dominant_kind() {
+        let config = default_config().agent;
+        let diff = diff_of(&[
+            "src/main.rs",
+            "src/lib.rs",
+            "src/git.rs",
+            "src/agent.rs",
+            "README.md",
+            "build.sh",
+        ]);
+
+        assert_eq!(
+            file_type_hint(&diff, &config).as_deref(),
+            Some("This is a change to Rust source files.")
+        );
+    }
+
+    #[test]
+    fn file_type_hint_of_a_mix_names_both() {
+        let config = default_config().agent;
+        let diff = diff_of(&["src/main.rs", "src/lib.rs", "README.md", "docs/USAGE.MD"]);
+
+        // ties are in alphabetical order
+        assert_eq!(
+            file_type_hint(&diff, &config).as_deref(),
+            Some("This is a change to Rust source files and documentation.")
+        );
+    }
+
+    #[test]
+    fn file_type_hint_without_a_dominant_kind() {
+        let config = default_config().agent;
+        let diff = diff_of(&["a.rs", "b.md", "c.go", "d.py", "Makefile"]);
+        assert_eq!(file_type_hint(&diff, &config), None);
+    }
+
+    #[test]
+    fn file_type_hint_with_custom_kinds() {
+        let mut config = default_config().agent;
+        config
+            .file_types
+            .insert("rs".into(), "the samwise code".into());
+        config.file_types.insert("md".into(), String::new());
+
+        let diff = diff_of(&["src/main.rs", "README.md", "CHANGELOG.md"]);
+
+        assert_eq!(
+            file_type_hint(&diff, &config).as_deref(),
+            Some("This is a change to the samwise code.")
+        );
+    }
+
+    #[test]
+    fn file_type_hint_turned_off() {
+        let mut config = default_config().agent;
+        config.file_type_hints = false;
+        assert_eq!(file_type_hint(&diff_of(&["src/main.rs"]), &config), None);
+    }
+}
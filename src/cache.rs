@@ -0,0 +1,127 @@
+//! The on-disk cache of model responses.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::config::CacheConfig;
+
+/// Summaries of previously seen diffs, saved in the cache directory and
+/// evicted least recently used first.
+///
+/// Keys come from the standard library's hasher, which may change between
+/// Rust releases. That only costs a few cache misses after an upgrade.
+#[derive(Default, Deserialize, Serialize)]
+pub struct ResponseCache {
+    /// Where it's saved, or `None` for a [snapshot](ResponseCache::snapshot).
+    #[serde(skip)]
+    path: Option<PathBuf>,
+
+    #[serde(skip)]
+    max_entries: usize,
+
+    /// Entries ordered from least to most recently used.
+    #[serde(default)]
+    entries: Vec<CacheEntry>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    key: String,
+    summary: String,
+}
+
+impl ResponseCache {
+    fn path() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("samwise").join("responses.toml"))
+    }
+
+    /// Loads the cache from disk, starting out empty if it's missing or
+    /// unreadable. Returns `None` if there's no cache directory at all.
+    pub fn load(config: &CacheConfig) -> Option<Self> {
+        Some(Self::load_from(Self::path()?, config))
+    }
+
+    /// Like [ResponseCache::load], but from and to the file at `path`.
+    pub fn load_from(path: PathBuf, config: &CacheConfig) -> Self {
+        let mut cache: Self = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|src| toml::from_str(&src).ok())
+            .unwrap_or_default();
+
+        cache.path = Some(path);
+        cache.max_entries = config.max_entries;
+        cache
+    }
+
+    /// An in-memory copy of the cache for looking summaries up away from it,
+    /// which is never saved.
+    pub fn snapshot(&self) -> Self {
+        Self {
+            path: None,
+            max_entries: self.max_entries,
+            entries: self.entries.clone(),
+        }
+    }
+
+    /// Looks up a summary, marking it as recently used.
+    pub fn get(&mut self, key: u64) -> Option<String> {
+        let key = format!("{key:016x}");
+        let index = self.entries.iter().position(|entry| entry.key == key)?;
+        let entry = self.entries.remove(index);
+        let summary = entry.summary.clone();
+        self.entries.push(entry);
+        self.save();
+        Some(summary)
+    }
+
+    /// Adds a summary, evicting the least recently used ones past the limit.
+    pub fn insert(&mut self, key: u64, summary: String) {
+        let key = format!("{key:016x}");
+        self.entries.retain(|entry| entry.key != key);
+        self.entries.push(CacheEntry { key, summary });
+
+        let excess = self.entries.len().saturating_sub(self.max_entries);
+        self.entries.drain(..excess);
+
+        self.save();
+    }
+
+    /// Writes the cache back to disk. Failures are only logged since the
+    /// cache is just an optimization.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let result = toml::to_string(self)
+            .map_err(std::io::Error::other)
+            .and_then(|src| {
+                path.parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| std::fs::write(path, src))
+            });
+
+        if let Err(err) = result {
+            tracing::warn!("failed to save response cache to {}: {err}", path.display());
+        }
+    }
+
+    /// Deletes the cache file.
+    pub fn clear() -> anyhow::Result<()> {
+        let path = Self::path().context("could not locate cache directory")?;
+
+        match std::fs::remove_file(&path) {
+            Ok(()) => println!("Cleared the response cache at {}.", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                println!("The response cache is already empty.")
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to remove {}", path.display()));
+            }
+        }
+
+        Ok(())
+    }
+}
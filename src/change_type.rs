@@ -0,0 +1,376 @@
+//! Guessing what kind of change a diff is, like the type of a conventional
+//! commit, for `{change_type}` and [VerbRule::change_type].
+//!
+//! [VerbRule::change_type]: crate::config::VerbRule::change_type
+
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ChangeTypeConfig, presence::is_test_path};
+
+/// Code changes of at most this many lines that edit existing ones are
+/// guessed to be fixes.
+const FIX_LINES: usize = 10;
+
+/// What kind of change a diff is, named like the types of conventional
+/// commits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeType {
+    Feat,
+    Fix,
+    Refactor,
+    Test,
+    Docs,
+    Chore,
+}
+
+impl ChangeType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeType::Feat => "feat",
+            ChangeType::Fix => "fix",
+            ChangeType::Refactor => "refactor",
+            ChangeType::Test => "test",
+            ChangeType::Docs => "docs",
+            ChangeType::Chore => "chore",
+        }
+    }
+
+    /// The type a branch is named for by its prefix, like `fix/parser` or
+    /// `feature-login`.
+    fn of_branch(branch: &str) -> Option<Self> {
+        let prefix = branch.split(['/', '-', '_']).next()?.to_lowercase();
+
+        match prefix.as_str() {
+            "feat" | "feature" => Some(ChangeType::Feat),
+            "fix" | "bugfix" | "hotfix" | "bug" => Some(ChangeType::Fix),
+            "refactor" => Some(ChangeType::Refactor),
+            "test" | "tests" => Some(ChangeType::Test),
+            "doc" | "docs" => Some(ChangeType::Docs),
+            "chore" | "ci" | "build" => Some(ChangeType::Chore),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ChangeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Guesses the kind of change `diff` is on `branch`, or `None` for an empty
+/// diff. A branch named for a type wins. Otherwise, changes to only tests,
+/// docs, or chores like lock files and CI are that type, going by
+/// [ChangeTypeConfig] first. Changes to code are a feature when they add
+/// files or mostly add lines, a fix when they edit a few lines, and a
+/// refactor when they remove about as much as they add.
+pub fn classify(diff: &str, branch: &str, config: &ChangeTypeConfig) -> Option<ChangeType> {
+    let files = file_changes(diff);
+
+    if files.is_empty() {
+        return None;
+    }
+
+    if let Some(change_type) = ChangeType::of_branch(branch) {
+        return Some(change_type);
+    }
+
+    let mut others: HashMap<ChangeType, usize> = HashMap::new();
+    let mut code = Vec::new();
+
+    for file in &files {
+        match path_type(file.path, config) {
+            Some(change_type) => *others.entry(change_type).or_default() += 1,
+            None => code.push(file),
+        }
+    }
+
+    if code.is_empty() {
+        // the type with the most files, with ties going to the first, which
+        // `max_by_key` picks when they're reversed since it keeps the last
+        return [ChangeType::Chore, ChangeType::Docs, ChangeType::Test]
+            .into_iter()
+            .rev()
+            .max_by_key(|change_type| others.get(change_type).copied().unwrap_or(0));
+    }
+
+    let insertions: usize = code.iter().map(|file| file.insertions).sum();
+    let deletions: usize = code.iter().map(|file| file.deletions).sum();
+
+    let change_type = if code.iter().any(|file| file.new) {
+        ChangeType::Feat
+    } else if deletions > 0 && insertions + deletions <= FIX_LINES {
+        ChangeType::Fix
+    } else if deletions * 2 >= insertions {
+        ChangeType::Refactor
+    } else {
+        ChangeType::Feat
+    };
+
+    Some(change_type)
+}
+
+/// The type that changes to `path` alone would be, or `None` for code. The
+/// globs of [ChangeTypeConfig] are checked before the built-in guesses.
+fn path_type(path: &str, config: &ChangeTypeConfig) -> Option<ChangeType> {
+    let matches = |globs: &[String]| {
+        globs
+            .iter()
+            .any(|glob| glob::Pattern::new(glob).is_ok_and(|glob| glob.matches(path)))
+    };
+
+    if matches(&config.test) {
+        Some(ChangeType::Test)
+    } else if matches(&config.docs) {
+        Some(ChangeType::Docs)
+    } else if matches(&config.chore) {
+        Some(ChangeType::Chore)
+    } else if is_test_path(path) {
+        Some(ChangeType::Test)
+    } else if is_doc_path(path) {
+        Some(ChangeType::Docs)
+    } else if is_chore_path(path) {
+        Some(ChangeType::Chore)
+    } else {
+        None
+    }
+}
+
+/// Whether a changed file looks like documentation, from being in a `docs`
+/// directory, being prose like markdown, or being a README or changelog.
+fn is_doc_path(path: &str) -> bool {
+    let mut components = path.rsplit('/');
+    let name = components.next().unwrap_or(path).to_lowercase();
+
+    let in_doc_dir = components.any(|dir| matches!(dir.to_lowercase().as_str(), "doc" | "docs"));
+
+    let prose = matches!(
+        name.rsplit_once('.').map(|(_, extension)| extension),
+        Some("md" | "markdown" | "rst" | "adoc" | "txt")
+    );
+
+    in_doc_dir
+        || prose
+        || ["readme", "changelog", "contributing", "license"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+/// Whether a changed file looks like upkeep rather than code, like a lock
+/// file, a dependency manifest, CI, or a dotfile.
+fn is_chore_path(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path).to_lowercase();
+
+    name.starts_with('.')
+        || path
+            .split('/')
+            .any(|dir| matches!(dir, ".github" | ".circleci"))
+        || name.ends_with(".lock")
+        || matches!(
+            name.as_str(),
+            "package-lock.json"
+                | "pnpm-lock.yaml"
+                | "go.sum"
+                | "go.mod"
+                | "cargo.toml"
+                | "package.json"
+                | "pyproject.toml"
+                | "requirements.txt"
+                | "dockerfile"
+        )
+}
+
+/// The changes to one file of a diff.
+struct FileChange<'a> {
+    path: &'a str,
+    new: bool,
+    insertions: usize,
+    deletions: usize,
+}
+
+/// The files a git-format diff changes and how many lines of each.
+fn file_changes(diff: &str) -> Vec<FileChange<'_>> {
+    let mut files: Vec<FileChange> = Vec::new();
+
+    // `---` and `+++` are only file headers before a file's first hunk
+    let mut in_hunk = false;
+
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("diff --git ") {
+            let path = header.rsplit_once(" b/").map_or(header, |(_, path)| path);
+
+            files.push(FileChange {
+                path,
+                new: false,
+                insertions: 0,
+                deletions: 0,
+            });
+
+            in_hunk = false;
+            continue;
+        }
+
+        let Some(file) = files.last_mut() else {
+            continue;
+        };
+
+        if line.starts_with("@@") {
+            in_hunk = true;
+        } else if !in_hunk && line.starts_with("new file mode") {
+            file.new = true;
+        } else if in_hunk && line.starts_with('+') {
+            file.insertions += 1;
+        } else if in_hunk && line.starts_with('-') {
+            file.deletions += 1;
+        }
+    }
+
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file's part of a diff, adding `insertions` lines and removing
+    /// `deletions`.
+    fn file(path: &str, new: bool, insertions: usize, deletions: usize) -> String {
+        let mut diff = format!("diff --git a/{path} b/{path}\n");
+
+        if new {
+            diff.push_str("new file mode 100644\n--- /dev/null\n");
+        } else {
+            diff.push_str(&format!("--- a/{path}\n"));
+        }
+
+        diff.push_str(&format!(
+            "+++ b/{path}\n@@ -1,{deletions} +1,{insertions} @@\n"
+        ));
+        diff.extend((0..deletions).map(|line| format!("-old {line}\n")));
+        diff.extend((0..insertions).map(|line| format!("+new {line}\n")));
+        diff
+    }
+
+    fn guess(diff: &str) -> Option<ChangeType> {
+        classify(diff, "main", &ChangeTypeConfig::default())
+    }
+
+    #[test]
+    fn empty_diffs_have_no_type() {
+        assert_eq!(guess(""), None);
+    }
+
+    #[test]
+    fn branch_prefixes_win() {
+        let diff = file("src/main.rs", true, 40, 0);
+        let config = ChangeTypeConfig::default();
+
+        assert_eq!(
+            classify(&diff, "fix/parser", &config),
+            Some(ChangeType::Fix)
+        );
+        assert_eq!(
+            classify(&diff, "docs-readme", &config),
+            Some(ChangeType::Docs)
+        );
+        assert_eq!(
+            classify(&diff, "Chore_deps", &config),
+            Some(ChangeType::Chore)
+        );
+        assert_eq!(classify(&diff, "fixup", &config), Some(ChangeType::Feat));
+    }
+
+    #[test]
+    fn other_files_alone_are_their_type() {
+        assert_eq!(
+            guess(&file("README.md", false, 3, 1)),
+            Some(ChangeType::Docs)
+        );
+        assert_eq!(
+            guess(&file("tests/cli.rs", false, 3, 1)),
+            Some(ChangeType::Test)
+        );
+        assert_eq!(
+            guess(&file("Cargo.lock", false, 3, 1)),
+            Some(ChangeType::Chore)
+        );
+
+        let diff = file("docs/usage.md", false, 3, 1) + &file("docs/index.md", true, 9, 0);
+        assert_eq!(guess(&diff), Some(ChangeType::Docs));
+    }
+
+    #[test]
+    fn other_files_go_by_the_most_changed() {
+        let diff = file("README.md", false, 1, 1)
+            + &file("tests/cli.rs", false, 1, 1)
+            + &file("tests/config.rs", false, 1, 1);
+
+        assert_eq!(guess(&diff), Some(ChangeType::Test));
+    }
+
+    #[test]
+    fn ties_between_other_files_go_to_the_first() {
+        let diff = file("README.md", false, 1, 1) + &file("tests/cli.rs", false, 1, 1);
+        assert_eq!(guess(&diff), Some(ChangeType::Docs));
+
+        let diff = diff + &file(".github/workflows/ci.yml", false, 1, 1);
+        assert_eq!(guess(&diff), Some(ChangeType::Chore));
+    }
+
+    #[test]
+    fn new_code_files_are_features() {
+        let diff = file("src/parser.rs", true, 3, 0) + &file("README.md", false, 9, 9);
+        assert_eq!(guess(&diff), Some(ChangeType::Feat));
+    }
+
+    #[test]
+    fn small_edits_are_fixes() {
+        assert_eq!(
+            guess(&file("src/parser.rs", false, 2, 2)),
+            Some(ChangeType::Fix)
+        );
+    }
+
+    #[test]
+    fn balanced_churn_is_a_refactor() {
+        let diff = file("src/parser.rs", false, 30, 25);
+        assert_eq!(guess(&diff), Some(ChangeType::Refactor));
+    }
+
+    #[test]
+    fn mostly_added_lines_are_features() {
+        let diff = file("src/parser.rs", false, 40, 3);
+        assert_eq!(guess(&diff), Some(ChangeType::Feat));
+    }
+
+    #[test]
+    fn globs_win_over_built_in_guesses() {
+        let config = ChangeTypeConfig {
+            test: vec!["fixtures/**".to_string()],
+            docs: vec!["tests/*.md".to_string()],
+            chore: vec!["src/generated.rs".to_string()],
+        };
+
+        let classify = |diff: &str| classify(diff, "main", &config);
+
+        assert_eq!(
+            classify(&file("fixtures/diff.rs", false, 2, 2)),
+            Some(ChangeType::Test)
+        );
+        assert_eq!(
+            classify(&file("tests/README.md", false, 2, 2)),
+            Some(ChangeType::Docs)
+        );
+        assert_eq!(
+            classify(&file("src/generated.rs", false, 2, 2)),
+            Some(ChangeType::Chore)
+        );
+        assert_eq!(
+            classify(&file("src/parser.rs", false, 2, 2)),
+            Some(ChangeType::Fix)
+        );
+    }
+}
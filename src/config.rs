@@ -0,0 +1,3882 @@
+//! The config file and everything in it.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{
+    agent::prompt_vars,
+    change_type::ChangeType,
+    git::{DiffMetadata, UPSTREAM_BASE, changed_size, get_diff, get_upstream, run_git},
+    presence::{MAX_FIELD_LEN, PresenceState, line_vars},
+    template::{has_single_placeholder, render_template},
+    webhook::webhook_body,
+};
+
+/// The default [Config::min_frequency], so that git and the model aren't
+/// hammered.
+pub const MIN_FREQUENCY: Duration = Duration::from_secs(1);
+
+/// Settings whose values [Config::to_redacted_toml] masks, wherever in the
+/// config they are. Every header under a `headers` table is masked too.
+const SECRET_FIELDS: &[&str] = &["api_key", "token", "password"];
+
+/// What a secret is shown as by [Config::to_redacted_toml].
+const REDACTED: &str = "<redacted>";
+
+/// How long [AgentConfig::check_auth] waits for Ollama to answer.
+const AUTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Polling more often than this is allowed, but warned about.
+pub const FAST_FREQUENCY: Duration = Duration::from_secs(5);
+
+/// The shortest time between summaries of an unchanged diff, so that
+/// [AgentConfig::resummarize_unchanged] with a short frequency doesn't keep
+/// the model busy.
+pub const MIN_RESUMMARIZE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many digits Discord's IDs have, from the earliest in 2015 to the
+/// largest a `u64` holds.
+pub const SNOWFLAKE_DIGITS: std::ops::RangeInclusive<usize> = 17..=20;
+
+/// Where to find the ID for [DiscordConfig::client].
+pub const APPLICATION_ID_HINT: &str = "which is the Application ID on the General Information \
+                                   page of your app at https://discord.com/developers/applications";
+
+/// The most commits [DiffConfig::startup_summary] can ask for.
+pub const MAX_STARTUP_COMMITS: usize = 20;
+
+/// The names the config file is looked for under in the config directory, in
+/// order. See [ConfigFormat]. The first is used when there's none of them.
+pub const CONFIG_FILE_NAMES: [&str; 4] = [
+    "samwise.toml",
+    "samwise.yaml",
+    "samwise.yml",
+    "samwise.json",
+];
+
+/// The file in a repository that overrides parts of the config for it. See
+/// [RepoOverrides].
+pub const REPO_CONFIG_FILE: &str = ".samwise.toml";
+
+/// The file in a repository listing paths whose changes are never summarized,
+/// like a `.gitignore`. See [read_ignore_file].
+pub const IGNORE_FILE: &str = ".samwiseignore";
+
+/// How far [Config::frequency] can randomly be off by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Jitter {
+    /// Up to this much sooner or later.
+    Fixed(Duration),
+
+    /// Up to this percentage of the frequency sooner or later.
+    Percent(f64),
+}
+
+impl Jitter {
+    /// Parses a duration like `"2s"` or a percentage like `"10%"`, optionally
+    /// written with a leading `±`.
+    pub fn parse(jitter: &str) -> anyhow::Result<Self> {
+        let band = jitter.trim().trim_start_matches('±').trim();
+
+        if let Some(percent) = band.strip_suffix('%') {
+            let percent: f64 = percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|percent: &f64| percent.is_finite())
+                .with_context(|| format!("{jitter:?} is not a percentage like 10%"))?;
+
+            if !(0.0..=100.0).contains(&percent) {
+                anyhow::bail!("{jitter:?} must be between 0% and 100%");
+            }
+
+            return Ok(Jitter::Percent(percent));
+        }
+
+        humantime::parse_duration(band)
+            .map(Jitter::Fixed)
+            .with_context(|| format!("{jitter:?} is not a duration like 2s or a percentage"))
+    }
+
+    /// `frequency`, moved by a random amount within the jitter either way.
+    pub fn apply(&self, frequency: Duration, rng: &mut fastrand::Rng) -> Duration {
+        let band = match *self {
+            Jitter::Fixed(band) => band,
+            Jitter::Percent(percent) => frequency.mul_f64(percent / 100.0),
+        };
+
+        let offset = band.mul_f64(rng.f64());
+
+        if rng.bool() {
+            frequency + offset
+        } else {
+            frequency.saturating_sub(offset)
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Config {
+    #[serde(with = "humantime_serde")]
+    pub frequency: Duration,
+
+    /// How far each wait of [Config::frequency] can randomly be off by, like
+    /// `"2s"` or `"10%"`, so that several instances don't all run git and
+    /// prompt at once. See [Jitter].
+    #[serde(default)]
+    pub frequency_jitter: Option<String>,
+
+    /// Seeds the randomness of [Config::frequency_jitter], for waits that are
+    /// the same every run.
+    #[serde(default)]
+    pub jitter_seed: Option<u64>,
+
+    /// The shortest allowed [Config::frequency], and the frequencies of
+    /// [Config::facets].
+    #[serde(default = "Config::default_min_frequency", with = "humantime_serde")]
+    pub min_frequency: Duration,
+
+    /// How long to wait after starting before connecting to Discord and
+    /// checking the repositories for the first time, for autostarts that run
+    /// before the desktop session has settled.
+    #[serde(default, with = "humantime_serde")]
+    pub startup_delay: Duration,
+
+    /// How long to run for before clearing the presence and exiting, for a
+    /// timeboxed session. Runs until stopped when unset.
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>,
+
+    /// Wait for files in the repositories to change instead of checking them
+    /// every [Config::frequency]. Falls back to polling if the work trees
+    /// can't be watched.
+    #[serde(default)]
+    pub watch: bool,
+
+    /// Repositories to watch. The one most recently worked on is summarized.
+    /// When empty, only [Config::repo_path] is watched.
+    #[serde(default)]
+    pub repos: Vec<PathBuf>,
+
+    /// The single repository to watch when [Config::repos] is empty. Defaults
+    /// to the current directory.
+    #[serde(default)]
+    pub repo_path: Option<PathBuf>,
+
+    /// Settings for single repositories, keyed by their directory's name,
+    /// that take the place of the global ones. A [REPO_CONFIG_FILE] in the
+    /// repository takes precedence over these.
+    #[serde(default)]
+    pub overrides: BTreeMap<String, RepoOverrides>,
+
+    pub agent: AgentConfig,
+
+    /// Prompts whose summaries take turns in the presence, like a slideshow.
+    /// When set, these are asked instead of [AgentConfig::prompt].
+    #[serde(default)]
+    pub facets: Vec<FacetConfig>,
+
+    /// The language summaries are asked for in, like `"German"` or `"de"`.
+    /// One with translations in [crate::language] also has the built-in text
+    /// of the presence, like the idle details, default to them.
+    #[serde(default)]
+    pub language: Option<String>,
+
+    pub discord: DiscordConfig,
+
+    /// Where presence updates are sent.
+    #[serde(default)]
+    pub output: OutputMode,
+
+    /// Exactly which sinks presence updates go to, in place of
+    /// [Config::output] and the sections that turn sinks on. Empty goes by
+    /// those instead. See [Config::sinks].
+    #[serde(default)]
+    pub sinks: Vec<OutputSink>,
+
+    /// How log lines are written to stderr.
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    /// Show desktop notifications when Discord connects or disconnects and
+    /// when something goes wrong. Only read at startup.
+    #[serde(default)]
+    pub notify_desktop: bool,
+
+    /// With [Config::notify_desktop], also notify when the branch of the
+    /// summarized changes switches. Only read at startup.
+    #[serde(default)]
+    pub notify_branch_switch: bool,
+
+    #[serde(default)]
+    pub file: FileConfig,
+
+    /// An HTTP endpoint to post every new summary to, alongside
+    /// [Config::output].
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+
+    /// Set the Slack custom status to every new summary, alongside
+    /// [Config::output].
+    #[serde(default)]
+    pub slack: Option<SlackConfig>,
+
+    /// Publish every presence change to an MQTT broker, alongside
+    /// [Config::output].
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+
+    /// Run a command whenever the summary changes, alongside
+    /// [Config::output]. Nothing is run unless it's set.
+    #[serde(default)]
+    pub hook: Option<HookConfig>,
+
+    /// A local server reporting samwise's state, for monitoring and
+    /// liveness probes. Off unless it's set.
+    #[serde(default)]
+    pub http: Option<HttpConfig>,
+
+    /// A Unix socket that takes commands like `pause` and `refresh`, for
+    /// scripts and editors. Off unless it's set.
+    #[serde(default)]
+    pub control: Option<ControlConfig>,
+
+    #[serde(default)]
+    pub diff: DiffConfig,
+
+    #[serde(default)]
+    pub redact: RedactConfig,
+
+    /// Globs for telling what kind of change a diff is. See
+    /// [crate::change_type::classify].
+    #[serde(default)]
+    pub change_types: ChangeTypeConfig,
+
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    #[serde(default)]
+    pub cache: CacheConfig,
+
+    /// When to stop showing what's being worked on.
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+
+    /// Where to learn which files are open in the editor, to only summarize
+    /// changes to those.
+    #[serde(default)]
+    pub editor: EditorConfig,
+}
+
+/// Where [crate::editor::open_files] learns which files are open.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EditorConfig {
+    /// How to ask the editor. Unset summarizes every change, like before.
+    pub provider: Option<EditorProvider>,
+
+    /// The file [EditorProvider::File] reads.
+    pub path: Option<PathBuf>,
+}
+
+/// A way of learning which files are open in the editor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditorProvider {
+    /// Read [EditorConfig::path], which the editor keeps up to date with the
+    /// absolute path of each open file on a line of its own.
+    File,
+}
+
+/// How log lines are formatted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+
+    /// One JSON object per line, for journald and log aggregators.
+    Json,
+}
+
+/// Where presence updates are sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    /// The Discord rich presence.
+    #[default]
+    Discord,
+
+    /// A JSON file, for status bars like Waybar and Polybar.
+    File,
+
+    /// Both Discord and the file.
+    Both,
+}
+
+impl OutputMode {
+    /// Whether updates go to Discord.
+    pub fn discord(&self) -> bool {
+        matches!(self, OutputMode::Discord | OutputMode::Both)
+    }
+
+    /// Whether updates go to the file.
+    pub fn file(&self) -> bool {
+        matches!(self, OutputMode::File | OutputMode::Both)
+    }
+}
+
+/// One of the places presence updates can go, for [Config::sinks].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputSink {
+    Discord,
+    File,
+    Webhook,
+    Slack,
+    Mqtt,
+    Hook,
+}
+
+impl OutputSink {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputSink::Discord => "discord",
+            OutputSink::File => "file",
+            OutputSink::Webhook => "webhook",
+            OutputSink::Slack => "slack",
+            OutputSink::Mqtt => "mqtt",
+            OutputSink::Hook => "hook",
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FileConfig {
+    /// Where to write the presence JSON. Defaults to `presence.json` in
+    /// samwise's cache directory.
+    pub path: Option<PathBuf>,
+
+    /// The JSON written when there's nothing to show, in place of
+    /// `{"active": false}`.
+    pub idle: Option<toml::Table>,
+
+    /// What's left in the file once samwise exits.
+    pub on_exit: OnExit,
+
+    /// The most characters of the summary to write, like 40 for a narrow
+    /// status bar. Unset writes all of it.
+    pub max_len: Option<usize>,
+
+    /// How the summary is cut down to [FileConfig::max_len].
+    pub truncate: Truncation,
+}
+
+/// How a sink cuts down a summary that's longer than it allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    /// Cut it off wherever the limit falls, with an ellipsis.
+    #[default]
+    Ellipsis,
+
+    /// Cut it off after the last whole word that fits, with an ellipsis.
+    Word,
+
+    /// Cut it off wherever the limit falls, without an ellipsis.
+    Cut,
+}
+
+/// What the file output leaves behind when samwise exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExit {
+    /// Write that there's nothing to show, like when there are no changes,
+    /// so a status bar doesn't keep showing a stale summary.
+    #[default]
+    Clear,
+
+    /// Leave the last presence in the file.
+    Leave,
+}
+
+impl FileConfig {
+    /// The path to write the presence JSON to, if it can be located.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.path
+            .clone()
+            .or_else(|| dirs::cache_dir().map(|dir| dir.join("samwise").join("presence.json")))
+    }
+}
+
+/// Times of day when what's being worked on isn't shown.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Ranges of local time like `"22:00-07:00"`. A range that ends before it
+    /// starts wraps past midnight.
+    pub quiet: Vec<String>,
+
+    /// What happens during the [ScheduleConfig::quiet] hours.
+    pub action: QuietAction,
+
+    /// The summary shown during quiet hours with [QuietAction::Away].
+    pub away_message: String,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            quiet: Vec::new(),
+            action: QuietAction::default(),
+            away_message: "Away".to_string(),
+        }
+    }
+}
+
+impl ScheduleConfig {
+    /// Parses every range of [ScheduleConfig::quiet].
+    pub fn quiet_hours(&self) -> anyhow::Result<Vec<QuietHours>> {
+        self.quiet
+            .iter()
+            .map(|range| QuietHours::parse(range))
+            .collect()
+    }
+
+    /// How long until the quiet hours that the local time is in now end, or
+    /// `None` outside of them.
+    pub fn quiet_remaining(&self) -> Option<Duration> {
+        let now = chrono::Local::now().time();
+
+        // the ranges were checked when the config was loaded
+        self.quiet_hours()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|hours| hours.remaining(now))
+            .max()
+    }
+}
+
+/// What happens during [ScheduleConfig::quiet] hours.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuietAction {
+    /// Clear the presence, like when there are no changes, and stop reading
+    /// diffs until the quiet hours end.
+    #[default]
+    Clear,
+
+    /// Show [ScheduleConfig::away_message] in place of the summary, without
+    /// the repository, branch, or anything else about the changes.
+    Away,
+
+    /// Stop everything until the quiet hours end, like `SIGUSR1`, not even
+    /// picking up changes to the config.
+    Pause,
+}
+
+/// A range of [ScheduleConfig::quiet] hours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+impl QuietHours {
+    /// Parses a range like `"22:00-07:00"`, in 24-hour time.
+    pub fn parse(range: &str) -> anyhow::Result<Self> {
+        let parse_time = |time: &str| {
+            chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .with_context(|| format!("{range:?}: {:?} is not a time like 22:00", time.trim()))
+        };
+
+        let (start, end) = range
+            .split_once('-')
+            .with_context(|| format!("{range:?} is not a range like 22:00-07:00"))?;
+
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+
+        if start == end {
+            anyhow::bail!("{range:?} must not start and end at the same time");
+        }
+
+        Ok(Self { start, end })
+    }
+
+    /// How long from `now` until these hours end, or `None` if `now` isn't in
+    /// them.
+    pub fn remaining(&self, now: chrono::NaiveTime) -> Option<Duration> {
+        let contains = if self.start < self.end {
+            self.start <= now && now < self.end
+        } else {
+            now >= self.start || now < self.end
+        };
+
+        if !contains {
+            return None;
+        }
+
+        // past midnight, the end is on the next day
+        let remaining = match self.end.signed_duration_since(now) {
+            remaining if remaining > chrono::TimeDelta::zero() => remaining,
+            remaining => remaining + chrono::TimeDelta::days(1),
+        };
+
+        remaining.to_std().ok()
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    /// Where to POST summaries.
+    pub url: String,
+
+    /// Extra headers to send, like `Authorization`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// The JSON to send, as a table whose strings can use the placeholders
+    /// in [crate::webhook::webhook_vars]. Defaults to sending all of them.
+    #[serde(default)]
+    pub body: Option<toml::Table>,
+
+    /// The most characters of the summary to send. Unset sends all of it.
+    #[serde(default)]
+    pub max_len: Option<usize>,
+
+    /// How the summary is cut down to [WebhookConfig::max_len].
+    #[serde(default)]
+    pub truncate: Truncation,
+}
+
+impl WebhookConfig {
+    /// Checks the URL, headers, and body template.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let url = reqwest::Url::parse(&self.url)
+            .with_context(|| format!("webhook.url: {:?} is not a valid URL", self.url))?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            anyhow::bail!(
+                "webhook.url: {:?} must be an http:// or https:// URL",
+                self.url
+            );
+        }
+
+        for (name, value) in &self.headers {
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("webhook.headers: {name:?} is not a valid header name"))?;
+
+            reqwest::header::HeaderValue::from_str(value)
+                .with_context(|| format!("webhook.headers.{name}: not a valid header value"))?;
+        }
+
+        let presence = PresenceState {
+            summary: String::new(),
+            metadata: None,
+            session_start: None,
+            model: String::new(),
+            repo_url: None,
+            verb: None,
+        };
+
+        webhook_body(&presence, self).context("webhook.body")?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SlackConfig {
+    /// A user token with the `users.profile:write` scope. Falls back to
+    /// `SLACK_TOKEN`.
+    pub token: Option<String>,
+
+    /// The emoji shown next to the status, like `:computer:`.
+    #[serde(default = "SlackConfig::default_emoji")]
+    pub emoji: String,
+
+    /// The most characters of the summary to show, up to the
+    /// [MAX_STATUS_LEN] Slack allows, which is also the default.
+    ///
+    /// [MAX_STATUS_LEN]: crate::slack::MAX_STATUS_LEN
+    #[serde(default)]
+    pub max_len: Option<usize>,
+
+    /// How the summary is cut down to [SlackConfig::max_len].
+    #[serde(default)]
+    pub truncate: Truncation,
+}
+
+impl SlackConfig {
+    fn default_emoji() -> String {
+        ":computer:".to_string()
+    }
+
+    /// Resolves the token from the config or the environment.
+    pub fn token(&self) -> Option<String> {
+        self.token
+            .clone()
+            .or_else(|| std::env::var("SLACK_TOKEN").ok())
+    }
+
+    /// Checks that there's a token and that the emoji looks like one.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.token().is_none_or(|token| token.trim().is_empty()) {
+            anyhow::bail!("slack.token: set this or SLACK_TOKEN to a Slack user token");
+        }
+
+        let name = self
+            .emoji
+            .strip_prefix(':')
+            .and_then(|emoji| emoji.strip_suffix(':'));
+
+        if name.is_none_or(|name| name.is_empty() || name.contains(char::is_whitespace)) {
+            anyhow::bail!(
+                "slack.emoji: {:?} should be an emoji name like \":computer:\"",
+                self.emoji
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HookConfig {
+    /// The command to run with the shell, given the summary and its details
+    /// in environment variables listed in [crate::hook_command::hook_env].
+    pub command: String,
+
+    /// How long the command can run before it's killed.
+    #[serde(default = "HookConfig::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+
+    /// The most characters of the summary to pass along. Unset passes all
+    /// of it.
+    #[serde(default)]
+    pub max_len: Option<usize>,
+
+    /// How the summary is cut down to [HookConfig::max_len].
+    #[serde(default)]
+    pub truncate: Truncation,
+}
+
+impl HookConfig {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MqttConfig {
+    /// The broker's hostname or address.
+    pub host: String,
+
+    #[serde(default = "MqttConfig::default_port")]
+    pub port: u16,
+
+    /// The topic the presence is published to, as JSON.
+    #[serde(default = "MqttConfig::default_topic")]
+    pub topic: String,
+
+    /// The client ID to connect with, which has to be unique on the broker.
+    #[serde(default = "MqttConfig::default_client_id")]
+    pub client_id: String,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// The MQTT quality of service to publish with: 0, 1, or 2.
+    #[serde(default)]
+    pub qos: u8,
+
+    /// Whether the broker keeps the last presence for clients that subscribe
+    /// later.
+    #[serde(default = "default_true")]
+    pub retain: bool,
+
+    /// The most characters of the summary to publish. Unset publishes all of
+    /// it.
+    #[serde(default)]
+    pub max_len: Option<usize>,
+
+    /// How the summary is cut down to [MqttConfig::max_len].
+    #[serde(default)]
+    pub truncate: Truncation,
+}
+
+impl MqttConfig {
+    fn default_port() -> u16 {
+        1883
+    }
+
+    fn default_topic() -> String {
+        "samwise/presence".to_string()
+    }
+
+    fn default_client_id() -> String {
+        "samwise".to_string()
+    }
+
+    /// Where and as whom to connect, which only applies on restart.
+    pub fn broker(&self) -> (&str, u16, &str, Option<&str>, Option<&str>) {
+        (
+            &self.host,
+            self.port,
+            &self.client_id,
+            self.username.as_deref(),
+            self.password.as_deref(),
+        )
+    }
+
+    /// Checks the broker, topic, credentials, and QoS.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.host.trim().is_empty() {
+            anyhow::bail!("mqtt.host: set this to the broker's address");
+        }
+
+        if self.topic.is_empty() || self.topic.contains(['+', '#']) {
+            anyhow::bail!(
+                "mqtt.topic: {:?} must be a topic without wildcards, like \"samwise/presence\"",
+                self.topic
+            );
+        }
+
+        if self.client_id.is_empty() {
+            anyhow::bail!("mqtt.client_id: can't be empty");
+        }
+
+        if self.password.is_some() && self.username.is_none() {
+            anyhow::bail!("mqtt.password: needs mqtt.username too");
+        }
+
+        if self.qos > 2 {
+            anyhow::bail!("mqtt.qos: {} isn't 0, 1, or 2", self.qos);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HttpConfig {
+    /// The address to serve on, like `127.0.0.1:8080`.
+    pub bind: SocketAddr,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Where to put the socket. Defaults to samwise.sock in the runtime
+    /// directory, or in samwise's cache directory without one.
+    pub path: Option<PathBuf>,
+
+    /// Keep the text given with `pin` pinned across restarts, until `unpin`.
+    pub persist_pin: bool,
+}
+
+impl ControlConfig {
+    /// The path to listen on, if it can be located.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.path.clone().or_else(|| {
+            dirs::runtime_dir()
+                .or_else(|| dirs::cache_dir().map(|dir| dir.join("samwise")))
+                .map(|dir| dir.join("samwise.sock"))
+        })
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Whether to reuse summaries of diffs that have been seen before.
+    pub enabled: bool,
+
+    /// The most summaries to keep, dropping the least recently used first.
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 256,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// The total number of times a prompt is attempted before giving up.
+    pub max_attempts: u32,
+
+    /// The delay before the first retry, doubled after each failure.
+    #[serde(with = "humantime_serde")]
+    pub base_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct DiffConfig {
+    #[serde(default)]
+    pub mode: DiffMode,
+
+    /// Which stash entry [DiffMode::Stash] summarizes, counting from 0 for
+    /// the latest, like `stash@{0}`.
+    #[serde(default)]
+    pub stash: usize,
+
+    /// Ignore changes that only touch whitespace, keeping the previous summary
+    /// when nothing else changed.
+    #[serde(default)]
+    pub ignore_whitespace: bool,
+
+    /// Changes with fewer added and removed lines than this aren't worth
+    /// asking the model about. Zero summarizes every change.
+    #[serde(default)]
+    pub min_lines: usize,
+
+    /// Like [DiffConfig::min_lines], but counting the bytes of the added and
+    /// removed lines.
+    #[serde(default)]
+    pub min_bytes: usize,
+
+    /// What to show for changes under [DiffConfig::min_lines] or
+    /// [DiffConfig::min_bytes], in place of the summary. Unset keeps the
+    /// previous summary instead.
+    #[serde(default)]
+    pub small_message: Option<String>,
+
+    /// A shell command the diff is piped through before anything else sees
+    /// it, whose output is summarized in its place. The diff is used as it
+    /// was if the command fails.
+    #[serde(default)]
+    pub filter: Option<String>,
+
+    /// How long [DiffConfig::filter] can run before the diff is used as it
+    /// was.
+    #[serde(
+        default = "DiffConfig::default_filter_timeout",
+        with = "humantime_serde"
+    )]
+    pub filter_timeout: Duration,
+
+    /// How many unchanged lines to show around each change, like git's `-U`.
+    /// Fewer make for smaller prompts. Defaults to git's own setting.
+    #[serde(default)]
+    pub context_lines: Option<u32>,
+
+    #[serde(default)]
+    pub algorithm: DiffAlgorithm,
+
+    #[serde(default)]
+    pub backend: GitBackend,
+
+    #[serde(default)]
+    pub vcs: Vcs,
+
+    /// A branch or commit to diff against instead of HEAD, so the summary
+    /// covers everything done since the current branch forked from it.
+    /// [UPSTREAM_BASE] stands for the current branch's upstream.
+    #[serde(default)]
+    pub base: Option<String>,
+
+    /// Only summarize changes to paths matching these git pathspecs, like
+    /// `src/*`. Empty includes every path.
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// Leave out changes to paths matching these, even if they're included,
+    /// like `*.lock`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// When there are no changes because they were just committed, summarize
+    /// the new commit instead of clearing the presence.
+    #[serde(default)]
+    pub summarize_commits: bool,
+
+    /// When samwise starts with a clean work tree, summarize this many of the
+    /// latest commits together instead of waiting for the first change. Zero
+    /// turns it off.
+    #[serde(default)]
+    pub startup_summary: usize,
+}
+
+/// How git lines up the old and new versions of each file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    /// Myers, spending extra time to find the smallest diff.
+    #[default]
+    Minimal,
+
+    /// git's default, which is quick but can line up braces and blank lines
+    /// of unrelated code.
+    Myers,
+
+    /// Lines up lines that only appear once in each version first, which
+    /// keeps moved functions apart.
+    Patience,
+
+    /// Like patience, but usually quicker, and often the best diff of code.
+    /// The libgit2 backend doesn't have it.
+    Histogram,
+}
+
+impl DiffAlgorithm {
+    /// The `git diff` flag that picks this algorithm.
+    pub fn arg(&self) -> &'static str {
+        match self {
+            DiffAlgorithm::Minimal => "--minimal",
+            DiffAlgorithm::Myers => "--diff-algorithm=myers",
+            DiffAlgorithm::Patience => "--diff-algorithm=patience",
+            DiffAlgorithm::Histogram => "--diff-algorithm=histogram",
+        }
+    }
+}
+
+/// How diffs are read from the repositories.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GitBackend {
+    /// Run the `git` command, which honors everything in the user's git
+    /// config, like textconv filters.
+    #[default]
+    Cli,
+
+    /// Read the repositories in-process with libgit2, which skips spawning a
+    /// process every tick and works without `git` installed.
+    #[cfg(feature = "libgit2")]
+    Libgit2,
+}
+
+/// Which version control system the repositories use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Vcs {
+    #[default]
+    Git,
+
+    /// Jujutsu, read by running `jj`. [DiffConfig::mode] and
+    /// [DiffConfig::backend] don't apply, since jj has no staging area.
+    Jj,
+}
+
+impl Config {
+    /// The whole config as TOML, with every default filled in and secrets
+    /// like API keys, tokens, and headers masked, for seeing what's actually
+    /// in effect.
+    pub fn to_redacted_toml(&self) -> anyhow::Result<String> {
+        let mut config = toml::Value::try_from(self).context("failed to serialize the config")?;
+        redact_secrets(&mut config);
+
+        // services like Slack put the token in the webhook's path
+        if let Some(toml::Value::String(url)) = config
+            .get_mut("webhook")
+            .and_then(|webhook| webhook.get_mut("url"))
+            && let Ok(parsed) = reqwest::Url::parse(url)
+            && let Some(host) = parsed.host_str()
+        {
+            let port = parsed
+                .port()
+                .map(|port| format!(":{port}"))
+                .unwrap_or_default();
+            *url = format!("{}://{host}{port}/{REDACTED}", parsed.scheme());
+        }
+        toml::to_string_pretty(&config).context("failed to serialize the config")
+    }
+
+    /// Where presence updates go: [Config::sinks] when it's set, and
+    /// otherwise [Config::output] followed by every sink with a section of
+    /// its own.
+    pub fn sinks(&self) -> Vec<OutputSink> {
+        if !self.sinks.is_empty() {
+            return self.sinks.clone();
+        }
+
+        [
+            (OutputSink::Discord, self.output.discord()),
+            (OutputSink::File, self.output.file()),
+            (OutputSink::Webhook, self.webhook.is_some()),
+            (OutputSink::Slack, self.slack.is_some()),
+            (OutputSink::Mqtt, self.mqtt.is_some()),
+            (OutputSink::Hook, self.hook.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(sink, enabled)| enabled.then_some(sink))
+        .collect()
+    }
+
+    /// Whether presence updates go to `sink`.
+    pub fn sends_to(&self, sink: OutputSink) -> bool {
+        self.sinks().contains(&sink)
+    }
+
+    /// Parses [Config::frequency_jitter], or `None` when there's none.
+    pub fn jitter(&self) -> anyhow::Result<Option<Jitter>> {
+        self.frequency_jitter
+            .as_deref()
+            .map(Jitter::parse)
+            .transpose()
+    }
+
+    /// The randomness for [Config::next_frequency], seeded with
+    /// [Config::jitter_seed] when it's set.
+    pub fn jitter_rng(&self) -> fastrand::Rng {
+        match self.jitter_seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        }
+    }
+
+    /// How long to wait until the next check: [Config::frequency], moved
+    /// randomly within [Config::frequency_jitter], but never below
+    /// [Config::min_frequency].
+    pub fn next_frequency(&self, rng: &mut fastrand::Rng) -> Duration {
+        // the jitter was checked when the config was loaded
+        match self.jitter().ok().flatten() {
+            Some(jitter) => jitter.apply(self.frequency, rng).max(self.min_frequency),
+            None => self.frequency,
+        }
+    }
+
+    /// The repositories to watch, falling back to [Config::repo_path] and
+    /// then the current directory.
+    pub fn repos(&self) -> Vec<PathBuf> {
+        if !self.repos.is_empty() {
+            return self.repos.clone();
+        }
+
+        vec![self.repo_path.clone().unwrap_or_else(|| PathBuf::from("."))]
+    }
+
+    /// Checks the whole config, reporting every problem found at once, each
+    /// with the field it's about. `discord` is whether Discord will be used,
+    /// since the application ID isn't needed otherwise.
+    pub async fn validate(&self, discord: bool) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if discord && self.sends_to(OutputSink::Discord) {
+            let client = self.discord.client;
+
+            if client == 0 {
+                problems.push(format!(
+                    "discord.client: set this to your Discord application ID, {APPLICATION_ID_HINT}"
+                ));
+            } else if !SNOWFLAKE_DIGITS.contains(&client.to_string().len()) {
+                problems.push(format!(
+                    "discord.client: {client} is too short to be an application ID, \
+                     {APPLICATION_ID_HINT}"
+                ));
+            }
+        }
+
+        if discord && self.sends_to(OutputSink::File) && self.file.path().is_none() {
+            problems
+                .push("file.path: could not locate the cache directory, set a path".to_string());
+        }
+
+        if self.editor.provider == Some(EditorProvider::File) && self.editor.path.is_none() {
+            problems.push(
+                "editor.path: set this to the file the editor lists its open files in".to_string(),
+            );
+        }
+
+        let max_lens = [
+            ("file.max_len", self.file.max_len),
+            (
+                "webhook.max_len",
+                self.webhook.as_ref().and_then(|webhook| webhook.max_len),
+            ),
+            (
+                "slack.max_len",
+                self.slack.as_ref().and_then(|slack| slack.max_len),
+            ),
+            (
+                "mqtt.max_len",
+                self.mqtt.as_ref().and_then(|mqtt| mqtt.max_len),
+            ),
+            (
+                "hook.max_len",
+                self.hook.as_ref().and_then(|hook| hook.max_len),
+            ),
+        ];
+
+        for (field, max_len) in max_lens {
+            if max_len == Some(0) {
+                problems.push(format!("{field}: must be more than zero"));
+            }
+        }
+
+        for (index, sink) in self.sinks.iter().enumerate() {
+            if self.sinks[..index].contains(sink) {
+                problems.push(format!("sinks: {} is listed more than once", sink.name()));
+            }
+
+            let missing = match sink {
+                OutputSink::Webhook => self.webhook.is_none(),
+                OutputSink::Slack => self.slack.is_none(),
+                OutputSink::Mqtt => self.mqtt.is_none(),
+                OutputSink::Hook => self.hook.is_none(),
+                OutputSink::Discord | OutputSink::File => false,
+            };
+
+            if missing {
+                problems.push(format!(
+                    "sinks: {0} needs a [{0}] section to be set up",
+                    sink.name()
+                ));
+            }
+        }
+
+        if let Some(hook) = &self.hook {
+            if hook.command.trim().is_empty() {
+                problems.push("hook.command: must not be empty".to_string());
+            }
+
+            if hook.timeout.is_zero() {
+                problems.push("hook.timeout: must be more than zero".to_string());
+            }
+        }
+
+        if self.min_frequency.is_zero() {
+            problems.push("min_frequency: must be more than zero".to_string());
+        }
+
+        if self.duration.is_some_and(|duration| duration.is_zero()) {
+            problems.push("duration: must be more than zero".to_string());
+        }
+
+        if self.frequency < self.min_frequency {
+            problems.push(format!(
+                "frequency: must be at least {}, since every check runs git and can prompt \
+                 the model (lower min_frequency if you really mean it)",
+                humantime::format_duration(self.min_frequency)
+            ));
+        }
+
+        if let Err(err) = self.jitter() {
+            problems.push(format!("frequency_jitter: {err}"));
+        }
+
+        if self.agent.model.is_empty()
+            || self.agent.model.iter().any(|model| model.trim().is_empty())
+        {
+            problems.push("agent.model: must not be empty".to_string());
+        }
+
+        if self
+            .language
+            .as_ref()
+            .is_some_and(|language| language.trim().is_empty())
+        {
+            problems.push("language: must not be empty".to_string());
+        }
+
+        for (field, value) in [
+            ("agent.preamble", &self.agent.preamble),
+            ("agent.prompt", &self.agent.prompt),
+            ("agent.commit_preamble", &self.agent.commit_preamble),
+            ("agent.commit_prompt", &self.agent.commit_prompt),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(format!("{field}: must not be empty"));
+            }
+        }
+
+        if self
+            .agent
+            .empty_message
+            .as_ref()
+            .is_some_and(|message| message.trim().is_empty())
+        {
+            problems.push("agent.empty_message: must not be empty".to_string());
+        }
+
+        if self.agent.daily_token_budget == Some(0) {
+            problems.push("agent.daily_token_budget: must be more than zero".to_string());
+        }
+
+        if self.agent.max_concurrent_prompts == 0 {
+            problems.push("agent.max_concurrent_prompts: must be at least 1".to_string());
+        }
+
+        if let Some(quiet_period) = self.agent.quiet_period {
+            if quiet_period.is_zero() {
+                problems.push("agent.quiet_period: must be more than zero".to_string());
+            }
+
+            if self.agent.max_quiet_wait < quiet_period {
+                problems.push(format!(
+                    "agent.max_quiet_wait: must be at least agent.quiet_period ({})",
+                    humantime::format_duration(quiet_period)
+                ));
+            }
+        }
+
+        for (index, facet) in self.facets.iter().enumerate() {
+            if facet.prompt.trim().is_empty() {
+                problems.push(format!("facets[{index}].prompt: must not be empty"));
+            }
+
+            if facet
+                .frequency
+                .is_some_and(|frequency| frequency < self.min_frequency)
+            {
+                problems.push(format!(
+                    "facets[{index}].frequency: must be at least min_frequency ({})",
+                    humantime::format_duration(self.min_frequency)
+                ));
+            }
+
+            if let Err(err) = facet.validate_templates() {
+                problems.push(format!("facets[{index}].{err:#}"));
+            }
+        }
+
+        if self.diff.startup_summary > MAX_STARTUP_COMMITS {
+            problems.push(format!(
+                "diff.startup_summary: at most {MAX_STARTUP_COMMITS} commits can be summarized, \
+                 so that their diffs fit in the model's context"
+            ));
+        }
+
+        if self
+            .diff
+            .small_message
+            .as_ref()
+            .is_some_and(|message| message.trim().is_empty())
+        {
+            problems.push("diff.small_message: must not be empty".to_string());
+        }
+
+        if self.diff.filter.is_some() && self.diff.filter_timeout.is_zero() {
+            problems.push("diff.filter_timeout: must be more than zero".to_string());
+        }
+
+        if matches!(self.diff.mode, DiffMode::Stash) {
+            if self.diff.vcs == Vcs::Jj {
+                problems.push("diff.mode: jj has no stash to summarize".to_string());
+            }
+
+            if self.diff.base.is_some() {
+                problems.push("diff.base: can't be used with mode = \"stash\"".to_string());
+            }
+        }
+
+        #[cfg(feature = "libgit2")]
+        if self.diff.backend == GitBackend::Libgit2
+            && self.diff.algorithm == DiffAlgorithm::Histogram
+        {
+            problems.push(
+                "diff.algorithm: the libgit2 backend can't diff with histogram; \
+                 use patience, or backend = \"cli\""
+                    .to_string(),
+            );
+        }
+
+        if let Some(state) = &self.discord.state
+            && let Err(err) = render_template(state, &line_vars(&DiffMetadata::default()))
+        {
+            problems.push(format!("discord.state: {err:#}"));
+        }
+
+        if !(0.0..=1.0).contains(&self.discord.similarity_threshold) {
+            problems.push("discord.similarity_threshold: must be between 0 and 1".to_string());
+        }
+
+        let mut details_vars = line_vars(&DiffMetadata::default()).to_vec();
+        details_vars.push(("summary", String::new()));
+
+        if let Err(err) = render_template(&self.discord.details_template, &details_vars) {
+            problems.push(format!("discord.details_template: {err:#}"));
+        } else if !has_single_placeholder(&self.discord.details_template, "summary")
+            .unwrap_or_default()
+        {
+            problems.push(
+                "discord.details_template: must have {summary} in it exactly once, outside of \
+                 any {if} and without filters"
+                    .to_string(),
+            );
+        } else if self.discord.summary_room(None) == 0 {
+            problems.push(format!(
+                "discord.details_template: the decoration around the summary, with \
+                 details_prefix and details_suffix, leaves no room for it within the line's \
+                 limit of {} characters",
+                self.discord.max_summary_len()
+            ));
+        }
+
+        for (index, range) in self.schedule.quiet.iter().enumerate() {
+            if let Err(err) = QuietHours::parse(range) {
+                problems.push(format!("schedule.quiet[{index}]: {err}"));
+            }
+        }
+
+        for (field, globs) in [
+            ("change_types.test", &self.change_types.test),
+            ("change_types.docs", &self.change_types.docs),
+            ("change_types.chore", &self.change_types.chore),
+        ] {
+            for (index, glob) in globs.iter().enumerate() {
+                if let Err(err) = glob::Pattern::new(glob) {
+                    problems.push(format!("{field}[{index}]: {err}"));
+                }
+            }
+        }
+
+        for (index, rule) in self.discord.verbs.iter().enumerate() {
+            if rule.verb.trim().is_empty() {
+                problems.push(format!("discord.verbs[{index}].verb: must not be empty"));
+            }
+
+            if let Some(branch) = &rule.branch
+                && let Err(err) = regex::Regex::new(branch)
+            {
+                problems.push(format!("discord.verbs[{index}].branch: {err}"));
+            }
+
+            if let (Some(min), Some(max)) = (rule.min_files, rule.max_files)
+                && min > max
+            {
+                problems.push(format!(
+                    "discord.verbs[{index}].max_files: must be at least min_files ({min})"
+                ));
+            }
+        }
+
+        for (field, len) in [
+            ("discord.max_details_len", self.discord.max_details_len),
+            ("discord.max_state_len", self.discord.max_state_len),
+        ] {
+            if len == 0 || len > MAX_FIELD_LEN {
+                problems.push(format!(
+                    "{field}: must be between 1 and {MAX_FIELD_LEN}, the most Discord shows"
+                ));
+            }
+        }
+
+        let repos = self.repos();
+        let names: Vec<_> = repos
+            .iter()
+            .filter_map(|repo| repo_dir_name(repo))
+            .collect();
+
+        for (name, overrides) in &self.overrides {
+            if !names.contains(name) {
+                problems.push(format!(
+                    "overrides.{name}: doesn't match the directory name of any of the repositories"
+                ));
+            }
+
+            if let Err(err) = overrides.validate() {
+                problems.push(format!("overrides.{name}.{err:#}"));
+            }
+        }
+
+        for repo in &repos {
+            if let Err(err) = read_repo_overrides(repo).and_then(|overrides| match overrides {
+                Some(overrides) => overrides.validate(),
+                None => Ok(()),
+            }) {
+                problems.push(format!(
+                    "{}: {err:#}",
+                    repo.join(REPO_CONFIG_FILE).display()
+                ));
+            }
+        }
+
+        if self.discord.idle.enabled && self.discord.idle.details.trim().is_empty() {
+            problems.push("discord.idle.details: must not be empty".to_string());
+        }
+
+        for (index, button) in self.discord.buttons.iter().enumerate() {
+            if let Err(err) = reqwest::Url::parse(&button.url) {
+                problems.push(format!(
+                    "discord.buttons[{index}].url: {:?} is not a valid URL ({err})",
+                    button.url
+                ));
+            }
+        }
+
+        let checks = [
+            self.agent.base_url().map(|_| ()),
+            self.agent.auth_headers().map(drop),
+            self.agent.check_auth().await,
+            self.agent.validate_templates(),
+            self.diff.check_vcs().await,
+            self.validate_repo_path().await,
+            self.webhook
+                .as_ref()
+                .map_or(Ok(()), WebhookConfig::validate),
+            self.slack.as_ref().map_or(Ok(()), SlackConfig::validate),
+            self.mqtt.as_ref().map_or(Ok(()), MqttConfig::validate),
+            self.discord
+                .commit
+                .render("")
+                .map(drop)
+                .context("discord.commit.template"),
+        ];
+
+        problems.extend(
+            checks
+                .into_iter()
+                .filter_map(Result::err)
+                .map(|err| format!("{err:#}")),
+        );
+
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+
+        if !self.watch && self.frequency < FAST_FREQUENCY {
+            tracing::warn!(
+                "checking for changes every {} runs git that often and can keep the model \
+                 busy; consider watch = true instead",
+                humantime::format_duration(self.frequency)
+            );
+        }
+
+        self.check_diff_base().await;
+
+        Ok(())
+    }
+
+    fn default_min_frequency() -> Duration {
+        MIN_FREQUENCY
+    }
+
+    /// This config with the agent's prompts swapped for those of `facet`.
+    pub fn with_facet(&self, facet: &FacetConfig) -> Config {
+        let mut config = self.clone();
+        config.agent.prompt = facet.prompt.clone();
+
+        if let Some(preamble) = &facet.preamble {
+            config.agent.preamble = preamble.clone();
+        }
+
+        config
+    }
+
+    /// This config with the overrides for `repo` applied: those in its
+    /// [REPO_CONFIG_FILE], then those in [Config::overrides], then the global
+    /// settings.
+    ///
+    /// The paths in its [IGNORE_FILE] are excluded on top of everything else.
+    pub fn for_repo(&self, repo: &Path) -> anyhow::Result<Config> {
+        let section = repo_dir_name(repo).and_then(|name| self.overrides.get(&name));
+
+        let file = read_repo_overrides(repo)
+            .with_context(|| format!("failed to read {}", repo.join(REPO_CONFIG_FILE).display()))?;
+
+        let overrides = match (file, section) {
+            (Some(file), Some(section)) => Some(file.or(section)),
+            (Some(file), None) => Some(file),
+            (None, Some(section)) => Some(section.clone()),
+            (None, None) => None,
+        };
+
+        let mut config = self.clone();
+
+        if let Some(overrides) = overrides {
+            overrides.apply(&mut config);
+        }
+
+        let ignored = read_ignore_file(repo)
+            .with_context(|| format!("failed to read {}", repo.join(IGNORE_FILE).display()))?;
+
+        config.diff.exclude.extend(ignored);
+        Ok(config)
+    }
+
+    /// How often an unchanged diff is summarized again with
+    /// [AgentConfig::resummarize_unchanged]: every [Config::frequency], but
+    /// never more often than [AgentConfig::min_prompt_interval] or
+    /// [MIN_RESUMMARIZE_INTERVAL] allow.
+    pub fn resummarize_interval(&self) -> Duration {
+        self.frequency
+            .max(self.agent.min_prompt_interval.unwrap_or_default())
+            .max(MIN_RESUMMARIZE_INTERVAL)
+    }
+
+    /// How long the summary of the facet at `index` is shown before moving on
+    /// to the next one.
+    pub fn facet_frequency(&self, index: usize) -> Duration {
+        self.facets
+            .get(index)
+            .and_then(|facet| facet.frequency)
+            .unwrap_or(self.frequency)
+    }
+
+    /// Warns about repositories that can't be diffed against
+    /// [DiffConfig::base]. These aren't errors, since another branch might
+    /// make the base resolve later.
+    pub async fn check_diff_base(&self) {
+        let Some(base) = &self.diff.base else {
+            return;
+        };
+
+        for repo in self.repos() {
+            if base == UPSTREAM_BASE {
+                if get_upstream(&repo, &self.diff).await.is_none() {
+                    tracing::warn!(
+                        "diff.base: {}'s branch has no upstream, so its uncommitted changes \
+                         are summarized until it does",
+                        repo.display()
+                    );
+                }
+
+                continue;
+            }
+
+            if let Err(err) = get_diff(&repo, &self.diff).await {
+                tracing::warn!(
+                    "diff.base: can't diff {} against {base:?}: {err}",
+                    repo.display()
+                );
+            }
+        }
+    }
+
+    /// Checks that [Config::repo_path], if set, points into a git work tree.
+    pub async fn validate_repo_path(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.repo_path else {
+            return Ok(());
+        };
+
+        if !self.repos.is_empty() {
+            anyhow::bail!("repo_path: set either this or repos, not both");
+        }
+
+        if !path.is_dir() {
+            anyhow::bail!("repo_path: {} is not a directory", path.display());
+        }
+
+        if self.diff.vcs == Vcs::Jj {
+            crate::jj::get_toplevel(path)
+                .await
+                .with_context(|| format!("repo_path: {} is not a jj repo", path.display()))?;
+
+            return Ok(());
+        }
+
+        let context = || format!("repo_path: {} is not a git work tree", path.display());
+
+        #[cfg(feature = "libgit2")]
+        if self.diff.backend == GitBackend::Libgit2 {
+            crate::libgit2::get_toplevel(path)
+                .await
+                .with_context(context)?;
+
+            return Ok(());
+        }
+
+        run_git(path, ["rev-parse", "--is-inside-work-tree"])
+            .await
+            .with_context(context)?;
+
+        Ok(())
+    }
+}
+
+impl DiffConfig {
+    /// Makes sure `jj` can be run when it's configured, rather than failing
+    /// the first time the diff is read.
+    async fn check_vcs(&self) -> anyhow::Result<()> {
+        if self.vcs == Vcs::Jj {
+            crate::jj::run_jj(Path::new("."), ["--version"])
+                .await
+                .context("diff.vcs: jj is configured but can't be run")?;
+        }
+
+        Ok(())
+    }
+
+    /// The `git diff` command line for the summarized changes, with `options`
+    /// for the kind of output wanted.
+    pub fn diff_args(&self, options: &[&str]) -> Vec<String> {
+        let mut args = vec!["diff"];
+        args.extend(options);
+
+        if self.ignore_whitespace {
+            args.push("--ignore-all-space");
+        }
+
+        let context = self.context_arg();
+        args.extend(context.as_deref());
+
+        // a stash entry is a commit on top of the one it was stashed from
+        let stash = self.stash_ref();
+        let stashed_from = format!("{stash}^1");
+
+        match (self.mode, &self.base) {
+            (DiffMode::Stash, _) => args.extend([stashed_from.as_str(), stash.as_str()]),
+            // compare with where HEAD forked from the base, and with the
+            // work tree unless only staged changes are wanted
+            (mode, Some(base)) => {
+                if let DiffMode::Staged = mode {
+                    args.push("--cached");
+                }
+
+                args.extend(["--merge-base", base.as_str()]);
+            }
+            (mode, None) => args.extend(mode.args()),
+        }
+
+        self.with_pathspecs(args)
+    }
+
+    fn default_filter_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    /// Whether `diff` is under [DiffConfig::min_lines] or
+    /// [DiffConfig::min_bytes], going by its added and removed lines.
+    pub fn is_small(&self, diff: &str) -> bool {
+        let (lines, bytes) = changed_size(diff);
+
+        (self.min_lines > 0 && lines < self.min_lines)
+            || (self.min_bytes > 0 && bytes < self.min_bytes)
+    }
+
+    /// The stash entry [DiffMode::Stash] summarizes, like `stash@{0}`.
+    pub fn stash_ref(&self) -> String {
+        format!("stash@{{{}}}", self.stash)
+    }
+
+    /// This config changed to diff the last `count` commits together, for
+    /// [DiffConfig::startup_summary].
+    pub fn recent_commits(&self, count: usize) -> DiffConfig {
+        let mut config = self.clone();
+
+        // the changes between the base and the index are just the commits
+        // when nothing is staged
+        config.mode = DiffMode::Staged;
+        config.base = Some(match self.vcs {
+            Vcs::Git => format!("HEAD~{count}"),
+            Vcs::Jj => format!("@{}", "-".repeat(count + 1)),
+        });
+
+        config
+    }
+
+    /// The `git show` command line for the commit at HEAD, filtered the same
+    /// way as [DiffConfig::diff_args].
+    pub fn show_args(&self, options: &[&str]) -> Vec<String> {
+        let mut args = vec!["show"];
+        args.extend(options);
+
+        if self.ignore_whitespace {
+            args.push("--ignore-all-space");
+        }
+
+        let context = self.context_arg();
+        args.extend(context.as_deref());
+
+        args.push("HEAD");
+        self.with_pathspecs(args)
+    }
+
+    /// The `-U` flag for [DiffConfig::context_lines], if it's set.
+    fn context_arg(&self) -> Option<String> {
+        self.context_lines.map(|lines| format!("-U{lines}"))
+    }
+
+    /// Appends the include and exclude pathspecs to a command line.
+    fn with_pathspecs(&self, args: Vec<&str>) -> Vec<String> {
+        let mut args: Vec<String> = args.into_iter().map(ToOwned::to_owned).collect();
+
+        if !self.include.is_empty() || !self.exclude.is_empty() {
+            args.push("--".to_string());
+            args.extend(self.include.iter().cloned());
+
+            args.extend(
+                self.exclude
+                    .iter()
+                    .map(|pattern| format!(":(exclude){pattern}")),
+            );
+        }
+
+        args
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RedactConfig {
+    /// Whether to scrub secrets from the diff at all.
+    pub enabled: bool,
+
+    /// Extra regexes to redact on top of the built-in set. If a pattern has a
+    /// capture group named `secret`, only that group is replaced.
+    pub patterns: Vec<String>,
+
+    /// Globs of file names whose changes are always redacted wholesale.
+    pub files: Vec<String>,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            patterns: Vec::new(),
+            files: vec![".env".to_string(), ".env.*".to_string()],
+        }
+    }
+}
+
+/// Globs of paths whose changes are always of one kind, checked before the
+/// built-in guesses, like `"fixtures/**"` for tests.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChangeTypeConfig {
+    pub test: Vec<String>,
+    pub docs: Vec<String>,
+    pub chore: Vec<String>,
+}
+
+/// Which set of changes gets summarized.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffMode {
+    /// Unstaged changes in the working tree (`git diff`).
+    #[default]
+    Working,
+
+    /// Changes staged for the next commit (`git diff --cached`).
+    Staged,
+
+    /// Everything in progress relative to HEAD (`git diff HEAD`).
+    All,
+
+    /// The staged and unstaged changes one after the other, each under its
+    /// own heading, so the model can tell them apart. Stats count them
+    /// together, like [DiffMode::All].
+    Combined,
+
+    /// The changes shelved in the [DiffConfig::stash] entry, like
+    /// `git stash show -p`, rather than the work tree's.
+    Stash,
+}
+
+impl DiffMode {
+    /// The arguments passed to `git diff` to select this mode.
+    pub fn args(&self) -> &'static [&'static str] {
+        match self {
+            DiffMode::Working => &[],
+            DiffMode::Staged => &["--cached"],
+            DiffMode::All | DiffMode::Combined => &["HEAD"],
+            // the entry is named by DiffConfig::stash_ref instead
+            DiffMode::Stash => &[],
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DiscordConfig {
+    /// The Application ID of the Discord app the presence is shown for,
+    /// which [Config::validate] checks looks like one.
+    pub client: u64,
+
+    /// Template for the state line under the summary. `{repo}`, `{branch}`,
+    /// `{files}`, and `{change_type}` are replaced with the repository's name,
+    /// the current branch, the number of changed files, and the kind of
+    /// change, like `fix`. An empty template leaves the
+    /// state line blank. See [DiscordConfig::state_template] for the default.
+    ///
+    /// With [SummaryField::State], this goes on the details line instead.
+    #[serde(default)]
+    pub state: Option<String>,
+
+    /// Which line of the activity the summary goes on.
+    #[serde(default)]
+    pub summary_field: SummaryField,
+
+    /// Template for the summary's line, where `{summary}` is the summary,
+    /// like `» {summary} «`. The decoration counts towards the line's limit,
+    /// and the summary is cut short to fit inside it.
+    #[serde(default = "DiscordConfig::default_details_template")]
+    pub details_template: String,
+
+    /// Put in front of the [DiscordConfig::details_template], like
+    /// `[samwise] `.
+    #[serde(default)]
+    pub details_prefix: String,
+
+    /// Put after the [DiscordConfig::details_template].
+    #[serde(default)]
+    pub details_suffix: String,
+
+    /// Put the repository's name in front of the summary, like
+    /// "samwise: fixing the diff parser".
+    #[serde(default)]
+    pub repo_prefix: bool,
+
+    /// The most characters of the summary to show, which is cut short with an
+    /// ellipsis past this. At most [MAX_FIELD_LEN], Discord's own limit.
+    #[serde(default = "DiscordConfig::default_max_len")]
+    pub max_details_len: usize,
+
+    /// How the summary is cut down to fit its line.
+    #[serde(default)]
+    pub truncate: Truncation,
+
+    /// Like [DiscordConfig::max_details_len], for the state line.
+    #[serde(default = "DiscordConfig::default_max_len")]
+    pub max_state_len: usize,
+
+    /// How similar a new summary has to be to the one showing, from 0 to 1,
+    /// for the presence to be left alone instead. See
+    /// [crate::presence::summary_similarity].
+    #[serde(default = "DiscordConfig::default_similarity_threshold")]
+    pub similarity_threshold: f64,
+
+    /// How long a summary stays up before a newer one can replace it, so
+    /// that a burst of edits doesn't flip through summaries faster than they
+    /// can be read. The newest one waits its turn. Clearing the presence
+    /// never waits.
+    #[serde(default, with = "humantime_serde")]
+    pub min_display_time: Option<Duration>,
+
+    /// Whether to show the current branch, or the commit when HEAD is
+    /// detached. Hiding it blanks `{branch}` everywhere it's shown.
+    #[serde(default = "default_true")]
+    pub show_branch: bool,
+
+    /// Show the size of the changes as the activity's party, which Discord
+    /// shows as "(insertions of insertions + deletions)".
+    #[serde(default)]
+    pub party_diff_size: bool,
+
+    /// The verb Discord shows before the application name.
+    #[serde(default)]
+    pub activity_type: ActivityType,
+
+    /// Whether to show how long the current coding session has lasted.
+    #[serde(default = "default_true")]
+    pub timer: bool,
+
+    /// Whether the session timer resets when there are no changes left.
+    #[serde(default = "default_true")]
+    pub reset_timer_on_idle: bool,
+
+    /// Whether to save the session start to disk so that the timer survives
+    /// restarting samwise.
+    #[serde(default)]
+    pub persist_timer: bool,
+
+    /// Whether to save the last presence to disk and put it back up as soon
+    /// as samwise restarts, instead of waiting for the first summary.
+    #[serde(default)]
+    pub persist_presence: bool,
+
+    /// How long the diff can go unchanged before the presence goes idle.
+    /// Unset never goes idle.
+    #[serde(default, with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+
+    /// The summary to show while idle. Unset clears the presence instead.
+    #[serde(default)]
+    pub idle_message: Option<String>,
+
+    /// How long the keyboard and mouse can go untouched before the presence
+    /// is cleared, until they're used again. Unset never checks. Where the
+    /// desktop can't say, this is ignored with a warning.
+    #[serde(default, with = "humantime_serde")]
+    pub input_idle_timeout: Option<Duration>,
+
+    /// Shown in place of a summary while the changes have merge conflicts in
+    /// them, since their markers only confuse the model. Empty summarizes
+    /// them anyway.
+    #[serde(default = "DiscordConfig::default_conflict_message")]
+    pub conflict_message: String,
+
+    /// Shown on Discord while Ollama loads the model for a prompt that's
+    /// taken longer than [DiscordConfig::loading_after], until the summary
+    /// comes in. Unset keeps the last presence up instead.
+    #[serde(default)]
+    pub loading_message: Option<String>,
+
+    /// How long a prompt can take before [DiscordConfig::loading_message] is
+    /// shown.
+    #[serde(
+        default = "DiscordConfig::default_loading_after",
+        with = "humantime_serde"
+    )]
+    pub loading_after: Duration,
+
+    #[serde(default)]
+    pub assets: AssetsConfig,
+
+    /// What to show when there are no changes, instead of clearing the
+    /// presence.
+    #[serde(default)]
+    pub idle: IdleConfig,
+
+    /// What to show for a moment right after a commit lands.
+    #[serde(default)]
+    pub commit: CommitConfig,
+
+    /// Add a "View Repository" button linking to the `origin` remote.
+    #[serde(default)]
+    pub link_repo: bool,
+
+    /// Custom buttons, shown after the repository link if it's enabled.
+    /// Discord only displays the first two.
+    #[serde(default)]
+    pub buttons: Vec<ButtonConfig>,
+
+    /// Rules for what's being done, like "Debugging", shown in front of the
+    /// summary. The first one whose conditions all hold is used.
+    #[serde(default)]
+    pub verbs: Vec<VerbRule>,
+
+    /// The verb when none of [DiscordConfig::verbs] match. Unset shows none.
+    #[serde(default)]
+    pub default_verb: Option<String>,
+}
+
+/// A verb for [DiscordConfig::verbs] and the conditions for using it. Unset
+/// conditions always hold.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VerbRule {
+    pub verb: String,
+
+    /// A regex the branch has to match, like `^fix/`.
+    #[serde(default)]
+    pub branch: Option<String>,
+
+    /// The fewest files that have to be changed.
+    #[serde(default)]
+    pub min_files: Option<usize>,
+
+    /// The most files that can be changed.
+    #[serde(default)]
+    pub max_files: Option<usize>,
+
+    /// Whether the changes have to touch tests, or have to leave them alone.
+    /// See [crate::presence::is_test_path].
+    #[serde(default)]
+    pub tests: Option<bool>,
+
+    /// The kind of change the diff has to look like. See
+    /// [crate::change_type::classify].
+    #[serde(default)]
+    pub change_type: Option<ChangeType>,
+}
+
+/// The line of the activity that shows the summary. Discord shows the
+/// details line more prominently, above the state line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryField {
+    #[default]
+    Details,
+    State,
+}
+
+/// How Discord describes the activity, like "Playing samwise".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityType {
+    #[default]
+    Playing,
+    Listening,
+    Watching,
+    Competing,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ButtonConfig {
+    pub label: String,
+    pub url: String,
+}
+
+/// Images shown in the presence, named after the art assets uploaded in the
+/// Discord developer portal. Unset fields are left out of the activity.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AssetsConfig {
+    pub large_image: Option<String>,
+    pub large_text: Option<String>,
+    pub small_image: Option<String>,
+    pub small_text: Option<String>,
+
+    /// Use the model name as the small image's hover text when `small_text`
+    /// isn't set, so viewers can see which LLM wrote the summary.
+    pub model_as_small_text: bool,
+}
+
+/// The activity shown when there's nothing to summarize, so that the presence
+/// doesn't vanish during breaks. Unset images fall back to [AssetsConfig].
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IdleConfig {
+    /// Show this instead of clearing the presence.
+    pub enabled: bool,
+    pub details: String,
+    pub state: Option<String>,
+    pub large_image: Option<String>,
+    pub large_text: Option<String>,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            details: "Taking a break".to_string(),
+            state: None,
+            large_image: None,
+            large_text: None,
+        }
+    }
+}
+
+/// A short celebration shown when HEAD moves to a new commit, before going
+/// back to summarizing the work tree.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CommitConfig {
+    pub enabled: bool,
+
+    /// What's shown in place of the summary. `{message}` is the commit's
+    /// subject line, or the model's summary of the commit with
+    /// [CommitConfig::summarize].
+    pub template: String,
+
+    /// How long it's shown for.
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+
+    /// Have the model summarize the commit for `{message}`, rather than
+    /// using the subject line as it is.
+    pub summarize: bool,
+}
+
+impl Default for CommitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            template: "✅ committed: {message}".to_string(),
+            duration: Duration::from_secs(10),
+            summarize: false,
+        }
+    }
+}
+
+impl CommitConfig {
+    /// The text shown for a commit whose message is `message`.
+    pub fn render(&self, message: &str) -> anyhow::Result<String> {
+        render_template(&self.template, &[("message", message.to_string())])
+    }
+}
+
+impl DiscordConfig {
+    fn default_max_len() -> usize {
+        MAX_FIELD_LEN
+    }
+
+    fn default_similarity_threshold() -> f64 {
+        1.0
+    }
+
+    fn default_loading_after() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn default_details_template() -> String {
+        "{summary}".to_string()
+    }
+
+    fn default_conflict_message() -> String {
+        "Resolving merge conflicts".to_string()
+    }
+
+    /// The most characters of the summary to show, which is the limit of the
+    /// line it's on.
+    pub fn max_summary_len(&self) -> usize {
+        match self.summary_field {
+            SummaryField::Details => self.max_details_len,
+            SummaryField::State => self.max_state_len,
+        }
+    }
+
+    /// The text that goes before and after the summary on its line, from
+    /// [DiscordConfig::details_template] filled in for `metadata`, and its
+    /// prefix and suffix. Without metadata, its placeholders are left empty.
+    pub fn summary_decoration(&self, metadata: Option<&DiffMetadata>) -> (String, String) {
+        // stands in for the summary, so the template's filled in around it
+        const SUMMARY: &str = "\0";
+
+        let metadata = metadata.cloned().unwrap_or_default();
+        let mut vars = line_vars(&metadata).to_vec();
+        vars.push(("summary", SUMMARY.to_string()));
+
+        let line = render_template(&self.details_template, &vars).unwrap_or_else(|err| {
+            tracing::warn!("failed to fill in discord.details_template: {err:#}");
+            SUMMARY.to_string()
+        });
+
+        let (before, after) = line.split_once(SUMMARY).unwrap_or((&line, ""));
+
+        (
+            format!("{}{before}", self.details_prefix),
+            format!("{after}{}", self.details_suffix),
+        )
+    }
+
+    /// How many characters of the summary fit on its line around its
+    /// [decoration](DiscordConfig::summary_decoration) for `metadata`.
+    pub fn summary_room(&self, metadata: Option<&DiffMetadata>) -> usize {
+        let (before, after) = self.summary_decoration(metadata);
+
+        self.max_summary_len()
+            .saturating_sub(before.chars().count() + after.chars().count())
+    }
+
+    /// Whether `summary` would read the same as what's shown while idle, by
+    /// [DiscordConfig::idle_message] or [DiscordConfig::idle], ignoring case
+    /// and surrounding whitespace.
+    pub fn is_idle_text(&self, summary: &str) -> bool {
+        let summary = summary.trim();
+
+        self.idle_message
+            .iter()
+            .chain(self.idle.enabled.then_some(&self.idle.details))
+            .any(|idle| !summary.is_empty() && idle.trim().eq_ignore_ascii_case(summary))
+    }
+
+    /// The state line template, defaulting to one that leaves out the branch
+    /// when it's hidden.
+    pub fn state_template(&self) -> &str {
+        match &self.state {
+            Some(state) => state,
+            None if self.show_branch => "{repo} on {branch} · {files} files",
+            None => "{repo} · {files} files",
+        }
+    }
+}
+
+/// Settings that one repository can change from the global ones, from
+/// [Config::overrides] or its [REPO_CONFIG_FILE]. Unset ones are left alone.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoOverrides {
+    /// In place of [AgentConfig::preamble].
+    pub preamble: Option<String>,
+
+    /// In place of [AgentConfig::prompt].
+    pub prompt: Option<String>,
+
+    /// In place of [AgentConfig::model].
+    #[serde(default, deserialize_with = "some_one_or_many")]
+    pub model: Option<Vec<String>>,
+
+    /// In place of [DiffConfig::include].
+    pub include: Option<Vec<String>>,
+
+    /// In place of [DiffConfig::exclude].
+    pub exclude: Option<Vec<String>>,
+}
+
+impl RepoOverrides {
+    /// Checks the settings that are overridden.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self
+            .model
+            .as_ref()
+            .is_some_and(|model| model.is_empty() || model.iter().any(|m| m.trim().is_empty()))
+        {
+            anyhow::bail!("model: must not be empty");
+        }
+
+        let vars = prompt_vars(&DiffMetadata::default());
+
+        for (field, value) in [("preamble", &self.preamble), ("prompt", &self.prompt)] {
+            let Some(value) = value else {
+                continue;
+            };
+
+            if value.trim().is_empty() {
+                anyhow::bail!("{field}: must not be empty");
+            }
+
+            render_template(value, &vars).context(field)?;
+        }
+
+        Ok(())
+    }
+
+    /// These overrides, with any that are unset taken from `fallback`.
+    fn or(self, fallback: &RepoOverrides) -> RepoOverrides {
+        RepoOverrides {
+            preamble: self.preamble.or_else(|| fallback.preamble.clone()),
+            prompt: self.prompt.or_else(|| fallback.prompt.clone()),
+            model: self.model.or_else(|| fallback.model.clone()),
+            include: self.include.or_else(|| fallback.include.clone()),
+            exclude: self.exclude.or_else(|| fallback.exclude.clone()),
+        }
+    }
+
+    fn apply(self, config: &mut Config) {
+        if let Some(preamble) = self.preamble {
+            config.agent.preamble = preamble;
+        }
+
+        if let Some(prompt) = self.prompt {
+            config.agent.prompt = prompt;
+        }
+
+        if let Some(model) = self.model {
+            config.agent.model = model;
+        }
+
+        if let Some(include) = self.include {
+            config.diff.include = include;
+        }
+
+        if let Some(exclude) = self.exclude {
+            config.diff.exclude = exclude;
+        }
+    }
+}
+
+/// The name [Config::overrides] knows `repo` by, which is the name of its
+/// directory.
+fn repo_dir_name(repo: &Path) -> Option<String> {
+    // the default repository is ".", which has no name of its own
+    let repo = std::fs::canonicalize(repo).unwrap_or_else(|_| repo.to_path_buf());
+
+    repo.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Reads the [REPO_CONFIG_FILE] in `repo`, if it has one.
+fn read_repo_overrides(repo: &Path) -> anyhow::Result<Option<RepoOverrides>> {
+    let src = match std::fs::read_to_string(repo.join(REPO_CONFIG_FILE)) {
+        Ok(src) => src,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(Some(toml::from_str(&src)?))
+}
+
+/// Reads the [IGNORE_FILE] in `repo` as exclude pathspecs, or none if it
+/// doesn't have one.
+///
+/// Lines follow `.gitignore`: blank ones and `#` comments are skipped, a
+/// pattern without a `/` in it matches at any depth, one with a `/` is
+/// relative to the repository, and a trailing `/` only matches directories.
+/// Negating a pattern with `!` isn't supported, since pathspecs can't.
+pub fn read_ignore_file(repo: &Path) -> anyhow::Result<Vec<String>> {
+    let src = match std::fs::read_to_string(repo.join(IGNORE_FILE)) {
+        Ok(src) => src,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut pathspecs = Vec::new();
+
+    for line in src.lines() {
+        let line = line.trim_end();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('!') {
+            tracing::warn!(
+                "{IGNORE_FILE}: ignoring '{line}', since negated patterns aren't supported"
+            );
+            continue;
+        }
+
+        // `\#` and `\!` start patterns with those characters
+        let line = line.strip_prefix('\\').unwrap_or(line);
+
+        let (pattern, dir_only) = match line.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (line, false),
+        };
+
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        if pattern.is_empty() {
+            continue;
+        }
+
+        // a matching directory takes everything in it along with it
+        let mut matches = vec![format!("{pattern}/*")];
+
+        if !dir_only {
+            matches.push(pattern.to_string());
+        }
+
+        if !anchored {
+            let nested: Vec<_> = matches.iter().map(|m| format!("*/{m}")).collect();
+            matches.extend(nested);
+        }
+
+        pathspecs.extend(matches);
+    }
+
+    Ok(pathspecs)
+}
+
+/// One of the rotating summaries, asked for with its own prompt.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FacetConfig {
+    /// The prompt, in place of [AgentConfig::prompt].
+    pub prompt: String,
+
+    /// The system prompt. Defaults to [AgentConfig::preamble].
+    #[serde(default)]
+    pub preamble: Option<String>,
+
+    /// How long this facet is shown before the next one. Defaults to
+    /// [Config::frequency].
+    #[serde(default, with = "humantime_serde")]
+    pub frequency: Option<Duration>,
+}
+
+impl FacetConfig {
+    /// Checks that the prompts only use known placeholders.
+    pub fn validate_templates(&self) -> anyhow::Result<()> {
+        let vars = prompt_vars(&DiffMetadata::default());
+
+        if let Some(preamble) = &self.preamble {
+            render_template(preamble, &vars).context("preamble")?;
+        }
+
+        render_template(&self.prompt, &vars).context("prompt")?;
+
+        Ok(())
+    }
+}
+
+/// One of [AgentConfig::preamble_includes]: the path of a file relative to
+/// the config, or `{ text = "..." }` for the text itself.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PreambleFragment {
+    File(PathBuf),
+    Text { text: String },
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AgentConfig {
+    #[serde(default)]
+    pub provider: Provider,
+
+    /// The models to summarize with, in order of preference. Each is tried
+    /// in turn when the ones before it fail. Accepts a single name, too.
+    #[serde(deserialize_with = "one_or_many")]
+    pub model: Vec<String>,
+
+    /// The built-in preset that [AgentConfig::preamble] and
+    /// [AgentConfig::prompt] default to. See [crate::preset].
+    #[serde(default)]
+    pub preset: Option<String>,
+
+    pub preamble: String,
+    pub prompt: String,
+
+    /// A file to read [AgentConfig::preamble] from instead, relative to the
+    /// config file. It's resolved and read when the config is loaded, and
+    /// edits to it reload the config like edits to the config itself.
+    #[serde(default)]
+    pub preamble_file: Option<PathBuf>,
+
+    /// Like [AgentConfig::preamble_file], for [AgentConfig::prompt].
+    #[serde(default)]
+    pub prompt_file: Option<PathBuf>,
+
+    /// Fragments put in front of [AgentConfig::preamble], in order, for
+    /// instructions shared between profiles. Like the preamble file, they're
+    /// read when the config is loaded, and the preamble is the result.
+    #[serde(default)]
+    pub preamble_includes: Vec<PreambleFragment>,
+
+    /// The system prompt for drafting commit messages with
+    /// `--write-commit-msg`, in place of [AgentConfig::preamble].
+    #[serde(default = "AgentConfig::default_commit_preamble")]
+    pub commit_preamble: String,
+
+    /// The prompt for drafting commit messages, in place of
+    /// [AgentConfig::prompt].
+    #[serde(default = "AgentConfig::default_commit_prompt")]
+    pub commit_prompt: String,
+
+    /// The base URL of the provider's API. Required for OpenAI-compatible
+    /// providers and optional for the others, which use their usual endpoint.
+    pub base_url: Option<String>,
+
+    /// The API key for remote providers. Falls back to `OPENAI_API_KEY`,
+    /// `ANTHROPIC_API_KEY` for Anthropic, or `SAMWISE_API_KEY` for Ollama,
+    /// which sends it as a bearer token for a reverse proxy in front of it.
+    pub api_key: Option<String>,
+
+    /// The header Ollama's [AgentConfig::api_key] is sent in, as the whole
+    /// value, for proxies that don't look at `Authorization`.
+    pub auth_header: Option<String>,
+
+    /// Diffs larger than this many bytes are cut down to a stat summary and
+    /// as many leading hunks as fit. Everything given along with the diff
+    /// counts toward it, so the whole prompt fits.
+    #[serde(default = "AgentConfig::default_max_diff_bytes")]
+    pub max_diff_bytes: usize,
+
+    /// A shell command run in the repository before every prompt, like a
+    /// build or test run, whose output is given to the model after the diff
+    /// so the summary can tell how the work is going.
+    #[serde(default)]
+    pub context_command: Option<String>,
+
+    /// How long [AgentConfig::context_command] can take before it's left out.
+    #[serde(
+        default = "AgentConfig::default_context_timeout",
+        with = "humantime_serde"
+    )]
+    pub context_timeout: Duration,
+
+    /// The most bytes of [AgentConfig::context_command]'s output to keep, from
+    /// the end of it.
+    #[serde(default = "AgentConfig::default_context_max_bytes")]
+    pub context_max_bytes: usize,
+
+    /// Whether the model is given the diff or only its `--stat`.
+    #[serde(default)]
+    pub summary_source: SummarySource,
+
+    /// How long to wait for the model to respond before giving up on a tick.
+    #[serde(
+        default = "AgentConfig::default_prompt_timeout",
+        with = "humantime_serde"
+    )]
+    pub prompt_timeout: Duration,
+
+    /// Stream the model's response and show it on Discord as it's written,
+    /// rather than only once it's finished. The other outputs only ever get
+    /// the finished summary.
+    #[serde(default)]
+    pub stream: bool,
+
+    /// Send the model a throwaway prompt on startup, so that it's already
+    /// loaded for the first summary.
+    #[serde(default)]
+    pub warmup: bool,
+
+    /// The most tokens to use in a day, by the providers' own counts, before
+    /// the model isn't asked again until the next day. The last presence
+    /// stays up in the meantime. Unlimited when unset.
+    #[serde(default)]
+    pub daily_token_budget: Option<u64>,
+
+    /// The shortest time between prompts, however quickly the diff changes.
+    /// Summaries that would come sooner wait, then use the latest diff.
+    #[serde(default, with = "humantime_serde")]
+    pub min_prompt_interval: Option<Duration>,
+
+    /// How many prompts can be out at once. Past one, the changed
+    /// [Config::repos] that aren't being shown are summarized in the
+    /// background, so that their summaries are ready once they are.
+    #[serde(default = "AgentConfig::default_max_concurrent_prompts")]
+    pub max_concurrent_prompts: usize,
+
+    /// Summarize the diff again every [Config::resummarize_interval] even
+    /// when it hasn't changed, for a fresh phrasing of it, instead of only
+    /// when it changes.
+    #[serde(default)]
+    pub resummarize_unchanged: bool,
+
+    /// How long the diff has to stop changing before it's summarized, so
+    /// that the model doesn't see a half-finished edit. Off when unset.
+    #[serde(default, with = "humantime_serde")]
+    pub quiet_period: Option<Duration>,
+
+    /// The longest to wait for a [AgentConfig::quiet_period] while the diff
+    /// keeps changing, before summarizing it anyway.
+    #[serde(
+        default = "AgentConfig::default_max_quiet_wait",
+        with = "humantime_serde"
+    )]
+    pub max_quiet_wait: Duration,
+
+    /// Regexes matched against the model's response and removed from it,
+    /// which by default strip out the reasoning of thinking models.
+    #[serde(default = "AgentConfig::default_strip_patterns")]
+    pub strip_patterns: Vec<String>,
+
+    /// Unwrap a response fenced in a markdown code block and drop the
+    /// backticks around inline code, since Discord shows them as they are.
+    #[serde(default = "default_true")]
+    pub strip_code_fences: bool,
+
+    /// How much of the response to keep, so that a model that rambles on
+    /// after a good first sentence isn't cut off mid-thought.
+    #[serde(default)]
+    pub response_mode: ResponseMode,
+
+    /// Joins the lines of a multi-line response, like a bulleted list, once
+    /// their list markers are trimmed off.
+    #[serde(default = "AgentConfig::default_line_separator")]
+    pub line_separator: String,
+
+    /// Shown when the model's response is empty once it's cleaned up. When
+    /// unset, the previous summary is kept instead.
+    #[serde(default)]
+    pub empty_message: Option<String>,
+
+    /// What to do with a summary too long for Discord's details line.
+    #[serde(default)]
+    pub overflow: Overflow,
+
+    /// Tell the model what kinds of files the diff mostly changes, like
+    /// "Rust source files", based on their extensions.
+    #[serde(default = "default_true")]
+    pub file_type_hints: bool,
+
+    /// List the changed files and whether each was added, modified, deleted,
+    /// or renamed before the diff, like `{file_list}`.
+    #[serde(default)]
+    pub include_file_list: bool,
+
+    /// What the files with each extension are, for
+    /// [AgentConfig::file_type_hints], in addition to or in place of the
+    /// built-in ones. An empty description leaves the extension out.
+    #[serde(default)]
+    pub file_types: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub params: ModelParams,
+}
+
+/// What the model is given to summarize.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SummarySource {
+    /// The diff itself, cut down to [AgentConfig::max_diff_bytes].
+    #[default]
+    Diff,
+
+    /// Only the `--stat` of the diff, with which files changed and by how
+    /// much, for quick summaries of sprawling changes.
+    Stat,
+
+    /// The diff, or only its stat when it's over
+    /// [AgentConfig::max_diff_bytes].
+    Auto,
+}
+
+/// How much of the model's response is kept as the summary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseMode {
+    /// All of it, with its lines joined by [AgentConfig::line_separator].
+    #[default]
+    Full,
+
+    /// Only its first line.
+    FirstLine,
+
+    /// Only its first sentence, up to the first `.`, `!`, or `?` that ends
+    /// one.
+    FirstSentence,
+}
+
+/// How a summary that's too long to show is shortened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Cut it off with an ellipsis.
+    #[default]
+    Truncate,
+
+    /// Ask the model once to shorten it, and cut it off if it's still too
+    /// long.
+    Recompress,
+}
+
+/// Sampling parameters for the model. Unset ones are left to the provider's
+/// defaults.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ModelParams {
+    /// How random the summaries are. Lower values keep them steadier between
+    /// ticks.
+    pub temperature: Option<f64>,
+
+    /// Nucleus sampling: only the most likely tokens adding up to this
+    /// probability are considered.
+    pub top_p: Option<f64>,
+
+    /// The most tokens the model may generate for a summary.
+    pub max_tokens: Option<u64>,
+
+    /// Anything else, which is warned about and ignored.
+    #[serde(flatten)]
+    pub unsupported: BTreeMap<String, toml::Value>,
+}
+
+impl ModelParams {
+    /// The parameters that rig has no builder methods for, under the names
+    /// that each provider expects.
+    pub fn additional_params(&self, provider: Provider) -> Option<serde_json::Value> {
+        let mut params = serde_json::Map::new();
+
+        if let Some(top_p) = self.top_p {
+            params.insert("top_p".to_string(), top_p.into());
+        }
+
+        if let Some(max_tokens) = self.max_tokens {
+            match provider {
+                // Ollama reads its options from here and ignores `max_tokens`
+                Provider::Ollama => params.insert("num_predict".to_string(), max_tokens.into()),
+                // rig's chat completions request has no field for it
+                Provider::OpenAICompatible => {
+                    params.insert("max_tokens".to_string(), max_tokens.into())
+                }
+                // both take it through the agent instead
+                Provider::OpenAI | Provider::Anthropic => None,
+            };
+        }
+
+        (!params.is_empty()).then_some(serde_json::Value::Object(params))
+    }
+}
+
+impl AgentConfig {
+    fn default_max_diff_bytes() -> usize {
+        16 * 1024
+    }
+
+    fn default_prompt_timeout() -> Duration {
+        Duration::from_secs(120)
+    }
+
+    fn default_max_concurrent_prompts() -> usize {
+        1
+    }
+
+    fn default_context_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_context_max_bytes() -> usize {
+        4 * 1024
+    }
+
+    fn default_max_quiet_wait() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_commit_preamble() -> String {
+        "You write git commit messages. You will be given the diff of the changes \
+         staged for a commit. Describe what the change does, not how the diff \
+         looks."
+            .to_string()
+    }
+
+    fn default_commit_prompt() -> String {
+        "Write the commit message: a summary line of at most 72 characters in the \
+         imperative mood, then a blank line and a short body only if the change \
+         needs explaining. Reply with only the commit message."
+            .to_string()
+    }
+
+    fn default_line_separator() -> String {
+        " ".to_string()
+    }
+
+    fn default_strip_patterns() -> Vec<String> {
+        vec![
+            // complete reasoning blocks
+            r"(?s)<(?:think|thinking|reasoning)>.*?</(?:think|thinking|reasoning)>".to_string(),
+            // reasoning whose opening tag was eaten by the chat template
+            r"(?s)^.*</(?:think|thinking|reasoning)>".to_string(),
+        ]
+    }
+
+    /// Parses and checks the configured base URL, if any.
+    pub fn base_url(&self) -> anyhow::Result<Option<reqwest::Url>> {
+        let Some(base_url) = &self.base_url else {
+            return Ok(None);
+        };
+
+        let url = reqwest::Url::parse(base_url)
+            .with_context(|| format!("agent.base_url: {base_url:?} is not a valid URL"))?;
+
+        if !matches!(url.scheme(), "http" | "https") {
+            anyhow::bail!("agent.base_url: {base_url:?} must be an http:// or https:// URL");
+        }
+
+        Ok(Some(url))
+    }
+
+    /// Checks that the preamble and prompt only use known placeholders.
+    pub fn validate_templates(&self) -> anyhow::Result<()> {
+        let vars = prompt_vars(&DiffMetadata::default());
+
+        render_template(&self.preamble, &vars).context("agent.preamble")?;
+        render_template(&self.prompt, &vars).context("agent.prompt")?;
+        render_template(&self.commit_preamble, &vars).context("agent.commit_preamble")?;
+        render_template(&self.commit_prompt, &vars).context("agent.commit_prompt")?;
+
+        Ok(())
+    }
+
+    /// The preferred model, which is the one named in the presence.
+    pub fn primary_model(&self) -> &str {
+        self.model.first().map_or("", String::as_str)
+    }
+
+    /// Resolves the API key from the config or the environment.
+    pub fn api_key(&self) -> Option<String> {
+        self.api_key
+            .clone()
+            .or_else(|| std::env::var(self.api_key_var()).ok())
+    }
+
+    /// The environment variable [AgentConfig::api_key] falls back to.
+    pub fn api_key_var(&self) -> &'static str {
+        match self.provider {
+            Provider::Anthropic => "ANTHROPIC_API_KEY",
+            Provider::Ollama => "SAMWISE_API_KEY",
+            _ => "OPENAI_API_KEY",
+        }
+    }
+
+    /// The headers every request to Ollama is sent with, which carry the
+    /// [AgentConfig::api_key] if there is one. The other providers' clients
+    /// send their keys themselves.
+    pub fn auth_headers(&self) -> anyhow::Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+
+        let (Provider::Ollama, Some(api_key)) = (self.provider, self.api_key()) else {
+            return Ok(headers);
+        };
+
+        let (name, value) = match &self.auth_header {
+            Some(name) => (
+                HeaderName::try_from(name.as_str())
+                    .with_context(|| format!("agent.auth_header: {name:?} is not a header name"))?,
+                api_key,
+            ),
+            None => (AUTHORIZATION, format!("Bearer {api_key}")),
+        };
+
+        let mut value =
+            HeaderValue::try_from(value).context("agent.api_key: can't be sent in a header")?;
+
+        value.set_sensitive(true);
+        headers.insert(name, value);
+
+        Ok(headers)
+    }
+
+    /// Checks that an Ollama behind [AgentConfig::base_url] doesn't turn
+    /// samwise away for not having a key. Anything other than a 401 or 403
+    /// passes, since the server might only be down for now.
+    pub async fn check_auth(&self) -> anyhow::Result<()> {
+        let (Provider::Ollama, Some(base_url), None) =
+            (self.provider, self.base_url()?, self.api_key())
+        else {
+            return Ok(());
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(AUTH_CHECK_TIMEOUT)
+            .build()
+            .context("failed to create HTTP client")?;
+
+        let url = format!("{}/api/tags", base_url.as_str().trim_end_matches('/'));
+
+        if let Ok(response) = client.get(url).send().await
+            && matches!(response.status().as_u16(), 401 | 403)
+        {
+            anyhow::bail!(
+                "agent.api_key: {base_url} asks for a key ({}); set this or SAMWISE_API_KEY",
+                response.status()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, Hash)]
+pub enum Provider {
+    #[default]
+    #[serde(rename = "ollama")]
+    Ollama,
+    #[serde(rename = "openai")]
+    OpenAI,
+    #[serde(rename = "openai-compatible")]
+    OpenAICompatible,
+    #[serde(rename = "anthropic")]
+    Anthropic,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Masks the values of [SECRET_FIELDS] and of `headers` tables throughout
+/// `value`.
+fn redact_secrets(value: &mut toml::Value) {
+    fn mask(value: &mut toml::Value) {
+        if let toml::Value::String(secret) = value
+            && !secret.is_empty()
+        {
+            *secret = REDACTED.to_string();
+        }
+    }
+
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) {
+                    mask(value);
+                } else if key == "headers"
+                    && let toml::Value::Table(headers) = value
+                {
+                    headers.iter_mut().for_each(|(_, value)| mask(value));
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+/// Deserializes either a single string or a list of them.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+/// [one_or_many] for optional fields.
+fn some_one_or_many<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    one_or_many(deserializer).map(Some)
+}
+
+/// Reads, parses, and checks the config file. See [read_config] for
+/// `profile` and [Config::validate] for `discord`.
+pub async fn load_config(
+    path: &Path,
+    profile: Option<&str>,
+    discord: bool,
+) -> Result<Config, ConfigError> {
+    let config = read_config(path, profile)?;
+    config.validate(discord).await?;
+    Ok(config)
+}
+
+/// Reads and parses the config file, with the selected profile, the
+/// environment's overrides, and the preset applied, but doesn't check it.
+///
+/// `profile` names the `[profiles.<name>]` section to merge over the rest of
+/// the file, falling back to the file's `default_profile`. Without either,
+/// the profiles are left out.
+pub fn read_config(path: &Path, profile: Option<&str>) -> Result<Config, ConfigError> {
+    let config_src = match std::fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ConfigError::Missing(path.to_path_buf()));
+        }
+        Err(source) => {
+            return Err(ConfigError::Unreadable {
+                path: path.to_path_buf(),
+                source,
+            });
+        }
+    };
+
+    parse_config(path, &config_src, profile).map_err(ConfigError::Parse)
+}
+
+/// The part of [read_config] after the file's been read.
+fn parse_config(path: &Path, config_src: &str, profile: Option<&str>) -> anyhow::Result<Config> {
+    parse_config_with_env(path, config_src, profile, |var| std::env::var(var).ok())
+}
+
+/// [parse_config] with the environment variables looked up by `env`.
+fn parse_config_with_env(
+    path: &Path,
+    config_src: &str,
+    profile: Option<&str>,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Config> {
+    let format = ConfigFormat::of(path);
+
+    let mut table = format
+        .parse(config_src)
+        .with_context(|| format!("failed to parse config file as {}", format.name()))?;
+
+    apply_profile(&mut table, profile)?;
+
+    // the settings that stand in for others go first, so that they're read
+    // like the file's, and the rest go over whatever they filled in
+    apply_env_overrides(&mut table, true, &env)?;
+    apply_prompt_files(&mut table, path)?;
+    apply_preset(&mut table)?;
+    apply_env_overrides(&mut table, false, &env)?;
+    apply_preamble_includes(&mut table, path)?;
+    apply_language(&mut table)?;
+
+    toml::Value::Table(table)
+        .try_into()
+        .context("failed to parse config file")
+}
+
+/// An error from loading the config. None of these go away on their own, so
+/// samwise stops at startup and keeps the old config on a reload.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// There's no file at the path, like before `samwise init`.
+    Missing(PathBuf),
+
+    /// The file exists but couldn't be read, like for its permissions.
+    Unreadable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// The file isn't valid TOML, YAML, or JSON, or a setting in it has the
+    /// wrong type.
+    Parse(anyhow::Error),
+
+    /// The settings parsed, but these "field: problem" lines are wrong with
+    /// them.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(path) => write!(
+                f,
+                "no config file at {}; run `samwise init` to create one there, or point \
+                 --config or SAMWISE_CONFIG at another",
+                path.display()
+            ),
+            ConfigError::Unreadable { path, source }
+                if source.kind() == std::io::ErrorKind::PermissionDenied =>
+            {
+                write!(
+                    f,
+                    "the config file at {} exists but isn't readable; check its permissions",
+                    path.display()
+                )
+            }
+            ConfigError::Unreadable { path, .. } => {
+                write!(f, "failed to read config file {}", path.display())
+            }
+            ConfigError::Parse(err) => write!(f, "{err}"),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid config:\n  - {}", problems.join("\n  - "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Unreadable { source, .. } => Some(source),
+            // the parse error is the message, with what caused it after
+            ConfigError::Parse(err) => err.source(),
+            ConfigError::Missing(_) | ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+/// What a config file is written in, picked by its extension. Each is read
+/// into the same table, so they all have the same settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// The format of the config file at `path`, which is TOML unless its
+    /// extension is `.yaml`, `.yml`, or `.json`.
+    pub fn of(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            Some("yaml" | "yml") => Self::Yaml,
+            Some("json") => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Toml => "TOML",
+            Self::Yaml => "YAML",
+            Self::Json => "JSON",
+        }
+    }
+
+    /// Parses a config file into the table TOML would have given.
+    fn parse(self, src: &str) -> anyhow::Result<toml::Table> {
+        let value: serde_json::Value = match self {
+            Self::Toml => return Ok(toml::from_str(src)?),
+            Self::Yaml => serde_yaml::from_str(src)?,
+            Self::Json => serde_json::from_str(src)?,
+        };
+
+        // an empty YAML file is null rather than an empty table
+        if value.is_null() {
+            return Ok(toml::Table::new());
+        }
+
+        Ok(serde_json::from_value(without_nulls(value))?)
+    }
+}
+
+/// Drops the nulls from a value, since TOML has no null and a setting that's
+/// null is as good as left out.
+fn without_nulls(value: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, without_nulls(value)))
+                .collect(),
+        ),
+        Value::Array(array) => Value::Array(
+            array
+                .into_iter()
+                .filter(|value| !value.is_null())
+                .map(without_nulls)
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// Takes the profiles out of the parsed config file and merges the selected
+/// one over the rest of it. See [read_config].
+fn apply_profile(table: &mut toml::Table, profile: Option<&str>) -> anyhow::Result<()> {
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => anyhow::bail!("profiles: expected a table of profiles"),
+        None => toml::Table::new(),
+    };
+
+    let default = match table.remove("default_profile") {
+        Some(toml::Value::String(default)) => Some(default),
+        Some(_) => anyhow::bail!("default_profile: expected the name of a profile"),
+        None => None,
+    };
+
+    let Some(name) = profile.or(default.as_deref()) else {
+        return Ok(());
+    };
+
+    let Some(selected) = profiles.get(name) else {
+        let source = if profile.is_some() {
+            "--profile"
+        } else {
+            "default_profile"
+        };
+
+        if profiles.is_empty() {
+            anyhow::bail!(
+                "{source}: there's no profile named '{name}'; add a [profiles.{name}] section"
+            );
+        }
+
+        let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+
+        anyhow::bail!(
+            "{source}: there's no profile named '{name}' (the config has {})",
+            names.join(", ")
+        );
+    };
+
+    let selected = selected
+        .as_table()
+        .with_context(|| format!("profiles.{name}: expected a table of settings"))?;
+
+    merge_tables(table, selected);
+
+    Ok(())
+}
+
+/// Reads [AgentConfig::preamble_file] and [AgentConfig::prompt_file] over the
+/// preamble and prompt they stand in for, resolving them against the
+/// directory of the config file at `config_path`.
+fn apply_prompt_files(table: &mut toml::Table, config_path: &Path) -> anyhow::Result<()> {
+    let Some(agent) = table.get_mut("agent").and_then(toml::Value::as_table_mut) else {
+        return Ok(());
+    };
+
+    let dir = config_path.parent().unwrap_or(Path::new("."));
+
+    for (file_key, key) in [("preamble_file", "preamble"), ("prompt_file", "prompt")] {
+        let path = match agent.get(file_key) {
+            Some(toml::Value::String(path)) => dir.join(path),
+            Some(_) => anyhow::bail!("agent.{file_key}: expected a path"),
+            None => continue,
+        };
+
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("agent.{file_key}: failed to read {}", path.display()))?;
+
+        let path = path.to_string_lossy().into_owned();
+        agent.insert(file_key.to_string(), toml::Value::String(path));
+
+        // an editor's trailing newline isn't part of the prompt
+        let text = text.trim_end().to_string();
+        agent.insert(key.to_string(), toml::Value::String(text));
+    }
+
+    Ok(())
+}
+
+/// Fills in the preamble and prompt from [AgentConfig::preset] where the
+/// parsed config file leaves them out.
+fn apply_preset(table: &mut toml::Table) -> anyhow::Result<()> {
+    let Some(agent) = table.get_mut("agent").and_then(toml::Value::as_table_mut) else {
+        return Ok(());
+    };
+
+    let name = match agent.get("preset") {
+        Some(toml::Value::String(name)) => name,
+        Some(_) => anyhow::bail!("agent.preset: expected the name of a preset"),
+        None => return Ok(()),
+    };
+
+    let Some(preset) = crate::preset::find(name) else {
+        let names: Vec<&str> = crate::preset::PRESETS
+            .iter()
+            .map(|preset| preset.name)
+            .collect();
+
+        anyhow::bail!(
+            "agent.preset: there's no preset named '{name}' (there's {})",
+            names.join(", ")
+        );
+    };
+
+    for (key, value) in [("preamble", preset.preamble), ("prompt", preset.prompt)] {
+        agent
+            .entry(key)
+            .or_insert_with(|| toml::Value::String(value.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Puts [AgentConfig::preamble_includes] in front of the preamble, after it's
+/// been read from its file or preset, resolving the fragment files against the
+/// directory of the config file at `config_path`.
+fn apply_preamble_includes(table: &mut toml::Table, config_path: &Path) -> anyhow::Result<()> {
+    let Some(agent) = table.get_mut("agent").and_then(toml::Value::as_table_mut) else {
+        return Ok(());
+    };
+
+    let includes = match agent.get_mut("preamble_includes") {
+        Some(toml::Value::Array(includes)) if !includes.is_empty() => includes,
+        Some(toml::Value::Array(_)) | None => return Ok(()),
+        Some(_) => anyhow::bail!("agent.preamble_includes: expected a list of fragments"),
+    };
+
+    let dir = config_path.parent().unwrap_or(Path::new("."));
+    let mut fragments = Vec::new();
+
+    for (index, include) in includes.iter_mut().enumerate() {
+        let text = match include {
+            toml::Value::String(path) => {
+                let resolved = dir.join(&*path);
+
+                let text = std::fs::read_to_string(&resolved).with_context(|| {
+                    format!(
+                        "agent.preamble_includes[{index}]: failed to read {}",
+                        resolved.display()
+                    )
+                })?;
+
+                *path = resolved.to_string_lossy().into_owned();
+                text
+            }
+            toml::Value::Table(fragment) => match fragment.get("text") {
+                Some(toml::Value::String(text)) => text.clone(),
+                _ => anyhow::bail!(
+                    "agent.preamble_includes[{index}]: expected a path or {{ text = \"...\" }}"
+                ),
+            },
+            _ => anyhow::bail!(
+                "agent.preamble_includes[{index}]: expected a path or {{ text = \"...\" }}"
+            ),
+        };
+
+        let text = text.trim();
+        if !text.is_empty() {
+            fragments.push(text.to_string());
+        }
+    }
+
+    if let Some(toml::Value::String(preamble)) = agent.get("preamble") {
+        let preamble = preamble.trim();
+        if !preamble.is_empty() {
+            fragments.push(preamble.to_string());
+        }
+    }
+
+    let preamble = fragments.join("\n\n");
+    tracing::debug!("composed the preamble from its includes:\n{preamble}");
+    agent.insert("preamble".to_string(), toml::Value::String(preamble));
+
+    Ok(())
+}
+
+/// Fills in the built-in text of the presence from the translations for
+/// [Config::language] where the parsed config file leaves it out.
+fn apply_language(table: &mut toml::Table) -> anyhow::Result<()> {
+    let language = match table.get("language") {
+        Some(toml::Value::String(language)) => language,
+        Some(_) => anyhow::bail!("language: expected the name of a language"),
+        None => return Ok(()),
+    };
+
+    let Some(language) = crate::language::find(language) else {
+        return Ok(());
+    };
+
+    let Some(discord) = table.get_mut("discord").and_then(toml::Value::as_table_mut) else {
+        return Ok(());
+    };
+
+    let show_branch = discord
+        .get("show_branch")
+        .and_then(toml::Value::as_bool)
+        .unwrap_or(true);
+
+    let state = if show_branch {
+        language.state
+    } else {
+        language.state_without_branch
+    };
+
+    for (key, value) in [("conflict_message", language.conflict), ("state", state)] {
+        discord
+            .entry(key)
+            .or_insert_with(|| toml::Value::String(value.to_string()));
+    }
+
+    for (section, key, value) in [
+        ("idle", "details", language.idle),
+        ("commit", "template", language.commit),
+    ] {
+        let section = discord
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+
+        if let Some(section) = section.as_table_mut() {
+            section
+                .entry(key)
+                .or_insert_with(|| toml::Value::String(value.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Recursively copies `over` into `base`, merging tables that both have and
+/// replacing everything else.
+fn merge_tables(base: &mut toml::Table, over: &toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_tables(base, over),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// The type of value a config field expects, for parsing environment
+/// variables into.
+#[derive(Clone, Copy)]
+enum EnvKind {
+    String,
+    Integer,
+    Float,
+    Bool,
+}
+
+/// The config fields that can be overridden from the environment. Each one's
+/// variable is `SAMWISE_` followed by its dotted path in uppercase with the
+/// dots replaced by underscores, e.g. `SAMWISE_AGENT_MODEL`.
+const ENV_OVERRIDES: &[(&str, EnvKind)] = &[
+    ("frequency", EnvKind::String),
+    ("frequency_jitter", EnvKind::String),
+    ("jitter_seed", EnvKind::Integer),
+    ("min_frequency", EnvKind::String),
+    ("startup_delay", EnvKind::String),
+    ("duration", EnvKind::String),
+    ("repo_path", EnvKind::String),
+    ("watch", EnvKind::Bool),
+    ("diff.backend", EnvKind::String),
+    ("diff.vcs", EnvKind::String),
+    ("agent.provider", EnvKind::String),
+    ("agent.model", EnvKind::String),
+    ("agent.preset", EnvKind::String),
+    ("language", EnvKind::String),
+    ("agent.preamble", EnvKind::String),
+    ("agent.prompt", EnvKind::String),
+    ("agent.preamble_file", EnvKind::String),
+    ("agent.prompt_file", EnvKind::String),
+    ("agent.commit_preamble", EnvKind::String),
+    ("agent.commit_prompt", EnvKind::String),
+    ("agent.base_url", EnvKind::String),
+    ("agent.api_key", EnvKind::String),
+    ("agent.auth_header", EnvKind::String),
+    ("agent.max_diff_bytes", EnvKind::Integer),
+    ("agent.summary_source", EnvKind::String),
+    ("agent.context_command", EnvKind::String),
+    ("agent.context_timeout", EnvKind::String),
+    ("agent.context_max_bytes", EnvKind::Integer),
+    ("agent.prompt_timeout", EnvKind::String),
+    ("agent.stream", EnvKind::Bool),
+    ("agent.warmup", EnvKind::Bool),
+    ("agent.daily_token_budget", EnvKind::Integer),
+    ("agent.min_prompt_interval", EnvKind::String),
+    ("agent.max_concurrent_prompts", EnvKind::Integer),
+    ("agent.resummarize_unchanged", EnvKind::Bool),
+    ("agent.quiet_period", EnvKind::String),
+    ("agent.max_quiet_wait", EnvKind::String),
+    ("agent.empty_message", EnvKind::String),
+    ("agent.strip_code_fences", EnvKind::Bool),
+    ("agent.line_separator", EnvKind::String),
+    ("agent.overflow", EnvKind::String),
+    ("agent.response_mode", EnvKind::String),
+    ("agent.file_type_hints", EnvKind::Bool),
+    ("agent.include_file_list", EnvKind::Bool),
+    ("agent.params.temperature", EnvKind::Float),
+    ("agent.params.top_p", EnvKind::Float),
+    ("agent.params.max_tokens", EnvKind::Integer),
+    ("diff.mode", EnvKind::String),
+    ("diff.stash", EnvKind::Integer),
+    ("diff.ignore_whitespace", EnvKind::Bool),
+    ("diff.min_lines", EnvKind::Integer),
+    ("diff.min_bytes", EnvKind::Integer),
+    ("diff.small_message", EnvKind::String),
+    ("diff.filter", EnvKind::String),
+    ("diff.filter_timeout", EnvKind::String),
+    ("diff.context_lines", EnvKind::Integer),
+    ("diff.algorithm", EnvKind::String),
+    ("diff.base", EnvKind::String),
+    ("diff.summarize_commits", EnvKind::Bool),
+    ("diff.startup_summary", EnvKind::Integer),
+    ("redact.enabled", EnvKind::Bool),
+    ("retry.max_attempts", EnvKind::Integer),
+    ("retry.base_delay", EnvKind::String),
+    ("cache.enabled", EnvKind::Bool),
+    ("cache.max_entries", EnvKind::Integer),
+    ("schedule.action", EnvKind::String),
+    ("editor.provider", EnvKind::String),
+    ("editor.path", EnvKind::String),
+    ("schedule.away_message", EnvKind::String),
+    ("output", EnvKind::String),
+    ("log_format", EnvKind::String),
+    ("notify_desktop", EnvKind::Bool),
+    ("notify_branch_switch", EnvKind::Bool),
+    ("file.path", EnvKind::String),
+    ("file.on_exit", EnvKind::String),
+    ("file.max_len", EnvKind::Integer),
+    ("file.truncate", EnvKind::String),
+    ("http.bind", EnvKind::String),
+    ("control.path", EnvKind::String),
+    ("control.persist_pin", EnvKind::Bool),
+    ("slack.token", EnvKind::String),
+    ("slack.emoji", EnvKind::String),
+    ("mqtt.host", EnvKind::String),
+    ("mqtt.port", EnvKind::Integer),
+    ("mqtt.topic", EnvKind::String),
+    ("mqtt.client_id", EnvKind::String),
+    ("mqtt.username", EnvKind::String),
+    ("mqtt.password", EnvKind::String),
+    ("mqtt.qos", EnvKind::Integer),
+    ("mqtt.retain", EnvKind::Bool),
+    ("discord.client", EnvKind::Integer),
+    ("discord.state", EnvKind::String),
+    ("discord.repo_prefix", EnvKind::Bool),
+    ("discord.summary_field", EnvKind::String),
+    ("discord.details_template", EnvKind::String),
+    ("discord.details_prefix", EnvKind::String),
+    ("discord.details_suffix", EnvKind::String),
+    ("discord.max_details_len", EnvKind::Integer),
+    ("discord.truncate", EnvKind::String),
+    ("discord.max_state_len", EnvKind::Integer),
+    ("discord.similarity_threshold", EnvKind::Float),
+    ("discord.min_display_time", EnvKind::String),
+    ("discord.show_branch", EnvKind::Bool),
+    ("discord.party_diff_size", EnvKind::Bool),
+    ("discord.activity_type", EnvKind::String),
+    ("discord.timer", EnvKind::Bool),
+    ("discord.reset_timer_on_idle", EnvKind::Bool),
+    ("discord.persist_timer", EnvKind::Bool),
+    ("discord.persist_presence", EnvKind::Bool),
+    ("discord.idle_timeout", EnvKind::String),
+    ("discord.idle_message", EnvKind::String),
+    ("discord.input_idle_timeout", EnvKind::String),
+    ("discord.conflict_message", EnvKind::String),
+    ("discord.loading_message", EnvKind::String),
+    ("discord.loading_after", EnvKind::String),
+    ("discord.commit.enabled", EnvKind::Bool),
+    ("discord.commit.template", EnvKind::String),
+    ("discord.commit.duration", EnvKind::String),
+    ("discord.commit.summarize", EnvKind::Bool),
+    ("discord.link_repo", EnvKind::Bool),
+    ("discord.default_verb", EnvKind::String),
+    ("discord.idle.enabled", EnvKind::Bool),
+    ("discord.idle.details", EnvKind::String),
+    ("discord.idle.state", EnvKind::String),
+];
+
+/// The [ENV_OVERRIDES] that fill in other fields rather than being used as
+/// they are: the files the preamble and prompt are read from, and the preset
+/// they come from.
+const ENV_STAND_INS: &[&str] = &["agent.preamble_file", "agent.prompt_file", "agent.preset"];
+
+/// Overwrites fields of the parsed config file with any `SAMWISE_*`
+/// environment variables `env` finds, either only the [ENV_STAND_INS] or only
+/// the rest. See [ENV_OVERRIDES].
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    stand_ins: bool,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    let overrides = ENV_OVERRIDES
+        .iter()
+        .filter(|(path, _)| ENV_STAND_INS.contains(path) == stand_ins);
+
+    for (path, kind) in overrides {
+        let var = format!("SAMWISE_{}", path.replace('.', "_").to_uppercase());
+
+        let Some(raw) = env(&var) else {
+            continue;
+        };
+
+        let value = match kind {
+            EnvKind::String => toml::Value::String(raw),
+            EnvKind::Integer => toml::Value::Integer(
+                raw.trim()
+                    .parse()
+                    .with_context(|| format!("{var}: {raw:?} is not an integer"))?,
+            ),
+            EnvKind::Float => toml::Value::Float(
+                raw.trim()
+                    .parse()
+                    .with_context(|| format!("{var}: {raw:?} is not a number"))?,
+            ),
+            EnvKind::Bool => toml::Value::Boolean(
+                raw.trim()
+                    .parse()
+                    .with_context(|| format!("{var}: {raw:?} is not true or false"))?,
+            ),
+        };
+
+        let (parents, key) = path.rsplit_once('.').unwrap_or(("", path));
+        let mut section = &mut *table;
+
+        for parent in parents.split('.').filter(|parent| !parent.is_empty()) {
+            section = section
+                .entry(parent)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .with_context(|| format!("{var}: {parent} in the config file is not a table"))?;
+        }
+
+        section.insert(key.to_string(), value);
+    }
+
+    Ok(())
+}
+
+/// Watches the config file so that edits apply without restarting, along with
+/// the [AgentConfig::preamble_file], [AgentConfig::prompt_file], and
+/// [AgentConfig::preamble_includes] it reads.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    profile: Option<String>,
+    discord: bool,
+    changed: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+
+    /// The names of the files whose changes count, in the watched directories.
+    files: Arc<Mutex<HashSet<OsString>>>,
+    dirs: Mutex<HashSet<PathBuf>>,
+    watcher: Mutex<notify::RecommendedWatcher>,
+}
+
+impl ConfigWatcher {
+    /// Watches the config file at `path`, and the prompt files of `config`,
+    /// which was loaded from it.
+    pub fn new(
+        path: &Path,
+        profile: Option<&str>,
+        discord: bool,
+        config: &Config,
+    ) -> anyhow::Result<Self> {
+        let changed = Arc::new(AtomicBool::new(false));
+        let notify = Arc::new(Notify::new());
+        let files = Arc::new(Mutex::new(HashSet::new()));
+
+        let watcher = notify::recommended_watcher({
+            let changed = changed.clone();
+            let notify = notify.clone();
+            let files = files.clone();
+
+            move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+
+                let files = files.lock().unwrap_or_else(PoisonError::into_inner);
+
+                let ours = event
+                    .paths
+                    .iter()
+                    .filter_map(|path| path.file_name())
+                    .any(|name| files.contains(name));
+
+                if ours && !event.kind.is_access() {
+                    changed.store(true, Ordering::SeqCst);
+                    notify.notify_one();
+                }
+            }
+        })
+        .context("failed to create file watcher")?;
+
+        let this = Self {
+            path: path.to_path_buf(),
+            profile: profile.map(ToOwned::to_owned),
+            discord,
+            changed,
+            notify,
+            files,
+            dirs: Mutex::new(HashSet::new()),
+            watcher: Mutex::new(watcher),
+        };
+
+        this.watch_file(path)?;
+        this.watch_prompt_files(config);
+
+        Ok(this)
+    }
+
+    /// Starts counting changes to the file at `path`.
+    fn watch_file(&self, path: &Path) -> anyhow::Result<()> {
+        use notify::Watcher;
+
+        let Some(name) = path.file_name() else {
+            anyhow::bail!("{} is not a file", path.display());
+        };
+
+        // editors often save by replacing the file, which would end a watch
+        // on the file itself, so watch its directory instead
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut dirs = self.dirs.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if !dirs.contains(dir) {
+            self.watcher
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .watch(dir, notify::RecursiveMode::NonRecursive)
+                .with_context(|| format!("failed to watch {}", dir.display()))?;
+
+            dirs.insert(dir.to_path_buf());
+        }
+
+        self.files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.to_owned());
+
+        Ok(())
+    }
+
+    /// Starts counting changes to the prompt files `config` reads, which can
+    /// change whenever it's reloaded.
+    fn watch_prompt_files(&self, config: &Config) {
+        let agent = &config.agent;
+
+        let includes = agent
+            .preamble_includes
+            .iter()
+            .filter_map(|fragment| match fragment {
+                PreambleFragment::File(path) => Some(path),
+                PreambleFragment::Text { .. } => None,
+            });
+
+        for path in [&agent.preamble_file, &agent.prompt_file]
+            .into_iter()
+            .flatten()
+            .chain(includes)
+        {
+            if let Err(err) = self.watch_file(path) {
+                tracing::warn!("not reloading the config when a prompt file changes: {err:#}");
+            }
+        }
+    }
+
+    /// Waits until the file changes, for when there's nothing else to wait
+    /// on. Follow it up with [ConfigWatcher::poll] to reload it.
+    pub async fn changed(&self) {
+        self.notify.notified().await;
+    }
+
+    /// Reloads the config if the file changed since the last call. A config
+    /// that fails to load is logged and skipped.
+    pub async fn poll(&self, current: &Config) -> Option<Config> {
+        if !self.changed.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+
+        let config = match load_config(&self.path, self.profile.as_deref(), self.discord).await {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!(
+                    "failed to reload config, keeping the old one: {}",
+                    crate::error_chain(&err)
+                );
+                return None;
+            }
+        };
+
+        if config.discord.client != current.discord.client {
+            tracing::warn!("discord.client changed; restart samwise to use the new application");
+        }
+
+        if config.output != current.output
+            || config.file.path() != current.file.path()
+            || config.webhook.is_some() != current.webhook.is_some()
+            || config.slack.as_ref().map(SlackConfig::token)
+                != current.slack.as_ref().map(SlackConfig::token)
+            || config.mqtt.as_ref().map(MqttConfig::broker)
+                != current.mqtt.as_ref().map(MqttConfig::broker)
+            || config.hook.is_some() != current.hook.is_some()
+        {
+            tracing::warn!("output changed; restart samwise to send updates somewhere else");
+        }
+
+        if config.http.as_ref().map(|http| http.bind) != current.http.as_ref().map(|http| http.bind)
+        {
+            tracing::warn!("http changed; restart samwise to serve the status somewhere else");
+        }
+
+        if config.control.as_ref().map(ControlConfig::path)
+            != current.control.as_ref().map(ControlConfig::path)
+        {
+            tracing::warn!("control changed; restart samwise to listen somewhere else");
+        }
+
+        if config.log_format != current.log_format {
+            tracing::warn!("log_format changed; restart samwise to use it");
+        }
+
+        self.watch_prompt_files(&config);
+
+        Some(config)
+    }
+}
+
+/// The commented config written by `samwise init`.
+pub const DEFAULT_CONFIG: &str = include_str!("default_config.toml");
+
+/// Writes the default config to `path`, refusing to replace an existing file
+/// unless `force` is set.
+pub fn init_config(path: &Path, force: bool) -> anyhow::Result<()> {
+    let format = ConfigFormat::of(path);
+
+    if format != ConfigFormat::Toml {
+        anyhow::bail!(
+            "the default config is only written as TOML, not {}; point --config at a \
+             .toml file",
+            format.name()
+        );
+    }
+
+    if path.exists() && !force {
+        anyhow::bail!(
+            "{} already exists; pass --force to overwrite it",
+            path.display()
+        );
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+
+    std::fs::write(path, DEFAULT_CONFIG)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    println!("Wrote a default config to {}.", path.display());
+    println!("Set discord.client to your Discord application ID before running samwise.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// The config `samwise init` writes, as it's loaded.
+    pub fn default_config() -> Config {
+        parse_config(Path::new("config.toml"), DEFAULT_CONFIG, None).unwrap()
+    }
+
+    /// The default config with `agent` set over its agent section, loaded as
+    /// a config file in `dir` with only the `vars` set in the environment.
+    fn config_with_env(dir: &Path, agent: &str, vars: &[(&str, &str)]) -> Config {
+        let mut table: toml::Table = toml::from_str(DEFAULT_CONFIG).unwrap();
+        let section = table["agent"].as_table_mut().unwrap();
+        section.extend(toml::from_str::<toml::Table>(agent).unwrap());
+
+        let env = |var: &str| {
+            vars.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.to_string())
+        };
+
+        let src = toml::to_string(&table).unwrap();
+        parse_config_with_env(&dir.join("config.toml"), &src, None, env).unwrap()
+    }
+
+    #[test]
+    fn unreadable_configs_say_why() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // a directory can't be read as a file
+        let Err(err) = read_config(dir.path(), None) else {
+            panic!("expected a directory not to be readable as a config");
+        };
+
+        let source = std::error::Error::source(&err).unwrap();
+        assert!(source.downcast_ref::<std::io::Error>().is_some());
+        assert!(crate::error_chain(&err).starts_with("failed to read config file"));
+    }
+
+    #[test]
+    fn unknown_prompt_placeholders_are_rejected() {
+        let mut agent = default_config().agent;
+        assert!(agent.validate_templates().is_ok());
+
+        // even in a branch that isn't taken for the metadata it's checked with
+        agent.prompt = "{if files == 0}{branch|title}{end}".to_string();
+        let err = format!("{:#}", agent.validate_templates().unwrap_err());
+        assert!(err.starts_with("agent.prompt: unknown filter title"));
+
+        agent.prompt = "Summarize the work on {brnach}.".to_string();
+        let err = format!("{:#}", agent.validate_templates().unwrap_err());
+        assert!(err.contains("unknown placeholder {brnach}"));
+    }
+
+    #[test]
+    fn env_overrides_win_over_prompt_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("prompt.txt"), "from the file\n").unwrap();
+
+        let agent = "prompt_file = \"prompt.txt\"";
+
+        let config = config_with_env(dir.path(), agent, &[]);
+        assert_eq!(config.agent.prompt, "from the file");
+
+        let vars = [("SAMWISE_AGENT_PROMPT", "from env")];
+        let config = config_with_env(dir.path(), agent, &vars);
+        assert_eq!(config.agent.prompt, "from env");
+    }
+
+    #[test]
+    fn env_overrides_win_over_presets() {
+        let dir = tempfile::tempdir().unwrap();
+        let preset = &crate::preset::PRESETS[0];
+
+        // the preset only fills in what the file leaves out
+        let mut table: toml::Table = toml::from_str(DEFAULT_CONFIG).unwrap();
+        let agent = table["agent"].as_table_mut().unwrap();
+        agent.remove("preamble");
+        agent.remove("prompt");
+        agent.insert("preset".to_string(), preset.name.into());
+        let src = toml::to_string(&table).unwrap();
+
+        let env = |var: &str| (var == "SAMWISE_AGENT_PREAMBLE").then(|| "from env".to_string());
+        let config =
+            parse_config_with_env(&dir.path().join("config.toml"), &src, None, env).unwrap();
+
+        assert_eq!(config.agent.preamble, "from env");
+        assert_eq!(config.agent.prompt, preset.prompt);
+    }
+
+    #[test]
+    fn env_prompt_files_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("prompt.txt"), "from the file").unwrap();
+
+        let vars = [("SAMWISE_AGENT_PROMPT_FILE", "prompt.txt")];
+        let config = config_with_env(dir.path(), "", &vars);
+        assert_eq!(config.agent.prompt, "from the file");
+    }
+}
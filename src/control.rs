@@ -0,0 +1,432 @@
+//! Controlling a running samwise over a Unix socket, for scripts and editor
+//! integrations.
+//!
+//! Each line sent to the socket is a command, answered with a line of its
+//! own:
+//!
+//! - `pause` and `resume` pause and resume updates, like `SIGUSR1` and
+//!   `SIGUSR2`.
+//! - `refresh` summarizes the current changes again right away, skipping the
+//!   cache.
+//! - `status` answers with the same JSON as the HTTP server's `GET /status`.
+//! - `set-idle <text>` shows `<text>` when there's nothing to summarize, in
+//!   place of [IdleConfig::details], and `set-idle` on its own goes back to
+//!   the config's.
+//! - `pin <text>` shows `<text>` in place of the summary until `unpin`, while
+//!   everything else about the presence keeps up with the changes.
+//!
+//! Commands other than `status` answer `ok`, and anything else answers
+//! `error: ` and what went wrong.
+//!
+//! [IdleConfig::details]: crate::config::IdleConfig::details
+
+use tokio::sync::mpsc;
+
+use crate::{
+    config::{Config, IdleConfig},
+    http::StatusHandle,
+    pause::Pause,
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+#[cfg(unix)]
+use crate::config::ControlConfig;
+
+/// Something the loop was asked to do over the socket.
+#[derive(Debug)]
+pub enum Command {
+    Pause,
+    Resume,
+    Refresh,
+    SetIdle(Option<String>),
+    Pin(String),
+    Unpin,
+}
+
+impl Command {
+    /// Parses a line sent to the socket. `status` isn't a [Command], since
+    /// it's answered without the loop.
+    fn parse(line: &str) -> Result<Self, String> {
+        let (name, arg) = line
+            .trim()
+            .split_once(char::is_whitespace)
+            .map_or((line.trim(), ""), |(name, arg)| (name, arg.trim()));
+
+        match (name, arg) {
+            ("pause", "") => Ok(Command::Pause),
+            ("resume", "") => Ok(Command::Resume),
+            ("refresh", "") => Ok(Command::Refresh),
+            ("set-idle", "") => Ok(Command::SetIdle(None)),
+            ("set-idle", text) => Ok(Command::SetIdle(Some(text.to_string()))),
+            ("pin", "") => Err("pin needs the text to show".to_string()),
+            ("pin", text) => Ok(Command::Pin(text.to_string())),
+            ("unpin", "") => Ok(Command::Unpin),
+            ("pause" | "resume" | "refresh" | "status" | "unpin", _) => {
+                Err(format!("{name} doesn't take an argument"))
+            }
+            ("", _) => Err("no command given".to_string()),
+            _ => Err(format!("unknown command {name:?}")),
+        }
+    }
+}
+
+/// The loop's end of the control socket, which hears the commands sent to
+/// it and keeps what they asked for. Without a socket, no commands ever
+/// come.
+pub struct Control {
+    commands: Option<mpsc::Receiver<Command>>,
+
+    /// Whether the current changes should be summarized again right away.
+    refresh: bool,
+
+    /// The text shown in place of the summary, given with `pin`.
+    pin: Option<String>,
+
+    /// Whether the pin changed since the loop last looked.
+    pin_changed: bool,
+
+    /// Whether the pin is kept for the next run, from
+    /// [ControlConfig::persist_pin].
+    persist_pin: bool,
+
+    /// The idle presence's text given with `set-idle`, if there is one.
+    idle_details: Option<String>,
+
+    /// The config's own idle presence, to go back to without `set-idle`.
+    config_idle: IdleConfig,
+
+    /// The idle presence last put in the config, which anything else in its
+    /// place, like after a reload, replaces [Control::config_idle].
+    applied_idle: Option<IdleConfig>,
+
+    /// The socket's path, removed once it's no longer listened on.
+    #[cfg(unix)]
+    path: Option<PathBuf>,
+
+    #[cfg(unix)]
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Control {
+    /// Starts listening on the socket [Config::control] asks for, logging a
+    /// warning if it can't be.
+    pub fn listen(config: &Config, status: StatusHandle) -> Self {
+        let mut control = Self {
+            commands: None,
+            refresh: false,
+            pin: None,
+            pin_changed: false,
+            persist_pin: false,
+            idle_details: None,
+            config_idle: IdleConfig::default(),
+            applied_idle: None,
+            #[cfg(unix)]
+            path: None,
+            #[cfg(unix)]
+            task: None,
+        };
+
+        let Some(control_config) = &config.control else {
+            return control;
+        };
+
+        if control_config.persist_pin {
+            control.persist_pin = true;
+            control.pin = load_pin();
+            control.pin_changed = control.pin.is_some();
+        }
+
+        #[cfg(unix)]
+        match bind(control_config) {
+            Ok((path, listener)) => {
+                tracing::info!("listening for commands on {}", path.display());
+
+                let (command_tx, command_rx) = mpsc::channel(16);
+                control.task = Some(tokio::spawn(accept(listener, command_tx, status)));
+                control.commands = Some(command_rx);
+                control.path = Some(path);
+            }
+            Err(err) => tracing::warn!("not listening for commands: {err:#}"),
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = (control_config, status);
+            tracing::warn!("not listening for commands, since there are no Unix sockets here");
+        }
+
+        control
+    }
+
+    /// Waits for the next command.
+    pub async fn recv(&mut self) -> Command {
+        match &mut self.commands {
+            Some(commands) => match commands.recv().await {
+                Some(command) => command,
+                None => std::future::pending().await,
+            },
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Does what `command` asks, pausing or resuming `pause`, or keeping it
+    /// for the loop to pick up.
+    pub fn apply(&mut self, command: Command, pause: &mut Pause) {
+        tracing::debug!(?command, "got a command");
+
+        match command {
+            Command::Pause => pause.set(true),
+            Command::Resume => pause.set(false),
+            Command::Refresh => self.refresh = true,
+            Command::SetIdle(details) => self.idle_details = details,
+            Command::Pin(text) => self.set_pin(Some(text)),
+            Command::Unpin => self.set_pin(None),
+        }
+    }
+
+    fn set_pin(&mut self, pin: Option<String>) {
+        match &pin {
+            Some(pin) => tracing::info!(pin, "pinning the presence"),
+            None => tracing::info!("unpinning the presence"),
+        }
+
+        if self.persist_pin {
+            save_pin(pin.as_deref());
+        }
+
+        self.pin = pin;
+        self.pin_changed = true;
+    }
+
+    /// The text shown in place of the summary, if it's pinned.
+    pub fn pin(&self) -> Option<&str> {
+        self.pin.as_deref()
+    }
+
+    /// Whether the pin changed since this was last called, so whatever's
+    /// showing needs to be replaced.
+    pub fn take_pin_changed(&mut self) -> bool {
+        std::mem::take(&mut self.pin_changed)
+    }
+
+    /// Whether a refresh was asked for since the last summary.
+    pub fn refresh_requested(&self) -> bool {
+        self.refresh
+    }
+
+    /// Marks the refresh that was asked for as done, once the summary is on
+    /// its way.
+    pub fn clear_refresh(&mut self) {
+        self.refresh = false;
+    }
+
+    /// Puts the text from `set-idle` into `config`'s idle presence, or puts
+    /// the config's own back once it's unset.
+    pub fn apply_idle(&mut self, config: &mut Config) {
+        let idle = &mut config.discord.idle;
+
+        if self.applied_idle.as_ref() != Some(idle) {
+            self.config_idle = idle.clone();
+        }
+
+        *idle = self.config_idle.clone();
+
+        if let Some(details) = &self.idle_details {
+            idle.enabled = true;
+            idle.details = details.clone();
+        }
+
+        self.applied_idle = Some(idle.clone());
+    }
+}
+
+/// Where [ControlConfig::persist_pin] keeps the pin between runs.
+fn pin_file() -> Option<std::path::PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("samwise").join("pin"))
+}
+
+/// Loads the pin saved by a previous run, if there is one.
+fn load_pin() -> Option<String> {
+    let pin = std::fs::read_to_string(pin_file()?).ok()?;
+    Some(pin.trim().to_string()).filter(|pin| !pin.is_empty())
+}
+
+/// Saves or removes the pin. Failures are only logged, like
+/// [save_presence](crate::presence::save_presence).
+fn save_pin(pin: Option<&str>) {
+    let Some(path) = pin_file() else {
+        return;
+    };
+
+    let result = match pin {
+        Some(pin) => path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, pin)),
+        None => std::fs::remove_file(&path).or_else(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(err),
+        }),
+    };
+
+    if let Err(err) = result {
+        tracing::warn!("failed to save the pin to {}: {err}", path.display());
+    }
+}
+
+/// Sends one command to the samwise listening on [Config::control] and
+/// returns its answer, failing if it answers with an error.
+pub async fn send(config: &Config, command: &str) -> anyhow::Result<String> {
+    use anyhow::Context;
+
+    let control_config = config
+        .control
+        .as_ref()
+        .context("samwise only takes commands with a [control] section in the config")?;
+
+    #[cfg(unix)]
+    {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let path = control_config
+            .path()
+            .context("failed to locate the control socket")?;
+
+        let mut stream = tokio::net::UnixStream::connect(&path)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to connect to {}; is samwise running?",
+                    path.display()
+                )
+            })?;
+
+        stream
+            .write_all(format!("{command}\n").as_bytes())
+            .await
+            .context("failed to send the command")?;
+
+        let mut reply = String::new();
+        BufReader::new(stream)
+            .read_line(&mut reply)
+            .await
+            .context("failed to read the answer")?;
+
+        let reply = reply.trim_end().to_string();
+
+        match reply.strip_prefix("error: ") {
+            Some(err) => anyhow::bail!("{err}"),
+            None => Ok(reply),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (control_config, command);
+        anyhow::bail!("there are no Unix sockets here to send commands over")
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Control {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+
+        if let Some(path) = &self.path
+            && let Err(err) = std::fs::remove_file(path)
+        {
+            tracing::warn!("failed to remove {}: {err}", path.display());
+        }
+    }
+}
+
+/// Binds the socket, replacing one left behind by a samwise that didn't get
+/// to clean up, but not one that's still being listened on.
+#[cfg(unix)]
+fn bind(config: &ControlConfig) -> anyhow::Result<(PathBuf, tokio::net::UnixListener)> {
+    use anyhow::Context;
+
+    let path = config
+        .path()
+        .context("failed to locate a directory for the socket")?;
+
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        anyhow::bail!("{} is already being listened on", path.display());
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+
+    match std::fs::remove_file(&path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("failed to remove {}", path.display()));
+        }
+        _ => {}
+    }
+
+    let listener = tokio::net::UnixListener::bind(&path)
+        .with_context(|| format!("failed to listen on {}", path.display()))?;
+
+    Ok((path, listener))
+}
+
+/// Takes each connection to the socket and answers the commands sent on it.
+#[cfg(unix)]
+async fn accept(
+    listener: tokio::net::UnixListener,
+    commands: mpsc::Sender<Command>,
+    status: StatusHandle,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(answer(stream, commands.clone(), status.clone()));
+            }
+            Err(err) => {
+                tracing::warn!("failed to accept a connection to the control socket: {err}");
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Answers each line sent on `stream` until it's closed.
+#[cfg(unix)]
+async fn answer(
+    stream: tokio::net::UnixStream,
+    commands: mpsc::Sender<Command>,
+    status: StatusHandle,
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = if line.trim() == "status" {
+            serde_json::to_string(&status.get()).unwrap_or_else(|err| format!("error: {err}"))
+        } else {
+            match Command::parse(&line) {
+                Ok(command) => match commands.send(command).await {
+                    Ok(()) => "ok".to_string(),
+                    Err(_) => "error: samwise is shutting down".to_string(),
+                },
+                Err(err) => format!("error: {err}"),
+            }
+        };
+
+        if writer
+            .write_all(format!("{reply}\n").as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
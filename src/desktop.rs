@@ -0,0 +1,113 @@
+//! Desktop notifications for when Discord connects or disconnects and when
+//! samwise runs into an error, so a presence that stopped updating doesn't go
+//! unnoticed, and optionally for branch switches.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::http::StatusHandle;
+
+/// The shortest time between two notifications of the same kind, so that a
+/// flapping connection doesn't keep popping them up.
+const THROTTLE: Duration = Duration::from_secs(5 * 60);
+
+/// What a notification is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Event {
+    Connected,
+    Disconnected,
+    Error,
+    BranchSwitched,
+}
+
+impl Event {
+    fn summary(&self) -> &'static str {
+        match self {
+            Event::Connected => "Connected to Discord",
+            Event::Disconnected => "Lost the connection to Discord",
+            Event::Error => "samwise ran into an error",
+            Event::BranchSwitched => "Switched branches",
+        }
+    }
+}
+
+/// Shows a notification for every change to `status` worth knowing about,
+/// until the future is dropped, including switches between shown branches
+/// with `branch_switches`. Each kind is shown at most once every [THROTTLE].
+pub async fn run(status: StatusHandle, branch_switches: bool) -> anyhow::Result<()> {
+    let mut updates = status.subscribe();
+    let mut previous = updates.borrow_and_update().clone();
+    let mut shown: HashMap<Event, Instant> = HashMap::new();
+
+    while updates.changed().await.is_ok() {
+        let current = updates.borrow_and_update().clone();
+        let mut events = Vec::new();
+
+        match (previous.discord_connected, current.discord_connected) {
+            (false, true) => events.push((Event::Connected, String::new())),
+            (true, false) => events.push((
+                Event::Disconnected,
+                "The presence won't update until it reconnects.".to_string(),
+            )),
+            _ => {}
+        }
+
+        if let Some(error) = &current.last_error
+            && previous.last_error.as_ref() != Some(error)
+        {
+            events.push((Event::Error, error.clone()));
+        }
+
+        // a hidden branch, or none while there's nothing to summarize, doesn't
+        // count as switching
+        if branch_switches
+            && let (Some(previous), Some(current)) = (&previous.branch, &current.branch)
+            && previous != current
+        {
+            events.push((
+                Event::BranchSwitched,
+                format!("The presence is now about {current}."),
+            ));
+        }
+
+        for (event, body) in events {
+            if shown
+                .get(&event)
+                .is_some_and(|shown| shown.elapsed() < THROTTLE)
+            {
+                tracing::debug!(?event, "not showing another notification so soon");
+                continue;
+            }
+
+            shown.insert(event, Instant::now());
+            show(event.summary(), body).await;
+        }
+
+        previous = current;
+    }
+
+    Ok(())
+}
+
+/// Shows a single notification, logging a warning if there's no notification
+/// server to show it.
+async fn show(summary: &'static str, body: String) {
+    // talking to the notification server blocks
+    let result = tokio::task::spawn_blocking(move || {
+        notify_rust::Notification::new()
+            .appname("samwise")
+            .summary(summary)
+            .body(&body)
+            .show()
+            .map(drop)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::warn!("failed to show a desktop notification: {err}"),
+        Err(err) => tracing::warn!("failed to show a desktop notification: {err}"),
+    }
+}
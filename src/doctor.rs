@@ -0,0 +1,341 @@
+//! `samwise doctor`, which checks everything samwise needs in order to run,
+//! and `samwise --check`, which does the same for scripts.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    agent::{is_pulled, pulled_models},
+    config::{
+        APPLICATION_ID_HINT, AgentConfig, Config, DiffConfig, OutputSink, Provider,
+        SNOWFLAKE_DIGITS, Vcs, read_config,
+    },
+    git::{get_diff, run_git},
+    jj::run_jj,
+};
+
+/// The version of Anthropic's API that its models are listed with.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// How long to wait for the model's server to answer before calling it
+/// unreachable.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs every check, printing a line for each with a hint for fixing it if
+/// it failed, and fails if any of them did.
+pub async fn doctor(config_path: &Path, profile: Option<&str>) -> anyhow::Result<()> {
+    run_checks(Checklist::default(), config_path, profile).await
+}
+
+/// Like [doctor], but for CI and health checks: each check is printed as
+/// `<pass|fail|skip> <check> <what>` on one line, without hints, and Discord
+/// only has its application ID checked, not whether it's running.
+pub async fn check(config_path: &Path, profile: Option<&str>) -> anyhow::Result<()> {
+    let checklist = Checklist {
+        failed: 0,
+        machine: true,
+    };
+
+    run_checks(checklist, config_path, profile).await
+}
+
+async fn run_checks(
+    mut checklist: Checklist,
+    config_path: &Path,
+    profile: Option<&str>,
+) -> anyhow::Result<()> {
+    let config = match read_config(config_path, profile) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            checklist.fail(
+                "config",
+                format!(
+                    "config {} can't be read: {}",
+                    config_path.display(),
+                    crate::error_chain(&err)
+                ),
+                "run `samwise init` to start from the defaults",
+            );
+            None
+        }
+    };
+
+    // the rest is still worth checking when the config has problems
+    if let Some(config) = &config {
+        match config.validate(true).await {
+            Ok(()) => checklist.pass(
+                "config",
+                format!("config {} is valid", config_path.display()),
+            ),
+            Err(err) => checklist.fail(
+                "config",
+                format!(
+                    "config {} has problems: {}",
+                    config_path.display(),
+                    crate::error_chain(&err)
+                ),
+                "fix the problems above",
+            ),
+        }
+    }
+
+    let default_diff = DiffConfig::default();
+    let diff = config.as_ref().map_or(&default_diff, |config| &config.diff);
+
+    check_vcs(&mut checklist, diff).await;
+
+    let repos = config
+        .as_ref()
+        .map_or_else(|| vec![PathBuf::from(".")], Config::repos);
+
+    for repo in &repos {
+        match get_diff(repo, diff).await {
+            Ok(_) => checklist.pass("repo", format!("{} is a repository", repo.display())),
+            Err(err) => checklist.fail(
+                "repo",
+                format!("{} can't be read: {err}", repo.display()),
+                "run samwise inside a repository, or set `repos` in the config",
+            ),
+        }
+    }
+
+    match &config {
+        Some(config) => {
+            for model in &config.agent.model {
+                match ping(&config.agent, model).await {
+                    Ok(()) => checklist.pass("model", format!("model {model} is reachable")),
+                    Err(err) => checklist.fail(
+                        "model",
+                        format!("model {model} isn't reachable: {err:#}"),
+                        model_hint(&config.agent, model),
+                    ),
+                }
+            }
+
+            if config.sends_to(OutputSink::Discord) {
+                check_discord(&mut checklist, config.discord.client);
+            } else {
+                checklist.skip("discord", "Discord isn't used by this config");
+            }
+        }
+        None => {
+            checklist.skip("model", "the model can't be checked without a valid config");
+            checklist.skip("discord", "Discord can't be checked without a valid config");
+        }
+    }
+
+    if checklist.failed > 0 {
+        anyhow::bail!("{} of the checks failed", checklist.failed);
+    }
+
+    Ok(())
+}
+
+/// Prints the result of each check as it's made, under the name of what's
+/// being checked.
+#[derive(Default)]
+struct Checklist {
+    failed: usize,
+
+    /// Print a line per check for [check], rather than for reading.
+    machine: bool,
+}
+
+impl Checklist {
+    fn pass(&self, name: &str, what: impl Display) {
+        self.print("pass", "✓", name, what);
+    }
+
+    fn fail(&mut self, name: &str, what: impl Display, hint: impl Display) {
+        self.print("fail", "✗", name, what);
+
+        if !self.machine {
+            println!("  {hint}");
+        }
+
+        self.failed += 1;
+    }
+
+    fn skip(&self, name: &str, what: impl Display) {
+        self.print("skip", "-", name, what);
+    }
+
+    fn print(&self, status: &str, mark: &str, name: &str, what: impl Display) {
+        if !self.machine {
+            println!("{mark} {what}");
+            return;
+        }
+
+        // multi-line errors, like the config's list of problems, go on the
+        // one line, with the problems split by semicolons
+        let mut line = String::new();
+        for part in what.to_string().lines().map(str::trim) {
+            let part = part.trim_start_matches("- ");
+            if part.is_empty() {
+                continue;
+            }
+
+            if !line.is_empty() {
+                line.push_str(if line.ends_with(':') { " " } else { "; " });
+            }
+
+            line.push_str(part);
+        }
+
+        println!("{status} {name} {line}");
+    }
+}
+
+/// Checks that the configured version control system can be run.
+async fn check_vcs(checklist: &mut Checklist, diff: &DiffConfig) {
+    let (name, version) = match diff.vcs {
+        Vcs::Git => ("git", run_git(Path::new("."), ["--version"]).await),
+        Vcs::Jj => ("jj", run_jj(Path::new("."), ["--version"]).await),
+    };
+
+    match version {
+        Ok(version) => checklist.pass(name, format!("{name} is installed ({})", version.trim())),
+        Err(err) => checklist.fail(
+            name,
+            format!("{name} can't be run: {err}"),
+            format!("install {name} and make sure it's on your PATH"),
+        ),
+    }
+}
+
+/// Asks the provider's server which models it has, failing if it can't be
+/// reached or, for Ollama, if `model` hasn't been pulled.
+async fn ping(agent: &AgentConfig, model: &str) -> anyhow::Result<()> {
+    if let Provider::Ollama = agent.provider {
+        if !is_pulled(&pulled_models(agent).await?, model) {
+            anyhow::bail!("Ollama doesn't have it");
+        }
+
+        return Ok(());
+    }
+
+    let default_url = match agent.provider {
+        Provider::Anthropic => "https://api.anthropic.com",
+        _ => "https://api.openai.com/v1",
+    };
+
+    let base_url = agent.base_url()?.map_or_else(
+        || default_url.to_string(),
+        |url| url.as_str().trim_end_matches('/').to_string(),
+    );
+
+    let client = reqwest::Client::builder()
+        .timeout(PING_TIMEOUT)
+        .build()
+        .context("failed to create HTTP client")?;
+
+    let mut request = match agent.provider {
+        Provider::Anthropic => client
+            .get(format!("{base_url}/v1/models"))
+            .header("anthropic-version", ANTHROPIC_VERSION),
+        _ => client.get(format!("{base_url}/models")),
+    };
+
+    match (agent.api_key(), agent.provider) {
+        (Some(api_key), Provider::Anthropic) => request = request.header("x-api-key", api_key),
+        (Some(api_key), _) => request = request.bearer_auth(api_key),
+        (None, _) => {}
+    }
+
+    // plenty of OpenAI-compatible servers don't list their models, but
+    // answering at all means they're up
+    if request.send().await?.status() == reqwest::StatusCode::UNAUTHORIZED {
+        anyhow::bail!("the API key was rejected");
+    }
+
+    Ok(())
+}
+
+/// What to do about a model that isn't reachable.
+fn model_hint(agent: &AgentConfig, model: &str) -> String {
+    match agent.provider {
+        Provider::Ollama => {
+            format!("start Ollama with `ollama serve` and run `ollama pull {model}`")
+        }
+        Provider::OpenAI | Provider::Anthropic => {
+            format!("check agent.api_key or {}", agent.api_key_var())
+        }
+        Provider::OpenAICompatible => "check that the server at agent.base_url is up".to_string(),
+    }
+}
+
+/// Checks that an application ID is set and that a Discord client is
+/// listening for it, unless it's a [check], which can't count on Discord
+/// running wherever it's run.
+fn check_discord(checklist: &mut Checklist, client: u64) {
+    if client == 0 {
+        checklist.fail(
+            "discord",
+            "discord.client isn't set",
+            "set it to the application ID from the Discord developer portal",
+        );
+
+        return;
+    }
+
+    if !SNOWFLAKE_DIGITS.contains(&client.to_string().len()) {
+        checklist.fail(
+            "discord",
+            format!("discord.client {client} is too short to be an application ID"),
+            format!("set it to the ID the Discord developer portal shows, {APPLICATION_ID_HINT}"),
+        );
+
+        return;
+    }
+
+    if checklist.machine {
+        checklist.pass(
+            "discord",
+            format!("discord.client {client} is an application ID"),
+        );
+        return;
+    }
+
+    match find_discord_socket() {
+        Some(socket) => checklist.pass(
+            "discord",
+            format!("Discord is running ({})", socket.display()),
+        ),
+        None => checklist.fail(
+            "discord",
+            "Discord doesn't seem to be running",
+            "start the Discord desktop app; the web client can't show rich presences",
+        ),
+    }
+}
+
+/// Finds the socket the Discord client listens for rich presence updates on.
+#[cfg(unix)]
+fn find_discord_socket() -> Option<PathBuf> {
+    let dirs = ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+        .into_iter()
+        .filter_map(std::env::var_os)
+        .map(PathBuf::from)
+        .chain([PathBuf::from("/tmp")]);
+
+    // Flatpak and Snap builds of Discord put theirs in a subdirectory
+    let sandboxes = ["", "app/com.discordapp.Discord", "snap.discord"];
+
+    dirs.flat_map(|dir| sandboxes.map(|sandbox| dir.join(sandbox)))
+        .flat_map(|dir| (0..10).map(move |index| dir.join(format!("discord-ipc-{index}"))))
+        .find(|socket| socket.exists())
+}
+
+/// Finds the named pipe the Discord client listens for rich presence updates
+/// on.
+#[cfg(not(unix))]
+fn find_discord_socket() -> Option<PathBuf> {
+    (0..10)
+        .map(|index| PathBuf::from(format!(r"\\.\pipe\discord-ipc-{index}")))
+        .find(|pipe| pipe.exists())
+}
@@ -0,0 +1,45 @@
+//! Learning which files are open in the editor, so that the summary can be
+//! about those rather than every change in the repository.
+
+use std::path::PathBuf;
+
+use crate::config::{EditorConfig, EditorProvider};
+
+/// The absolute paths of the files open in the editor, or `None` when
+/// there's no telling, like without a provider or while the editor isn't
+/// running. The changes to every file are summarized then.
+pub fn open_files(config: &EditorConfig) -> Option<Vec<PathBuf>> {
+    let files = match config.provider? {
+        EditorProvider::File => read_file_list(config)?,
+    };
+
+    (!files.is_empty()).then_some(files)
+}
+
+/// Reads [EditorConfig::path], one path per line. A missing file means the
+/// editor isn't running, so it isn't worth a warning.
+fn read_file_list(config: &EditorConfig) -> Option<Vec<PathBuf>> {
+    let path = config.path.as_ref()?;
+
+    let list = match std::fs::read_to_string(path) {
+        Ok(list) => list,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            tracing::warn!("failed to read open files from {}: {err}", path.display());
+            return None;
+        }
+    };
+
+    let files = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            // deleted files can't be resolved, but can still have changes
+            let file = PathBuf::from(line);
+            file.canonicalize().unwrap_or(file)
+        })
+        .collect();
+
+    Some(files)
+}
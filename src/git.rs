@@ -0,0 +1,1364 @@
+//! Running git and making sense of its output.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    ffi::OsStr,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "libgit2")]
+use crate::{config::GitBackend, libgit2};
+
+use crate::{
+    change_type::{self, ChangeType},
+    config::{ChangeTypeConfig, DiffConfig, DiffMode, Vcs},
+    jj,
+};
+
+/// Checks whether a diff adds merge conflict markers, like one in the middle
+/// of a merge or rebase that stopped on conflicts. Git shows unmerged files
+/// as combined diffs, whose lines start with two columns of `+`.
+pub fn has_conflicts(diff: &str) -> bool {
+    diff.lines().any(|line| {
+        line.starts_with("diff --cc ")
+            || line
+                .strip_prefix('+')
+                .is_some_and(|added| added.trim_start_matches('+').starts_with("<<<<<<< "))
+    })
+}
+
+/// Checks whether a diff changes nothing but whitespace, comparing all of its
+/// removed and added lines with the whitespace stripped out.
+pub fn is_whitespace_only(diff: &str) -> bool {
+    let mut removed = String::new();
+    let mut added = String::new();
+
+    for line in diff.lines() {
+        if line.starts_with("---") || line.starts_with("+++") {
+            continue;
+        }
+
+        let (side, content) = if let Some(content) = line.strip_prefix('-') {
+            (&mut removed, content)
+        } else if let Some(content) = line.strip_prefix('+') {
+            (&mut added, content)
+        } else {
+            continue;
+        };
+
+        side.extend(content.chars().filter(|c| !c.is_whitespace()));
+    }
+
+    removed == added
+}
+
+/// How many lines a git-format diff adds and removes, and how many bytes they
+/// come to, leaving out the file headers.
+pub fn changed_size(diff: &str) -> (usize, usize) {
+    diff.lines()
+        .filter(|line| !line.starts_with("---") && !line.starts_with("+++"))
+        .filter_map(|line| line.strip_prefix('-').or_else(|| line.strip_prefix('+')))
+        .fold((0, 0), |(lines, bytes), line| {
+            (lines + 1, bytes + line.len())
+        })
+}
+
+/// The paths of the files a git-format diff changes. A combined diff can have
+/// the same file twice, but it's only listed once.
+pub fn changed_paths(diff: &str) -> BTreeSet<&str> {
+    diff.lines()
+        .filter_map(|line| line.strip_prefix("diff --git "))
+        .filter_map(|header| header.rsplit_once(" b/"))
+        .map(|(_, path)| path)
+        .collect()
+}
+
+/// At most this many files are listed by [file_list], so a sprawling change
+/// doesn't crowd out the diff.
+pub const MAX_LISTED_FILES: usize = 50;
+
+/// How a diff changes one file, like a line of `git diff --name-status`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileStatus {
+    Added(String),
+    Modified(String),
+    Deleted(String),
+    Renamed { from: String, to: String },
+}
+
+impl FileStatus {
+    /// The path of the file after the change.
+    pub fn path(&self) -> &str {
+        match self {
+            FileStatus::Added(path) | FileStatus::Modified(path) | FileStatus::Deleted(path) => {
+                path
+            }
+            FileStatus::Renamed { to, .. } => to,
+        }
+    }
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileStatus::Added(path) => write!(f, "added {path}"),
+            FileStatus::Modified(path) => write!(f, "modified {path}"),
+            FileStatus::Deleted(path) => write!(f, "deleted {path}"),
+            FileStatus::Renamed { from, to } => write!(f, "renamed {from} -> {to}"),
+        }
+    }
+}
+
+/// How a git-format diff changes each of its files, going by the extended
+/// headers after each `diff --git` line. Copies count as added files.
+pub fn name_status(diff: &str) -> Vec<FileStatus> {
+    let mut files = Vec::new();
+
+    // `---` and `+++` are only file headers before a file's first hunk
+    let mut in_hunk = false;
+
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("diff --git ") {
+            let path = header.rsplit_once(" b/").map_or(header, |(_, path)| path);
+            files.push(FileStatus::Modified(path.to_string()));
+            in_hunk = false;
+            continue;
+        }
+
+        if line.starts_with("@@") {
+            in_hunk = true;
+        }
+
+        let Some(file) = files.last_mut().filter(|_| !in_hunk) else {
+            continue;
+        };
+
+        if line.starts_with("new file mode") || line.starts_with("copy to ") {
+            *file = FileStatus::Added(file.path().to_string());
+        } else if line.starts_with("deleted file mode") {
+            *file = FileStatus::Deleted(file.path().to_string());
+        } else if let Some(from) = line.strip_prefix("rename from ") {
+            *file = FileStatus::Renamed {
+                from: from.to_string(),
+                to: file.path().to_string(),
+            };
+        }
+    }
+
+    files
+}
+
+/// The files a git-format diff changes, one per line with how they changed,
+/// like "added src/parser.rs". Past [MAX_LISTED_FILES], the rest are only
+/// counted.
+pub fn file_list(diff: &str) -> String {
+    let files = name_status(diff);
+
+    let mut lines: Vec<String> = files
+        .iter()
+        .take(MAX_LISTED_FILES)
+        .map(FileStatus::to_string)
+        .collect();
+
+    if files.len() > MAX_LISTED_FILES {
+        lines.push(format!("and {} more", files.len() - MAX_LISTED_FILES));
+    }
+
+    lines.join("\n")
+}
+
+/// Takes the changes to binary files out of a diff, since all they say is that
+/// the files differ, and names the files in a note at the end instead. A diff
+/// of only binary files is left with just the note.
+pub fn strip_binary(diff: &str) -> String {
+    let mut out = String::with_capacity(diff.len());
+    let mut binary = Vec::new();
+
+    let mut sections = Vec::new();
+    let mut start = 0;
+
+    // the headings of a combined diff get sections of their own, so that
+    // they're kept even when a binary file comes before them
+    for (idx, _) in diff.match_indices('\n') {
+        let rest = &diff[idx + 1..];
+
+        if rest.starts_with("diff --git ") || rest.starts_with("# ") {
+            sections.push(&diff[start..=idx]);
+            start = idx + 1;
+        }
+    }
+
+    sections.push(&diff[start..]);
+
+    for section in sections {
+        let is_binary = section.lines().any(|line| {
+            line == "GIT binary patch"
+                || (line.starts_with("Binary files ") && line.ends_with(" differ"))
+        });
+
+        if !is_binary {
+            out.push_str(section);
+            continue;
+        }
+
+        // headers look like "diff --git a/path/to/file b/path/to/file"
+        let path = section
+            .lines()
+            .next()
+            .and_then(|header| header.rsplit_once(" b/"))
+            .map_or("?", |(_, path)| path);
+
+        binary.push(path);
+    }
+
+    if !binary.is_empty() {
+        let files = if binary.len() == 1 { "file" } else { "files" };
+
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+
+        out.push_str(&format!(
+            "{} binary {files} changed: {}\n",
+            binary.len(),
+            binary.join(", ")
+        ));
+    }
+
+    out
+}
+
+/// Hashes a diff so that changes can be detected without keeping it around.
+pub fn diff_hash(diff: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    diff.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// An error from running `git` or `jj`.
+#[derive(Debug)]
+pub enum GitError {
+    /// `git` couldn't be spawned at all, which usually means it isn't installed.
+    NotInstalled(std::io::Error),
+
+    /// The current directory isn't inside a git work tree.
+    NotARepository,
+
+    /// `git` ran but exited unsuccessfully for some other reason.
+    Failed { status: ExitStatus, stderr: String },
+
+    /// `jj` couldn't be spawned, when `diff.vcs` asks for it.
+    JjNotInstalled(std::io::Error),
+
+    /// `jj` ran but exited unsuccessfully.
+    JjFailed { status: ExitStatus, stderr: String },
+
+    /// Reading `git`'s output failed.
+    Io(std::io::Error),
+
+    /// libgit2 failed to read the repository.
+    #[cfg(feature = "libgit2")]
+    Libgit2(git2::Error),
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitError::NotInstalled(err) => {
+                write!(f, "failed to spawn git (is it installed?): {err}")
+            }
+            GitError::NotARepository => write!(f, "not inside a git repository"),
+            GitError::Failed { status, stderr } => {
+                write!(f, "git failed ({status}): {}", stderr.trim())
+            }
+            GitError::JjNotInstalled(err) => {
+                write!(f, "failed to spawn jj (is it installed?): {err}")
+            }
+            GitError::JjFailed { status, stderr } => {
+                write!(f, "jj failed ({status}): {}", stderr.trim())
+            }
+            GitError::Io(err) => write!(f, "failed to read git output: {err}"),
+            #[cfg(feature = "libgit2")]
+            GitError::Libgit2(err) => write!(f, "libgit2 failed: {}", err.message()),
+        }
+    }
+}
+
+impl std::error::Error for GitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GitError::NotInstalled(err) | GitError::JjNotInstalled(err) | GitError::Io(err) => {
+                Some(err)
+            }
+            #[cfg(feature = "libgit2")]
+            GitError::Libgit2(err) => Some(err),
+            GitError::NotARepository | GitError::Failed { .. } | GitError::JjFailed { .. } => None,
+        }
+    }
+}
+
+/// The [DiffConfig::base] that stands for wherever the current branch was
+/// last pushed to.
+pub const UPSTREAM_BASE: &str = "upstream";
+
+/// Gets the upstream of the current branch, like `origin/main`, or for jj, the
+/// nearest ancestor of the working copy that's on a remote. `None` if there
+/// isn't one.
+pub async fn get_upstream(repo: &Path, config: &DiffConfig) -> Option<String> {
+    if config.vcs == Vcs::Jj {
+        return jj::get_upstream(repo).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_upstream(repo).await;
+    }
+
+    run_git(
+        repo,
+        ["rev-parse", "--abbrev-ref", "--symbolic-full-name", "@{u}"],
+    )
+    .await
+    .ok()
+    .map(|upstream| upstream.trim().to_string())
+    .filter(|upstream| !upstream.is_empty())
+}
+
+/// Swaps an [UPSTREAM_BASE] for the current branch's upstream, which is
+/// looked up every time since the branch can change. Without an upstream,
+/// there's no base, and the uncommitted changes are diffed instead.
+async fn resolve_base<'c>(repo: &Path, config: &'c DiffConfig) -> Cow<'c, DiffConfig> {
+    if config.base.as_deref() != Some(UPSTREAM_BASE) {
+        return Cow::Borrowed(config);
+    }
+
+    let upstream = get_upstream(repo, config).await;
+
+    if upstream.is_none() {
+        tracing::debug!("{} has no upstream, diffing without a base", repo.display());
+    }
+
+    let mut config = config.clone();
+    config.base = upstream;
+    Cow::Owned(config)
+}
+
+pub async fn get_diff(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    let config = &*resolve_base(repo, config).await;
+
+    if config.vcs == Vcs::Jj {
+        return jj::get_diff(repo, config).await;
+    }
+
+    let DiffMode::Combined = config.mode else {
+        return get_git_diff(repo, config).await;
+    };
+
+    let mut combined = String::new();
+
+    for (heading, part) in combined_parts(config) {
+        let diff = get_git_diff(repo, &part).await?;
+
+        if !diff.is_empty() {
+            combined += &format!("{heading}\n{diff}");
+        }
+    }
+
+    Ok(combined)
+}
+
+/// Gets a single diff with whichever backend `config` picks.
+async fn get_git_diff(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_diff(repo, config).await;
+    }
+
+    if stash_missing(repo, config).await {
+        return Ok(String::new());
+    }
+
+    run_git(repo, &config.diff_args(&[config.algorithm.arg()])).await
+}
+
+/// Whether `config` is for a [DiffMode::Stash] entry that isn't there, like
+/// when everything's been popped, which is treated like there being no
+/// changes. It's checked every time, since stashes come and go.
+async fn stash_missing(repo: &Path, config: &DiffConfig) -> bool {
+    let DiffMode::Stash = config.mode else {
+        return false;
+    };
+
+    let stash = config.stash_ref();
+    let missing = run_git(repo, ["rev-parse", "--verify", "--quiet", &stash])
+        .await
+        .is_err();
+
+    if missing {
+        tracing::debug!(
+            "{} has no {stash}, so there's nothing to summarize",
+            repo.display()
+        );
+    }
+
+    missing
+}
+
+/// The headed halves of a [DiffMode::Combined] diff: the staged changes,
+/// starting from the base if there is one, then the unstaged ones on top.
+fn combined_parts(config: &DiffConfig) -> [(&'static str, DiffConfig); 2] {
+    let mut staged = config.clone();
+    staged.mode = DiffMode::Staged;
+
+    let mut unstaged = config.clone();
+    unstaged.mode = DiffMode::Working;
+    unstaged.base = None;
+
+    [("# staged", staged), ("# unstaged", unstaged)]
+}
+
+/// Gets the `git diff --stat` summary of the same changes as [get_diff].
+pub async fn get_diff_stat(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    let config = &*resolve_base(repo, config).await;
+
+    if config.vcs == Vcs::Jj {
+        return jj::get_diff_stat(repo, config).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_diff_stat(repo, config).await;
+    }
+
+    if stash_missing(repo, config).await {
+        return Ok(String::new());
+    }
+
+    run_git(repo, &config.diff_args(&["--stat"])).await
+}
+
+/// Metadata about the current changes, shown alongside the summary.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffMetadata {
+    /// The name of the repository's directory.
+    pub repo: String,
+
+    /// The name of the checked-out branch.
+    pub branch: String,
+
+    /// The number of files with changes.
+    pub files: usize,
+
+    /// The number of added lines.
+    pub insertions: usize,
+
+    /// The number of removed lines.
+    pub deletions: usize,
+
+    /// What kind of change this looks like, once [DiffMetadata::classify]
+    /// has seen the diff.
+    #[serde(default)]
+    pub change_type: Option<ChangeType>,
+
+    /// The changed files and how each changed, from [file_list], once
+    /// [DiffMetadata::classify] has seen the diff.
+    #[serde(skip)]
+    pub file_list: String,
+}
+
+impl DiffMetadata {
+    /// Counts the files and lines changed in a git-format diff, for when
+    /// there's no `--numstat` to ask. The repository and branch are left
+    /// blank.
+    pub fn count(diff: &str) -> Self {
+        let mut metadata = Self::default();
+
+        // `---` and `+++` are only file headers before a file's first hunk
+        let mut in_hunk = false;
+
+        for line in diff.lines() {
+            if line.starts_with("diff --git ") {
+                metadata.files += 1;
+                in_hunk = false;
+            } else if line.starts_with("@@") {
+                in_hunk = true;
+            } else if in_hunk && line.starts_with('+') {
+                metadata.insertions += 1;
+            } else if in_hunk && line.starts_with('-') {
+                metadata.deletions += 1;
+            }
+        }
+
+        metadata
+    }
+
+    /// Adds up the output of `git diff --numstat`. The repository and branch
+    /// are left blank.
+    pub fn from_numstat(numstat: &str) -> Self {
+        let mut metadata = Self::default();
+
+        // each line is "<insertions>\t<deletions>\t<path>", with "-" for binaries
+        for line in numstat.lines().filter(|line| !line.is_empty()) {
+            let mut counts = line.split('\t').map(|count| count.parse().unwrap_or(0));
+            metadata.files += 1;
+            metadata.insertions += counts.next().unwrap_or(0);
+            metadata.deletions += counts.next().unwrap_or(0);
+        }
+
+        metadata
+    }
+
+    /// Sets [DiffMetadata::change_type] from `diff` and the branch, and
+    /// [DiffMetadata::file_list] from `diff`.
+    pub fn classify(mut self, diff: &str, config: &ChangeTypeConfig) -> Self {
+        self.change_type = change_type::classify(diff, &self.branch, config);
+        self.file_list = file_list(diff);
+        self
+    }
+
+    /// Summarizes the size of the changes like `git diff --shortstat`, such
+    /// as "3 files changed, 10 insertions(+), 2 deletions(-)".
+    pub fn shortstat(&self) -> String {
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+
+        let mut stat = format!("{} file{} changed", self.files, plural(self.files));
+
+        if self.insertions > 0 {
+            stat += &format!(
+                ", {} insertion{}(+)",
+                self.insertions,
+                plural(self.insertions)
+            );
+        }
+
+        if self.deletions > 0 {
+            stat += &format!(", {} deletion{}(-)", self.deletions, plural(self.deletions));
+        }
+
+        stat
+    }
+}
+
+pub async fn get_metadata(repo: &Path, config: &DiffConfig) -> Result<DiffMetadata, GitError> {
+    let config = &*resolve_base(repo, config).await;
+
+    if config.vcs == Vcs::Jj {
+        return jj::get_metadata(repo, config).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_metadata(repo, config).await;
+    }
+
+    let numstat = if stash_missing(repo, config).await {
+        String::new()
+    } else {
+        run_git(repo, &config.diff_args(&["--numstat"])).await?
+    };
+
+    metadata_with_numstat(repo, &numstat).await
+}
+
+/// Gets the hash of the commit at HEAD, or `None` before the first commit.
+pub async fn get_head(repo: &Path, config: &DiffConfig) -> Option<String> {
+    if config.vcs == Vcs::Jj {
+        return jj::get_head(repo).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_head(repo).await;
+    }
+
+    run_git(repo, ["rev-parse", "--verify", "--quiet", "HEAD"])
+        .await
+        .ok()
+        .map(|head| head.trim().to_string())
+}
+
+/// Gets the checked-out branch, or jj's nearest bookmark, like the branch of
+/// [DiffMetadata], for noticing when it changes. `None` if it can't be read.
+pub async fn get_current_branch(repo: &Path, config: &DiffConfig) -> Option<String> {
+    if config.vcs == Vcs::Jj {
+        return jj::get_branch(repo).await.ok();
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_branch(repo).await;
+    }
+
+    get_branch(repo).await.ok()
+}
+
+/// Gets the message and changes of the commit at HEAD, like `git show`.
+pub async fn get_commit(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    if config.vcs == Vcs::Jj {
+        return jj::get_commit(repo, config).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_commit(repo, config).await;
+    }
+
+    run_git(
+        repo,
+        &config.show_args(&[config.algorithm.arg(), "--format=%B"]),
+    )
+    .await
+}
+
+/// Gets the messages of the last `count` commits, newest first.
+pub async fn get_log(repo: &Path, config: &DiffConfig, count: usize) -> Result<String, GitError> {
+    if config.vcs == Vcs::Jj {
+        return jj::get_log(repo, count).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_log(repo, count).await;
+    }
+
+    let count = format!("--max-count={count}");
+    run_git(repo, ["log", &count, "--format=%B"]).await
+}
+
+/// Gets the metadata of the commit at HEAD, like [get_metadata] does for the
+/// uncommitted changes.
+pub async fn get_commit_metadata(
+    repo: &Path,
+    config: &DiffConfig,
+) -> Result<DiffMetadata, GitError> {
+    if config.vcs == Vcs::Jj {
+        return jj::get_commit_metadata(repo, config).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_commit_metadata(repo, config).await;
+    }
+
+    let numstat = run_git(repo, &config.show_args(&["--numstat", "--format="])).await?;
+    metadata_with_numstat(repo, &numstat).await
+}
+
+/// Combines `git diff --numstat` output with the repository's name and
+/// branch.
+async fn metadata_with_numstat(repo: &Path, numstat: &str) -> Result<DiffMetadata, GitError> {
+    let toplevel = get_toplevel(repo).await?;
+    let repo_name = get_repo_name(repo, &toplevel).await;
+    let branch = get_branch(repo).await?;
+
+    Ok(DiffMetadata {
+        repo: repo_name,
+        branch,
+        ..DiffMetadata::from_numstat(numstat)
+    })
+}
+
+/// Gets the name of the checked-out branch, or the short hash of the commit
+/// when HEAD is detached.
+pub async fn get_branch(repo: &Path) -> Result<String, GitError> {
+    let branch = run_git(repo, ["rev-parse", "--abbrev-ref", "HEAD"]).await?;
+
+    if branch.trim() != "HEAD" {
+        return Ok(branch.trim().to_string());
+    }
+
+    Ok(run_git(repo, ["rev-parse", "--short", "HEAD"])
+        .await?
+        .trim()
+        .to_string())
+}
+
+/// Gets the root directory of the work tree containing `repo`.
+pub async fn get_toplevel(repo: &Path) -> Result<PathBuf, GitError> {
+    Ok(PathBuf::from(
+        run_git(repo, ["rev-parse", "--show-toplevel"])
+            .await?
+            .trim(),
+    ))
+}
+
+/// The changes in one of the watched repositories.
+#[derive(Clone, Debug)]
+pub struct RepoDiff {
+    /// The path to the repository, as configured.
+    pub path: PathBuf,
+
+    /// Which of the repositories given to [get_active_diff] it is.
+    pub index: usize,
+
+    /// Its unredacted diff.
+    pub diff: String,
+}
+
+/// Gets the diff of every repository in `repos`, each read with its own
+/// config, and picks the one whose changed files were modified most recently,
+/// or `None` if nothing has changed. Repositories that fail are logged and
+/// skipped, unless all of them fail.
+pub async fn get_active_diff(repos: &[(&Path, &DiffConfig)]) -> Result<Option<RepoDiff>, GitError> {
+    Ok(get_changed_diffs(repos).await?.into_iter().next())
+}
+
+/// Like [get_active_diff], but gets every changed repository's diff, the
+/// most recently modified first.
+pub async fn get_changed_diffs(repos: &[(&Path, &DiffConfig)]) -> Result<Vec<RepoDiff>, GitError> {
+    let mut changed = Vec::new();
+    let mut succeeded = false;
+    let mut last_err = None;
+
+    for (index, &(path, config)) in repos.iter().enumerate() {
+        match get_diff(path, config).await {
+            Ok(diff) => {
+                succeeded = true;
+
+                if !diff.is_empty() {
+                    changed.push((
+                        RepoDiff {
+                            path: path.to_path_buf(),
+                            index,
+                            diff,
+                        },
+                        config,
+                    ));
+                }
+            }
+            Err(err @ (GitError::NotInstalled(_) | GitError::JjNotInstalled(_))) => {
+                return Err(err);
+            }
+            Err(err) => {
+                if repos.len() > 1 {
+                    tracing::warn!("skipping {}: {err}", path.display());
+                }
+
+                last_err = Some(err);
+            }
+        }
+    }
+
+    if let (false, Some(err)) = (succeeded, last_err) {
+        return Err(err);
+    }
+
+    if changed.len() <= 1 {
+        return Ok(changed.into_iter().map(|(repo, _)| repo).collect());
+    }
+
+    let mut latest = Vec::with_capacity(changed.len());
+
+    for (repo, config) in changed {
+        latest.push((get_last_modified(&repo.path, config).await, repo));
+    }
+
+    // the sort is stable, so reversing first keeps ties going to the last of
+    // the repositories
+    latest.reverse();
+    latest.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    Ok(latest.into_iter().map(|(_, repo)| repo).collect())
+}
+
+/// Finds when the most recently modified of the changed files in `repo` was
+/// last written to.
+pub async fn get_last_modified(repo: &Path, config: &DiffConfig) -> Option<SystemTime> {
+    let config = &*resolve_base(repo, config).await;
+
+    if config.vcs == Vcs::Jj {
+        return jj::get_last_modified(repo, config).await;
+    }
+
+    #[cfg(feature = "libgit2")]
+    if config.backend == GitBackend::Libgit2 {
+        return libgit2::get_last_modified(repo, config).await;
+    }
+
+    let toplevel = get_toplevel(repo).await.ok()?;
+
+    let files = run_git(repo, &config.diff_args(&["--name-only"]))
+        .await
+        .ok()?;
+
+    let mut latest = None;
+
+    for file in files.lines() {
+        let Ok(metadata) = tokio::fs::metadata(toplevel.join(file)).await else {
+            continue;
+        };
+
+        latest = latest.max(metadata.modified().ok());
+    }
+
+    latest
+}
+
+/// Checks which of `paths` git ignores in `repo`, returning those that are
+/// ignored exactly as they were given.
+pub async fn get_ignored(repo: &Path, paths: &[&str]) -> Result<Vec<String>, GitError> {
+    match run_git(
+        repo,
+        ["check-ignore", "--"]
+            .into_iter()
+            .chain(paths.iter().copied()),
+    )
+    .await
+    {
+        Ok(ignored) => Ok(ignored.lines().map(ToOwned::to_owned).collect()),
+        // check-ignore exits with 1 when none of the paths are ignored
+        Err(GitError::Failed { status, .. }) if status.code() == Some(1) => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Names the repository after its `origin` remote, which stays the same
+/// across clones and worktrees, or after the directory of its work tree at
+/// `toplevel` when it has no remote.
+pub async fn get_repo_name(repo: &Path, toplevel: &Path) -> String {
+    if let Some(name) = get_remote_url(repo)
+        .await
+        .and_then(|url| repo_name_from_url(&url))
+    {
+        return name;
+    }
+
+    toplevel
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The last part of a remote URL's path without `.git`, like `samwise` for
+/// `git@github.com:marceline-cramer/samwise.git`.
+pub fn repo_name_from_url(url: &str) -> Option<String> {
+    let path = url.trim().trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    let name = path.rsplit(['/', ':', '\\']).next()?;
+
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Gets the URL of the `origin` remote, if there is one.
+pub async fn get_remote_url(repo: &Path) -> Option<String> {
+    run_git(repo, ["remote", "get-url", "origin"])
+        .await
+        .ok()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+}
+
+/// Converts a git remote URL into one that can be opened in a browser,
+/// turning SSH remotes into HTTPS and dropping credentials and `.git`.
+pub fn browser_url(remote: &str) -> Option<String> {
+    let remote = remote.trim();
+
+    let (host, path) = if let Some(rest) = remote
+        .strip_prefix("https://")
+        .or_else(|| remote.strip_prefix("http://"))
+        .or_else(|| remote.strip_prefix("ssh://"))
+        .or_else(|| remote.strip_prefix("git://"))
+    {
+        let (authority, path) = rest.split_once('/')?;
+        let host = authority.rsplit('@').next()?;
+
+        // ssh://host:22/path carries a port that the website doesn't use
+        let host = if remote.starts_with("ssh://") {
+            host.split(':').next()?
+        } else {
+            host
+        };
+
+        (host, path)
+    } else {
+        // scp-like syntax: git@host:path
+        let (authority, path) = remote.split_once(':')?;
+
+        if authority.contains('/') {
+            return None;
+        }
+
+        (authority.rsplit('@').next()?, path)
+    };
+
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    if host.is_empty() || path.is_empty() {
+        return None;
+    }
+
+    Some(format!("https://{host}/{path}"))
+}
+
+/// Runs `git` in `repo` with the given arguments, returning its standard output.
+///
+/// Paging is turned off and stdin is closed, whatever the user's git config
+/// sets for `core.pager`, so that nothing git starts can wait on input.
+pub async fn run_git(
+    repo: &Path,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<String, GitError> {
+    if !repo.is_dir() {
+        return Err(GitError::NotARepository);
+    }
+
+    let output = tokio::process::Command::new("git")
+        .arg("--no-pager")
+        .args(args)
+        .current_dir(repo)
+        .env("GIT_PAGER", "cat")
+        .env("PAGER", "cat")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(GitError::NotInstalled)?
+        .wait_with_output()
+        .await
+        .map_err(GitError::Io)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+        if stderr.to_lowercase().contains("not a git repository") {
+            return Err(GitError::NotARepository);
+        }
+
+        return Err(GitError::Failed {
+            status: output.status,
+            stderr,
+        });
+    }
+
+    Ok(decode_output("git", output.stdout))
+}
+
+/// Decodes the output of `git` or `jj`, replacing invalid UTF-8 instead of
+/// failing on it, since diffs of files in other encodings are full of it.
+pub fn decode_output(program: &str, output: Vec<u8>) -> String {
+    match String::from_utf8(output) {
+        Ok(output) => output,
+        Err(err) => {
+            tracing::warn!("replacing invalid UTF-8 in the output of {program}");
+            String::from_utf8_lossy(err.as_bytes()).into_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(metadata: &DiffMetadata) -> (usize, usize, usize) {
+        (metadata.files, metadata.insertions, metadata.deletions)
+    }
+
+    #[test]
+    fn numstat_adds_up_every_file() {
+        let numstat = "10\t2\tsrc/main.rs\n0\t5\tREADME.md\n3\t0\tsrc/new.rs\n";
+        assert_eq!(stats(&DiffMetadata::from_numstat(numstat)), (3, 13, 7));
+    }
+
+    #[test]
+    fn numstat_counts_binary_files_without_lines() {
+        let numstat = "-\t-\tlogo.png\n1\t1\tsrc/lib.rs\n";
+        assert_eq!(stats(&DiffMetadata::from_numstat(numstat)), (2, 1, 1));
+    }
+
+    #[test]
+    fn numstat_of_nothing() {
+        assert_eq!(stats(&DiffMetadata::from_numstat("")), (0, 0, 0));
+        assert_eq!(stats(&DiffMetadata::from_numstat("\n")), (0, 0, 0));
+    }
+
+    #[test]
+    fn shortstat_like_git() {
+        let metadata = DiffMetadata::from_numstat("10\t2\ta\n0\t0\tb\n0\t0\tc\n");
+        assert_eq!(
+            metadata.shortstat(),
+            "3 files changed, 10 insertions(+), 2 deletions(-)"
+        );
+    }
+
+    #[test]
+    fn shortstat_leaves_out_zero_counts() {
+        let insertions = DiffMetadata::from_numstat("4\t0\ta\n");
+        assert_eq!(insertions.shortstat(), "1 file changed, 4 insertions(+)");
+
+        let deletions = DiffMetadata::from_numstat("0\t1\ta\n0\t2\tb\n");
+        assert_eq!(deletions.shortstat(), "2 files changed, 3 deletions(-)");
+
+        let neither = DiffMetadata::from_numstat("-\t-\tlogo.png\n");
+        assert_eq!(neither.shortstat(), "1 file changed");
+    }
+
+    #[test]
+    fn shortstat_of_one_line_each() {
+        let metadata = DiffMetadata::from_numstat("1\t1\ta\n");
+        assert_eq!(
+            metadata.shortstat(),
+            "1 file changed, 1 insertion(+), 1 deletion(-)"
+        );
+    }
+
+    #[test]
+    fn count_skips_file_headers() {
+        let diff = "diff --git a/a b/a
+--- a/a
++++ b/a
+@@ -1,2 +1,2 @@
+-old
++new
++--- not a header
+ same
+";
+        assert_eq!(stats(&DiffMetadata::count(diff)), (1, 2, 1));
+    }
+
+    const NAME_STATUS_DIFF: &str = "diff --git a/src/main.rs b/src/main.rs
+index 1111111..2222222 100644
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1 +1 @@
+-old
++new
+diff --git a/src/new.rs b/src/new.rs
+new file mode 100644
+index 0000000..3333333
+--- /dev/null
++++ b/src/new.rs
+@@ -0,0 +1 @@
++new
+diff --git a/src/gone.rs b/src/gone.rs
+deleted file mode 100644
+index 4444444..0000000
+--- a/src/gone.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-gone
+diff --git a/src/old name.rs b/src/new name.rs
+similarity index 100%
+rename from src/old name.rs
+rename to src/new name.rs
+diff --git a/src/lib.rs b/src/copy.rs
+similarity index 90%
+copy from src/lib.rs
+copy to src/copy.rs
+";
+
+    #[test]
+    fn name_status_of_every_kind_of_change() {
+        assert_eq!(
+            name_status(NAME_STATUS_DIFF),
+            [
+                FileStatus::Modified("src/main.rs".into()),
+                FileStatus::Added("src/new.rs".into()),
+                FileStatus::Deleted("src/gone.rs".into()),
+                FileStatus::Renamed {
+                    from: "src/old name.rs".into(),
+                    to: "src/new name.rs".into(),
+                },
+                FileStatus::Added("src/copy.rs".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn name_status_ignores_hunk_lines_like_headers() {
+        let diff = "diff --git a/notes.txt b/notes.txt
+--- a/notes.txt
++++ b/notes.txt
+@@ -1 +1,2 @@
+ rename from somewhere
++deleted file mode 100644
+";
+        assert_eq!(
+            name_status(diff),
+            [FileStatus::Modified("notes.txt".into())]
+        );
+    }
+
+    #[test]
+    fn file_list_lists_renames() {
+        assert_eq!(
+            file_list(NAME_STATUS_DIFF),
+            "modified src/main.rs
+added src/new.rs
+deleted src/gone.rs
+renamed src/old name.rs -> src/new name.rs
+added src/copy.rs"
+        );
+    }
+
+    #[test]
+    fn file_list_counts_the_rest() {
+        let diff: String = (0..MAX_LISTED_FILES + 3)
+            .map(|i| format!("diff --git a/{i}.rs b/{i}.rs\n"))
+            .collect();
+
+        let list = file_list(&diff);
+        assert_eq!(list.lines().count(), MAX_LISTED_FILES + 1);
+        assert_eq!(list.lines().last(), Some("and 3 more"));
+    }
+
+    #[test]
+    fn decode_output_keeps_valid_utf8() {
+        let output = "+fn café() {}\n".as_bytes().to_vec();
+        assert_eq!(decode_output("git", output), "+fn café() {}\n");
+    }
+
+    #[test]
+    fn decode_output_replaces_invalid_utf8() {
+        // Latin-1 é, a lone continuation byte, and a truncated 3-byte sequence
+        let output = b"caf\xe9 \x80 \xe2\x82\n".to_vec();
+        assert_eq!(
+            decode_output("git", output),
+            "caf\u{fffd} \u{fffd} \u{fffd}\n"
+        );
+    }
+}
+
+#[cfg(all(test, unix))]
+pub mod fake_git {
+    use std::{ffi::OsString, os::unix::fs::PermissionsExt};
+
+    use tempfile::TempDir;
+    use tokio::sync::{Mutex, MutexGuard};
+
+    use super::*;
+
+    /// Held by every test that runs a program, since [FakeGit] changes `PATH`
+    /// for the whole process.
+    pub static PATH_LOCK: Mutex<()> = Mutex::const_new(());
+
+    /// A `git` that runs a shell script instead, first on `PATH` for as long
+    /// as it's around.
+    pub struct FakeGit {
+        /// Where the script is, which also works as a repository to run it in.
+        pub dir: TempDir,
+        path: Option<OsString>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl FakeGit {
+        /// Puts a `git` that runs `script` ahead of the real one.
+        pub async fn new(script: &str) -> Self {
+            Self::with_path(Some(script)).await
+        }
+
+        /// Takes `git` off `PATH` entirely, like it was never installed.
+        pub async fn missing() -> Self {
+            Self::with_path(None).await
+        }
+
+        async fn with_path(script: Option<&str>) -> Self {
+            let lock = PATH_LOCK.lock().await;
+            let dir = tempfile::tempdir().unwrap();
+            let path = std::env::var_os("PATH");
+            let mut paths = vec![dir.path().to_path_buf()];
+
+            if let Some(script) = script {
+                let git = dir.path().join("git");
+                std::fs::write(&git, format!("#!/bin/sh\n{script}\n")).unwrap();
+                std::fs::set_permissions(&git, std::fs::Permissions::from_mode(0o755)).unwrap();
+                paths.extend(path.iter().flat_map(std::env::split_paths));
+            }
+
+            // SAFETY: only std reads the environment here, which locks it, and
+            // every test that spawns a program holds PATH_LOCK
+            unsafe { std::env::set_var("PATH", std::env::join_paths(paths).unwrap()) };
+
+            Self {
+                dir,
+                path,
+                _lock: lock,
+            }
+        }
+
+        /// The repository the script pretends to be.
+        pub fn repo(&self) -> &Path {
+            self.dir.path()
+        }
+    }
+
+    impl Drop for FakeGit {
+        fn drop(&mut self) {
+            // SAFETY: as in FakeGit::with_path
+            unsafe {
+                match &self.path {
+                    Some(path) => std::env::set_var("PATH", path),
+                    None => std::env::remove_var("PATH"),
+                }
+            }
+        }
+    }
+
+    const NOT_A_REPOSITORY: &str =
+        "echo 'fatal: not a git repository (or any of the parent directories): .git' >&2
+exit 128";
+
+    #[tokio::test]
+    async fn run_git_outside_a_repository() {
+        let git = FakeGit::new(NOT_A_REPOSITORY).await;
+        let result = run_git(git.repo(), ["diff"]).await;
+        assert!(matches!(result, Err(GitError::NotARepository)));
+    }
+
+    #[tokio::test]
+    async fn get_diff_in_a_directory_without_git() {
+        let _lock = PATH_LOCK.lock().await;
+        let dir = tempfile::tempdir().unwrap();
+        let result = get_diff(dir.path(), &DiffConfig::default()).await;
+        assert!(
+            matches!(result, Err(GitError::NotARepository)),
+            "{result:?}"
+        );
+    }
+
+    #[cfg(feature = "libgit2")]
+    #[tokio::test]
+    async fn libgit2_diff_in_a_directory_without_git() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = DiffConfig {
+            backend: GitBackend::Libgit2,
+            ..Default::default()
+        };
+
+        let result = get_diff(dir.path(), &config).await;
+        assert!(
+            matches!(result, Err(GitError::NotARepository)),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn run_git_in_a_missing_directory() {
+        let git = FakeGit::new("echo diff").await;
+        let result = run_git(&git.repo().join("gone"), ["diff"]).await;
+        assert!(matches!(result, Err(GitError::NotARepository)));
+    }
+
+    #[tokio::test]
+    async fn run_git_without_git() {
+        let git = FakeGit::missing().await;
+        let result = run_git(git.repo(), ["diff"]).await;
+        assert!(matches!(result, Err(GitError::NotInstalled(_))));
+    }
+
+    #[tokio::test]
+    async fn run_git_reports_other_failures() {
+        let git = FakeGit::new("echo 'fatal: bad revision' >&2; exit 1").await;
+
+        match run_git(git.repo(), ["diff"]).await {
+            Err(GitError::Failed { status, stderr }) => {
+                assert_eq!(status.code(), Some(1));
+                assert_eq!(stderr.trim(), "fatal: bad revision");
+            }
+            result => panic!("expected a failure, got {result:?}"),
+        }
+    }
+
+    // stdin is read back from /proc
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn run_git_without_a_pager_or_stdin() {
+        let git =
+            FakeGit::new(r#"echo "$@"; echo "$GIT_PAGER $PAGER"; readlink "/proc/$$/fd/0""#).await;
+
+        let output = run_git(git.repo(), ["log", "-1"]).await.unwrap();
+        assert_eq!(output, "--no-pager log -1\ncat cat\n/dev/null\n");
+    }
+
+    #[tokio::test]
+    async fn run_git_replaces_invalid_utf8() {
+        // \351 is é in Latin-1, which isn't valid UTF-8 on its own
+        let git = FakeGit::new(r"printf '+caf\351\n'").await;
+        let output = run_git(git.repo(), ["diff"]).await.unwrap();
+        assert_eq!(output, "+caf\u{fffd}\n");
+    }
+
+    #[tokio::test]
+    async fn run_git_reads_very_large_diffs() {
+        // much more than a pipe holds, so stdout has to be read while git runs
+        let git = FakeGit::new("yes '+a line that was added' | head -n 500000").await;
+        let output = run_git(git.repo(), ["diff"]).await.unwrap();
+
+        assert_eq!(output.len(), 500_000 * "+a line that was added\n".len());
+        assert!(output.lines().all(|line| line == "+a line that was added"));
+    }
+
+    #[tokio::test]
+    async fn active_diff_outside_a_repository() {
+        let git = FakeGit::new(NOT_A_REPOSITORY).await;
+        let config = DiffConfig::default();
+        let result = get_active_diff(&[(git.repo(), &config)]).await;
+        assert!(matches!(result, Err(GitError::NotARepository)));
+    }
+
+    #[tokio::test]
+    async fn active_diff_without_git() {
+        let git = FakeGit::missing().await;
+        let config = DiffConfig::default();
+        let result = get_active_diff(&[(git.repo(), &config)]).await;
+        assert!(matches!(result, Err(GitError::NotInstalled(_))));
+    }
+
+    #[tokio::test]
+    async fn active_diff_without_changes() {
+        let git = FakeGit::new("true").await;
+        let config = DiffConfig::default();
+        let active = get_active_diff(&[(git.repo(), &config)]).await.unwrap();
+        assert!(active.is_none());
+    }
+
+    #[tokio::test]
+    async fn active_diff_skips_failing_repositories() {
+        let git = FakeGit::new(&format!(
+            "case \"$PWD\" in */broken) {NOT_A_REPOSITORY};; esac
+printf 'diff --git a/f b/f\\n+caf\\351\\n'"
+        ))
+        .await;
+
+        let broken = git.repo().join("broken");
+        let working = git.repo().join("working");
+        std::fs::create_dir(&broken).unwrap();
+        std::fs::create_dir(&working).unwrap();
+
+        let config = DiffConfig::default();
+        let active = get_active_diff(&[(&broken, &config), (&working, &config)])
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(active.index, 1);
+        assert_eq!(active.path, working);
+        assert_eq!(active.diff, "diff --git a/f b/f\n+caf\u{fffd}\n");
+    }
+
+    #[tokio::test]
+    async fn active_diff_of_a_very_large_diff() {
+        let git = FakeGit::new("echo 'diff --git a/f b/f'; yes '+added' | head -n 500000").await;
+        let config = DiffConfig::default();
+        let active = get_active_diff(&[(git.repo(), &config)])
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(active.diff.lines().count(), 500_001);
+        assert_eq!(
+            changed_size(&active.diff),
+            (500_000, 500_000 * "added".len())
+        );
+    }
+}
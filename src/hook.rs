@@ -0,0 +1,118 @@
+//! Drafting commit messages from git's `prepare-commit-msg` hook.
+
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::git::run_git;
+
+/// The hook that [install] sets up, which git runs with the path to the
+/// commit message file before opening the editor.
+pub const HOOK_NAME: &str = "prepare-commit-msg";
+
+/// Marks a hook as one [install] wrote, so that it can be replaced without
+/// `--force`.
+const HOOK_MARKER: &str = "# installed by samwise";
+
+/// Whether the commit message in `message` is still empty, with nothing but
+/// blank lines and the `#` comments git adds. Anything below the scissors line
+/// of `git commit --verbose` is ignored, since that's the diff.
+pub fn is_empty_message(message: &str) -> bool {
+    message
+        .lines()
+        .take_while(|line| !is_scissors(line))
+        .all(|line| line.trim().is_empty() || line.starts_with('#'))
+}
+
+/// Whether `line` is git's `# ---- >8 ----` line, below which everything is
+/// cut from the message.
+fn is_scissors(line: &str) -> bool {
+    line.starts_with("# -") && line.contains(">8")
+}
+
+/// Writes `draft` into the message file at `path` above its `existing`
+/// contents, so that git's comments and status are still shown under it.
+pub fn prepend_message(path: &Path, existing: &str, draft: &str) -> anyhow::Result<()> {
+    let existing = existing.trim_start_matches('\n');
+
+    std::fs::write(path, format!("{draft}\n\n{existing}"))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Sets up the `prepare-commit-msg` hook of the git repository at `repo` to
+/// draft a message with `--write-commit-msg` whenever `git commit` is run
+/// without one. `config` and `profile` are passed along to the hook when
+/// they're given. A hook that samwise didn't write is only replaced with
+/// `force`.
+pub async fn install(
+    repo: &Path,
+    config: Option<&Path>,
+    profile: Option<&str>,
+    force: bool,
+) -> anyhow::Result<()> {
+    // respects core.hooksPath and linked worktrees
+    let hooks = run_git(repo, ["rev-parse", "--git-path", "hooks"])
+        .await
+        .context("failed to find the repository's hooks directory")?;
+
+    let hooks = repo.join(hooks.trim());
+
+    std::fs::create_dir_all(&hooks)
+        .with_context(|| format!("failed to create {}", hooks.display()))?;
+
+    let path = hooks.join(HOOK_NAME);
+
+    if path.exists() && !force {
+        let existing = std::fs::read_to_string(&path).unwrap_or_default();
+
+        if !existing.contains(HOOK_MARKER) {
+            anyhow::bail!(
+                "{} already exists; pass --force to replace it",
+                path.display()
+            );
+        }
+    }
+
+    let exe = std::env::current_exe().context("failed to locate the samwise executable")?;
+
+    let mut command = shell_quote(&exe.to_string_lossy());
+
+    if let Some(config) = config {
+        command.push_str(" --config ");
+        command.push_str(&shell_quote(&config.to_string_lossy()));
+    }
+
+    if let Some(profile) = profile {
+        command.push_str(" --profile ");
+        command.push_str(&shell_quote(profile));
+    }
+
+    // $2 is set for -m, -F, templates, merges, squashes, and amends, which
+    // already have a message; a failure never stops the commit
+    let script = format!(
+        "#!/bin/sh\n\
+         {HOOK_MARKER}: drafts a message for a plain `git commit`\n\
+         [ -z \"$2\" ] || exit 0\n\
+         {command} --quiet --write-commit-msg \"$1\" || true\n"
+    );
+
+    std::fs::write(&path, script).with_context(|| format!("failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to make {} executable", path.display()))?;
+    }
+
+    println!("Installed the {HOOK_NAME} hook at {}.", path.display());
+    println!("Run `git commit` without a message to get a draft of one.");
+
+    Ok(())
+}
+
+/// Quotes `arg` for `sh` in single quotes.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
@@ -0,0 +1,135 @@
+//! Running a command of the user's own whenever the summary changes.
+
+use std::{process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use tokio::task::JoinHandle;
+
+use crate::{
+    agent::shell_command,
+    config::{Config, HookConfig},
+    presence::{PresenceSink, PresenceState},
+    webhook::webhook_vars,
+};
+
+/// Runs [HookConfig::command] with the summary in its environment each time
+/// it changes, including when the presence is cleared.
+#[derive(Default)]
+pub struct HookCommand {
+    /// The summary the command was last run for, or `None` for a clear.
+    last: Option<String>,
+
+    /// The settings as of the last update, for shutting down with.
+    config: Option<HookConfig>,
+}
+
+#[async_trait]
+impl PresenceSink for HookCommand {
+    fn name(&self) -> &'static str {
+        "hook"
+    }
+
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        self.config = config.hook.clone();
+
+        let Some(hook) = &config.hook else {
+            return Ok(());
+        };
+
+        let presence = presence.map(|presence| presence.truncated(hook.max_len, hook.truncate));
+        let summary = presence.as_ref().map(|presence| presence.summary.clone());
+
+        // updates come every tick, but the command only cares when they change
+        if summary == self.last {
+            return Ok(());
+        }
+
+        self.last = summary;
+        run(hook, presence.as_ref());
+        Ok(())
+    }
+
+    async fn shutdown(self: Box<Self>, timeout: Duration) {
+        // the command gets to clear whatever it put up
+        let Some(hook) = self.config.filter(|_| self.last.is_some()) else {
+            return;
+        };
+
+        let _ = tokio::time::timeout(timeout, run(&hook, None)).await;
+    }
+}
+
+/// Runs the hook's command in the background with `presence` in its
+/// environment, killing it if it takes longer than [HookConfig::timeout].
+/// Failures and non-zero exits are logged and otherwise ignored.
+fn run(hook: &HookConfig, presence: Option<&PresenceState>) -> JoinHandle<()> {
+    let mut process = shell_command(&hook.command);
+
+    process
+        .envs(hook_env(presence))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let timeout = hook.timeout;
+
+    tokio::spawn(async move {
+        let output = match process.spawn() {
+            Ok(child) => tokio::time::timeout(timeout, child.wait_with_output()).await,
+            Err(err) => {
+                tracing::warn!("failed to run hook.command: {err}");
+                return;
+            }
+        };
+
+        match output {
+            Ok(Ok(output)) if output.status.success() => {}
+            Ok(Ok(output)) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+
+                tracing::warn!(
+                    "hook.command exited with {}: {}",
+                    output.status,
+                    stderr.trim()
+                );
+            }
+            Ok(Err(err)) => tracing::warn!("failed to run hook.command: {err}"),
+            Err(_) => tracing::warn!(
+                "hook.command didn't finish within {}, killed it",
+                humantime::format_duration(timeout)
+            ),
+        }
+    })
+}
+
+/// The environment the hook's command is run with: each of
+/// [webhook_vars] as `SAMWISE_` followed by its name in capitals. A cleared
+/// presence leaves them all empty.
+pub fn hook_env(presence: Option<&PresenceState>) -> Vec<(String, String)> {
+    let empty = PresenceState {
+        summary: String::new(),
+        metadata: None,
+        session_start: None,
+        model: String::new(),
+        repo_url: None,
+        verb: None,
+    };
+
+    webhook_vars(presence.unwrap_or(&empty))
+        .into_iter()
+        .map(|(name, value)| {
+            let value = if presence.is_some() {
+                value
+            } else {
+                String::new()
+            };
+
+            (format!("SAMWISE_{}", name.to_uppercase()), value)
+        })
+        .collect()
+}
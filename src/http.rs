@@ -0,0 +1,152 @@
+//! The local HTTP server reporting what samwise is up to.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum::{Json, Router, extract::State, http::header, response::IntoResponse, routing::get};
+use serde::Serialize;
+
+use crate::{
+    config::HttpConfig,
+    git::DiffMetadata,
+    metrics,
+    presence::PresenceState,
+    usage::{self, DailyUsage},
+};
+
+/// What `GET /status` reports.
+#[derive(Clone, Default, Serialize)]
+pub struct Status {
+    /// The summary currently shown, if any.
+    pub summary: Option<String>,
+
+    /// The branch of the summarized changes, unless it's hidden.
+    pub branch: Option<String>,
+
+    /// The size of the summarized changes, like "3 files changed, 10
+    /// insertions(+)".
+    pub diff_stat: Option<String>,
+
+    /// When the presence last changed, as an RFC 3339 timestamp.
+    pub last_update: Option<String>,
+
+    /// The model that answered the last prompt.
+    pub last_prompt_model: Option<String>,
+
+    /// How long the last prompt took, in milliseconds.
+    pub last_prompt_ms: Option<u64>,
+
+    /// The tokens the models have used today, as of the last prompt.
+    pub tokens_today: DailyUsage,
+
+    /// Whether samwise is connected to the Discord client.
+    pub discord_connected: bool,
+
+    /// The most recent error from the main loop.
+    pub last_error: Option<String>,
+}
+
+/// The [Status] shared between the main loop, the Discord RPC task, the
+/// server, and the TUI, which can [subscribe](StatusHandle::subscribe) to
+/// hear about every change.
+#[derive(Clone)]
+pub struct StatusHandle(Arc<tokio::sync::watch::Sender<Status>>);
+
+impl Default for StatusHandle {
+    fn default() -> Self {
+        Self(Arc::new(tokio::sync::watch::Sender::new(Status::default())))
+    }
+}
+
+impl StatusHandle {
+    /// Records a new presence, or that it was cleared.
+    pub fn set_presence(&self, presence: Option<&PresenceState>) {
+        let metadata = presence.and_then(|presence| presence.metadata.as_ref());
+
+        let branch = metadata
+            .map(|metadata| metadata.branch.clone())
+            .filter(|branch| !branch.is_empty());
+
+        let diff_stat = metadata.map(DiffMetadata::shortstat);
+
+        self.update(|status| {
+            let summary = presence.map(|presence| presence.summary.clone());
+
+            if summary != status.summary || branch != status.branch {
+                let now = humantime::format_rfc3339_seconds(SystemTime::now());
+                status.last_update = Some(now.to_string());
+            }
+
+            status.summary = summary;
+            status.branch = branch;
+            status.diff_stat = diff_stat;
+        });
+    }
+
+    /// Records which model answered the last prompt and how long it took.
+    pub fn set_prompt(&self, model: &str, elapsed: Duration) {
+        self.update(|status| {
+            status.last_prompt_model = Some(model.to_string());
+            status.last_prompt_ms = Some(elapsed.as_millis() as u64);
+            status.tokens_today = usage::today();
+        });
+    }
+
+    pub fn set_discord_connected(&self, connected: bool) {
+        self.update(|status| status.discord_connected = connected);
+    }
+
+    pub fn set_error(&self, error: &anyhow::Error) {
+        self.update(|status| status.last_error = Some(format!("{error:#}")));
+    }
+
+    pub fn get(&self) -> Status {
+        self.0.borrow().clone()
+    }
+
+    /// Follows every change to the status.
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<Status> {
+        self.0.subscribe()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Status)) {
+        self.0.send_modify(f);
+    }
+}
+
+/// Serves `GET /status`, `GET /healthz`, and `GET /metrics` until the future
+/// is dropped.
+///
+/// Everything runs on the same runtime as the main loop, so `/healthz`
+/// answering at all means the loop isn't wedged.
+pub async fn serve(config: &HttpConfig, status: StatusHandle) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(config.bind)
+        .await
+        .with_context(|| format!("failed to listen on {}", config.bind))?;
+
+    tracing::info!("serving status on http://{}", config.bind);
+
+    let app = Router::new()
+        .route("/status", get(get_status))
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/metrics", get(get_metrics))
+        .with_state(status);
+
+    axum::serve(listener, app)
+        .await
+        .context("status server failed")
+}
+
+async fn get_status(State(status): State<StatusHandle>) -> Json<Status> {
+    Json(status.get())
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
@@ -0,0 +1,204 @@
+//! How long it's been since the keyboard or mouse were last used, asked of
+//! whatever the desktop offers, for [DiscordConfig::input_idle_timeout].
+//!
+//! [DiscordConfig::input_idle_timeout]: crate::config::DiscordConfig::input_idle_timeout
+
+use std::time::{Duration, Instant};
+
+use tokio::process::Command;
+
+/// The longest any one way of asking can take, so that one that hangs, like
+/// `gdbus` waiting on a session bus that never answers, can't hold up the
+/// main loop.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait before asking again once there was no way to tell, in
+/// case one turns up, like `xprintidle` being installed.
+const RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Asks [idle_time] every tick, but not again for [RETRY_AFTER] after it
+/// couldn't tell, so the same programs aren't spawned to fail every tick.
+#[derive(Default)]
+pub struct IdleProbe {
+    failed_at: Option<Instant>,
+}
+
+impl IdleProbe {
+    /// Like [idle_time], but `None` without asking for a while after it
+    /// last couldn't tell.
+    pub async fn idle_time(&mut self) -> Option<Duration> {
+        if self
+            .failed_at
+            .is_some_and(|failed_at| failed_at.elapsed() < RETRY_AFTER)
+        {
+            return None;
+        }
+
+        let idle = idle_time().await;
+        self.failed_at = idle.is_none().then(Instant::now);
+        idle
+    }
+}
+
+/// How long the user hasn't touched the keyboard or mouse for, or `None`
+/// when there's no way to tell here. Each way of asking is tried in turn:
+/// GNOME's idle monitor, which works on Wayland, then `xprintidle` on X11,
+/// then logind's idle hint, and on macOS, the HID system's idle time.
+pub async fn idle_time() -> Option<Duration> {
+    #[cfg(target_os = "macos")]
+    return macos_idle_time().await;
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        if let Some(idle) = gnome_idle_time().await {
+            return Some(idle);
+        }
+
+        if std::env::var_os("DISPLAY").is_some()
+            && let Some(idle) = xprintidle().await
+        {
+            return Some(idle);
+        }
+
+        logind_idle_time().await
+    }
+}
+
+/// Runs `program` and returns what it printed, or `None` if it couldn't be
+/// run, failed, like when it isn't installed, or took longer than
+/// [PROBE_TIMEOUT], in which case it's killed.
+async fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let output = tokio::time::timeout(PROBE_TIMEOUT, output)
+        .await
+        .ok()?
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Asks Mutter's idle monitor, which answers like `(uint64 1234,)` in
+/// milliseconds.
+#[cfg(not(target_os = "macos"))]
+async fn gnome_idle_time() -> Option<Duration> {
+    let reply = output(
+        "gdbus",
+        &[
+            "call",
+            "--session",
+            "--dest",
+            "org.gnome.Mutter.IdleMonitor",
+            "--object-path",
+            "/org/gnome/Mutter/IdleMonitor/Core",
+            "--method",
+            "org.gnome.Mutter.IdleMonitor.GetIdletime",
+        ],
+    )
+    .await?;
+
+    let millis = reply
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .trim_end_matches(',')
+        .trim_start_matches("uint64")
+        .trim();
+
+    millis.parse().ok().map(Duration::from_millis)
+}
+
+/// Asks X11 with `xprintidle`, which prints milliseconds.
+#[cfg(not(target_os = "macos"))]
+async fn xprintidle() -> Option<Duration> {
+    let millis = output("xprintidle", &[]).await?;
+    millis.trim().parse().ok().map(Duration::from_millis)
+}
+
+/// Asks logind whether the session is idle and since when, which is only
+/// set when the desktop reports it. A session that isn't idle has been idle
+/// for no time at all.
+#[cfg(not(target_os = "macos"))]
+async fn logind_idle_time() -> Option<Duration> {
+    let session = std::env::var("XDG_SESSION_ID").ok()?;
+
+    let properties = output(
+        "loginctl",
+        &[
+            "show-session",
+            &session,
+            "--property=IdleHint",
+            "--property=IdleSinceHint",
+        ],
+    )
+    .await?;
+
+    let property = |name: &str| {
+        properties
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+    };
+
+    if property("IdleHint")? != "yes" {
+        return Some(Duration::ZERO);
+    }
+
+    // microseconds since the epoch, and zero when it's unknown
+    let since: u64 = property("IdleSinceHint")?.parse().ok()?;
+
+    if since == 0 {
+        return None;
+    }
+
+    let since = std::time::SystemTime::UNIX_EPOCH + Duration::from_micros(since);
+    Some(since.elapsed().unwrap_or_default())
+}
+
+/// Asks the HID system, whose `HIDIdleTime` is in nanoseconds.
+#[cfg(target_os = "macos")]
+async fn macos_idle_time() -> Option<Duration> {
+    let registry = output("ioreg", &["-c", "IOHIDSystem", "-d", "4"]).await?;
+
+    let nanos = registry.lines().find_map(|line| {
+        let (_, value) = line.split_once("\"HIDIdleTime\" = ")?;
+        value.trim().parse().ok()
+    })?;
+
+    Some(Duration::from_nanos(nanos))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn probes_that_hang_are_given_up_on() {
+        let _path = crate::git::fake_git::PATH_LOCK.lock().await;
+
+        let started = Instant::now();
+        assert_eq!(output("sleep", &["10"]).await, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(output("echo", &["42"]).await.as_deref(), Some("42\n"));
+    }
+
+    #[tokio::test]
+    async fn failed_probes_arent_retried_for_a_while() {
+        let failed_at = Instant::now();
+        let mut probe = IdleProbe {
+            failed_at: Some(failed_at),
+        };
+
+        assert_eq!(probe.idle_time().await, None);
+        assert_eq!(probe.failed_at, Some(failed_at));
+    }
+}
@@ -0,0 +1,259 @@
+//! Reading diffs from Jujutsu repositories by running `jj`.
+//!
+//! jj has no staging area, so [DiffConfig::mode] doesn't apply: the diff is
+//! always the working-copy commit. Its output is git-format, so everything
+//! after reading it works just like it does for git.
+
+use std::{ffi::OsStr, path::Path, process::Stdio};
+
+use crate::{
+    config::DiffConfig,
+    git::{DiffMetadata, GitError, decode_output, get_repo_name},
+};
+
+pub async fn get_diff(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    run_jj(repo, diff_args(config, "@", &["--git"])).await
+}
+
+/// Gets the `--stat` summary of the same changes as [get_diff].
+pub async fn get_diff_stat(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    run_jj(repo, diff_args(config, "@", &["--stat"])).await
+}
+
+pub async fn get_metadata(repo: &Path, config: &DiffConfig) -> Result<DiffMetadata, GitError> {
+    let diff = get_diff(repo, config).await?;
+    metadata(repo, &diff).await
+}
+
+/// Gets the ID of the parent of the working-copy commit, which changes when
+/// the working copy is committed with `jj commit` or `jj new`.
+pub async fn get_head(repo: &Path) -> Option<String> {
+    run_jj(repo, ["log", "--no-graph", "-r", "@-", "-T", "commit_id"])
+        .await
+        .ok()
+        .map(|head| head.trim().to_string())
+        .filter(|head| !head.is_empty())
+}
+
+/// Gets the nearest ancestor of the working copy that's on a remote, which is
+/// where it was last pushed to.
+pub async fn get_upstream(repo: &Path) -> Option<String> {
+    let pushed = run_jj(
+        repo,
+        [
+            "log",
+            "--no-graph",
+            "-r",
+            "heads(::@ & remote_bookmarks())",
+            "-T",
+            r#"commit_id ++ "\n""#,
+        ],
+    )
+    .await
+    .ok()?;
+
+    pushed.lines().next().map(ToOwned::to_owned)
+}
+
+/// Gets the description and changes of the parent of the working-copy commit,
+/// which is what was last committed.
+pub async fn get_commit(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    let description = run_jj(repo, ["log", "--no-graph", "-r", "@-", "-T", "description"]).await?;
+    let diff = run_jj(repo, diff_args(config, "@-", &["--git"])).await?;
+
+    Ok(format!("{}\n\n\n{diff}", description.trim_end()))
+}
+
+/// Gets the descriptions of the last `count` commits behind the working
+/// copy, newest first.
+pub async fn get_log(repo: &Path, count: usize) -> Result<String, GitError> {
+    let revset = format!("ancestors(@-, {count})");
+
+    run_jj(
+        repo,
+        [
+            "log",
+            "--no-graph",
+            "-r",
+            &revset,
+            "-T",
+            r#"description ++ "\n""#,
+        ],
+    )
+    .await
+}
+
+pub async fn get_commit_metadata(
+    repo: &Path,
+    config: &DiffConfig,
+) -> Result<DiffMetadata, GitError> {
+    let diff = run_jj(repo, diff_args(config, "@-", &["--git"])).await?;
+    metadata(repo, &diff).await
+}
+
+/// Gets the root directory of the workspace containing `repo`.
+pub async fn get_toplevel(repo: &Path) -> Result<std::path::PathBuf, GitError> {
+    Ok(run_jj(repo, ["root"]).await?.trim().into())
+}
+
+/// Finds when the most recently modified of the changed files in `repo` was
+/// last written to.
+pub async fn get_last_modified(repo: &Path, config: &DiffConfig) -> Option<std::time::SystemTime> {
+    let toplevel = get_toplevel(repo).await.ok()?;
+
+    let files = run_jj(repo, diff_args(config, "@", &["--name-only"]))
+        .await
+        .ok()?;
+
+    let mut latest = None;
+
+    for file in files.lines() {
+        let Ok(metadata) = tokio::fs::metadata(toplevel.join(file)).await else {
+            continue;
+        };
+
+        latest = latest.max(metadata.modified().ok());
+    }
+
+    latest
+}
+
+/// The nearest bookmark behind the working copy, which is the closest thing
+/// jj has to the checked-out branch, or the short change ID without one.
+pub async fn get_branch(repo: &Path) -> Result<String, GitError> {
+    let bookmarks = run_jj(
+        repo,
+        [
+            "log",
+            "--no-graph",
+            "-r",
+            "latest(::@ & bookmarks())",
+            "-T",
+            "bookmarks",
+        ],
+    )
+    .await?;
+
+    // bookmarks that differ from their remote are marked with a `*`
+    if let Some(bookmark) = bookmarks.split_whitespace().next() {
+        return Ok(bookmark.trim_end_matches('*').to_string());
+    }
+
+    Ok(run_jj(
+        repo,
+        ["log", "--no-graph", "-r", "@", "-T", "change_id.short()"],
+    )
+    .await?
+    .trim()
+    .to_string())
+}
+
+/// The `jj diff` command line for the changes in `revision`, with `options`
+/// for the kind of output wanted.
+fn diff_args(config: &DiffConfig, revision: &str, options: &[&str]) -> Vec<String> {
+    let mut args: Vec<String> = ["diff"]
+        .iter()
+        .chain(options)
+        .map(|arg| arg.to_string())
+        .collect();
+
+    if config.ignore_whitespace {
+        args.push("--ignore-all-space".into());
+    }
+
+    if let Some(lines) = config.context_lines {
+        args.push(format!("--context={lines}"));
+    }
+
+    match &config.base {
+        // everything since the working copy forked from the base
+        Some(base) if revision == "@" => {
+            args.push("--from".into());
+            args.push(format!("heads(::({base}) & ::@)"));
+        }
+        _ => {
+            args.push("-r".into());
+            args.push(revision.into());
+        }
+    }
+
+    if let Some(fileset) = fileset(config) {
+        args.push("--".into());
+        args.push(fileset);
+    }
+
+    args
+}
+
+/// Turns [DiffConfig::include] and [DiffConfig::exclude] into a single jj
+/// fileset expression, or `None` to diff everything.
+fn fileset(config: &DiffConfig) -> Option<String> {
+    let union = |patterns: &[String]| {
+        patterns
+            .iter()
+            .map(|pattern| format!("glob:{pattern:?}"))
+            .collect::<Vec<_>>()
+            .join(" | ")
+    };
+
+    match (config.include.is_empty(), config.exclude.is_empty()) {
+        (true, true) => None,
+        (false, true) => Some(union(&config.include)),
+        (true, false) => Some(format!("~({})", union(&config.exclude))),
+        (false, false) => Some(format!(
+            "({}) & ~({})",
+            union(&config.include),
+            union(&config.exclude)
+        )),
+    }
+}
+
+/// Counts the changes in a git-format diff, since jj has no `--numstat`, and
+/// adds the repository's name and bookmark.
+async fn metadata(repo: &Path, diff: &str) -> Result<DiffMetadata, GitError> {
+    let toplevel = get_toplevel(repo).await?;
+
+    Ok(DiffMetadata {
+        repo: get_repo_name(repo, &toplevel).await,
+        branch: get_branch(repo).await?,
+        ..DiffMetadata::count(diff)
+    })
+}
+
+/// Runs `jj` in `repo` with the given arguments, returning its standard output.
+pub async fn run_jj(
+    repo: &Path,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<String, GitError> {
+    if !repo.is_dir() {
+        return Err(GitError::NotARepository);
+    }
+
+    let output = tokio::process::Command::new("jj")
+        .args(["--no-pager", "--color=never"])
+        .args(args)
+        .current_dir(repo)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(GitError::JjNotInstalled)?
+        .wait_with_output()
+        .await
+        .map_err(GitError::Io)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+        if stderr.contains("There is no jj repo") {
+            return Err(GitError::NotARepository);
+        }
+
+        return Err(GitError::JjFailed {
+            status: output.status,
+            stderr,
+        });
+    }
+
+    Ok(decode_output("jj", output.stdout))
+}
@@ -0,0 +1,122 @@
+//! The languages samwise has its own text translated into, for
+//! [Config::language].
+//!
+//! [Config::language]: crate::config::Config::language
+
+/// The built-in text of the presence in one language, which the config's own
+/// text always wins over.
+pub struct Language {
+    /// The ISO 639-1 code, like `de`.
+    pub code: &'static str,
+
+    /// The name in English, which is how the model is asked to answer in it.
+    pub name: &'static str,
+
+    /// In place of [IdleConfig::details](crate::config::IdleConfig::details).
+    pub idle: &'static str,
+
+    /// In place of
+    /// [DiscordConfig::conflict_message](crate::config::DiscordConfig::conflict_message).
+    pub conflict: &'static str,
+
+    /// In place of [CommitConfig::template](crate::config::CommitConfig::template).
+    pub commit: &'static str,
+
+    /// The state line, with and without the branch, in place of
+    /// [DiscordConfig::state_template](crate::config::DiscordConfig::state_template).
+    pub state: &'static str,
+    pub state_without_branch: &'static str,
+}
+
+/// Every language with translations, with English first.
+pub const LANGUAGES: &[Language] = &[
+    Language {
+        code: "en",
+        name: "English",
+        idle: "Taking a break",
+        conflict: "Resolving merge conflicts",
+        commit: "✅ committed: {message}",
+        state: "{repo} on {branch} · {files} files",
+        state_without_branch: "{repo} · {files} files",
+    },
+    Language {
+        code: "de",
+        name: "German",
+        idle: "Macht gerade Pause",
+        conflict: "Löst Merge-Konflikte",
+        commit: "✅ committet: {message}",
+        state: "{repo} auf {branch} · {files} Dateien",
+        state_without_branch: "{repo} · {files} Dateien",
+    },
+    Language {
+        code: "fr",
+        name: "French",
+        idle: "En pause",
+        conflict: "Résout des conflits de fusion",
+        commit: "✅ commité : {message}",
+        state: "{repo} sur {branch} · {files} fichiers",
+        state_without_branch: "{repo} · {files} fichiers",
+    },
+    Language {
+        code: "es",
+        name: "Spanish",
+        idle: "Tomando un descanso",
+        conflict: "Resolviendo conflictos de fusión",
+        commit: "✅ commit hecho: {message}",
+        state: "{repo} en {branch} · {files} archivos",
+        state_without_branch: "{repo} · {files} archivos",
+    },
+    Language {
+        code: "pt",
+        name: "Portuguese",
+        idle: "Fazendo uma pausa",
+        conflict: "Resolvendo conflitos de merge",
+        commit: "✅ commit feito: {message}",
+        state: "{repo} em {branch} · {files} arquivos",
+        state_without_branch: "{repo} · {files} arquivos",
+    },
+    Language {
+        code: "it",
+        name: "Italian",
+        idle: "In pausa",
+        conflict: "Risolve conflitti di merge",
+        commit: "✅ commit fatto: {message}",
+        state: "{repo} su {branch} · {files} file",
+        state_without_branch: "{repo} · {files} file",
+    },
+    Language {
+        code: "nl",
+        name: "Dutch",
+        idle: "Even pauze",
+        conflict: "Lost mergeconflicten op",
+        commit: "✅ gecommit: {message}",
+        state: "{repo} op {branch} · {files} bestanden",
+        state_without_branch: "{repo} · {files} bestanden",
+    },
+    Language {
+        code: "ja",
+        name: "Japanese",
+        idle: "休憩中",
+        conflict: "マージの競合を解決中",
+        commit: "✅ コミット: {message}",
+        state: "{repo}（{branch}）· {files} ファイル",
+        state_without_branch: "{repo} · {files} ファイル",
+    },
+];
+
+/// Looks up a language by its code or English name, ignoring case.
+pub fn find(language: &str) -> Option<&'static Language> {
+    let language = language.trim();
+
+    LANGUAGES.iter().find(|known| {
+        known.code.eq_ignore_ascii_case(language) || known.name.eq_ignore_ascii_case(language)
+    })
+}
+
+/// The instruction added to the prompt to get answers in `language`, which
+/// doesn't have to be one with translations, since the model may know it
+/// anyway.
+pub fn instruction(language: &str) -> String {
+    let name = find(language).map_or(language.trim(), |known| known.name);
+    format!("Respond in {name}.")
+}
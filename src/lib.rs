@@ -0,0 +1,3028 @@
+//! LLM summary of current Git diff as a Discord rich presence.
+
+pub mod agent;
+pub mod cache;
+pub mod change_type;
+pub mod config;
+pub mod control;
+#[cfg(feature = "notifications")]
+pub mod desktop;
+pub mod doctor;
+pub mod editor;
+pub mod git;
+pub mod hook;
+pub mod hook_command;
+pub mod http;
+pub mod input_idle;
+pub mod jj;
+pub mod language;
+#[cfg(feature = "libgit2")]
+pub mod libgit2;
+pub mod metrics;
+pub mod mqtt;
+pub mod pause;
+pub mod presence;
+pub mod preset;
+pub mod redact;
+pub mod rpc;
+pub mod slack;
+pub mod systemd;
+pub mod template;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod usage;
+pub mod watch;
+pub mod webhook;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use discord_presence::models::Activity;
+use tokio::sync::{
+    Semaphore,
+    mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+
+use crate::{
+    agent::{
+        ModelChain, ModelError, ResponseFilter, cache_key, diff_context, file_type_hint, is_pulled,
+        loaded_models, prompt_vars, run_context_command, shell_command, skip_missing_models,
+        warm_up,
+    },
+    cache::ResponseCache,
+    config::{
+        AgentConfig, Config, ConfigWatcher, DiffConfig, DiffMode, IGNORE_FILE, Overflow, Provider,
+        QuietAction, SummarySource, Vcs,
+    },
+    control::Control,
+    git::{
+        DiffMetadata, GitError, RepoDiff, browser_url, diff_hash, get_active_diff,
+        get_changed_diffs, get_commit, get_commit_metadata, get_current_branch, get_diff,
+        get_diff_stat, get_head, get_log, get_metadata, get_remote_url, has_conflicts,
+        is_whitespace_only, strip_binary,
+    },
+    pause::Pause,
+    presence::{
+        Output, PresenceState, SavedPresence, Verbs, build_activity, load_presence,
+        load_session_start, presence_fingerprint, save_presence, save_session_start,
+        summary_similarity, truncate_summary, unix_now,
+    },
+    redact::Redactor,
+    template::render_template,
+    watch::RepoWatcher,
+};
+
+/// Runs the main diff-summarizing loop forever.
+///
+/// Changes to the config picked up by `watcher` are applied at the start of
+/// the next tick. With [Config::watch] set, ticks wait for the repositories to
+/// change instead of running every [Config::frequency].
+pub async fn run(
+    mut config: Config,
+    output: &mut Output,
+    mut cache: Option<ResponseCache>,
+    watcher: Option<ConfigWatcher>,
+) -> anyhow::Result<()> {
+    let (mut repos, mut filters) = build_pipeline(&config).await?;
+    let mut verbs = Verbs::new(&config.discord)?;
+    let mut prefetch = Prefetch::new(&config.agent, cache.as_ref());
+
+    // loads the model while Discord connects and the first diff is read
+    if config.agent.warmup {
+        tokio::spawn(warm_up(repos[0].config.agent.clone()));
+    }
+
+    let mut repo_watcher = watch_repos(&config).await;
+
+    let mut pause = Pause::listen();
+    let mut control = Control::listen(&config, output.status().clone());
+
+    let mut last_hash = None;
+
+    // the branch of the last summary, so that switching summarizes again
+    let mut last_branch: Option<String> = None;
+
+    // when the diff last changed, for going idle, and what was shown then
+    let mut last_change = Instant::now();
+    let mut last_state: Option<PresenceState> = None;
+    let mut idle = false;
+
+    // whether it's one of the schedule's quiet hours
+    let mut quiet = false;
+
+    // whether the keyboard and mouse have gone untouched for too long, and
+    // whether it's been warned that there's no telling
+    let mut away = false;
+    let mut input_idle = input_idle::IdleProbe::default();
+    let mut input_idle_unknown = false;
+
+    // the summary that was last sent, which a near-identical one is replaced
+    // with so the presence doesn't flicker
+    let mut last_summary: Option<String> = None;
+
+    // the last HEAD seen in each repository, and the one that was just
+    // committed to, for summarizing new commits
+    let mut heads = HashMap::new();
+    let mut committed = None;
+
+    // which facet is up, when it was put up, and the summaries of the facets
+    // that have had a turn since the diff last changed
+    let mut facet = 0;
+    let mut facet_shown = Instant::now();
+    let mut facet_summaries: HashMap<usize, Summarized> = HashMap::new();
+
+    // when the model was last asked for a summary, for throttling
+    let mut last_prompt: Option<Instant> = None;
+
+    // spreads out the waits between checks by config.frequency_jitter
+    let mut rng = config.jitter_rng();
+
+    // the changed diff waiting for a quiet period, when it last changed, and
+    // when it first did since the last summary
+    let mut settling: Option<((u64, PathBuf), Instant, Instant)> = None;
+
+    let mut session_start = config
+        .discord
+        .persist_timer
+        .then(load_session_start)
+        .flatten();
+
+    // put the last presence back up while the first diff is read, and skip
+    // summarizing it again if it hasn't changed
+    if config.discord.persist_presence
+        && let Some(saved) = load_presence(&config)
+    {
+        tracing::info!("restoring the last presence");
+        output.update(Some(&saved.presence), &config).await;
+        last_summary = Some(saved.presence.summary.clone());
+
+        session_start = session_start.or(saved.presence.session_start);
+        last_hash = Some((saved.diff_hash, saved.repo));
+        last_state = Some(saved.presence);
+    }
+
+    // the last few commits stand in until there are changes, if asked for
+    let mut startup = (config.diff.startup_summary > 0 && last_hash.is_none()).then(|| {
+        repos[0]
+            .config
+            .diff
+            .recent_commits(config.diff.startup_summary)
+    });
+
+    loop {
+        let new_config = match &watcher {
+            Some(watcher) => watcher.poll(&config).await,
+            None => None,
+        };
+
+        if let Some(new_config) = new_config {
+            let pipeline = build_pipeline(&new_config)
+                .await
+                .and_then(|pipeline| Ok((pipeline, Verbs::new(&new_config.discord)?)));
+
+            match pipeline {
+                Ok((pipeline, new_verbs)) => {
+                    tracing::info!("reloaded config");
+                    (repos, filters) = pipeline;
+                    verbs = new_verbs;
+                    prefetch = Prefetch::new(&new_config.agent, cache.as_ref());
+                    config = new_config;
+                    rng = config.jitter_rng();
+                    repo_watcher = watch_repos(&config).await;
+                    last_hash = None;
+                    heads.clear();
+                    committed = None;
+                    facet = 0;
+                    quiet = false;
+
+                    last_summary = None;
+                }
+                Err(err) => {
+                    tracing::error!("failed to apply the new config, keeping the old one: {err:#}");
+                    output.status().set_error(&err);
+                }
+            }
+        }
+
+        control.apply_idle(&mut config);
+
+        // pinning or unpinning replaces whatever's showing
+        if control.take_pin_changed() {
+            last_hash = None;
+        }
+
+        if pause.is_paused() {
+            output.update(None, &config).await;
+            last_summary = None;
+            systemd::status("paused");
+
+            if config.discord.persist_presence {
+                save_presence(None);
+            }
+
+            // nothing is read or summarized until updates are resumed
+            while pause.is_paused() {
+                tokio::select! {
+                    _ = pause.changed() => {}
+                    command = control.recv() => control.apply(command, &mut pause),
+                }
+            }
+
+            last_hash = None;
+            continue;
+        }
+
+        if let Some(remaining) = config.schedule.quiet_remaining() {
+            if !quiet {
+                tracing::info!(
+                    "quiet hours for the next {}",
+                    humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+                );
+
+                let away = (config.schedule.action == QuietAction::Away).then(|| PresenceState {
+                    summary: config.schedule.away_message.clone(),
+                    metadata: None,
+                    session_start: None,
+                    model: String::new(),
+                    repo_url: None,
+                    verb: None,
+                });
+
+                output.update(away.as_ref(), &config).await;
+                last_summary = None;
+                systemd::status("quiet hours");
+
+                if config.discord.persist_presence {
+                    save_presence(None);
+                }
+
+                quiet = true;
+            }
+
+            // anything seen before is summarized afresh once they're over
+            last_hash = None;
+
+            if config.schedule.action == QuietAction::Pause {
+                tokio::select! {
+                    _ = tokio::time::sleep(remaining) => {}
+                    _ = pause.changed() => {}
+                    command = control.recv() => control.apply(command, &mut pause),
+                }
+            } else {
+                wait_for_changes(
+                    &config,
+                    repo_watcher.as_mut(),
+                    watcher.as_ref(),
+                    &mut pause,
+                    &mut control,
+                    &mut rng,
+                    Some(remaining),
+                )
+                .await;
+            }
+
+            continue;
+        }
+
+        if quiet {
+            tracing::info!("quiet hours are over");
+            quiet = false;
+        }
+
+        // how much longer until the user counts as away from the keyboard
+        let mut away_in = None;
+
+        if let Some(timeout) = config.discord.input_idle_timeout {
+            match input_idle.idle_time().await {
+                Some(idle_for) if idle_for >= timeout => {
+                    if !away {
+                        tracing::info!(
+                            "no keyboard or mouse input in a while, clearing the presence"
+                        );
+                        output.update(None, &config).await;
+                        last_summary = None;
+                        systemd::status("away from the keyboard");
+                        away = true;
+                    }
+
+                    // what's changed meanwhile is summarized once they're back
+                    last_hash = None;
+
+                    wait_for_changes(
+                        &config,
+                        repo_watcher.as_mut(),
+                        watcher.as_ref(),
+                        &mut pause,
+                        &mut control,
+                        &mut rng,
+                        Some(config.frequency),
+                    )
+                    .await;
+                    continue;
+                }
+                Some(idle_for) => away_in = Some(timeout - idle_for),
+                None if !input_idle_unknown => {
+                    tracing::warn!(
+                        "can't tell how long the keyboard and mouse have been idle here, \
+                         ignoring discord.input_idle_timeout"
+                    );
+                    input_idle_unknown = true;
+                }
+                None => {}
+            }
+        }
+
+        if away {
+            tracing::info!("back at the keyboard");
+            away = false;
+        }
+
+        output.check().await;
+
+        let open_files = editor::open_files(&config.editor);
+
+        for repo in &mut repos {
+            repo.reload_ignore_file(&config);
+            repo.scope_to_open_files(open_files.as_deref());
+        }
+
+        let started = Instant::now();
+        let changed = get_changed_diffs(&diff_configs(&repos)).await;
+        metrics::DIFF_DURATION.observe(started.elapsed());
+
+        // the others can still be summarized ahead of their turn
+        let (active, others) = match changed {
+            Ok(mut changed) => {
+                let active = (!changed.is_empty()).then(|| changed.remove(0));
+                (active, changed)
+            }
+            Err(
+                err @ (GitError::NotInstalled(_)
+                | GitError::JjNotInstalled(_)
+                | GitError::NotARepository),
+            ) => {
+                return Err(err).context("cannot summarize changes here");
+            }
+            Err(err) => {
+                tracing::warn!("failed to get diff, retrying next tick: {err}");
+                output.status().set_error(&err.into());
+                tokio::time::sleep(config.next_frequency(&mut rng)).await;
+                continue;
+            }
+        };
+
+        let mut landed = None;
+
+        if config.diff.summarize_commits || config.discord.commit.enabled {
+            for (index, repo) in repos.iter().enumerate() {
+                let head = get_head(&repo.path, &repo.config.diff).await;
+
+                // only a HEAD that moves counts, not the one found at startup
+                if let Some(previous) = heads.insert(repo.path.clone(), head.clone())
+                    && previous != head
+                    && head.is_some()
+                {
+                    landed = Some(index);
+
+                    if config.diff.summarize_commits {
+                        committed = Some(index);
+                    }
+                }
+            }
+        }
+
+        // a moment for the commit before going back to the work tree
+        if let Some(index) = landed.filter(|_| config.discord.commit.enabled)
+            && let Some(presence) =
+                commit_presence(&repos[index], &filters, cache.as_mut(), session_start).await
+        {
+            tracing::info!(summary = presence.summary, "a commit landed");
+            output.update(Some(&presence), &config).await;
+
+            if let Some((model, elapsed)) = repos[index].models.take_last_prompt() {
+                output.status().set_prompt(&model, elapsed);
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(config.discord.commit.duration) => {}
+                _ = pause.changed() => {}
+                command = control.recv() => control.apply(command, &mut pause),
+            }
+
+            last_hash = None;
+            last_summary = None;
+            continue;
+        }
+
+        // go back to the work tree as soon as there are changes again
+        if active.is_some() {
+            committed = None;
+        }
+
+        if active.is_some() || committed.is_some() {
+            startup = None;
+        }
+
+        let active = match (active, &committed, &startup) {
+            (None, Some(index), _) => {
+                match get_commit(&repos[*index].path, &repos[*index].config.diff).await {
+                    Ok(diff) => Some(RepoDiff {
+                        path: repos[*index].path.clone(),
+                        index: *index,
+                        diff,
+                    }),
+                    Err(err) => {
+                        tracing::warn!("failed to get the new commit: {err}");
+                        None
+                    }
+                }
+            }
+            (None, None, Some(recent)) => {
+                match get_recent_commits(&repos[0].path, recent, config.diff.startup_summary).await
+                {
+                    Ok(diff) => Some(RepoDiff {
+                        path: repos[0].path.clone(),
+                        index: 0,
+                        diff,
+                    }),
+                    Err(err) => {
+                        tracing::warn!("failed to get the latest commits: {err}");
+                        None
+                    }
+                }
+            }
+            (active, _, _) => active,
+        };
+
+        if active.is_none() {
+            startup = None;
+        }
+
+        let Some(RepoDiff {
+            path: repo,
+            index,
+            diff,
+        }) = active
+        else {
+            if config.discord.reset_timer_on_idle
+                && session_start.take().is_some()
+                && config.discord.persist_timer
+            {
+                save_session_start(None);
+            }
+
+            if config.discord.persist_presence {
+                save_presence(None);
+            }
+
+            // a pin stays up even without changes
+            let pinned = control.pin().map(|pin| PresenceState {
+                summary: pin.to_string(),
+                metadata: None,
+                session_start,
+                model: String::new(),
+                repo_url: None,
+                verb: None,
+            });
+
+            output.update(pinned.as_ref(), &config).await;
+            last_summary = None;
+
+            wait_for_changes(
+                &config,
+                repo_watcher.as_mut(),
+                watcher.as_ref(),
+                &mut pause,
+                &mut control,
+                &mut rng,
+                None,
+            )
+            .await;
+            continue;
+        };
+
+        // what it's summarized with, once its overrides are applied
+        let Repo {
+            config: repo_config,
+            models,
+            ..
+        } = &repos[index];
+
+        // the hash is of the filtered diff, so it only changes when what the
+        // model would see does
+        let diff = filter_diff(&config.diff, &repo, strip_binary(&diff)).await;
+        let diff = filters.redactor.redact(&diff);
+
+        // small changes get the small message, or nothing new at all, and
+        // so does a diff the filter left nothing of
+        let small = config.diff.is_small(&diff);
+
+        if small && config.diff.small_message.is_none()
+            || config.diff.ignore_whitespace && is_whitespace_only(&diff)
+            || diff.trim().is_empty()
+        {
+            wait_for_changes(
+                &config,
+                repo_watcher.as_mut(),
+                watcher.as_ref(),
+                &mut pause,
+                &mut control,
+                &mut rng,
+                None,
+            )
+            .await;
+            continue;
+        }
+
+        if session_start.is_none() {
+            let now = unix_now();
+            session_start = Some(now);
+
+            if config.discord.persist_timer {
+                save_session_start(Some(now));
+            }
+        }
+
+        let hash = (diff_hash(&diff), repo.clone());
+        let branch = get_current_branch(&repo, &repo_config.diff).await;
+
+        // switching branches can leave the diff as it was, but not what it's
+        // part of
+        let switched = last_branch
+            .as_ref()
+            .is_some_and(|last| Some(last) != branch.as_ref());
+
+        if switched {
+            tracing::debug!(?last_branch, ?branch, "the branch changed");
+        }
+
+        let changed = Some(&hash) != last_hash.as_ref() || switched;
+
+        if changed {
+            facet_summaries.clear();
+        } else {
+            settling = None;
+        }
+
+        // with nothing new to summarize, the next facet takes its turn
+        let rotate = !changed
+            && !idle
+            && config.facets.len() > 1
+            && facet_shown.elapsed() >= config.facet_frequency(facet);
+
+        // or the same diff is summarized again for a fresh take on it
+        let refresh_in = (config.agent.resummarize_unchanged && !changed && !idle).then(|| {
+            last_prompt.map_or(Duration::ZERO, |last_prompt| {
+                config
+                    .resummarize_interval()
+                    .saturating_sub(last_prompt.elapsed())
+            })
+        });
+
+        let refresh = control.refresh_requested()
+            || !rotate && refresh_in.is_some_and(|refresh_in| refresh_in.is_zero());
+
+        if !changed && !rotate && !refresh {
+            let idle_in = config
+                .discord
+                .idle_timeout
+                .filter(|_| !idle)
+                .map(|timeout| timeout.saturating_sub(last_change.elapsed()));
+
+            if idle_in.is_some_and(|idle_in| idle_in.is_zero()) {
+                tracing::info!("the diff hasn't changed in a while, going idle");
+
+                let idle_state = config
+                    .discord
+                    .idle_message
+                    .as_ref()
+                    .zip(last_state.as_ref())
+                    .map(|(message, state)| PresenceState {
+                        summary: message.clone(),
+                        verb: None,
+                        ..state.clone()
+                    });
+
+                output.update(idle_state.as_ref(), &config).await;
+                last_summary = None;
+                idle = true;
+            }
+
+            let next_facet_in = (config.facets.len() > 1 && !idle).then(|| {
+                config
+                    .facet_frequency(facet)
+                    .saturating_sub(facet_shown.elapsed())
+            });
+
+            let timeout = idle_in
+                .into_iter()
+                .chain(away_in)
+                .chain(next_facet_in)
+                .chain(refresh_in.filter(|_| !idle))
+                .min();
+
+            wait_for_changes(
+                &config,
+                repo_watcher.as_mut(),
+                watcher.as_ref(),
+                &mut pause,
+                &mut control,
+                &mut rng,
+                timeout,
+            )
+            .await;
+            continue;
+        }
+
+        if refresh {
+            tracing::debug!("summarizing the unchanged diff again");
+            facet_summaries.remove(&facet);
+        }
+
+        let next_facet = if rotate {
+            (facet + 1) % config.facets.len()
+        } else {
+            facet
+        };
+
+        // let the diff settle first, so that the model doesn't see an edit
+        // that's still being made
+        if changed && let Some(quiet_period) = config.agent.quiet_period {
+            let now = Instant::now();
+            let (settling_hash, last_edit, first_edit) =
+                settling.get_or_insert_with(|| (hash.clone(), now, now));
+
+            if *settling_hash != hash {
+                *settling_hash = hash.clone();
+                *last_edit = now;
+            }
+
+            let wait = quiet_period.saturating_sub(last_edit.elapsed()).min(
+                config
+                    .agent
+                    .max_quiet_wait
+                    .saturating_sub(first_edit.elapsed()),
+            );
+
+            if !wait.is_zero() {
+                tracing::debug!("waiting {}ms for the diff to settle", wait.as_millis());
+
+                wait_for_changes(
+                    &config,
+                    repo_watcher.as_mut(),
+                    watcher.as_ref(),
+                    &mut pause,
+                    &mut control,
+                    &mut rng,
+                    Some(wait),
+                )
+                .await;
+                continue;
+            }
+        }
+
+        // hold off on prompting again too soon, rechecking the diff once it's
+        // time so that the latest one is summarized
+        let throttled = config
+            .agent
+            .min_prompt_interval
+            .zip(last_prompt)
+            .map(|(interval, last_prompt)| interval.saturating_sub(last_prompt.elapsed()))
+            .filter(|wait| !wait.is_zero() && !facet_summaries.contains_key(&next_facet));
+
+        if let Some(wait) = throttled {
+            tracing::debug!("waiting {}ms before prompting again", wait.as_millis());
+
+            wait_for_changes(
+                &config,
+                repo_watcher.as_mut(),
+                watcher.as_ref(),
+                &mut pause,
+                &mut control,
+                &mut rng,
+                Some(wait),
+            )
+            .await;
+            continue;
+        }
+
+        facet = next_facet;
+        control.clear_refresh();
+
+        let metadata = match (&committed, &startup) {
+            (Some(_), _) => get_commit_metadata(&repo, &repo_config.diff).await,
+            (None, Some(recent)) => get_metadata(&repo, recent).await,
+            (None, None) => get_metadata(&repo, &repo_config.diff).await,
+        };
+
+        let metadata = match metadata {
+            Ok(metadata) => Some(metadata.classify(&diff, &config.change_types)),
+            Err(err) => {
+                tracing::warn!("failed to get diff metadata: {err}");
+                None
+            }
+        };
+
+        let conflicted = !config.discord.conflict_message.is_empty() && has_conflicts(&diff);
+
+        let repo_url = if config.discord.link_repo {
+            get_remote_url(&repo)
+                .await
+                .and_then(|url| browser_url(&url))
+        } else {
+            None
+        };
+
+        // the conflict message and pins already say what's being done
+        let verb = metadata
+            .as_ref()
+            .filter(|_| !conflicted && control.pin().is_none())
+            .and_then(|metadata| verbs.pick(metadata, &diff));
+
+        // the prompt can still use the branch, it's just never shown
+        let shown_metadata = metadata.clone().map(|mut metadata| {
+            if !config.discord.show_branch {
+                metadata.branch.clear();
+            }
+
+            metadata
+        });
+
+        // what's shown without asking the models
+        let unprompted = |text: &str| Summarized {
+            text: text.to_string(),
+            model: repo_config.agent.primary_model().to_string(),
+        };
+
+        let summary = match facet_summaries.get(&facet) {
+            // the pin is shown as it is, without asking the model
+            _ if control.pin().is_some() => Ok(control.pin().map(unprompted)),
+            Some(summary) => Ok(Some(summary.clone())),
+            None if conflicted => {
+                tracing::info!("the changes have merge conflicts, not summarizing them");
+                Ok(Some(unprompted(&config.discord.conflict_message)))
+            }
+            None if small => {
+                tracing::debug!("the changes are too small to summarize");
+                Ok(config.diff.small_message.as_deref().map(unprompted))
+            }
+            None => {
+                let facet_config = config.facets.get(facet).map(|f| repo_config.with_facet(f));
+                last_prompt = Some(Instant::now());
+
+                // what's been summarized in the background may be this one
+                if let Some(cache) = &mut cache {
+                    prefetch.collect(cache);
+
+                    for changes in others {
+                        let repo = &repos[changes.index];
+                        prefetch.summarize(&config, repo, &filters, cache, facet, changes);
+                    }
+                }
+
+                // the cache would only give back the same summary
+                let cache = if refresh { None } else { cache.as_mut() };
+
+                let summarizing = summarize(
+                    facet_config.as_ref().unwrap_or(repo_config),
+                    models,
+                    &filters,
+                    cache,
+                    Some(&repo),
+                    &diff,
+                    metadata.as_ref(),
+                );
+
+                let partial_state = PresenceState {
+                    summary: String::new(),
+                    metadata: shown_metadata.clone(),
+                    session_start,
+                    model: repo_config.agent.primary_model().to_string(),
+                    repo_url: repo_url.clone(),
+                    verb: verb.clone(),
+                };
+
+                let (summary, interim) = show_partial_summaries(
+                    summarizing,
+                    models,
+                    output,
+                    &config,
+                    &repo_config.agent,
+                    &filters.response,
+                    partial_state,
+                )
+                .await;
+
+                // what was shown while waiting can't stay up without a
+                // summary to replace it
+                if interim && !matches!(summary, Ok(Some(_))) {
+                    match &last_state {
+                        Some(state) => output.update_partial(state, &config).await,
+                        None => output.update(None, &config).await,
+                    }
+                }
+
+                // cached summaries don't prompt at all
+                if let Some((model, elapsed)) = models.take_last_prompt() {
+                    output.status().set_prompt(&model, elapsed);
+                }
+
+                summary
+            }
+        };
+
+        // a summary that reads like the idle text would look like going idle
+        // and back, so it's treated like no summary at all
+        let summary = summary.map(|summary| {
+            summary.filter(|response| {
+                let collides =
+                    control.pin().is_none() && config.discord.is_idle_text(&response.text);
+
+                if collides {
+                    tracing::debug!("the summary is the same as the idle text, not showing it");
+                }
+
+                !collides
+            })
+        });
+
+        let mut response = match summary {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                // keep showing the previous summary until the diff changes
+                last_hash = Some(hash);
+                last_branch = branch;
+
+                let next_facet_in =
+                    (config.facets.len() > 1).then(|| config.facet_frequency(facet));
+                wait_for_changes(
+                    &config,
+                    repo_watcher.as_mut(),
+                    watcher.as_ref(),
+                    &mut pause,
+                    &mut control,
+                    &mut rng,
+                    next_facet_in,
+                )
+                .await;
+                continue;
+            }
+            // the last presence stays up until there's budget for a new one,
+            // and over_budget has already said why
+            Err(err) if is_over_budget(&err) => {
+                last_hash = Some(hash);
+                last_branch = branch;
+
+                wait_for_changes(
+                    &config,
+                    repo_watcher.as_mut(),
+                    watcher.as_ref(),
+                    &mut pause,
+                    &mut control,
+                    &mut rng,
+                    Some(usage::until_tomorrow()),
+                )
+                .await;
+                continue;
+            }
+            Err(err) => {
+                tracing::error!("{err:#}, skipping this tick");
+                output.status().set_error(&err);
+                tokio::time::sleep(config.next_frequency(&mut rng)).await;
+                continue;
+            }
+        };
+
+        // a summary that barely changed isn't worth the flicker of showing it
+        if let Some(last_summary) = &last_summary
+            && summary_similarity(last_summary, &response.text)
+                >= config.discord.similarity_threshold
+        {
+            response.text = last_summary.clone();
+        }
+
+        facet_summaries.insert(facet, response.clone());
+
+        // the summary that's up gets its time on screen before this one
+        // replaces it
+        let hold = config
+            .discord
+            .min_display_time
+            .filter(|_| {
+                last_summary
+                    .as_ref()
+                    .is_some_and(|last| *last != response.text)
+            })
+            .map(|min| min.saturating_sub(facet_shown.elapsed()))
+            .filter(|hold| !hold.is_zero());
+
+        if let Some(hold) = hold {
+            tracing::debug!("holding the new summary back for {}ms", hold.as_millis());
+
+            let until = Instant::now() + hold;
+            let mut cleared = false;
+
+            while !cleared && Instant::now() < until {
+                wait_for_changes(
+                    &config,
+                    repo_watcher.as_mut(),
+                    watcher.as_ref(),
+                    &mut pause,
+                    &mut control,
+                    &mut rng,
+                    Some(until.saturating_duration_since(Instant::now())),
+                )
+                .await;
+
+                // clearing the presence never waits, but commits are only
+                // summarized when there are no changes
+                cleared = committed.is_none()
+                    && startup.is_none()
+                    && matches!(get_active_diff(&diff_configs(&repos)).await, Ok(None));
+            }
+
+            if cleared {
+                continue;
+            }
+        }
+
+        tracing::info!(
+            repo = %repo.display(),
+            diff_bytes = diff.len(),
+            facet,
+            summary = %response.text,
+            model = %response.model,
+            "updating presence"
+        );
+
+        let state = PresenceState {
+            summary: response.text,
+            metadata: shown_metadata,
+            session_start,
+            model: response.model,
+            repo_url,
+            verb,
+        };
+
+        output.update(Some(&state), &config).await;
+        last_summary = Some(state.summary.clone());
+
+        if config.discord.persist_presence {
+            save_presence(Some(&SavedPresence {
+                fingerprint: presence_fingerprint(&config),
+                repo: repo.clone(),
+                diff_hash: hash.0,
+                presence: state.clone(),
+            }));
+        }
+
+        if changed {
+            last_change = Instant::now();
+        }
+
+        last_state = Some(state);
+        idle = false;
+        facet_shown = Instant::now();
+
+        let idle_in = config
+            .discord
+            .idle_timeout
+            .map(|timeout| timeout.saturating_sub(last_change.elapsed()));
+
+        let next_facet_in = (config.facets.len() > 1).then(|| config.facet_frequency(facet));
+
+        let refresh_in = config
+            .agent
+            .resummarize_unchanged
+            .then(|| config.resummarize_interval());
+
+        let timeout = idle_in
+            .into_iter()
+            .chain(next_facet_in)
+            .chain(refresh_in)
+            .min();
+        wait_for_changes(
+            &config,
+            repo_watcher.as_mut(),
+            watcher.as_ref(),
+            &mut pause,
+            &mut control,
+            &mut rng,
+            timeout,
+        )
+        .await;
+
+        last_hash = Some(hash);
+        last_branch = branch;
+    }
+}
+
+/// Gets the messages of the last `count` commits followed by their combined
+/// changes, diffed with `recent` from [DiffConfig::recent_commits].
+async fn get_recent_commits(
+    repo: &Path,
+    recent: &DiffConfig,
+    count: usize,
+) -> Result<String, GitError> {
+    let log = get_log(repo, recent, count).await?;
+    let diff = get_diff(repo, recent).await?;
+
+    Ok(format!("{}\n\n\n{diff}", log.trim_end()))
+}
+
+/// Starts watching the repositories if the config asks for it, falling back
+/// to polling if they can't be watched.
+async fn watch_repos(config: &Config) -> Option<RepoWatcher> {
+    if !config.watch {
+        return None;
+    }
+
+    match RepoWatcher::new(&config.repos()).await {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            tracing::warn!(
+                "falling back to checking every {}: {err:#}",
+                humantime::format_duration(config.frequency)
+            );
+
+            None
+        }
+    }
+}
+
+/// Waits until it's time for the next tick. When watching, that's once a
+/// repository or the config file changes, or after `timeout` if it's given;
+/// otherwise it's after [Config::next_frequency], or `timeout` if that's sooner.
+/// Either way, pausing or resuming updates, or any other command from the
+/// control socket, ends the wait early.
+async fn wait_for_changes(
+    config: &Config,
+    repo_watcher: Option<&mut RepoWatcher>,
+    config_watcher: Option<&ConfigWatcher>,
+    pause: &mut Pause,
+    control: &mut Control,
+    rng: &mut fastrand::Rng,
+    timeout: Option<Duration>,
+) {
+    let Some(repo_watcher) = repo_watcher else {
+        let frequency = config.next_frequency(rng);
+        let frequency = timeout.map_or(frequency, |timeout| timeout.min(frequency));
+
+        tokio::select! {
+            _ = tokio::time::sleep(frequency) => {}
+            _ = pause.changed() => {}
+            command = control.recv() => control.apply(command, pause),
+        }
+
+        return;
+    };
+
+    let config_changed = async {
+        match config_watcher {
+            Some(watcher) => watcher.changed().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let timed_out = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        _ = repo_watcher.changed() => {}
+        _ = config_changed => {}
+        _ = timed_out => {}
+        _ = pause.changed() => {}
+        command = control.recv() => control.apply(command, pause),
+    }
+}
+
+/// The presence shown for a moment once a commit lands in `repo`, for
+/// [DiscordConfig::commit], or `None` if the commit can't be read.
+///
+/// [DiscordConfig::commit]: crate::config::DiscordConfig::commit
+async fn commit_presence(
+    repo: &Repo,
+    filters: &Filters,
+    cache: Option<&mut ResponseCache>,
+    session_start: Option<u64>,
+) -> Option<PresenceState> {
+    let config = &repo.config;
+
+    let log = match get_log(&repo.path, &config.diff, 1).await {
+        Ok(log) => log,
+        Err(err) => {
+            tracing::warn!("failed to read the new commit: {err}");
+            return None;
+        }
+    };
+
+    let mut message = log
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .to_string();
+
+    let metadata = get_commit_metadata(&repo.path, &config.diff)
+        .await
+        .ok()
+        .map(|mut metadata| {
+            if !config.discord.show_branch {
+                metadata.branch.clear();
+            }
+
+            metadata
+        });
+
+    let mut model = String::new();
+
+    if config.discord.commit.summarize {
+        let summary = match get_commit(&repo.path, &config.diff).await {
+            Ok(diff) => {
+                let diff = filters.redactor.redact(&strip_binary(&diff));
+                let metadata = metadata.as_ref();
+                summarize(
+                    config,
+                    &repo.models,
+                    filters,
+                    cache,
+                    Some(&repo.path),
+                    &diff,
+                    metadata,
+                )
+                .await
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        match summary {
+            Ok(Some(summary)) => {
+                message = summary.text;
+                model = summary.model;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!("failed to summarize the new commit, using its subject: {err:#}");
+            }
+        }
+    }
+
+    let summary = match config.discord.commit.render(&message) {
+        Ok(summary) => summary,
+        Err(err) => {
+            tracing::warn!("failed to fill in discord.commit.template: {err:#}");
+            return None;
+        }
+    };
+
+    Some(PresenceState {
+        summary,
+        metadata,
+        session_start,
+        model,
+        repo_url: None,
+        verb: None,
+    })
+}
+
+/// A repository to summarize, with the config and models for it once its
+/// overrides are applied.
+struct Repo {
+    path: PathBuf,
+    config: Config,
+    models: ModelChain,
+
+    /// Models of its own for [Prefetch], so that what it prompts in the
+    /// background never shows up as partial summaries or prompt timings.
+    /// `None` when only one prompt can be out at a time.
+    background: Option<Arc<ModelChain>>,
+
+    /// When its [IGNORE_FILE] was last changed, if it has one.
+    ignore_modified: Option<SystemTime>,
+
+    /// Its [DiffConfig::include] from the config, for when no files in it are
+    /// open in the editor.
+    include: Vec<String>,
+}
+
+impl Repo {
+    /// Narrows the diff to the files in `open_files` that are in this
+    /// repository, or widens it back to [DiffConfig::include] when none are.
+    fn scope_to_open_files(&mut self, open_files: Option<&[PathBuf]>) {
+        let root = self
+            .path
+            .canonicalize()
+            .unwrap_or_else(|_| self.path.clone());
+
+        let open: Vec<String> = open_files
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|file| file.strip_prefix(&root).ok())
+            .filter_map(Path::to_str)
+            .map(ToOwned::to_owned)
+            .collect();
+
+        let include = if open.is_empty() {
+            self.include.clone()
+        } else {
+            open
+        };
+
+        if include != self.config.diff.include {
+            tracing::debug!(repo = %self.path.display(), ?include, "scoped to the open files");
+            self.config.diff.include = include;
+        }
+    }
+
+    /// Picks up changes to the [IGNORE_FILE], keeping the previous excludes if
+    /// it can't be read.
+    fn reload_ignore_file(&mut self, config: &Config) {
+        let modified = ignore_modified(&self.path);
+
+        if modified == self.ignore_modified {
+            return;
+        }
+
+        self.ignore_modified = modified;
+
+        match config.for_repo(&self.path) {
+            Ok(repo_config) => {
+                tracing::info!(repo = %self.path.display(), "reloaded {IGNORE_FILE}");
+                self.config.diff.exclude = repo_config.diff.exclude;
+            }
+            Err(err) => tracing::warn!("keeping the old excludes: {err:#}"),
+        }
+    }
+}
+
+/// When the [IGNORE_FILE] in `repo` was last changed, or `None` without one.
+fn ignore_modified(repo: &Path) -> Option<SystemTime> {
+    std::fs::metadata(repo.join(IGNORE_FILE))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Whether `err` is from the [daily_token_budget] being used up, which isn't
+/// worth reporting as an error every tick.
+///
+/// [daily_token_budget]: config::AgentConfig::daily_token_budget
+fn is_over_budget(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref(), Some(ModelError::OverBudget(_)))
+}
+
+/// `err` followed by each error that caused it, like anyhow's `{:#}`, for
+/// the errors that are logged without being wrapped in one.
+pub(crate) fn error_chain(err: &(dyn std::error::Error + 'static)) -> String {
+    anyhow::Chain::new(err)
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Pairs each repository with the config its diff is read with, for
+/// [get_changed_diffs].
+fn diff_configs(repos: &[Repo]) -> Vec<(&Path, &DiffConfig)> {
+    repos
+        .iter()
+        .map(|repo| (repo.path.as_path(), &repo.config.diff))
+        .collect()
+}
+
+/// Summaries of the changed repositories that aren't being shown, written in
+/// the background so that one's ready in the cache as soon as it is. At most
+/// [AgentConfig::max_concurrent_prompts] prompts are out at once, counting
+/// the one for the repository that's shown, and each runs on its own, so a
+/// slow or failing one holds up nothing else. Without a cache to put them in,
+/// none are written at all.
+struct Prefetch {
+    permits: Arc<Semaphore>,
+
+    /// Whether there's room for more prompts than the one that's shown, and
+    /// a cache for what they answer.
+    enabled: bool,
+
+    /// The hash of the last diff of each repository that's been summarized,
+    /// or is being summarized, so that it's only asked about once.
+    seen: HashMap<PathBuf, u64>,
+
+    /// When each repository was last prompted for, for
+    /// [AgentConfig::min_prompt_interval].
+    last_prompt: HashMap<PathBuf, Instant>,
+
+    done_tx: UnboundedSender<(u64, String)>,
+    done_rx: UnboundedReceiver<(u64, String)>,
+}
+
+impl Prefetch {
+    fn new(config: &AgentConfig, cache: Option<&ResponseCache>) -> Self {
+        let (done_tx, done_rx) = unbounded_channel();
+        let permits = config.max_concurrent_prompts.saturating_sub(1);
+
+        Self {
+            permits: Arc::new(Semaphore::new(permits)),
+            enabled: permits > 0 && cache.is_some(),
+            seen: HashMap::new(),
+            last_prompt: HashMap::new(),
+            done_tx,
+            done_rx,
+        }
+    }
+
+    /// Starts summarizing `changes`, from `repo`, which isn't the one that's
+    /// shown, with the facet that's up. What's already in `cache` isn't
+    /// asked about again.
+    fn summarize(
+        &mut self,
+        config: &Config,
+        repo: &Repo,
+        filters: &Arc<Filters>,
+        cache: &ResponseCache,
+        facet: usize,
+        changes: RepoDiff,
+    ) {
+        let Some(models) = repo.background.clone().filter(|_| self.enabled) else {
+            return;
+        };
+
+        let repo_config = match config.facets.get(facet) {
+            Some(facet) => repo.config.with_facet(facet),
+            None => repo.config.clone(),
+        };
+
+        let hash = diff_hash(&changes.diff);
+        let path = changes.path.clone();
+
+        let summarizing = prefetch_summary(
+            config.clone(),
+            repo_config,
+            models,
+            filters.clone(),
+            cache.snapshot(),
+            changes,
+        );
+
+        self.spawn(path, hash, config.agent.min_prompt_interval, summarizing);
+    }
+
+    /// Runs `summarizing`, which gives back what to cache for the diff of
+    /// `repo` with `hash`, once a prompt is free, unless it's been run for
+    /// that diff already or the repository was prompted for less than
+    /// `min_interval` ago.
+    fn spawn(
+        &mut self,
+        repo: PathBuf,
+        hash: u64,
+        min_interval: Option<Duration>,
+        summarizing: impl Future<Output = Option<(u64, String)>> + Send + 'static,
+    ) {
+        let throttled = min_interval
+            .zip(self.last_prompt.get(&repo))
+            .is_some_and(|(interval, last_prompt)| last_prompt.elapsed() < interval);
+
+        // a throttled diff is tried again on a later tick
+        if throttled || self.seen.get(&repo) == Some(&hash) {
+            return;
+        }
+
+        self.seen.insert(repo.clone(), hash);
+        self.last_prompt.insert(repo, Instant::now());
+
+        let permits = self.permits.clone();
+        let done_tx = self.done_tx.clone();
+
+        tokio::spawn(async move {
+            // the semaphore is never closed
+            let Ok(_permit) = permits.acquire_owned().await else {
+                return;
+            };
+
+            if let Some(entry) = summarizing.await {
+                let _ = done_tx.send(entry);
+            }
+        });
+    }
+
+    /// Puts the summaries that have finished in `cache`.
+    fn collect(&mut self, cache: &mut ResponseCache) {
+        while let Ok((key, text)) = self.done_rx.try_recv() {
+            cache.insert(key, text);
+        }
+    }
+}
+
+/// Summarizes the `changes` of a repository that isn't shown, filtered like
+/// the main loop's and with `repo_config`, for [Prefetch]. Returns what to
+/// cache, if the model was asked at all, which it isn't for a summary that's
+/// already in `cache`.
+async fn prefetch_summary(
+    config: Config,
+    repo_config: Config,
+    models: Arc<ModelChain>,
+    filters: Arc<Filters>,
+    mut cache: ResponseCache,
+    changes: RepoDiff,
+) -> Option<(u64, String)> {
+    let RepoDiff { path, diff, .. } = changes;
+
+    let diff = filter_diff(&config.diff, &path, strip_binary(&diff)).await;
+    let diff = filters.redactor.redact(&diff);
+
+    // the ones the main loop wouldn't ask the model about
+    if diff.trim().is_empty()
+        || config.diff.is_small(&diff)
+        || config.diff.ignore_whitespace && is_whitespace_only(&diff)
+        || !config.discord.conflict_message.is_empty() && has_conflicts(&diff)
+    {
+        return None;
+    }
+
+    let metadata = match get_metadata(&path, &repo_config.diff).await {
+        Ok(metadata) => Some(metadata.classify(&diff, &config.change_types)),
+        Err(err) => {
+            tracing::warn!("failed to get diff metadata: {err}");
+            None
+        }
+    };
+
+    let summary = write_summary(
+        &repo_config,
+        &models,
+        &filters,
+        Some(&mut cache),
+        Some(&path),
+        &diff,
+        metadata.as_ref(),
+    )
+    .await;
+
+    match summary {
+        Ok(summary) => {
+            tracing::debug!(repo = %path.display(), "summarized in the background");
+            summary.fresh
+        }
+        Err(err) => {
+            tracing::warn!(
+                repo = %path.display(),
+                "failed to summarize in the background: {err:#}"
+            );
+            None
+        }
+    }
+}
+
+/// What diffs and responses go through on their way to and from the models.
+pub struct Filters {
+    /// Scrubs secrets from everything the models are given.
+    pub redactor: Redactor,
+
+    /// Cleans up what they answer.
+    pub response: ResponseFilter,
+}
+
+/// Builds everything the config describes that goes into making a summary.
+///
+/// Models that Ollama doesn't have are left out, as long as it has one of the
+/// others.
+async fn build_pipeline(config: &Config) -> anyhow::Result<(Vec<Repo>, Arc<Filters>)> {
+    let mut repos = Vec::new();
+
+    for path in config.repos() {
+        let ignore_modified = ignore_modified(&path);
+        let mut config = config.for_repo(&path)?;
+        skip_missing_models(&mut config.agent).await;
+        let models = ModelChain::new(&config.agent)?;
+
+        let background = (config.agent.max_concurrent_prompts > 1)
+            .then(|| ModelChain::new(&config.agent).map(Arc::new))
+            .transpose()?;
+
+        repos.push(Repo {
+            path,
+            include: config.diff.include.clone(),
+            config,
+            models,
+            background,
+            ignore_modified,
+        });
+    }
+
+    let redactor = Redactor::new(&config.redact).context("failed to build redactor")?;
+
+    let response = ResponseFilter::new(&config.agent).context("failed to build response filter")?;
+
+    Ok((repos, Arc::new(Filters { redactor, response })))
+}
+
+/// Summarizes the current diff a single time and prints it to stdout, with
+/// one line for each of the [Config::facets] if there are any. `diff` is
+/// summarized instead when it's given, like one piped to
+/// `samwise summarize --stdin`, with the settings of the first repository.
+///
+/// Fails if there are no changes to summarize or the prompt fails, so that
+/// scripts and git hooks can tell when there's no summary.
+pub async fn run_once(
+    config: &Config,
+    cache: Option<ResponseCache>,
+    diff: Option<String>,
+) -> anyhow::Result<()> {
+    let (repos, filters) = build_pipeline(config).await?;
+
+    if let Some(diff) = diff {
+        if diff.trim().is_empty() {
+            anyhow::bail!("no changes to summarize");
+        }
+
+        let diff = filters.redactor.redact(&strip_binary(&diff));
+        let metadata = DiffMetadata::count(&diff).classify(&diff, &config.change_types);
+
+        return print_summaries(
+            config,
+            &repos[0],
+            &filters,
+            cache,
+            None,
+            &diff,
+            Some(&metadata),
+        )
+        .await;
+    }
+
+    let (index, repo, diff, metadata) = read_changes(config, &repos, &filters.redactor).await?;
+
+    print_summaries(
+        config,
+        &repos[index],
+        &filters,
+        cache,
+        Some(&repo),
+        &diff,
+        metadata.as_ref(),
+    )
+    .await
+}
+
+/// Reads the changes [run_once] summarizes: the first repository with any, or
+/// the latest commit with [DiffConfig::summarize_commits]. Returns the index
+/// of the repository in `repos`, its path, the redacted diff, and its
+/// metadata if it could be gathered.
+///
+/// [DiffConfig::summarize_commits]: crate::config::DiffConfig::summarize_commits
+async fn read_changes(
+    config: &Config,
+    repos: &[Repo],
+    redactor: &Redactor,
+) -> anyhow::Result<(usize, PathBuf, String, Option<DiffMetadata>)> {
+    let active = get_active_diff(&diff_configs(repos))
+        .await
+        .context("failed to get diff")?;
+
+    // right after committing, like from a hook, summarize the new commit
+    let (
+        RepoDiff {
+            path: repo,
+            index,
+            diff,
+        },
+        commit,
+    ) = match active {
+        Some(active) => (active, false),
+        None if config.diff.summarize_commits => {
+            let path = repos[0].path.clone();
+
+            let diff = get_commit(&path, &repos[0].config.diff)
+                .await
+                .context("failed to get the latest commit")?;
+
+            (
+                RepoDiff {
+                    path,
+                    index: 0,
+                    diff,
+                },
+                true,
+            )
+        }
+        None => anyhow::bail!("no changes to summarize"),
+    };
+
+    let repo_config = &repos[index].config;
+    let diff = filter_diff(&config.diff, &repo, strip_binary(&diff)).await;
+    let diff = redactor.redact(&diff);
+
+    if diff.trim().is_empty() {
+        anyhow::bail!("diff.filter left nothing to summarize");
+    }
+
+    if config.diff.ignore_whitespace && is_whitespace_only(&diff) {
+        anyhow::bail!("only whitespace has changed");
+    }
+
+    let metadata = if commit {
+        get_commit_metadata(&repo, &repo_config.diff).await
+    } else {
+        get_metadata(&repo, &repo_config.diff).await
+    };
+
+    let metadata = match metadata {
+        Ok(metadata) => Some(metadata.classify(&diff, &config.change_types)),
+        Err(err) => {
+            tracing::warn!("failed to get diff metadata: {err}");
+            None
+        }
+    };
+
+    Ok((index, repo, diff, metadata))
+}
+
+/// Pipes `diff` through [DiffConfig::filter] in `repo`, for its output to be
+/// summarized instead. Without a filter, or if it fails or takes longer than
+/// [DiffConfig::filter_timeout], the diff is returned as it was.
+async fn filter_diff(config: &DiffConfig, repo: &Path, diff: String) -> String {
+    use tokio::io::AsyncWriteExt;
+
+    let Some(filter) = &config.filter else {
+        return diff;
+    };
+
+    let mut process = shell_command(filter);
+
+    process
+        .current_dir(repo)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = match process.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            tracing::warn!("failed to run diff.filter, using the diff as it is: {err}");
+            return diff;
+        }
+    };
+
+    // written alongside reading the output, so that a filter that writes
+    // as it reads can't fill up its stdout and stall
+    let mut stdin = child.stdin.take();
+    let input = diff.clone();
+
+    let filtering = async move {
+        let write = async {
+            if let Some(stdin) = &mut stdin {
+                // a filter that doesn't read all of the diff isn't wrong
+                let _ = stdin.write_all(input.as_bytes()).await;
+            }
+
+            drop(stdin);
+        };
+
+        let (_, output) = tokio::join!(write, child.wait_with_output());
+        output
+    };
+
+    let output = match tokio::time::timeout(config.filter_timeout, filtering).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(err)) => {
+            tracing::warn!("failed to run diff.filter, using the diff as it is: {err}");
+            return diff;
+        }
+        Err(_) => {
+            tracing::warn!(
+                "diff.filter didn't finish within {}, using the diff as it is",
+                humantime::format_duration(config.filter_timeout)
+            );
+            return diff;
+        }
+    };
+
+    if !output.status.success() {
+        tracing::warn!(
+            "diff.filter exited with {}, using the diff as it is: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+
+        return diff;
+    }
+
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Drafts a commit message for the changes staged in the first repository
+/// and writes it into the commit message file at `path`, for git's
+/// `prepare-commit-msg` hook. A message that's already been written there is
+/// left alone, and so is the file when nothing is staged.
+///
+/// The staged diff goes through the same filtering, redaction, and capping as
+/// a summary's, so only the preamble and prompt differ.
+pub async fn write_commit_msg(
+    config: &Config,
+    mut cache: Option<ResponseCache>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let existing = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    if !hook::is_empty_message(&existing) {
+        tracing::info!("leaving the commit message that's already there");
+        return Ok(());
+    }
+
+    let (repos, filters) = build_pipeline(config).await?;
+    let repo = &repos[0];
+
+    if repo.config.diff.vcs != Vcs::Git {
+        anyhow::bail!("commit messages can only be drafted in git repositories");
+    }
+
+    // everything about the diff is read from what's staged, stats included
+    let config = &Config {
+        diff: DiffConfig {
+            mode: DiffMode::Staged,
+            ..repo.config.diff.clone()
+        },
+        ..repo.config.clone()
+    };
+
+    let diff = get_diff(&repo.path, &config.diff)
+        .await
+        .context("failed to get the staged diff")?;
+
+    if diff.trim().is_empty() {
+        tracing::info!("nothing is staged to draft a commit message for");
+        return Ok(());
+    }
+
+    let diff = filter_diff(&config.diff, &repo.path, strip_binary(&diff)).await;
+    let diff = filters.redactor.redact(&diff);
+
+    if diff.trim().is_empty() {
+        anyhow::bail!("diff.filter left nothing to draft a commit message for");
+    }
+
+    let metadata = get_metadata(&repo.path, &config.diff)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::warn!("failed to get diff metadata: {err}");
+            DiffMetadata::default()
+        })
+        .classify(&diff, &config.change_types);
+
+    let vars = prompt_vars(&metadata);
+
+    let request = DiffPrompt {
+        preamble: render_template(&config.agent.commit_preamble, &vars)
+            .context("failed to fill in agent.commit_preamble")?,
+        prompt: render_template(&config.agent.commit_prompt, &vars)
+            .context("failed to fill in agent.commit_prompt")?,
+        diff: &diff,
+        metadata: Some(&metadata),
+    };
+
+    let response = prompt_diff(
+        config,
+        &repo.models,
+        &filters.redactor,
+        cache.as_mut(),
+        Some(&repo.path),
+        &request,
+    )
+    .await?;
+
+    let message = filters.response.strip(&response.text);
+    let message = message.trim();
+
+    if message.is_empty() {
+        anyhow::bail!("the model gave an empty commit message");
+    }
+
+    if let Some(cache) = cache.as_mut().filter(|_| !response.cached) {
+        cache.insert(response.key, response.text.clone());
+    }
+
+    hook::prepend_message(path, &existing, message)
+}
+
+/// Prints [run_once]'s summaries of `diff`, one for each facet.
+async fn print_summaries(
+    config: &Config,
+    repo: &Repo,
+    filters: &Filters,
+    mut cache: Option<ResponseCache>,
+    path: Option<&Path>,
+    diff: &str,
+    metadata: Option<&DiffMetadata>,
+) -> anyhow::Result<()> {
+    let facets: Vec<_> = config
+        .facets
+        .iter()
+        .map(|facet| repo.config.with_facet(facet))
+        .collect();
+
+    let configs = if facets.is_empty() {
+        std::slice::from_ref(&repo.config)
+    } else {
+        &facets
+    };
+
+    for config in configs {
+        let summary = summarize(
+            config,
+            &repo.models,
+            filters,
+            cache.as_mut(),
+            path,
+            diff,
+            metadata,
+        )
+        .await?
+        .context("the model gave an empty summary")?;
+
+        println!("{}", summary.text);
+    }
+
+    Ok(())
+}
+
+/// The shortest time between two partial summaries on Discord, which only
+/// takes a few updates every 20 seconds.
+const PARTIAL_SUMMARY_INTERVAL: Duration = Duration::from_secs(4);
+
+/// Waits for `summarizing`, showing the summary `models` have streamed so far
+/// in place of `state`'s, with [AgentConfig::stream], or the
+/// [DiscordConfig::loading_message] while `agent`'s model is loaded. Does
+/// nothing more than wait otherwise. Also returns whether anything was shown
+/// in the meantime.
+///
+/// [AgentConfig::stream]: crate::config::AgentConfig::stream
+/// [DiscordConfig::loading_message]: crate::config::DiscordConfig::loading_message
+async fn show_partial_summaries<T>(
+    summarizing: impl Future<Output = T>,
+    models: &ModelChain,
+    output: &mut Output,
+    config: &Config,
+    agent: &AgentConfig,
+    filter: &ResponseFilter,
+    mut state: PresenceState,
+) -> (T, bool) {
+    // nothing's been streamed until it's first polled
+    let mut partial = models.subscribe_partial();
+    tokio::pin!(summarizing);
+
+    // only Ollama says whether the model is still being loaded
+    let loading_message = config
+        .discord
+        .loading_message
+        .as_ref()
+        .filter(|_| matches!(agent.provider, Provider::Ollama));
+
+    let loading = tokio::time::sleep(config.discord.loading_after);
+    tokio::pin!(loading);
+
+    let mut waiting_on_load = loading_message.is_some();
+    let mut shown = false;
+
+    loop {
+        tokio::select! {
+            result = &mut summarizing => return (result, shown),
+            _ = &mut loading, if waiting_on_load => {
+                waiting_on_load = false;
+
+                let loaded = tokio::select! {
+                    result = &mut summarizing => return (result, shown),
+                    loaded = loaded_models(agent) => loaded,
+                };
+
+                // a model that's in memory is just slow to answer
+                let model = agent.primary_model();
+
+                if let Ok(loaded) = &loaded
+                    && is_pulled(loaded, model)
+                {
+                    continue;
+                }
+
+                tracing::info!("{model} is taking a while to answer, it's probably being loaded");
+
+                let loading_state = PresenceState {
+                    summary: loading_message.cloned().unwrap_or_default(),
+                    verb: None,
+                    ..state.clone()
+                };
+
+                output.update_partial(&loading_state, config).await;
+                shown = true;
+                continue;
+            }
+            changed = partial.changed() => {
+                if changed.is_err() {
+                    return (summarizing.await, shown);
+                }
+            }
+        }
+
+        // the model's answering, so it's been loaded
+        waiting_on_load = false;
+
+        let summary = filter.filter(&partial.borrow_and_update());
+
+        if summary.is_empty() {
+            continue;
+        }
+
+        // the ellipsis says there's more to come, from whichever model is
+        // answering after the others failed
+        state.summary = format!("{summary}…");
+        state.model = models.answering();
+        truncate_summary(
+            &mut state.summary,
+            config.discord.summary_room(state.metadata.as_ref()),
+            config.discord.truncate,
+        );
+
+        tracing::debug!(summary = %state.summary, "showing the summary so far");
+        output.update_partial(&state, config).await;
+        shown = true;
+
+        tokio::select! {
+            result = &mut summarizing => return (result, shown),
+            _ = tokio::time::sleep(PARTIAL_SUMMARY_INTERVAL) => {}
+        }
+    }
+}
+
+/// Summarizes the current changes once, like [run_once], and prints exactly
+/// what each field of the Discord activity would be set to, along with which
+/// model answered and how long it took. Never connects to Discord.
+pub async fn run_test(config: &Config, mut cache: Option<ResponseCache>) -> anyhow::Result<()> {
+    let (repos, filters) = build_pipeline(config).await?;
+    let (index, path, diff, metadata) = read_changes(config, &repos, &filters.redactor).await?;
+    let repo = &repos[index];
+    let verbs = Verbs::new(&config.discord)?;
+
+    println!("repository: {}", path.display());
+    println!("diff: {} bytes", diff.len());
+
+    let facets: Vec<_> = config
+        .facets
+        .iter()
+        .map(|facet| repo.config.with_facet(facet))
+        .collect();
+
+    let configs = if facets.is_empty() {
+        std::slice::from_ref(&repo.config)
+    } else {
+        &facets
+    };
+
+    let conflicted = !config.discord.conflict_message.is_empty() && has_conflicts(&diff);
+
+    let repo_url = if config.discord.link_repo {
+        get_remote_url(&path)
+            .await
+            .and_then(|url| browser_url(&url))
+    } else {
+        None
+    };
+
+    for (facet, facet_config) in configs.iter().enumerate() {
+        println!();
+
+        if configs.len() > 1 {
+            println!("facet {} of {}", facet + 1, configs.len());
+        }
+
+        let start = Instant::now();
+
+        let summary = if conflicted {
+            Some(Summarized {
+                text: config.discord.conflict_message.clone(),
+                model: repo.config.agent.primary_model().to_string(),
+            })
+        } else {
+            summarize(
+                facet_config,
+                &repo.models,
+                &filters,
+                cache.as_mut(),
+                Some(&path),
+                &diff,
+                metadata.as_ref(),
+            )
+            .await?
+        };
+
+        match repo.models.take_last_prompt() {
+            Some((model, elapsed)) => println!("model: {model}, in {elapsed:.2?}"),
+            None if conflicted => println!("model: none, the changes have merge conflicts"),
+            None => println!("model: none, cached in {:.2?}", start.elapsed()),
+        }
+
+        let Some(Summarized {
+            text: mut summary,
+            model,
+        }) = summary
+        else {
+            println!("the model gave an empty summary, so the previous one would stay up");
+            continue;
+        };
+
+        truncate_summary(
+            &mut summary,
+            config.discord.summary_room(metadata.as_ref()),
+            config.discord.truncate,
+        );
+
+        let verb = metadata
+            .as_ref()
+            .filter(|_| !conflicted)
+            .and_then(|metadata| verbs.pick(metadata, &diff));
+
+        let metadata = metadata.clone().map(|mut metadata| {
+            if !config.discord.show_branch {
+                metadata.branch.clear();
+            }
+
+            metadata
+        });
+
+        let state = PresenceState {
+            summary,
+            metadata,
+            session_start: Some(unix_now()),
+            model,
+            repo_url: repo_url.clone(),
+            verb,
+        };
+
+        print_activity(&build_activity(&state, &config.discord));
+    }
+
+    Ok(())
+}
+
+/// Runs the pipeline over the current changes again and again, for
+/// `duration` or until interrupted, and prints how long each stage took:
+/// reading the diff, prompting the model, and everything else, like the
+/// context command and filtering. The cache is skipped and the summaries go
+/// nowhere, so it's only ever measuring.
+pub async fn run_bench(config: &Config, duration: Option<Duration>) -> anyhow::Result<()> {
+    let (repos, filters) = build_pipeline(config).await?;
+
+    let stop = async {
+        match duration {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::pin!(stop);
+
+    let mut stages: [(&str, Vec<Duration>); 3] = [
+        ("git", Vec::new()),
+        ("prompt", Vec::new()),
+        ("other", Vec::new()),
+    ];
+
+    for iteration in 1.. {
+        let run = async {
+            let started = Instant::now();
+            let (index, path, diff, metadata) =
+                read_changes(config, &repos, &filters.redactor).await?;
+            let git = started.elapsed();
+
+            let repo = &repos[index];
+            let started = Instant::now();
+
+            summarize(
+                &repo.config,
+                &repo.models,
+                &filters,
+                None,
+                Some(&path),
+                &diff,
+                metadata.as_ref(),
+            )
+            .await?;
+
+            let total = started.elapsed();
+
+            // an empty response can still have taken the model a while
+            let (model, prompt) = repo.models.take_last_prompt().unwrap_or_default();
+
+            anyhow::Ok((git, prompt, total.saturating_sub(prompt), model))
+        };
+
+        let (git, prompt, other, model) = tokio::select! {
+            result = run => result?,
+            _ = &mut stop => break,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+
+        println!("{iteration}: git {git:.2?}, prompt {prompt:.2?} ({model}), other {other:.2?}");
+
+        for ((_, times), time) in stages.iter_mut().zip([git, prompt, other]) {
+            times.push(time);
+        }
+    }
+
+    if stages[0].1.is_empty() {
+        anyhow::bail!("stopped before the first run finished");
+    }
+
+    println!();
+    println!("{} runs:", stages[0].1.len());
+
+    for (stage, times) in &mut stages {
+        times.sort();
+
+        let mean = times.iter().sum::<Duration>() / times.len() as u32;
+        let median = times[times.len() / 2];
+
+        println!(
+            "{stage}: mean {mean:.2?}, median {median:.2?}, min {:.2?}, max {:.2?}",
+            times[0],
+            times[times.len() - 1]
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints each field of `activity` that's set, for [run_test].
+fn print_activity(activity: &Activity) {
+    let fields = [("details", &activity.details), ("state", &activity.state)];
+
+    for (name, value) in fields {
+        if let Some(value) = value {
+            println!("{name}: {value:?} ({} chars)", value.chars().count());
+        }
+    }
+
+    if let Some(assets) = &activity.assets {
+        let fields = [
+            ("large image", &assets.large_image),
+            ("large text", &assets.large_text),
+            ("small image", &assets.small_image),
+            ("small text", &assets.small_text),
+        ];
+
+        for (name, value) in fields {
+            if let Some(value) = value {
+                println!("{name}: {value:?}");
+            }
+        }
+    }
+
+    if let Some((current, max)) = activity.party.as_ref().and_then(|party| party.size) {
+        println!("party: {current} of {max}");
+    }
+
+    if activity.timestamps.is_some() {
+        println!("timer: elapsed since the session started");
+    }
+
+    for button in &activity.buttons {
+        let label = button.label.as_deref().unwrap_or_default();
+        let url = button.url.as_deref().unwrap_or_default();
+        println!("button: {label:?} -> {url}");
+    }
+}
+
+/// Prompts the models for a summary of `diff`, capping it to the configured
+/// size and falling back to the next model when one fails or times out.
+/// Summaries are reused from and saved to `cache` when it's given. `repo` is
+/// where the diff came from, if it came from a repository, for a stat of a
+/// diff that has to be capped.
+///
+/// An empty response is replaced with [AgentConfig::empty_message], which is
+/// `None` when the previous summary should stay up.
+///
+/// [AgentConfig::empty_message]: crate::config::AgentConfig::empty_message
+pub async fn summarize(
+    config: &Config,
+    models: &ModelChain,
+    filters: &Filters,
+    mut cache: Option<&mut ResponseCache>,
+    repo: Option<&Path>,
+    diff: &str,
+    metadata: Option<&DiffMetadata>,
+) -> anyhow::Result<Option<Summarized>> {
+    let summary = write_summary(
+        config,
+        models,
+        filters,
+        cache.as_deref_mut(),
+        repo,
+        diff,
+        metadata,
+    )
+    .await?;
+
+    if let (Some(cache), Some((key, text))) = (cache, summary.fresh) {
+        cache.insert(key, text);
+    }
+
+    let model = summary.model;
+    Ok(summary.text.map(|text| Summarized { text, model }))
+}
+
+/// A summary from [summarize].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summarized {
+    pub text: String,
+
+    /// The model that wrote it. The cache doesn't say which one did, so
+    /// cached summaries are put down to the primary model.
+    pub model: String,
+}
+
+/// A summary from [write_summary].
+struct Summary {
+    /// What's shown, or `None` to keep the previous summary up.
+    text: Option<String>,
+
+    /// The model that answered, like [Summarized::model].
+    model: String,
+
+    /// The model's response and its cache key, when it didn't come from the
+    /// cache, for the caller to cache.
+    fresh: Option<(u64, String)>,
+}
+
+/// [summarize], but leaving the new response for the caller to cache, so
+/// that it can be written without the cache at hand.
+async fn write_summary(
+    config: &Config,
+    models: &ModelChain,
+    filters: &Filters,
+    cache: Option<&mut ResponseCache>,
+    repo: Option<&Path>,
+    diff: &str,
+    metadata: Option<&DiffMetadata>,
+) -> anyhow::Result<Summary> {
+    let vars = prompt_vars(&metadata.cloned().unwrap_or_default());
+
+    let preamble = render_template(&config.agent.preamble, &vars)
+        .context("failed to fill in agent.preamble")?;
+
+    let mut prompt =
+        render_template(&config.agent.prompt, &vars).context("failed to fill in agent.prompt")?;
+
+    if let Some(language) = &config.language {
+        prompt = format!("{prompt}\n\n{}", language::instruction(language));
+    }
+
+    let request = DiffPrompt {
+        preamble,
+        prompt,
+        diff,
+        metadata,
+    };
+
+    let response = prompt_diff(config, models, &filters.redactor, cache, repo, &request).await?;
+
+    let mut summary = filters.response.filter(&response.text);
+
+    let model = response
+        .model
+        .unwrap_or_else(|| config.agent.primary_model().to_string());
+
+    if response.cached {
+        return Ok(Summary {
+            text: Some(summary),
+            model,
+            fresh: None,
+        });
+    }
+
+    if summary.is_empty() {
+        tracing::debug!("the model gave an empty summary");
+
+        return Ok(Summary {
+            text: config.agent.empty_message.clone(),
+            model,
+            fresh: None,
+        });
+    }
+
+    // the repository prefix takes up part of the summary's line
+    let room = match metadata.filter(|_| config.discord.repo_prefix) {
+        Some(metadata) if !metadata.repo.is_empty() => config
+            .discord
+            .summary_room(Some(metadata))
+            .saturating_sub(metadata.repo.chars().count() + 2),
+        _ => config.discord.summary_room(metadata),
+    };
+
+    let text = match config.agent.overflow {
+        Overflow::Recompress if summary.chars().count() > room => {
+            let shorter = recompress(
+                config,
+                models,
+                &filters.response,
+                &request.preamble,
+                &summary,
+                room,
+            )
+            .await;
+
+            match shorter {
+                Some(shorter) => {
+                    summary = filters.response.filter(&shorter);
+                    shorter
+                }
+                None => response.text,
+            }
+        }
+        _ => response.text,
+    };
+
+    Ok(Summary {
+        text: Some(summary),
+        model,
+        fresh: Some((response.key, text)),
+    })
+}
+
+/// What to ask the models about a diff, with the preamble and prompt filled
+/// in.
+struct DiffPrompt<'a> {
+    preamble: String,
+    prompt: String,
+    diff: &'a str,
+    metadata: Option<&'a DiffMetadata>,
+}
+
+/// What the models answered about a diff, and where it goes in the cache.
+struct DiffResponse {
+    text: String,
+    key: u64,
+
+    /// The model that answered, or `None` when it came from the cache.
+    model: Option<String>,
+
+    /// Whether it came from the cache rather than the models.
+    cached: bool,
+}
+
+/// Asks the models about a diff, or finds what they answered before in
+/// `cache`, for both summaries and commit messages. The diff is given after
+/// the size of the change, its kinds of files, and the list of them, and
+/// before the redacted output of [AgentConfig::context_command], all capped
+/// to [AgentConfig::max_diff_bytes] together. A fresh response is left to the
+/// caller to cache, since a summary may still be shortened.
+///
+/// [AgentConfig::context_command]: crate::config::AgentConfig::context_command
+/// [AgentConfig::max_diff_bytes]: crate::config::AgentConfig::max_diff_bytes
+async fn prompt_diff(
+    config: &Config,
+    models: &ModelChain,
+    redactor: &Redactor,
+    cache: Option<&mut ResponseCache>,
+    repo: Option<&Path>,
+    request: &DiffPrompt<'_>,
+) -> anyhow::Result<DiffResponse> {
+    let DiffPrompt {
+        preamble,
+        prompt,
+        diff,
+        metadata,
+    } = request;
+
+    // run before looking in the cache, since a new build or test result
+    // deserves a new summary of the same diff, and redacted like the diff,
+    // since build scripts and tests print secrets too
+    let command_output = run_context_command(&config.agent, repo)
+        .await
+        .map(|output| redactor.redact(&output));
+
+    let command_output = command_output.as_deref();
+    let key = cache_key(diff, command_output, &config.agent, preamble, prompt);
+
+    if let Some(text) = cache.and_then(|cache| cache.get(key)) {
+        tracing::info!("reusing cached response");
+        metrics::CACHE_HITS.inc();
+
+        return Ok(DiffResponse {
+            text,
+            key,
+            model: None,
+            cached: true,
+        });
+    }
+
+    // the overall size of the change and what it's to help the model judge
+    // what matters
+    let header: Vec<String> = metadata
+        .map(DiffMetadata::shortstat)
+        .into_iter()
+        .chain(file_type_hint(diff, &config.agent))
+        .chain(
+            metadata
+                .filter(|metadata| config.agent.include_file_list && !metadata.file_list.is_empty())
+                .map(|metadata| format!("Changed files:\n{}", metadata.file_list)),
+        )
+        .collect();
+
+    let max_bytes = config.agent.max_diff_bytes;
+
+    // what goes with the diff counts against the cap too
+    let context = diff_context(diff, "", false, &header, command_output, usize::MAX);
+    let oversized = context.len() > max_bytes;
+
+    let stat_only = match config.agent.summary_source {
+        SummarySource::Diff => false,
+        SummarySource::Stat => true,
+        SummarySource::Auto => oversized,
+    };
+
+    let stat = match repo {
+        Some(repo) if stat_only || oversized => match get_diff_stat(repo, &config.diff).await {
+            Ok(stat) => stat,
+            Err(err) => {
+                tracing::warn!("failed to get diff stat: {err}");
+                String::new()
+            }
+        },
+        _ => String::new(),
+    };
+
+    let context = if stat_only || oversized {
+        diff_context(diff, &stat, stat_only, &header, command_output, max_bytes)
+    } else {
+        context
+    };
+
+    let started = Instant::now();
+    let retry = &config.retry;
+    let timeout = config.agent.prompt_timeout;
+
+    let response = if config.agent.stream {
+        models
+            .prompt_streaming(preamble, prompt, &context, retry, timeout)
+            .await
+    } else {
+        models
+            .prompt(preamble, prompt, &context, retry, timeout)
+            .await
+    };
+
+    let (model, text) = response.context("failed to run prompt")?;
+
+    tracing::info!(
+        model,
+        diff_bytes = diff.len(),
+        context_bytes = context.len(),
+        latency_ms = started.elapsed().as_millis() as u64,
+        "got a response from the model"
+    );
+
+    Ok(DiffResponse {
+        text,
+        key,
+        model: Some(model.to_string()),
+        cached: false,
+    })
+}
+
+/// Asks the models to shorten `summary` to at most `max_len` characters, for
+/// [Overflow::Recompress]. Returns the shorter response, or `None` to fall
+/// back to truncating if that fails or doesn't come out any shorter.
+async fn recompress(
+    config: &Config,
+    models: &ModelChain,
+    filter: &ResponseFilter,
+    preamble: &str,
+    summary: &str,
+    max_len: usize,
+) -> Option<String> {
+    let prompt = format!(
+        "Shorten this summary to under {max_len} characters, keeping what matters most. \
+         Answer with only the shortened summary."
+    );
+
+    let response = models
+        .prompt(
+            preamble,
+            &prompt,
+            summary,
+            &config.retry,
+            config.agent.prompt_timeout,
+        )
+        .await;
+
+    let (model, response) = match response {
+        Ok(response) => response,
+        Err(err) => {
+            tracing::warn!("failed to shorten the summary, truncating it instead: {err:#}");
+            return None;
+        }
+    };
+
+    let len = filter.filter(&response).chars().count();
+
+    if len == 0 || len >= summary.chars().count() {
+        tracing::warn!("the model didn't shorten the summary, truncating it instead");
+        return None;
+    }
+
+    tracing::debug!(model, len, max_len, "shortened the summary");
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::{agent::tests::MockSummarizer, config::tests::default_config};
+
+    fn filters(config: &Config) -> Filters {
+        Filters {
+            redactor: Redactor::new(&config.redact).unwrap(),
+            response: ResponseFilter::new(&config.agent).unwrap(),
+        }
+    }
+
+    const DIFF: &str = "diff --git a/src/main.rs b/src/main.rs
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1 +1 @@
+-fn main() {}
++fn main() { run(); }
+";
+
+    #[tokio::test]
+    async fn reuses_cached_summaries() {
+        let config = default_config();
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = ResponseCache::load_from(dir.path().join("responses.toml"), &config.cache);
+
+        let mock = MockSummarizer::answering("Fixing main");
+        let calls = mock.calls();
+        let models = mock.chain("model");
+        let filters = filters(&config);
+
+        for _ in 0..3 {
+            let summary = summarize(
+                &config,
+                &models,
+                &filters,
+                Some(&mut cache),
+                None,
+                DIFF,
+                None,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                summary.map(|summary| summary.text).as_deref(),
+                Some("Fixing main")
+            );
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // and it's still there after a restart
+        let mut cache = ResponseCache::load_from(dir.path().join("responses.toml"), &config.cache);
+        summarize(
+            &config,
+            &models,
+            &filters,
+            Some(&mut cache),
+            None,
+            DIFF,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // but a different diff isn't
+        summarize(
+            &config,
+            &models,
+            &filters,
+            Some(&mut cache),
+            None,
+            "+other\n",
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn summaries_name_the_model_that_answered() {
+        let config = default_config();
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = cache(&dir);
+        let filters = filters(&config);
+
+        let models = ModelChain::with_summarizers(vec![
+            (
+                "primary".into(),
+                Box::new(MockSummarizer::new([Err(crate::agent::tests::fatal(
+                    "gone",
+                ))])),
+            ),
+            (
+                "fallback".into(),
+                Box::new(MockSummarizer::answering("Fixing main")),
+            ),
+        ]);
+
+        let summary = summarize(
+            &config,
+            &models,
+            &filters,
+            Some(&mut cache),
+            None,
+            DIFF,
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(summary.model, "fallback");
+        assert_eq!(models.answering(), "fallback");
+
+        // the cache doesn't say who wrote it
+        let summary = summarize(
+            &config,
+            &models,
+            &filters,
+            Some(&mut cache),
+            None,
+            DIFF,
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(summary.text, "Fixing main");
+        assert_eq!(summary.model, config.agent.primary_model());
+    }
+
+    #[tokio::test]
+    async fn empty_responses_arent_shown() {
+        let mut config = default_config();
+        let filters = filters(&config);
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = ResponseCache::load_from(dir.path().join("responses.toml"), &config.cache);
+
+        for response in ["", "  \n\t ", "<think>nothing to say</think>", "```\n```"] {
+            let models = MockSummarizer::answering(response).chain("model");
+
+            config.agent.empty_message = None;
+            let summary = summarize(
+                &config,
+                &models,
+                &filters,
+                Some(&mut cache),
+                None,
+                DIFF,
+                None,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(summary, None, "summarizing as {response:?}");
+
+            config.agent.empty_message = Some("Working on changes…".into());
+            let summary = summarize(
+                &config,
+                &models,
+                &filters,
+                Some(&mut cache),
+                None,
+                DIFF,
+                None,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                summary.map(|summary| summary.text).as_deref(),
+                Some("Working on changes…")
+            );
+        }
+
+        // and the next real summary isn't mistaken for one that's cached
+        let models = MockSummarizer::answering("Fixing main").chain("model");
+        let summary = summarize(
+            &config,
+            &models,
+            &filters,
+            Some(&mut cache),
+            None,
+            DIFF,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            summary.map(|summary| summary.text).as_deref(),
+            Some("Fixing main")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn caps_the_whole_prompt() {
+        let _path = crate::git::fake_git::PATH_LOCK.lock().await;
+
+        let mut config = default_config();
+        config.agent.max_diff_bytes = 400;
+        config.agent.context_command = Some("echo 'test result: ok. 60 passed'".into());
+
+        let diff = DIFF.repeat(20);
+        let metadata = DiffMetadata::count(&diff).classify(&diff, &config.change_types);
+
+        let mock = MockSummarizer::answering("Fixing main");
+        let diffs = mock.diffs();
+        let models = mock.chain("model");
+
+        summarize(
+            &config,
+            &models,
+            &filters(&config),
+            None,
+            None,
+            &diff,
+            Some(&metadata),
+        )
+        .await
+        .unwrap();
+
+        let prompt = &diffs.lock().unwrap()[0];
+        assert!(prompt.len() <= config.agent.max_diff_bytes);
+        assert!(prompt.starts_with("20 files changed"));
+        assert!(prompt.contains("NOTE: this diff was truncated"));
+        assert!(prompt.trim_end().ends_with("test result: ok. 60 passed"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn redacts_the_context_commands_output() {
+        let _path = crate::git::fake_git::PATH_LOCK.lock().await;
+
+        let mut config = default_config();
+        config.agent.context_command = Some("echo 'export API_KEY=hunter22'".into());
+
+        let mock = MockSummarizer::answering("Fixing main");
+        let diffs = mock.diffs();
+        let models = mock.chain("model");
+
+        let summary = summarize(&config, &models, &filters(&config), None, None, DIFF, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            summary.map(|summary| summary.text).as_deref(),
+            Some("Fixing main")
+        );
+
+        let diffs = diffs.lock().unwrap();
+        assert!(!diffs[0].contains("hunter22"));
+        assert!(diffs[0].contains("export API_KEY=[REDACTED]"));
+    }
+
+    fn prefetch(max_concurrent_prompts: usize, cache: &ResponseCache) -> Prefetch {
+        let mut config = default_config();
+        config.agent.max_concurrent_prompts = max_concurrent_prompts;
+        Prefetch::new(&config.agent, Some(cache))
+    }
+
+    /// A background summary that's done after `delay`, cached as `key`.
+    async fn summarizing(delay: Duration, key: u64, text: &str) -> Option<(u64, String)> {
+        tokio::time::sleep(delay).await;
+        Some((key, text.to_string()))
+    }
+
+    fn cache(dir: &tempfile::TempDir) -> ResponseCache {
+        ResponseCache::load_from(dir.path().join("responses.toml"), &default_config().cache)
+    }
+
+    #[tokio::test]
+    async fn prefetch_doesnt_wait_for_slow_repositories() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = cache(&dir);
+        let mut prefetch = prefetch(3, &cache);
+
+        let slow = summarizing(Duration::from_secs(60), 1, "slow");
+        prefetch.spawn("slow".into(), 1, None, slow);
+        prefetch.spawn(
+            "fast".into(),
+            2,
+            None,
+            summarizing(Duration::ZERO, 2, "fast"),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        prefetch.collect(&mut cache);
+
+        assert_eq!(cache.get(2).as_deref(), Some("fast"));
+        assert_eq!(cache.get(1), None);
+    }
+
+    #[tokio::test]
+    async fn prefetch_leaves_a_prompt_for_the_shown_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = cache(&dir);
+
+        // one for the shown repository, so the background ones take turns
+        let mut prefetch = prefetch(2, &cache);
+
+        let first = summarizing(Duration::from_millis(200), 1, "first");
+        prefetch.spawn("first".into(), 1, None, first);
+        prefetch.spawn(
+            "second".into(),
+            2,
+            None,
+            summarizing(Duration::ZERO, 2, "second"),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        prefetch.collect(&mut cache);
+        assert_eq!(cache.get(2), None);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        prefetch.collect(&mut cache);
+        assert_eq!(cache.get(1).as_deref(), Some("first"));
+        assert_eq!(cache.get(2).as_deref(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn prefetch_summarizes_each_diff_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = cache(&dir);
+        let mut prefetch = prefetch(2, &cache);
+
+        prefetch.spawn(
+            "repo".into(),
+            1,
+            None,
+            summarizing(Duration::ZERO, 1, "first"),
+        );
+        prefetch.spawn(
+            "repo".into(),
+            1,
+            None,
+            summarizing(Duration::ZERO, 2, "again"),
+        );
+        prefetch.spawn(
+            "repo".into(),
+            3,
+            None,
+            summarizing(Duration::ZERO, 3, "changed"),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        prefetch.collect(&mut cache);
+
+        assert_eq!(cache.get(1).as_deref(), Some("first"));
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(3).as_deref(), Some("changed"));
+    }
+
+    #[test]
+    fn prefetch_needs_a_cache() {
+        let mut config = default_config();
+        config.agent.max_concurrent_prompts = 3;
+
+        assert!(!Prefetch::new(&config.agent, None).enabled);
+    }
+
+    #[tokio::test]
+    async fn prefetch_waits_out_the_prompt_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = cache(&dir);
+        let mut prefetch = prefetch(2, &cache);
+        let interval = Some(Duration::from_secs(60));
+
+        prefetch.spawn(
+            "repo".into(),
+            1,
+            interval,
+            summarizing(Duration::ZERO, 1, "first"),
+        );
+        prefetch.spawn(
+            "repo".into(),
+            2,
+            interval,
+            summarizing(Duration::ZERO, 2, "soon"),
+        );
+        prefetch.spawn(
+            "other".into(),
+            3,
+            interval,
+            summarizing(Duration::ZERO, 3, "other"),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        prefetch.collect(&mut cache);
+
+        assert_eq!(cache.get(1).as_deref(), Some("first"));
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(3).as_deref(), Some("other"));
+
+        // throttled, not summarized, so it's tried again later
+        assert_eq!(prefetch.seen.get(Path::new("repo")), Some(&1));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn prefetched_summaries_are_found_once_shown() {
+        let git = crate::git::fake_git::FakeGit::new("true").await;
+        let config = default_config();
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = cache(&dir);
+
+        let changes = RepoDiff {
+            path: git.repo().to_path_buf(),
+            index: 0,
+            diff: DIFF.to_string(),
+        };
+
+        let mock = MockSummarizer::answering("Fixing main");
+        let calls = mock.calls();
+        let models = Arc::new(mock.chain("model"));
+        let filters = Arc::new(filters(&config));
+
+        let entry = prefetch_summary(
+            config.clone(),
+            config.clone(),
+            models.clone(),
+            filters.clone(),
+            cache.snapshot(),
+            changes.clone(),
+        )
+        .await;
+
+        let (key, text) = entry.unwrap();
+        cache.insert(key, text);
+
+        // and not asked about again in the background once it's cached
+        let again = prefetch_summary(
+            config.clone(),
+            config.clone(),
+            models,
+            filters.clone(),
+            cache.snapshot(),
+            changes,
+        )
+        .await;
+
+        assert_eq!(again, None);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // filtered and with its metadata like the main loop does
+        let diff = filter_diff(&config.diff, git.repo(), strip_binary(DIFF)).await;
+        let diff = filters.redactor.redact(&diff);
+        let metadata = get_metadata(git.repo(), &config.diff)
+            .await
+            .ok()
+            .map(|metadata| metadata.classify(&diff, &config.change_types));
+
+        let mock = MockSummarizer::answering("Something else");
+        let calls = mock.calls();
+        let models = mock.chain("model");
+
+        let summary = summarize(
+            &config,
+            &models,
+            &filters,
+            Some(&mut cache),
+            Some(git.repo()),
+            &diff,
+            metadata.as_ref(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            summary.map(|summary| summary.text).as_deref(),
+            Some("Fixing main")
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+}
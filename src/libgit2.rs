@@ -0,0 +1,356 @@
+//! Reading diffs in-process with libgit2 instead of running `git`.
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use git2::{
+    BranchType, Commit, Diff, DiffFormat, DiffOptions, DiffStatsFormat, ErrorCode, Repository,
+};
+
+use crate::{
+    config::{DiffAlgorithm, DiffConfig, DiffMode},
+    git::{DiffMetadata, GitError, repo_name_from_url},
+};
+
+/// The width `git diff --stat` uses when it isn't writing to a terminal.
+const STAT_WIDTH: usize = 80;
+
+pub async fn get_diff(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    let config = config.clone();
+
+    with_repo(repo, move |repo| patch(&diff(repo, &config)?)).await
+}
+
+/// Gets the `git diff --stat` summary of the same changes as [get_diff].
+pub async fn get_diff_stat(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    let config = config.clone();
+
+    with_repo(repo, move |repo| {
+        let stats = diff(repo, &config)?.stats()?;
+        let buf = stats.to_buf(DiffStatsFormat::FULL, STAT_WIDTH)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    })
+    .await
+}
+
+pub async fn get_metadata(repo: &Path, config: &DiffConfig) -> Result<DiffMetadata, GitError> {
+    let config = config.clone();
+
+    with_repo(repo, move |repo| metadata(repo, &diff(repo, &config)?)).await
+}
+
+/// Gets the hash of the commit at HEAD, or `None` before the first commit.
+pub async fn get_head(repo: &Path) -> Option<String> {
+    with_repo(repo, |repo| {
+        Ok(repo.head()?.target().map(|id| id.to_string()))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Gets the name of the checked-out branch, or the short hash of the commit
+/// when HEAD is detached.
+pub async fn get_branch(repo: &Path) -> Option<String> {
+    with_repo(repo, branch).await.ok()
+}
+
+/// Gets the upstream of the current branch, like `origin/main`.
+pub async fn get_upstream(repo: &Path) -> Option<String> {
+    with_repo(repo, |repo| {
+        let head = repo.head()?;
+
+        if !head.is_branch() {
+            return Ok(None);
+        }
+
+        let name = head.shorthand()?;
+
+        let upstream = repo.find_branch(name, BranchType::Local)?.upstream()?;
+        Ok(upstream.name()?.map(ToOwned::to_owned))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Gets the message and changes of the commit at HEAD, like `git show`.
+pub async fn get_commit(repo: &Path, config: &DiffConfig) -> Result<String, GitError> {
+    let config = config.clone();
+
+    with_repo(repo, move |repo| {
+        let commit = repo.head()?.peel_to_commit()?;
+        let message = String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        // `git show --format=%B` leaves two blank lines before the patch
+        Ok(format!(
+            "{}\n\n\n{}",
+            message.trim_end(),
+            patch(&commit_diff(repo, &config)?)?
+        ))
+    })
+    .await
+}
+
+/// Gets the messages of the last `count` commits, newest first, like
+/// `git log --format=%B`.
+pub async fn get_log(repo: &Path, count: usize) -> Result<String, GitError> {
+    with_repo(repo, move |repo| {
+        let mut walk = repo.revwalk()?;
+        walk.push_head()?;
+
+        let mut log = String::new();
+
+        for id in walk.take(count) {
+            let commit = repo.find_commit(id?)?;
+            log += String::from_utf8_lossy(commit.message_bytes()).trim_end();
+            log += "\n\n";
+        }
+
+        Ok(log)
+    })
+    .await
+}
+
+pub async fn get_commit_metadata(
+    repo: &Path,
+    config: &DiffConfig,
+) -> Result<DiffMetadata, GitError> {
+    let config = config.clone();
+
+    with_repo(repo, move |repo| {
+        metadata(repo, &commit_diff(repo, &config)?)
+    })
+    .await
+}
+
+/// Gets the root directory of the work tree containing `repo`.
+pub async fn get_toplevel(repo: &Path) -> Result<PathBuf, GitError> {
+    with_repo(repo, |repo| Ok(workdir(repo).to_path_buf())).await
+}
+
+/// Finds when the most recently modified of the changed files in `repo` was
+/// last written to.
+pub async fn get_last_modified(repo: &Path, config: &DiffConfig) -> Option<SystemTime> {
+    let config = config.clone();
+
+    with_repo(repo, move |repo| {
+        let workdir = workdir(repo);
+
+        Ok(diff(repo, &config)?
+            .deltas()
+            .filter_map(|delta| delta.new_file().path())
+            .filter_map(|path| std::fs::metadata(workdir.join(path)).ok()?.modified().ok())
+            .max())
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Opens the repository containing `repo` and runs `f` on it on tokio's
+/// blocking pool, since libgit2 does all of its I/O synchronously.
+async fn with_repo<T: Send + 'static>(
+    repo: &Path,
+    f: impl FnOnce(&Repository) -> Result<T, git2::Error> + Send + 'static,
+) -> Result<T, GitError> {
+    if !repo.is_dir() {
+        return Err(GitError::NotARepository);
+    }
+
+    let path = repo.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let repo = match Repository::discover(&path) {
+            Ok(repo) if !repo.is_bare() => repo,
+            Ok(_) => return Err(GitError::NotARepository),
+            Err(err) if err.code() == ErrorCode::NotFound => {
+                return Err(GitError::NotARepository);
+            }
+            Err(err) => return Err(GitError::Libgit2(err)),
+        };
+
+        f(&repo).map_err(GitError::Libgit2)
+    })
+    .await
+    .map_err(|err| GitError::Io(std::io::Error::other(err)))?
+}
+
+/// The root of the work tree, which every opened repository has since bare
+/// ones are rejected.
+fn workdir(repo: &Repository) -> &Path {
+    repo.workdir().unwrap_or_else(|| repo.path())
+}
+
+/// Diffs the changes selected by `config`, matching the options that the
+/// `git diff` path passes.
+fn diff<'r>(repo: &'r Repository, config: &DiffConfig) -> Result<Diff<'r>, git2::Error> {
+    let mut options = diff_options(config);
+
+    let tree = match &config.base {
+        Some(base) => Some(merge_base(repo, base)?.tree()?),
+        None => match repo.head() {
+            Ok(head) => Some(head.peel_to_tree()?),
+            // nothing has been committed yet, so diff against the empty tree
+            Err(err) if matches!(err.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound) => None,
+            Err(err) => return Err(err),
+        },
+    };
+
+    match config.mode {
+        DiffMode::Stash => stash_diff(repo, config, &mut options),
+        DiffMode::Working if config.base.is_none() => {
+            repo.diff_index_to_workdir(None, Some(&mut options))
+        }
+        DiffMode::Staged => repo.diff_tree_to_index(tree.as_ref(), None, Some(&mut options)),
+        DiffMode::Working | DiffMode::All | DiffMode::Combined => {
+            repo.diff_tree_to_workdir_with_index(tree.as_ref(), Some(&mut options))
+        }
+    }
+}
+
+/// Diffs the [DiffConfig::stash] entry against the commit it was stashed
+/// from, like `git stash show -p`. A missing entry diffs as empty, like there
+/// being no changes.
+fn stash_diff<'r>(
+    repo: &'r Repository,
+    config: &DiffConfig,
+    options: &mut DiffOptions,
+) -> Result<Diff<'r>, git2::Error> {
+    let stash = config.stash_ref();
+
+    let commit = match repo.revparse_single(&stash) {
+        Ok(stash) => stash.peel_to_commit()?,
+        Err(err) if err.code() == ErrorCode::NotFound => {
+            tracing::debug!(
+                "{} has no {stash}, so there's nothing to summarize",
+                workdir(repo).display()
+            );
+            return repo.diff_tree_to_tree(None, None, Some(options));
+        }
+        Err(err) => return Err(err),
+    };
+
+    repo.diff_tree_to_tree(
+        Some(&commit.parent(0)?.tree()?),
+        Some(&commit.tree()?),
+        Some(options),
+    )
+}
+
+/// Diffs the commit at HEAD against its first parent, like `git show`.
+fn commit_diff<'r>(repo: &'r Repository, config: &DiffConfig) -> Result<Diff<'r>, git2::Error> {
+    let commit = repo.head()?.peel_to_commit()?;
+
+    let parent = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+
+    repo.diff_tree_to_tree(
+        parent.as_ref(),
+        Some(&commit.tree()?),
+        Some(&mut diff_options(config)),
+    )
+}
+
+/// The options that match what the `git` path passes to every diff.
+fn diff_options(config: &DiffConfig) -> DiffOptions {
+    let mut options = DiffOptions::new();
+    options.minimal(config.algorithm == DiffAlgorithm::Minimal);
+    options.patience(config.algorithm == DiffAlgorithm::Patience);
+    options.ignore_whitespace(config.ignore_whitespace);
+
+    if let Some(lines) = config.context_lines {
+        options.context_lines(lines);
+    }
+
+    // libgit2 uses the first pathspec that matches, and negates ones that
+    // start with `!`, so the excludes have to come first
+    for pattern in &config.exclude {
+        options.pathspec(format!("!{pattern}"));
+    }
+
+    for pattern in &config.include {
+        options.pathspec(pattern);
+    }
+
+    if config.include.is_empty() && !config.exclude.is_empty() {
+        options.pathspec("*");
+    }
+
+    options
+}
+
+/// Finds where HEAD forked from `base`, like `git merge-base <base> HEAD`.
+fn merge_base<'r>(repo: &'r Repository, base: &str) -> Result<Commit<'r>, git2::Error> {
+    let base = repo.revparse_single(base)?.peel_to_commit()?;
+    let head = repo.head()?.peel_to_commit()?;
+    repo.find_commit(repo.merge_base(base.id(), head.id())?)
+}
+
+/// Formats a diff as the same unified diff text that `git diff` prints.
+fn patch(diff: &Diff) -> Result<String, git2::Error> {
+    let mut text = String::new();
+
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        // file and hunk headers come with their text already, but the
+        // content lines need their origin put back in front of them
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            text.push(line.origin());
+        }
+
+        text.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })?;
+
+    Ok(text)
+}
+
+/// Combines the stats of `diff` with the repository's name and branch.
+fn metadata(repo: &Repository, diff: &Diff) -> Result<DiffMetadata, git2::Error> {
+    let stats = diff.stats()?;
+
+    // named like [crate::git::get_repo_name] does
+    let remote_name = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|remote| remote.url().ok().and_then(repo_name_from_url));
+
+    let repo_name = remote_name.unwrap_or_else(|| {
+        repo.workdir()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+
+    Ok(DiffMetadata {
+        repo: repo_name,
+        branch: branch(repo)?,
+        files: stats.files_changed(),
+        insertions: stats.insertions(),
+        deletions: stats.deletions(),
+        change_type: None,
+        file_list: String::new(),
+    })
+}
+
+/// The short name of the checked-out branch, or the short hash of the commit
+/// when HEAD is detached, like [crate::git::get_branch].
+fn branch(repo: &Repository) -> Result<String, git2::Error> {
+    match repo.head() {
+        Ok(head) if head.is_branch() => Ok(head.shorthand().unwrap_or("HEAD").to_string()),
+        Ok(head) => {
+            let id = head.peel_to_commit()?.into_object().short_id()?;
+            Ok(String::from_utf8_lossy(&id).into_owned())
+        }
+        Err(err) if err.code() == ErrorCode::UnbornBranch => {
+            // the branch HEAD points to doesn't exist until the first commit
+            let head = repo.find_reference("HEAD")?;
+            let target = head.symbolic_target()?.unwrap_or("HEAD");
+            Ok(target.trim_start_matches("refs/heads/").to_string())
+        }
+        Err(err) => Err(err),
+    }
+}
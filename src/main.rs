@@ -1,158 +1,662 @@
 use std::{
-    process::{Command, Stdio},
-    sync::mpsc::{Receiver, channel},
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
     time::Duration,
 };
 
 use anyhow::Context;
-use discord_presence::models::Activity;
-use rig::{
-    client::{CompletionClient, Nothing},
-    completion::Prompt,
-    providers::ollama,
+use clap::{Parser, Subcommand};
+use samwise::{
+    cache::ResponseCache,
+    config::{
+        CONFIG_FILE_NAMES, ConfigError, ConfigFormat, ConfigWatcher, LogFormat, OutputSink,
+        init_config, load_config, read_config,
+    },
+    doctor::{check, doctor},
+    hook,
+    http::{StatusHandle, serve},
+    presence::Output,
+    run, run_bench, run_once, run_test, systemd, write_commit_msg,
 };
-use serde::Deserialize;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-#[derive(Clone, Deserialize)]
-pub struct Config {
-    #[serde(with = "humantime_serde")]
-    pub frequency: Duration,
-    pub agent: AgentConfig,
-    pub discord: DiscordConfig,
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, filter::LevelFilter, layer::SubscriberExt, reload,
+    util::SubscriberInitExt,
+};
+
+/// LLM summary of current Git diff as a Discord rich presence.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to the config file, in TOML, or YAML or JSON by its extension.
+    /// Defaults to `samwise.toml` in `$XDG_CONFIG_HOME` or the user's config
+    /// directory, or `samwise.yaml`, `samwise.yml`, or `samwise.json` there.
+    /// `~` and environment variables are expanded.
+    #[arg(long, short, global = true, env = "SAMWISE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// The `[profiles.<name>]` section of the config to use, in place of its
+    /// `default_profile`.
+    #[arg(long, short, global = true, env = "SAMWISE_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Print summaries to stdout instead of connecting to Discord.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Print a single summary of the current changes and exit. Never
+    /// connects to Discord.
+    #[arg(long)]
+    pub once: bool,
+
+    /// Check the config, the version control system, the repositories, the
+    /// models, and the Discord application ID, printing one line for each,
+    /// like `pass model llama3.2 is reachable`, and exit. Fails if any
+    /// check does. Never connects to Discord.
+    #[arg(long, conflicts_with_all = ["once", "write_commit_msg", "bench", "dry_run"])]
+    pub check: bool,
+
+    /// Draft a commit message for the staged changes of the current
+    /// repository into this file if it doesn't have one yet, and exit. This
+    /// is what the hook from `samwise hook install` runs.
+    #[arg(long, value_name = "FILE", conflicts_with = "once")]
+    pub write_commit_msg: Option<PathBuf>,
+
+    /// Summarize the current changes over and over without the cache,
+    /// sending the summaries nowhere, and print how long reading the diff,
+    /// prompting, and the rest took, for comparing models. Runs until
+    /// `--duration` is up or it's interrupted.
+    #[arg(long, conflicts_with_all = ["once", "write_commit_msg", "dry_run"])]
+    pub bench: bool,
+
+    /// Clear the presence and exit after running for this long, like `25m`,
+    /// in place of the config's `duration`.
+    #[arg(long, value_parser = humantime::parse_duration, conflicts_with = "once")]
+    pub duration: Option<Duration>,
+
+    /// Always ask the model, ignoring and not updating the response cache.
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+
+    /// Log more: `-v` for debug logs and `-vv` for trace logs. `RUST_LOG`
+    /// wins when it's set.
+    #[arg(long, short, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only log errors.
+    #[arg(long, short, global = true)]
+    pub quiet: bool,
+
+    /// Show the current summary, the Discord connection, and the log in a
+    /// live view in the terminal.
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with = "once")]
+    pub tui: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
 }
 
-#[derive(Clone, Deserialize)]
-pub struct DiscordConfig {
-    pub client: u64,
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Write a commented default config file to get started with.
+    Init {
+        /// Overwrite the config file if it already exists.
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Check that git, the model, and Discord are all set up, with hints for
+    /// fixing whatever isn't.
+    Doctor,
+
+    /// Print a summary of a repository's changes, or of a diff piped in, and
+    /// exit. Like `--once`, but for any repository.
+    Summarize {
+        /// The repository to summarize, in place of those in the config.
+        /// `~` and environment variables are expanded.
+        #[arg(long, conflicts_with = "stdin")]
+        path: Option<PathBuf>,
+
+        /// Summarize the diff read from stdin, like `git diff | samwise
+        /// summarize --stdin`.
+        #[arg(long)]
+        stdin: bool,
+    },
+
+    /// Summarize the current changes once and print exactly what would be
+    /// sent to Discord, field by field, with the model that answered and how
+    /// long it took. Never connects to Discord.
+    Test {
+        /// The repository to summarize, in place of those in the config.
+        /// `~` and environment variables are expanded.
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
+
+    /// Manage the cache of model responses.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+
+    /// Manage the git hook that drafts commit messages.
+    Hook {
+        #[command(subcommand)]
+        command: HookCommand,
+    },
+
+    /// See the built-in presets for `agent.preset`.
+    Presets {
+        #[command(subcommand)]
+        command: PresetsCommand,
+    },
+
+    /// See the config that's actually in effect.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Show `text` in place of the summary of the running samwise until
+    /// `samwise unpin`. Needs a `[control]` section in the config.
+    Pin {
+        /// What to show, like "pairing with Alex on the parser".
+        text: String,
+    },
+
+    /// Go back to summarizing after `samwise pin`.
+    Unpin,
 }
 
-#[derive(Clone, Deserialize)]
-pub struct AgentConfig {
-    pub model: String,
-    pub preamble: String,
-    pub prompt: String,
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Delete every cached response.
+    Clear,
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+#[derive(Subcommand)]
+pub enum HookCommand {
+    /// Set up a `prepare-commit-msg` hook that drafts a message whenever
+    /// `git commit` is run without one.
+    Install {
+        /// The repository to install the hook in. Defaults to the current
+        /// directory. `~` and environment variables are expanded.
+        #[arg(long)]
+        path: Option<PathBuf>,
+
+        /// Replace a `prepare-commit-msg` hook that samwise didn't write.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PresetsCommand {
+    /// Print every preset's name, preamble, and prompt.
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the whole config as TOML once the profile, the environment's
+    /// overrides, the preset, prompt files, `--duration`, and the defaults
+    /// are applied, with API keys, tokens, and headers masked.
+    Show,
+}
+
+impl Args {
+    /// The config path to use. Without `--config`, it's in the first of the
+    /// [config_dirs] with a config in it, or else the first that exists, so
+    /// that headless machines without the usual directories still work.
+    pub fn config_path(&self) -> anyhow::Result<PathBuf> {
+        if let Some(path) = &self.config {
+            return expand_path(path);
+        }
+
+        let candidates = config_dirs();
+
+        let existing = candidates.iter().find_map(|(dir, source)| {
+            CONFIG_FILE_NAMES
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.exists())
+                .map(|path| (path, source))
+        });
+
+        let (path, source) = match existing {
+            Some(existing) => existing,
+            None => candidates
+                .iter()
+                .find(|(dir, _)| dir.is_dir())
+                .map(|(dir, source)| (dir.join(CONFIG_FILE_NAMES[0]), source))
+                .context(
+                    "could not locate a config directory; point --config or SAMWISE_CONFIG at \
+                     the config instead",
+                )?,
+        };
+
+        tracing::info!("using the config at {}, from {source}", path.display());
+        Ok(path)
+    }
+
+    /// The level to log at when `RUST_LOG` isn't set. Running samwise logs
+    /// what it's doing, but commands that print a result only log errors, so
+    /// that what they print can be piped somewhere.
+    pub fn log_level(&self) -> LevelFilter {
+        match self.verbose {
+            _ if self.quiet => LevelFilter::ERROR,
+            0 if self.once
+                || self.check
+                || self.bench
+                || self.write_commit_msg.is_some()
+                || self.command.is_some() =>
+            {
+                LevelFilter::ERROR
+            }
+            0 => LevelFilter::INFO,
+            1 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
+    }
+}
 
-    let config_path = dirs::config_dir()
-        .context("could not locate config path")?
-        .join("samwise.toml");
+/// Where to look for the config without `--config`, in order, each with
+/// where it came from for the logs.
+fn config_dirs() -> Vec<(PathBuf, &'static str)> {
+    let mut candidates = Vec::new();
 
-    let config_src = std::fs::read_to_string(&config_path).context("failed to read config file")?;
+    // honored everywhere, not just where `dirs` follows it
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        candidates.push((PathBuf::from(dir), "$XDG_CONFIG_HOME"));
+    }
 
-    let config: Config = toml::from_str(&config_src).context("failed to parse config file")?;
+    candidates.extend(dirs::config_dir().map(|dir| (dir, "the config directory")));
+    candidates.extend(dirs::home_dir().map(|home| (home.join(".config"), "~/.config")));
+    candidates.extend(std::env::current_dir().map(|dir| (dir, "the current directory")));
 
-    let (presence_tx, presence_rx) = channel();
+    candidates
+}
 
-    std::thread::spawn({
-        let config = config.clone();
-        move || rpc_thread(config, presence_rx)
-    });
+/// Expands a leading `~` to the home directory and `$VAR` or `${VAR}` to the
+/// variable's value.
+fn expand_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let path = path
+        .to_str()
+        .with_context(|| format!("config path {} is not valid UTF-8", path.display()))?;
 
-    let client: ollama::Client<reqwest::Client> =
-        ollama::Client::new(Nothing).context("failed to create Ollama client")?;
+    let mut expanded = String::with_capacity(path.len());
 
-    let mut last_diff = None;
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(std::path::is_separator) => {
+            let home = dirs::home_dir().context("could not locate the home directory")?;
+            expanded.push_str(&home.to_string_lossy());
+            rest
+        }
+        _ => path,
+    };
 
-    loop {
-        let diff = get_diff().context("failed to get diff")?;
+    let mut chars = rest.chars().peekable();
 
-        if diff.is_empty() {
-            presence_tx.send(None).unwrap();
-            std::thread::sleep(config.frequency);
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
             continue;
         }
 
-        if Some(&diff) == last_diff.as_ref() {
-            std::thread::sleep(config.frequency);
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+
+        if braced && chars.next_if_eq(&'}').is_none() {
+            anyhow::bail!("config path {path}: unclosed ${{{name}");
+        }
+
+        if name.is_empty() {
+            expanded.push('$');
             continue;
         }
 
-        let agent = client
-            .agent(&config.agent.model)
-            .preamble(&config.agent.preamble)
-            .context(&diff)
-            .build();
+        let value = std::env::var(&name)
+            .with_context(|| format!("config path {path}: ${name} is not set"))?;
+
+        expanded.push_str(&value);
+    }
+
+    Ok(expanded.into())
+}
+
+/// Handles the config file at `path` not existing yet, on the first run. In
+/// a terminal, offers to write the default config there. Otherwise, or if
+/// the offer is turned down, fails with the `samwise init` command that would
+/// write it, since a service or a hook can't be asked.
+fn bootstrap_config(args: &Args, path: &Path) -> anyhow::Result<()> {
+    let mut command = "samwise init".to_string();
+
+    if args.config.is_some() {
+        command += &format!(" --config {}", path.display());
+    }
 
-        let mut response = agent
-            .prompt(&config.agent.prompt)
-            .await
-            .context("failed to run prompt")?;
+    // the hook's and `--stdin`'s input isn't for answering questions
+    let interactive = std::io::stdin().is_terminal()
+        && std::io::stderr().is_terminal()
+        && args.write_commit_msg.is_none()
+        && ConfigFormat::of(path) == ConfigFormat::Toml;
 
-        // responses need to be at most 120 characters or setting activity fails
-        response.truncate(120);
+    if interactive {
+        eprint!(
+            "There's no config file at {} yet. Create a default one there? [Y/n] ",
+            path.display()
+        );
 
-        let activity = Activity::new().details(&response);
+        std::io::stderr().flush()?;
 
-        presence_tx.send(Some(activity)).unwrap();
+        let mut answer = String::new();
 
-        std::thread::sleep(config.frequency);
+        std::io::stdin()
+            .lock()
+            .read_line(&mut answer)
+            .context("failed to read the answer")?;
 
-        last_diff = Some(diff);
+        if matches!(answer.trim().to_lowercase().as_str(), "" | "y" | "yes") {
+            return init_config(path, false);
+        }
     }
+
+    anyhow::bail!(
+        "no config file at {}; run `{command}` to create one there, or point --config or \
+         SAMWISE_CONFIG at another",
+        path.display()
+    )
 }
 
-/// The Discord RPC needs to run its own thread because it uses crossbeam on
-/// the inside. I'd love to write my own async bindings at some point but...
-/// one thing at a time.
-pub fn rpc_thread(config: Config, presence_rx: Receiver<Option<Activity>>) -> anyhow::Result<()> {
-    let mut drpc = discord_presence::Client::new(config.discord.client);
+fn main() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("failed to start the async runtime")?;
 
-    drpc.on_error(|ctx| {
-        println!("RPC error: {:?}", ctx.event);
-    })
-    .persist();
+    let result = runtime.block_on(async_main());
 
-    drpc.on_connected(|ctx| {
-        println!("RPC connected: {:?}", ctx.event);
-    })
-    .persist();
+    // calls into the Discord client run on the blocking pool and can hang
+    // forever, which dropping the runtime would wait on
+    runtime.shutdown_background();
 
-    drpc.on_disconnected(|ctx| {
-        println!("RPC disconnected: {:?}", ctx.event);
-    })
-    .persist();
+    result
+}
 
-    drpc.start();
+async fn async_main() -> anyhow::Result<()> {
+    let args = Args::parse();
 
-    println!("waiting for Discord RPC...");
+    // the format is only known once the config is loaded, which can log too
+    let (logs, log_handle) = reload::Layer::new(log_layer(LogFormat::Text));
 
-    drpc.block_until_event(discord_presence::Event::Ready)
-        .context("failed to wait for ready state")?;
+    tracing_subscriber::registry()
+        .with(logs)
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(args.log_level().into())
+                .from_env_lossy(),
+        )
+        .init();
 
-    println!("Discord RPC is ready.");
+    let config_path = args.config_path()?;
 
-    while let Ok(activity) = presence_rx.recv() {
-        match activity {
-            Some(activity) => {
-                drpc.set_activity(|_| activity)
-                    .context("failed to set Discord activity")?;
-            }
-            None => {
-                drpc.clear_activity()
-                    .context("failed to clear Discord activity")?;
+    if args.check {
+        return check(&config_path, args.profile.as_deref()).await;
+    }
+
+    match &args.command {
+        Some(Commands::Init { force }) => return init_config(&config_path, *force),
+        Some(Commands::Doctor) => return doctor(&config_path, args.profile.as_deref()).await,
+        Some(Commands::Cache {
+            command: CacheCommand::Clear,
+        }) => return ResponseCache::clear(),
+        Some(Commands::Hook {
+            command: HookCommand::Install { path, force },
+        }) => {
+            let repo = match path {
+                Some(path) => expand_path(path)?,
+                None => PathBuf::from("."),
+            };
+
+            // the hook runs from wherever the repository is
+            let config = match &args.config {
+                Some(_) => Some(std::path::absolute(&config_path)?),
+                None => None,
+            };
+
+            return hook::install(&repo, config.as_deref(), args.profile.as_deref(), *force).await;
+        }
+        Some(Commands::Presets {
+            command: PresetsCommand::List,
+        }) => {
+            samwise::preset::list();
+            return Ok(());
+        }
+        Some(Commands::Config {
+            command: ConfigCommand::Show,
+        }) => {
+            // shown even when it doesn't pass validation, to see why
+            let mut config = read_config(&config_path, args.profile.as_deref())?;
+            config.duration = args.duration.or(config.duration);
+
+            print!("{}", config.to_redacted_toml()?);
+            return Ok(());
+        }
+        Some(command @ (Commands::Pin { .. } | Commands::Unpin)) => {
+            let config = read_config(&config_path, args.profile.as_deref())?;
+
+            let command = match command {
+                Commands::Pin { text } if text.contains('\n') => {
+                    anyhow::bail!("the pinned text must be on one line")
+                }
+                Commands::Pin { text } => format!("pin {text}"),
+                _ => "unpin".to_string(),
+            };
+
+            samwise::control::send(&config, &command).await?;
+            return Ok(());
+        }
+        Some(Commands::Summarize { .. } | Commands::Test { .. }) | None => {}
+    }
+
+    let discord = !args.dry_run
+        && !args.once
+        && !args.bench
+        && args.write_commit_msg.is_none()
+        && args.command.is_none();
+
+    let mut config = match load_config(&config_path, args.profile.as_deref(), discord).await {
+        Err(ConfigError::Missing(path)) => {
+            // a fresh config has no Discord application ID to connect with yet
+            bootstrap_config(&args, &path)?;
+
+            if discord {
+                return Ok(());
             }
+
+            load_config(&path, args.profile.as_deref(), discord).await?
+        }
+        result => result?,
+    };
+
+    if config.log_format != LogFormat::Text
+        && let Err(err) = log_handle.reload(log_layer(config.log_format))
+    {
+        tracing::warn!("failed to switch the log format: {err}");
+    }
+
+    let cache = (config.cache.enabled && !args.no_cache)
+        .then(|| ResponseCache::load(&config.cache))
+        .flatten();
+
+    if let Some(Commands::Summarize { path, stdin }) = &args.command {
+        let diff = if *stdin {
+            Some(std::io::read_to_string(std::io::stdin()).context("failed to read stdin")?)
+        } else {
+            None
+        };
+
+        if let Some(path) = path {
+            config.repos = vec![expand_path(path)?];
+        }
+
+        return run_once(&config, cache, diff).await;
+    }
+
+    if let Some(Commands::Test { path }) = &args.command {
+        if let Some(path) = path {
+            config.repos = vec![expand_path(path)?];
+        }
+
+        return run_test(&config, cache).await;
+    }
+
+    if let Some(path) = &args.write_commit_msg {
+        // git runs hooks from the top of the repository being committed to
+        config.repos = vec![PathBuf::from(".")];
+        return write_commit_msg(&config, cache, path).await;
+    }
+
+    if args.once {
+        return run_once(&config, cache, None).await;
+    }
+
+    if args.bench {
+        return run_bench(&config, args.duration.or(config.duration)).await;
+    }
+
+    if !config.startup_delay.is_zero() {
+        tracing::info!("waiting {:?} before starting", config.startup_delay);
+        tokio::time::sleep(config.startup_delay).await;
+    }
+
+    #[cfg(feature = "tui")]
+    let tui = if args.tui && !samwise::tui::is_supported() {
+        tracing::warn!("not showing the TUI, since stdout isn't a terminal");
+        None
+    } else {
+        args.tui.then(samwise::tui::LogBuffer::default)
+    };
+
+    #[cfg(not(feature = "tui"))]
+    let tui: Option<std::convert::Infallible> = None;
+
+    let status = StatusHandle::default();
+
+    let mut output = match (args.dry_run, &tui) {
+        // the TUI already shows every summary
+        (true, Some(_)) => Output::none(status.clone()),
+        (true, None) => Output::stdout(status.clone()),
+        (false, _) => Output::new(&config, status.clone())?,
+    };
+
+    // with Discord, samwise is ready once the RPC is
+    if !config.sends_to(OutputSink::Discord) || args.dry_run {
+        systemd::ready();
+    }
+
+    let watcher = match ConfigWatcher::new(&config_path, args.profile.as_deref(), discord, &config)
+    {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            tracing::warn!("not watching the config file for changes: {err:#}");
+            None
+        }
+    };
+
+    let http = config.http.clone();
+    let notify_desktop = config.notify_desktop;
+    #[cfg(feature = "notifications")]
+    let notify_branch_switch = config.notify_branch_switch;
+
+    #[cfg(not(feature = "notifications"))]
+    if notify_desktop {
+        tracing::warn!("not showing desktop notifications, since samwise was built without them");
+    }
+
+    let notifications = async {
+        #[cfg(feature = "notifications")]
+        if notify_desktop {
+            return samwise::desktop::run(status.clone(), notify_branch_switch).await;
+        }
+
+        std::future::pending().await
+    };
+
+    let server = async {
+        match &http {
+            Some(http) => serve(http, status.clone()).await,
+            None => std::future::pending().await,
         }
+    };
+
+    #[cfg(feature = "tui")]
+    if let Some(logs) = &tui
+        && let Err(err) = log_handle.reload(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(logs.clone())
+                .boxed(),
+        )
+    {
+        tracing::warn!("failed to send logs to the TUI: {err}");
     }
 
-    drpc.block_on().context("failed to join Discord RPC client")
+    let tui = async {
+        match tui {
+            #[cfg(feature = "tui")]
+            Some(logs) => samwise::tui::run(status.clone(), logs).await,
+            _ => std::future::pending().await,
+        }
+    };
+
+    let duration = args.duration.or(config.duration);
+
+    let deadline = async {
+        match duration {
+            Some(duration) => {
+                tokio::time::sleep(duration).await;
+
+                tracing::info!(
+                    "ran for {}, shutting down...",
+                    humantime::format_duration(duration)
+                );
+            }
+            None => std::future::pending().await,
+        }
+    };
+
+    let result = tokio::select! {
+        result = run(config, &mut output, cache, watcher) => result,
+        result = server => result,
+        result = tui => result,
+        result = notifications => result,
+        _ = systemd::watchdog() => Ok(()),
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("shutting down...");
+            Ok(())
+        }
+        _ = deadline => Ok(()),
+    };
+
+    systemd::stopping();
+    output.shutdown(SHUTDOWN_TIMEOUT).await;
+
+    result
 }
 
-pub fn get_diff() -> anyhow::Result<String> {
-    Command::new("git")
-        .arg("diff")
-        .arg("--minimal")
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("failed to spawn git diff")?
-        .wait_with_output()
-        .context("failed to read git diff output")
-        .and_then(|io| String::from_utf8(io.stdout).context("failed to parse git diff UTF-8"))
+/// The layer that writes log lines in `format`.
+fn log_layer(format: LogFormat) -> Box<dyn Layer<Registry> + Send + Sync> {
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    }
 }
+
+/// How long to wait for the Discord RPC task to clear the presence when
+/// exiting before giving up on it.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
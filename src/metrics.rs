@@ -0,0 +1,151 @@
+//! Counters and timings, served in Prometheus' text format.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub static PROMPTS: Counter = Counter::new(
+    "samwise_prompts_total",
+    "Prompts sent to the model, counting each retry.",
+);
+
+pub static PROMPT_ERRORS: Counter = Counter::new(
+    "samwise_prompt_errors_total",
+    "Prompts that failed, counting each retry.",
+);
+
+pub static CACHE_HITS: Counter = Counter::new(
+    "samwise_cache_hits_total",
+    "Summaries reused from the response cache instead of prompting.",
+);
+
+pub static PRESENCE_UPDATES: Counter = Counter::new(
+    "samwise_presence_updates_total",
+    "New summaries shown in the presence.",
+);
+
+pub static DISCORD_RECONNECTS: Counter = Counter::new(
+    "samwise_discord_reconnects_total",
+    "Times the connection to Discord was lost and rebuilt.",
+);
+
+pub static PROMPT_DURATION: Histogram = Histogram::new(
+    "samwise_prompt_duration_seconds",
+    "How long each prompt to the model took.",
+    [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0],
+);
+
+pub static DIFF_DURATION: Histogram = Histogram::new(
+    "samwise_diff_duration_seconds",
+    "How long reading the diffs of the repositories took.",
+    [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0],
+);
+
+/// A number that only goes up.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        let _ = writeln!(out, "{} {}", self.name, self.value.load(Ordering::Relaxed));
+    }
+}
+
+/// How many upper bounds each [Histogram] has, not counting `+Inf`.
+const BUCKETS: usize = 10;
+
+/// Durations counted into buckets by their upper bounds in seconds.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: [f64; BUCKETS],
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str, bounds: [f64; BUCKETS]) -> Self {
+        Self {
+            name,
+            help,
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        // buckets are counted individually and added up when rendered
+        if let Some(index) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        let micros = duration.as_micros().try_into().unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+
+        let mut cumulative = 0;
+
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{bound}\"}} {cumulative}", self.name);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {count}", self.name);
+        let _ = writeln!(out, "{}_sum {sum}", self.name);
+        let _ = writeln!(out, "{}_count {count}", self.name);
+    }
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    for counter in [
+        &PROMPTS,
+        &PROMPT_ERRORS,
+        &CACHE_HITS,
+        &PRESENCE_UPDATES,
+        &DISCORD_RECONNECTS,
+    ] {
+        counter.render(&mut out);
+    }
+
+    for histogram in [&PROMPT_DURATION, &DIFF_DURATION] {
+        histogram.render(&mut out);
+    }
+
+    out
+}
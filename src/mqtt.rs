@@ -0,0 +1,195 @@
+//! Publishing the presence to an MQTT broker.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    config::{Config, MqttConfig},
+    presence::{PresenceSink, PresenceState, presence_json},
+};
+
+/// How many publishes can wait on the connection before more are refused.
+const REQUEST_CAPACITY: usize = 10;
+
+/// How often to ping the broker to keep the connection alive.
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// The first delay before reconnecting to the broker, doubled on each
+/// failure.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay between attempts to reconnect to the broker.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A presence to publish, with where and how.
+#[derive(Clone, PartialEq, Eq)]
+struct Message {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    payload: String,
+}
+
+/// Publishes the presence to an MQTT broker.
+///
+/// Like [crate::slack::Slack], updates go through a background task that
+/// only ever publishes the latest one. The task also keeps the connection up,
+/// reconnecting with backoff whenever the broker goes away and publishing the
+/// latest presence again once it's back.
+pub struct Mqtt {
+    message_tx: watch::Sender<Option<Message>>,
+    task: JoinHandle<()>,
+}
+
+impl Mqtt {
+    pub fn new(config: &MqttConfig) -> anyhow::Result<Self> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+        }
+
+        let (client, eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+        let (message_tx, message_rx) = watch::channel(None);
+        let task = tokio::spawn(mqtt_task(client, eventloop, message_rx));
+
+        Ok(Self { message_tx, task })
+    }
+
+    /// Publishes the presence, or that there isn't one when `presence` is
+    /// `None`. Repeats of the last message aren't published again.
+    pub fn send(
+        &self,
+        presence: Option<&PresenceState>,
+        config: &MqttConfig,
+    ) -> anyhow::Result<()> {
+        let presence = presence.map(|presence| presence.truncated(config.max_len, config.truncate));
+        let payload = presence_json(presence.as_ref()).context("failed to build MQTT message")?;
+
+        let message = Message {
+            topic: config.topic.clone(),
+            qos: rumqttc::qos(config.qos).unwrap_or(QoS::AtMostOnce),
+            retain: config.retain,
+            payload,
+        };
+
+        self.message_tx.send_if_modified(|current| {
+            if current.as_ref() == Some(&message) {
+                return false;
+            }
+
+            *current = Some(message);
+            true
+        });
+
+        Ok(())
+    }
+
+    /// Publishes that there's no presence and disconnects from the broker,
+    /// giving up after `timeout`.
+    pub async fn shutdown(self, timeout: Duration) {
+        self.message_tx.send_modify(|message| {
+            if let Some(message) = message {
+                message.payload = presence_json(None).unwrap_or_default();
+            }
+        });
+
+        // closing the channel tells the task to disconnect once it's caught up
+        drop(self.message_tx);
+
+        if tokio::time::timeout(timeout, self.task).await.is_err() {
+            tracing::warn!("timed out disconnecting from the MQTT broker");
+        }
+    }
+}
+
+#[async_trait]
+impl PresenceSink for Mqtt {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        match &config.mqtt {
+            Some(mqtt_config) => self.send(presence, mqtt_config),
+            None => Ok(()),
+        }
+    }
+
+    async fn shutdown(self: Box<Self>, timeout: Duration) {
+        Mqtt::shutdown(*self, timeout).await;
+    }
+}
+
+/// Drives the connection to the broker and publishes each new message until
+/// the channel closes. Messages that come in while disconnected wait for the
+/// connection to come back, and only the latest is published then.
+async fn mqtt_task(
+    client: AsyncClient,
+    mut eventloop: EventLoop,
+    mut message_rx: watch::Receiver<Option<Message>>,
+) {
+    let mut connected = false;
+    let mut delay = RECONNECT_BASE_DELAY;
+
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    tracing::info!("connected to the MQTT broker");
+                    connected = true;
+                    delay = RECONNECT_BASE_DELAY;
+
+                    // the broker may have missed updates while disconnected
+                    message_rx.mark_changed();
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::warn!("lost the MQTT broker ({err}), reconnecting in {delay:?}");
+                    connected = false;
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                }
+            },
+            changed = message_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+
+                // reconnecting marks the latest one to be published then
+                let Some(message) = message_rx.borrow_and_update().clone().filter(|_| connected)
+                else {
+                    continue;
+                };
+
+                if let Err(err) =
+                    client.try_publish(message.topic, message.qos, message.retain, message.payload)
+                {
+                    tracing::warn!("failed to publish to MQTT: {err}");
+                }
+            }
+        }
+    }
+
+    if client.try_disconnect().is_err() {
+        return;
+    }
+
+    // keep the connection going until the last message and the disconnect
+    // have gone out
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
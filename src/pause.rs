@@ -0,0 +1,70 @@
+//! Pausing and resuming updates with `SIGUSR1` and `SIGUSR2`, or the
+//! [control socket](crate::control).
+
+/// Whether updates are paused, following the signals sent to samwise. Outside
+/// of unix there are no such signals, so it's never paused.
+pub struct Pause {
+    paused: bool,
+
+    #[cfg(unix)]
+    signals: Option<(tokio::signal::unix::Signal, tokio::signal::unix::Signal)>,
+}
+
+impl Pause {
+    /// Starts listening for the signals, logging a warning if they can't be.
+    pub fn listen() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            let signals = signal(SignalKind::user_defined1())
+                .and_then(|pause| Ok((pause, signal(SignalKind::user_defined2())?)));
+
+            let signals = match signals {
+                Ok(signals) => Some(signals),
+                Err(err) => {
+                    tracing::warn!("pausing with SIGUSR1 and SIGUSR2 is unavailable: {err}");
+                    None
+                }
+            };
+
+            Self {
+                paused: false,
+                signals,
+            }
+        }
+
+        #[cfg(not(unix))]
+        Self { paused: false }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pauses or resumes updates, as asked over the control socket.
+    pub fn set(&mut self, paused: bool) {
+        if paused != self.paused {
+            tracing::info!("{} updates", if paused { "pausing" } else { "resuming" });
+        }
+
+        self.paused = paused;
+    }
+
+    /// Waits for the next signal that pauses or resumes updates, returning
+    /// whether they're now paused.
+    pub async fn changed(&mut self) -> bool {
+        #[cfg(unix)]
+        if let Some((pause, resume)) = &mut self.signals {
+            let paused = tokio::select! {
+                _ = pause.recv() => true,
+                _ = resume.recv() => false,
+            };
+
+            self.set(paused);
+            return paused;
+        }
+
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,1055 @@
+//! Turning summaries into Discord rich presence activities and friends.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use discord_presence::models::{self, Activity};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    config::{
+        ActivityType, Config, DiscordConfig, FileConfig, OnExit, OutputSink, SummaryField,
+        Truncation, VerbRule,
+    },
+    git::{DiffMetadata, changed_paths},
+    hook_command::HookCommand,
+    http::StatusHandle,
+    metrics,
+    mqtt::Mqtt,
+    rpc::Rpc,
+    slack::Slack,
+    systemd,
+    template::render_template,
+    webhook::Webhook,
+};
+
+/// Discord rejects activities whose text fields exceed this many characters.
+pub const MAX_FIELD_LEN: usize = 128;
+
+/// How many times the file output tries to write an update before giving up
+/// on it.
+const FILE_WRITE_ATTEMPTS: usize = 3;
+
+/// How long the file output waits before trying a failed write again.
+const FILE_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Everything that goes into a presence update.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PresenceState {
+    /// The (truncated) summary of the current changes.
+    pub summary: String,
+
+    /// Metadata about the changes, if it could be gathered.
+    #[serde(flatten)]
+    pub metadata: Option<DiffMetadata>,
+
+    /// When the current coding session started, in Unix seconds.
+    pub session_start: Option<u64>,
+
+    /// The name of the model that wrote the summary.
+    pub model: String,
+
+    /// A browser URL for the repository, if it's known and wanted.
+    pub repo_url: Option<String>,
+
+    /// What's being done, like "Debugging", from [DiscordConfig::verbs].
+    #[serde(default)]
+    pub verb: Option<String>,
+}
+
+impl PresenceState {
+    /// This presence with its summary cut down to `max_len` characters, for
+    /// a sink with a limit of its own. Without one, it's left whole.
+    pub fn truncated(&self, max_len: Option<usize>, truncation: Truncation) -> PresenceState {
+        let mut presence = self.clone();
+
+        if let Some(max_len) = max_len {
+            truncate_summary(&mut presence.summary, max_len, truncation);
+        }
+
+        presence
+    }
+}
+
+/// Discord shows at most this many buttons on an activity.
+pub const MAX_BUTTONS: usize = 2;
+
+/// Discord rejects button labels longer than this many characters.
+pub const MAX_BUTTON_LABEL_LEN: usize = 32;
+
+/// The largest party size Discord accepts, since it reads them as an `i32`.
+const MAX_PARTY_SIZE: u32 = i32::MAX as u32;
+
+/// Discord's own version of [DiscordConfig::activity_type].
+fn activity_type(config: &DiscordConfig) -> models::ActivityType {
+    match config.activity_type {
+        ActivityType::Playing => models::ActivityType::Playing,
+        ActivityType::Listening => models::ActivityType::Listening,
+        ActivityType::Watching => models::ActivityType::Watching,
+        ActivityType::Competing => models::ActivityType::Competing,
+    }
+}
+
+/// The placeholders available in [DiscordConfig::state] and
+/// [DiscordConfig::details_template], which also has `{summary}`.
+pub fn line_vars(metadata: &DiffMetadata) -> [(&'static str, String); 6] {
+    [
+        ("repo", metadata.repo.clone()),
+        ("branch", metadata.branch.clone()),
+        ("files", metadata.files.to_string()),
+        ("insertions", metadata.insertions.to_string()),
+        ("deletions", metadata.deletions.to_string()),
+        (
+            "change_type",
+            metadata
+                .change_type
+                .map_or("", |change_type| change_type.name())
+                .to_string(),
+        ),
+    ]
+}
+
+/// Builds the Discord activity for a presence.
+pub fn build_activity(presence: &PresenceState, config: &DiscordConfig) -> Activity {
+    let repo = presence
+        .metadata
+        .as_ref()
+        .filter(|metadata| config.repo_prefix && !metadata.repo.is_empty())
+        .map(|metadata| metadata.repo.as_str());
+
+    // like "Debugging samwise: fixing the diff parser"
+    let prefix: Vec<&str> = presence.verb.as_deref().into_iter().chain(repo).collect();
+
+    let mut summary = if prefix.is_empty() {
+        presence.summary.clone()
+    } else {
+        format!("{}: {}", prefix.join(" "), presence.summary)
+    };
+
+    let metadata = presence.metadata.as_ref();
+    truncate_summary(&mut summary, config.summary_room(metadata), config.truncate);
+
+    // the decoration can fill the line by itself, like with a long branch
+    let (before, after) = config.summary_decoration(metadata);
+    let mut summary = format!("{before}{summary}{after}");
+    truncate_chars(&mut summary, config.max_summary_len());
+
+    let activity = Activity::new().activity_type(activity_type(config));
+
+    let mut activity = match config.summary_field {
+        SummaryField::Details => activity.details(summary),
+        SummaryField::State => activity.state(summary),
+    };
+
+    if let Some(start) = presence.session_start.filter(|_| config.timer) {
+        activity = activity.timestamps(|ts| ts.start(start));
+    }
+
+    let assets = &config.assets;
+
+    let small_text = assets
+        .small_text
+        .clone()
+        .or_else(|| assets.model_as_small_text.then(|| presence.model.clone()));
+
+    if assets.large_image.is_some()
+        || assets.large_text.is_some()
+        || assets.small_image.is_some()
+        || small_text.is_some()
+    {
+        activity = activity.assets(|mut a| {
+            a.large_image = assets.large_image.clone();
+            a.large_text = assets.large_text.clone();
+            a.small_image = assets.small_image.clone();
+            a.small_text = small_text;
+            a
+        });
+    }
+
+    let repo_button = presence
+        .repo_url
+        .as_ref()
+        .map(|url| ("View Repository", url.as_str()));
+
+    let buttons = repo_button
+        .into_iter()
+        .chain(
+            config
+                .buttons
+                .iter()
+                .map(|b| (b.label.as_str(), b.url.as_str())),
+        )
+        .take(MAX_BUTTONS);
+
+    for (label, url) in buttons {
+        let mut label = label.to_string();
+        truncate_chars(&mut label, MAX_BUTTON_LABEL_LEN);
+        activity = activity.append_buttons(|button| button.label(label).url(url));
+    }
+
+    if let Some(metadata) = &presence.metadata {
+        let mut line = render_template(config.state_template(), &line_vars(metadata))
+            .unwrap_or_else(|err| {
+                tracing::warn!("failed to fill in discord.state: {err:#}");
+                String::new()
+            });
+
+        // the template goes on whichever line the summary isn't on
+        match config.summary_field {
+            SummaryField::Details => {
+                truncate_chars(&mut line, config.max_state_len);
+
+                if !line.is_empty() {
+                    activity = activity.state(line);
+                }
+            }
+            SummaryField::State => {
+                truncate_chars(&mut line, config.max_details_len);
+
+                if !line.is_empty() {
+                    activity = activity.details(line);
+                }
+            }
+        }
+
+        if config.party_diff_size
+            && let Some(size) = party_size(metadata.insertions, metadata.deletions)
+        {
+            activity = activity.party(|party| party.size(size));
+        }
+    }
+
+    activity
+}
+
+/// Picks the verb for the changes from [DiscordConfig::verbs].
+pub struct Verbs {
+    rules: Vec<(VerbRule, Option<Regex>)>,
+    default: Option<String>,
+}
+
+impl Verbs {
+    pub fn new(config: &DiscordConfig) -> anyhow::Result<Self> {
+        let rules = config
+            .verbs
+            .iter()
+            .map(|rule| {
+                let branch = rule
+                    .branch
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .with_context(|| format!("invalid branch pattern for {:?}", rule.verb))?;
+
+                Ok((rule.clone(), branch))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            rules,
+            default: config.default_verb.clone(),
+        })
+    }
+
+    /// The verb of the first rule that `diff` and its `metadata` meet, or the
+    /// default one.
+    pub fn pick(&self, metadata: &DiffMetadata, diff: &str) -> Option<String> {
+        let touches_tests = || changed_paths(diff).into_iter().any(is_test_path);
+
+        self.rules
+            .iter()
+            .find(|(rule, branch)| {
+                branch
+                    .as_ref()
+                    .is_none_or(|branch| branch.is_match(&metadata.branch))
+                    && rule.min_files.is_none_or(|min| metadata.files >= min)
+                    && rule.max_files.is_none_or(|max| metadata.files <= max)
+                    && rule.tests.is_none_or(|tests| tests == touches_tests())
+                    && rule
+                        .change_type
+                        .is_none_or(|change_type| metadata.change_type == Some(change_type))
+            })
+            .map(|(rule, _)| rule.verb.clone())
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// Whether a changed file looks like a test, from being in a `tests`-like
+/// directory or having a name like `parser_test.go`, `test_parser.py`, or
+/// `parser.spec.ts`.
+pub fn is_test_path(path: &str) -> bool {
+    let mut components = path.rsplit('/');
+    let name = components.next().unwrap_or(path).to_lowercase();
+
+    let in_test_dir = components.any(|dir| {
+        matches!(
+            dir.to_lowercase().as_str(),
+            "test" | "tests" | "spec" | "specs" | "__tests__" | "testing"
+        )
+    });
+
+    let stem = name.split('.').next().unwrap_or(&name);
+
+    in_test_dir
+        || matches!(stem, "test" | "tests" | "spec")
+        || stem.starts_with("test_")
+        || stem.ends_with("_test")
+        || stem.ends_with("_tests")
+        || stem.ends_with("_spec")
+        || name.contains(".test.")
+        || name.contains(".spec.")
+}
+
+/// Discord's party size for a change, as insertions of the lines changed,
+/// or `None` for an empty one. Discord wants a current size of at least one.
+fn party_size(insertions: usize, deletions: usize) -> Option<(u32, u32)> {
+    let changed = insertions.saturating_add(deletions);
+
+    if changed == 0 {
+        return None;
+    }
+
+    let max = u32::try_from(changed)
+        .unwrap_or(MAX_PARTY_SIZE)
+        .min(MAX_PARTY_SIZE);
+
+    let current = u32::try_from(insertions)
+        .unwrap_or(MAX_PARTY_SIZE)
+        .clamp(1, max);
+
+    Some((current, max))
+}
+
+/// Builds the activity shown when there's no presence, per
+/// [DiscordConfig::idle], or `None` to clear it instead.
+pub fn build_idle_activity(config: &DiscordConfig) -> Option<Activity> {
+    let idle = &config.idle;
+
+    if !idle.enabled {
+        return None;
+    }
+
+    let mut details = idle.details.clone();
+    truncate_chars(&mut details, config.max_details_len);
+
+    let mut activity = Activity::new()
+        .activity_type(activity_type(config))
+        .details(details);
+
+    if let Some(state) = &idle.state {
+        let mut state = state.clone();
+        truncate_chars(&mut state, config.max_state_len);
+        activity = activity.state(state);
+    }
+
+    let large_image = idle
+        .large_image
+        .clone()
+        .or_else(|| config.assets.large_image.clone());
+
+    let large_text = idle
+        .large_text
+        .clone()
+        .or_else(|| config.assets.large_text.clone());
+
+    if large_image.is_some() || large_text.is_some() || config.assets.small_image.is_some() {
+        activity = activity.assets(|mut a| {
+            a.large_image = large_image;
+            a.large_text = large_text;
+            a.small_image = config.assets.small_image.clone();
+            a.small_text = config.assets.small_text.clone();
+            a
+        });
+    }
+
+    Some(activity)
+}
+
+/// The current time in seconds since the Unix epoch, as Discord expects.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn session_file() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("samwise").join("session"))
+}
+
+/// Loads the start of a session saved by a previous run, if any.
+pub fn load_session_start() -> Option<u64> {
+    std::fs::read_to_string(session_file()?)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Saves or removes the persisted session start. Failures are only logged
+/// since the timer is purely cosmetic.
+pub fn save_session_start(start: Option<u64>) {
+    let Some(path) = session_file() else {
+        return;
+    };
+
+    let result = match start {
+        Some(start) => path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, start.to_string())),
+        None => std::fs::remove_file(&path).or_else(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(err),
+        }),
+    };
+
+    if let Err(err) = result {
+        tracing::warn!("failed to save session start to {}: {err}", path.display());
+    }
+}
+
+/// The last presence shown, saved so that it can be put back up right away
+/// after a restart.
+#[derive(Serialize, Deserialize)]
+pub struct SavedPresence {
+    /// The [presence_fingerprint] of the config it was made with.
+    pub fingerprint: u64,
+
+    /// The repository the summarized diff was in.
+    pub repo: PathBuf,
+
+    /// The [crate::git::diff_hash] of the summarized diff, so that it isn't
+    /// summarized again if it hasn't changed since.
+    pub diff_hash: u64,
+
+    pub presence: PresenceState,
+}
+
+/// Identifies everything in the config that would make a saved presence
+/// stale: the repositories and what the model is asked.
+pub fn presence_fingerprint(config: &Config) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    // the default repository is the current directory, wherever that is
+    for repo in config.repos() {
+        std::fs::canonicalize(&repo)
+            .unwrap_or(repo)
+            .hash(&mut hasher);
+    }
+
+    config.agent.provider.hash(&mut hasher);
+    config.agent.base_url.hash(&mut hasher);
+    config.agent.model.hash(&mut hasher);
+    config.agent.preamble.hash(&mut hasher);
+    config.agent.prompt.hash(&mut hasher);
+
+    for facet in &config.facets {
+        facet.prompt.hash(&mut hasher);
+        facet.preamble.hash(&mut hasher);
+    }
+
+    hasher.finish()
+}
+
+fn presence_state_file() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("samwise").join("last_presence.json"))
+}
+
+/// Loads the presence saved by a previous run, unless it was made with a
+/// different config.
+pub fn load_presence(config: &Config) -> Option<SavedPresence> {
+    let json = std::fs::read_to_string(presence_state_file()?).ok()?;
+    let saved: SavedPresence = serde_json::from_str(&json).ok()?;
+    (saved.fingerprint == presence_fingerprint(config)).then_some(saved)
+}
+
+/// Saves or removes the last presence. Failures are only logged, like
+/// [save_session_start].
+pub fn save_presence(saved: Option<&SavedPresence>) {
+    let Some(path) = presence_state_file() else {
+        return;
+    };
+
+    let result = match saved {
+        Some(saved) => serde_json::to_string(saved)
+            .map_err(std::io::Error::other)
+            .and_then(|json| {
+                path.parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| std::fs::write(&path, json))
+            }),
+        None => std::fs::remove_file(&path).or_else(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(err),
+        }),
+    };
+
+    if let Err(err) = result {
+        tracing::warn!("failed to save presence to {}: {err}", path.display());
+    }
+}
+
+/// Where presence updates go.
+pub struct Output {
+    sinks: Vec<Box<dyn PresenceSink>>,
+    status: StatusHandle,
+}
+
+/// One of the places presence updates go, like Discord or a file. Each sink
+/// is updated on its own and at the same time as the others, so one that
+/// fails or is slow doesn't hold up the rest.
+#[async_trait]
+pub trait PresenceSink: Send {
+    /// What the sink is called in logs, like `"discord"`.
+    fn name(&self) -> &'static str;
+
+    /// Shows a new presence, or clears it when `presence` is `None`.
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        config: &Config,
+    ) -> anyhow::Result<()>;
+
+    /// Shows a summary that's still being written, for [AgentConfig::stream].
+    /// Sinks that only want the finished one ignore it.
+    ///
+    /// [AgentConfig::stream]: crate::config::AgentConfig::stream
+    async fn update_partial(
+        &mut self,
+        _presence: &PresenceState,
+        _config: &Config,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Recovers from any background failures.
+    async fn check(&mut self) {}
+
+    /// Clears the presence before exiting, giving up after `timeout`.
+    async fn shutdown(self: Box<Self>, _timeout: Duration) {}
+}
+
+impl Output {
+    /// Creates the sinks that [Config::sinks] lists, in its order. Every
+    /// update is also recorded in `status`.
+    pub fn new(config: &Config, status: StatusHandle) -> anyhow::Result<Self> {
+        let mut sinks: Vec<Box<dyn PresenceSink>> = Vec::new();
+
+        for sink in config.sinks() {
+            match sink {
+                OutputSink::Discord => {
+                    sinks.push(Box::new(Rpc::spawn(config.clone(), status.clone())));
+                }
+                OutputSink::File => {
+                    let path = config
+                        .file
+                        .path()
+                        .context("could not locate the presence file")?;
+
+                    sinks.push(Box::new(FileSink {
+                        path,
+                        config: config.file.clone(),
+                    }));
+                }
+                OutputSink::Webhook => sinks.push(Box::new(Webhook::new()?)),
+                OutputSink::Slack => {
+                    let slack = config
+                        .slack
+                        .as_ref()
+                        .context("sinks: slack needs a [slack] section")?;
+
+                    sinks.push(Box::new(Slack::new(slack)?));
+                }
+                OutputSink::Mqtt => {
+                    let mqtt = config
+                        .mqtt
+                        .as_ref()
+                        .context("sinks: mqtt needs an [mqtt] section")?;
+
+                    sinks.push(Box::new(Mqtt::new(mqtt)?));
+                }
+                OutputSink::Hook => sinks.push(Box::new(HookCommand::default())),
+            }
+        }
+
+        Ok(Self { sinks, status })
+    }
+
+    /// Prints summaries to stdout instead of anywhere else.
+    pub fn stdout(status: StatusHandle) -> Self {
+        Self {
+            sinks: vec![Box::new(StdoutSink { showing: false })],
+            status,
+        }
+    }
+
+    /// Sends updates nowhere but `status`, for when they're watched there
+    /// instead, like in the TUI.
+    pub fn none(status: StatusHandle) -> Self {
+        Self {
+            sinks: Vec::new(),
+            status,
+        }
+    }
+
+    /// Where updates and errors are recorded for the status server.
+    pub fn status(&self) -> &StatusHandle {
+        &self.status
+    }
+
+    /// Shows a new presence, or clears it when `presence` is `None`.
+    pub async fn update(&mut self, presence: Option<&PresenceState>, config: &Config) {
+        self.status.set_presence(presence);
+
+        match presence {
+            Some(presence) => {
+                metrics::PRESENCE_UPDATES.inc();
+                systemd::status(&presence.summary);
+            }
+            None => systemd::status("no changes"),
+        }
+
+        futures::future::join_all(self.sinks.iter_mut().map(|sink| async move {
+            if let Err(err) = sink.update(presence, config).await {
+                tracing::warn!("failed to update the {} sink: {err:#}", sink.name());
+            }
+        }))
+        .await;
+    }
+
+    /// Shows a summary that's still being written, for
+    /// [AgentConfig::stream]. It isn't recorded, since the finished one soon
+    /// replaces it.
+    ///
+    /// [AgentConfig::stream]: crate::config::AgentConfig::stream
+    pub async fn update_partial(&mut self, presence: &PresenceState, config: &Config) {
+        futures::future::join_all(self.sinks.iter_mut().map(|sink| async move {
+            if let Err(err) = sink.update_partial(presence, config).await {
+                tracing::warn!("failed to update the {} sink: {err:#}", sink.name());
+            }
+        }))
+        .await;
+    }
+
+    /// Recovers from any background failures.
+    pub async fn check(&mut self) {
+        futures::future::join_all(self.sinks.iter_mut().map(|sink| sink.check())).await;
+    }
+
+    /// Clears the presence before exiting.
+    pub async fn shutdown(self, timeout: Duration) {
+        futures::future::join_all(self.sinks.into_iter().map(|sink| sink.shutdown(timeout))).await;
+    }
+}
+
+/// Writes the presence as JSON to a file.
+struct FileSink {
+    path: PathBuf,
+
+    /// The settings as of the last update, for shutting down with.
+    config: FileConfig,
+}
+
+impl FileSink {
+    /// Writes the presence, or [FileConfig::idle] when there isn't one, and
+    /// tries again a couple of times if that fails, like when the disk is
+    /// briefly full.
+    async fn write(&self, presence: Option<&PresenceState>) -> anyhow::Result<()> {
+        let json = match (presence, &self.config.idle) {
+            (None, Some(idle)) => {
+                serde_json::to_string(idle).context("failed to serialize file.idle")?
+            }
+            _ => {
+                let presence = presence
+                    .map(|presence| presence.truncated(self.config.max_len, self.config.truncate));
+                presence_json(presence.as_ref())?
+            }
+        };
+
+        let mut attempt = 1;
+
+        loop {
+            match write_file_atomically(&self.path, &json) {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < FILE_WRITE_ATTEMPTS => {
+                    tracing::debug!("failed to write presence, trying again: {err:#}");
+                    tokio::time::sleep(FILE_RETRY_DELAY).await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    return Err(err.context(format!("gave up after {attempt} attempts")));
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PresenceSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        self.config = config.file.clone();
+        self.write(presence).await
+    }
+
+    async fn shutdown(self: Box<Self>, _timeout: Duration) {
+        if self.config.on_exit == OnExit::Clear
+            && let Err(err) = self.write(None).await
+        {
+            tracing::warn!("failed to update the file sink: {err:#}");
+        }
+    }
+}
+
+/// Prints summaries to stdout, for trying out prompts without Discord.
+struct StdoutSink {
+    /// Whether a summary is currently shown, so that clears aren't printed
+    /// over and over.
+    showing: bool,
+}
+
+#[async_trait]
+impl PresenceSink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        _config: &Config,
+    ) -> anyhow::Result<()> {
+        let now = humantime::format_rfc3339_seconds(SystemTime::now());
+
+        match presence {
+            Some(presence) => println!("[{now}] {}", presence.summary),
+            None if self.showing => println!("[{now}] (no changes)"),
+            None => {}
+        }
+
+        self.showing = presence.is_some();
+        Ok(())
+    }
+}
+
+/// The JSON written by the file output.
+#[derive(Serialize)]
+struct PresenceFile<'a> {
+    /// Whether there's a presence at all. Everything else is left out when
+    /// there isn't.
+    active: bool,
+
+    #[serde(flatten)]
+    presence: Option<&'a PresenceState>,
+}
+
+/// The presence as the JSON written by the file output, which other outputs
+/// send too.
+pub fn presence_json(presence: Option<&PresenceState>) -> anyhow::Result<String> {
+    let file = PresenceFile {
+        active: presence.is_some(),
+        presence,
+    };
+
+    serde_json::to_string(&file).context("failed to serialize presence")
+}
+
+/// Writes `json` to `path`, replacing the file in one go so that status bars
+/// reading it never see half an update.
+fn write_file_atomically(path: &Path, json: &str) -> anyhow::Result<()> {
+    let write = || -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("failed to create directory")?;
+        }
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        std::fs::write(&temp, format!("{json}\n")).context("failed to write temporary file")?;
+        std::fs::rename(&temp, path).context("failed to replace file")?;
+
+        Ok(())
+    };
+
+    write().with_context(|| format!("failed to write presence to {}", path.display()))
+}
+
+/// How alike two summaries are, from 0 for nothing in common to 1 for the
+/// same words, ignoring case, spacing, and punctuation. It's one minus the
+/// edit distance between them as a fraction of the longer one's length.
+pub fn summary_similarity(a: &str, b: &str) -> f64 {
+    let normalize = |s: &str| -> Vec<char> {
+        s.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .flat_map(|word| word.chars().chain([' ']))
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+
+    let (a, b) = (normalize(a), normalize(b));
+    let longest = a.len().max(b.len());
+
+    if longest == 0 {
+        return 1.0;
+    }
+
+    // Levenshtein distance, keeping only the previous row
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    1.0 - row[b.len()] as f64 / longest as f64
+}
+
+/// Truncates a string to at most `max_chars` characters (not bytes), always
+/// cutting between graphemes so that emoji and accented letters aren't split
+/// apart. If anything was cut off, the last kept grapheme is replaced with an
+/// ellipsis so the result stays within budget, unless there's no room even
+/// for that.
+pub fn truncate_chars(s: &mut String, max_chars: usize) {
+    if s.chars().nth(max_chars).is_none() {
+        return;
+    }
+
+    if max_chars == 0 {
+        s.clear();
+        return;
+    }
+
+    s.truncate(grapheme_end(s, max_chars - 1));
+    s.push('…');
+}
+
+/// The byte length of the longest run of whole graphemes at the start of `s`
+/// that's at most `max_chars` characters long.
+fn grapheme_end(s: &str, max_chars: usize) -> usize {
+    let mut chars = 0;
+    let mut end = 0;
+
+    for (idx, grapheme) in s.grapheme_indices(true) {
+        chars += grapheme.chars().count();
+
+        if chars > max_chars {
+            break;
+        }
+
+        end = idx + grapheme.len();
+    }
+
+    end
+}
+
+/// Cuts a summary down to at most `max_len` characters the way `truncation`
+/// says to.
+pub fn truncate_summary(s: &mut String, max_len: usize, truncation: Truncation) {
+    if s.chars().nth(max_len).is_none() {
+        return;
+    }
+
+    match truncation {
+        Truncation::Ellipsis => truncate_chars(s, max_len),
+        Truncation::Cut => s.truncate(grapheme_end(s, max_len)),
+        Truncation::Word => {
+            // leave room for the ellipsis, and fall back to cutting mid-word
+            // when even the first word doesn't fit
+            let end = grapheme_end(s, max_len.saturating_sub(1));
+
+            let space = if s[end..].starts_with(char::is_whitespace) {
+                Some(end)
+            } else {
+                s[..end].rfind(char::is_whitespace)
+            };
+
+            match space {
+                Some(space) if !s[..space].trim_end().is_empty() => {
+                    s.truncate(space);
+                    s.truncate(s.trim_end().len());
+                    s.push('…');
+                }
+                _ => truncate_chars(s, max_len),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn truncated(s: &str, max_chars: usize) -> String {
+        let mut s = s.to_string();
+        truncate_chars(&mut s, max_chars);
+        s
+    }
+
+    #[test]
+    fn truncate_chars_leaves_short_strings() {
+        assert_eq!(truncated("fix parser", 10), "fix parser");
+        assert_eq!(truncated("", 0), "");
+    }
+
+    #[test]
+    fn truncate_chars_to_zero_is_empty() {
+        assert_eq!(truncated("fix parser", 0), "");
+    }
+
+    #[test]
+    fn truncate_chars_ends_with_ellipsis() {
+        assert_eq!(truncated("fix parser", 4), "fix…");
+        assert_eq!(truncated("fix parser", 1), "…");
+    }
+
+    #[test]
+    fn truncate_chars_keeps_emoji_whole() {
+        // 🎉 is one character, but a flag is two regional indicators
+        assert_eq!(truncated("ab🎉cd", 4), "ab🎉…");
+        assert_eq!(truncated("ab🇳🇱cd", 4), "ab…");
+        assert_eq!(truncated("ab🇳🇱cd", 5), "ab🇳🇱…");
+    }
+
+    #[test]
+    fn truncate_chars_keeps_combining_marks() {
+        // "e" followed by a combining acute accent
+        let s = "cafe\u{301} au lait";
+        assert_eq!(truncated(s, 5), "caf…");
+        assert_eq!(truncated(s, 6), "cafe\u{301}…");
+    }
+
+    #[test]
+    fn truncate_chars_keeps_zwj_sequences() {
+        // a family emoji is three people joined by two zero-width joiners
+        let family = "👩\u{200d}👩\u{200d}👧";
+        let s = format!("hi {family} there");
+
+        assert_eq!(truncated(&s, 6), "hi …");
+        assert_eq!(truncated(&s, 7), "hi …");
+        assert_eq!(truncated(&s, 9), format!("hi {family}…"));
+    }
+
+    #[test]
+    fn truncate_chars_stays_within_budget() {
+        let s = "👍🏽 cafe\u{301} 👩\u{200d}💻 done";
+
+        for max_chars in 0..s.chars().count() + 2 {
+            assert!(truncated(s, max_chars).chars().count() <= max_chars);
+        }
+    }
+
+    #[test]
+    fn truncate_summary_cut_keeps_graphemes_whole() {
+        let mut s = "ab🇳🇱cd".to_string();
+        truncate_summary(&mut s, 3, Truncation::Cut);
+        assert_eq!(s, "ab");
+    }
+
+    #[test]
+    fn truncate_summary_word_cuts_between_words() {
+        let mut s = "fixing the parser".to_string();
+        truncate_summary(&mut s, 12, Truncation::Word);
+        assert_eq!(s, "fixing the…");
+    }
+
+    fn presence(summary: &str, metadata: Option<DiffMetadata>) -> PresenceState {
+        PresenceState {
+            summary: summary.to_string(),
+            metadata,
+            session_start: None,
+            model: "llama3.2".to_string(),
+            repo_url: None,
+            verb: None,
+        }
+    }
+
+    #[test]
+    fn build_activity_caps_a_long_decoration() {
+        let mut config = crate::config::tests::default_config().discord;
+        config.details_template = "{branch}: {summary}".to_string();
+
+        let metadata = DiffMetadata {
+            branch: "b".repeat(200),
+            ..Default::default()
+        };
+
+        let activity = build_activity(&presence("fixing the parser", Some(metadata)), &config);
+        let details = activity.details.unwrap();
+        assert_eq!(details.chars().count(), config.max_details_len);
+        assert!(details.ends_with('…'));
+    }
+
+    #[test]
+    fn each_sink_gets_its_own_length() {
+        let summary = "rewriting the diff parser to handle renames";
+        let presence = presence(summary, None);
+
+        let sinks = [
+            (Some(20), Truncation::Ellipsis, "rewriting the diff …"),
+            (Some(20), Truncation::Word, "rewriting the diff…"),
+            (Some(20), Truncation::Cut, "rewriting the diff p"),
+            (None, Truncation::Ellipsis, summary),
+        ];
+
+        for (max_len, truncation, expected) in sinks {
+            assert_eq!(presence.truncated(max_len, truncation).summary, expected);
+        }
+
+        // the presence itself keeps the whole summary for the next sink
+        assert_eq!(presence.summary, summary);
+    }
+
+    #[test]
+    fn discord_gets_its_own_length() {
+        let mut config = crate::config::tests::default_config().discord;
+        config.max_details_len = 12;
+
+        let activity = build_activity(&presence("fixing the parser", None), &config);
+        assert_eq!(activity.details.as_deref(), Some("fixing the …"));
+    }
+
+    #[test]
+    fn summaries_like_the_idle_message_are_idle_text() {
+        let mut config = crate::config::tests::default_config().discord;
+        config.idle_message = Some("Taking a break".to_string());
+
+        assert!(config.is_idle_text("Taking a break"));
+        assert!(config.is_idle_text("  taking a BREAK\n"));
+        assert!(!config.is_idle_text("Taking a break from the parser"));
+        assert!(!config.is_idle_text(""));
+    }
+
+    #[test]
+    fn summaries_like_the_idle_activity_are_idle_text_only_when_enabled() {
+        let mut config = crate::config::tests::default_config().discord;
+        config.idle_message = None;
+        config.idle.details = "Away from the keyboard".to_string();
+
+        config.idle.enabled = false;
+        assert!(!config.is_idle_text("Away from the keyboard"));
+
+        config.idle.enabled = true;
+        assert!(config.is_idle_text("away from the keyboard"));
+    }
+}
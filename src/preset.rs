@@ -0,0 +1,63 @@
+//! Built-in preambles and prompts to start from.
+
+/// A named preamble and prompt, picked with [AgentConfig::preset].
+///
+/// [AgentConfig::preset]: crate::config::AgentConfig::preset
+pub struct Preset {
+    pub name: &'static str,
+
+    /// What the summaries sound like, for `samwise presets list`.
+    pub description: &'static str,
+
+    pub preamble: &'static str,
+    pub prompt: &'static str,
+}
+
+/// Every built-in preset.
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "concise",
+        description: "a short, plain description of the work",
+        preamble: "You are watching over a programmer's shoulder. You will be given the git \
+                   diff of the work they currently have in progress. Describe what they are \
+                   working on in one short, plain sentence, without mentioning the diff itself.",
+        prompt: "What am I working on? Answer in under 80 characters.",
+    },
+    Preset {
+        name: "playful",
+        description: "a friendly narration of the work, with the odd emoji",
+        preamble: "You are a cheerful friend watching over a programmer's shoulder. You will \
+                   be given the git diff of the work they currently have in progress. Narrate \
+                   what they are up to in one short, playful sentence with at most one emoji, \
+                   without mentioning the diff itself.",
+        prompt: "What am I up to? Answer in under 100 characters.",
+    },
+    Preset {
+        name: "technical",
+        description: "the specific parts of the code being changed, and how",
+        preamble: "You are a senior engineer reviewing a colleague's work in progress. You \
+                   will be given its git diff. Name the specific modules, functions, or \
+                   features being changed and how, in one precise sentence, without \
+                   mentioning the diff itself.",
+        prompt: "What exactly am I changing? Answer in under 120 characters.",
+    },
+];
+
+/// Looks up a built-in preset by name.
+pub fn find(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+/// Prints every preset with its preamble and prompt, for `samwise presets
+/// list`.
+pub fn list() {
+    for (index, preset) in PRESETS.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+
+        println!("{}: {}", preset.name, preset.description);
+        println!("  preamble: {}", preset.preamble);
+        println!("  prompt:   {}", preset.prompt);
+    }
+}
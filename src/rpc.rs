@@ -0,0 +1,677 @@
+//! The connection to the Discord client.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use discord_presence::{DiscordError, event_handler::EventCallbackHandle, models::Activity};
+use tokio::{
+    sync::{
+        Notify,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    },
+    task::{JoinError, JoinHandle},
+};
+
+use crate::{
+    config::Config,
+    http::StatusHandle,
+    metrics,
+    presence::{PresenceSink, PresenceState, build_activity, build_idle_activity},
+    systemd,
+};
+
+/// The main loop's handle on the Discord RPC task, which restarts it if it
+/// dies, like a supervisor.
+pub struct Rpc {
+    config: Config,
+    status: StatusHandle,
+    presence_tx: UnboundedSender<Option<Activity>>,
+
+    /// `None` once the task has stopped too often and Discord is given up on.
+    task: Option<JoinHandle<Result<(), RpcError>>>,
+
+    /// The last activity sent, which a restarted task is given to show again.
+    last_activity: Option<Activity>,
+
+    /// When the task was restarted within the last [RESTART_WINDOW].
+    restarts: VecDeque<Instant>,
+}
+
+#[async_trait]
+impl PresenceSink for Rpc {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        let activity = match presence {
+            Some(presence) => Some(build_activity(presence, &config.discord)),
+            None => build_idle_activity(&config.discord),
+        };
+
+        self.send(activity).await;
+        Ok(())
+    }
+
+    async fn update_partial(
+        &mut self,
+        presence: &PresenceState,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        self.send(Some(build_activity(presence, &config.discord)))
+            .await;
+        Ok(())
+    }
+
+    async fn check(&mut self) {
+        Rpc::check(self).await;
+    }
+
+    async fn shutdown(self: Box<Self>, timeout: Duration) {
+        Rpc::shutdown(*self, timeout).await;
+    }
+}
+
+/// How many times the RPC task can be restarted within [RESTART_WINDOW]
+/// before Discord is given up on, so that a task that dies as soon as it
+/// starts doesn't loop forever.
+const MAX_RESTARTS: usize = 5;
+
+/// How far back restarts count towards [MAX_RESTARTS].
+const RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// The first delay before restarting the RPC task, doubled with each recent
+/// restart up to [RECONNECT_MAX_DELAY].
+const RESTART_BASE_DELAY: Duration = Duration::from_secs(1);
+
+impl Rpc {
+    pub fn spawn(config: Config, status: StatusHandle) -> Self {
+        let (presence_tx, task) = spawn_task(&config, &status, Duration::ZERO);
+
+        Self {
+            config,
+            status,
+            presence_tx,
+            task: Some(task),
+            last_activity: None,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Sends an activity update to Discord. If the RPC task has died, its
+    /// error is logged and it's restarted with the update instead.
+    pub async fn send(&mut self, activity: Option<Activity>) {
+        self.last_activity = activity;
+
+        let finished = self.task.as_ref().is_some_and(JoinHandle::is_finished);
+
+        // the task can also die between checking and sending
+        if finished
+            || (self.task.is_some() && self.presence_tx.send(self.last_activity.clone()).is_err())
+        {
+            self.restart().await;
+        }
+    }
+
+    /// Clears the presence and waits for the RPC task to finish, giving up
+    /// after `timeout` so a wedged Discord client can't hang the exit.
+    pub async fn shutdown(self, timeout: Duration) {
+        let Some(task) = self.task else {
+            return;
+        };
+
+        let _ = self.presence_tx.send(None);
+
+        // closing the channel tells the task to disconnect
+        drop(self.presence_tx);
+
+        match tokio::time::timeout(timeout, task).await {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(err))) => {
+                tracing::error!("Discord RPC task failed: {}", crate::error_chain(&err));
+            }
+            Ok(Err(err)) => tracing::error!("Discord RPC task panicked: {err}"),
+            Err(_) => tracing::warn!("timed out waiting for Discord RPC to shut down"),
+        }
+    }
+
+    /// Restarts the RPC task if it has finished, logging why it stopped.
+    pub async fn check(&mut self) {
+        if self.task.as_ref().is_some_and(JoinHandle::is_finished) {
+            self.restart().await;
+        }
+    }
+
+    /// Starts a new RPC task in place of the one that stopped, after a delay
+    /// that grows with each recent restart, and gives it the last activity.
+    /// Past [MAX_RESTARTS], Discord is given up on and the other outputs
+    /// carry on without it.
+    async fn restart(&mut self) {
+        let Some(task) = self.task.take() else {
+            return;
+        };
+
+        let stopped = match task.await {
+            Ok(Ok(())) => "exited".to_string(),
+            Ok(Err(err)) => format!("failed: {}", crate::error_chain(&err)),
+            Err(err) => format!("panicked: {err}"),
+        };
+
+        let now = Instant::now();
+
+        while self
+            .restarts
+            .front()
+            .is_some_and(|restart| now.duration_since(*restart) >= RESTART_WINDOW)
+        {
+            self.restarts.pop_front();
+        }
+
+        if self.restarts.len() >= MAX_RESTARTS {
+            tracing::error!(
+                "Discord RPC task {stopped}; it's stopped {MAX_RESTARTS} times in {}, so \
+                 Discord is off until samwise restarts",
+                humantime::format_duration(RESTART_WINDOW)
+            );
+
+            self.status.set_discord_connected(false);
+            self.status.set_error(&anyhow::anyhow!(
+                "the Discord RPC task kept stopping, so Discord is off"
+            ));
+            systemd::status("gave up on Discord");
+            return;
+        }
+
+        let delay =
+            (RESTART_BASE_DELAY * 2u32.pow(self.restarts.len() as u32)).min(RECONNECT_MAX_DELAY);
+
+        tracing::error!("Discord RPC task {stopped}, restarting it in {delay:?}");
+        self.restarts.push_back(now);
+
+        let (presence_tx, task) = spawn_task(&self.config, &self.status, delay);
+
+        if self.last_activity.is_some() {
+            let _ = presence_tx.send(self.last_activity.clone());
+        }
+
+        self.presence_tx = presence_tx;
+        self.task = Some(task);
+    }
+}
+
+/// Spawns an [rpc_task] that starts after `delay`. Updates sent in the
+/// meantime wait in the channel.
+fn spawn_task(
+    config: &Config,
+    status: &StatusHandle,
+    delay: Duration,
+) -> (
+    UnboundedSender<Option<Activity>>,
+    JoinHandle<Result<(), RpcError>>,
+) {
+    let (presence_tx, presence_rx) = unbounded_channel();
+    let (config, status) = (config.clone(), status.clone());
+
+    let task = tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        rpc_task(config, presence_rx, status).await
+    });
+
+    (presence_tx, task)
+}
+
+/// The first delay before reconnecting to Discord, doubled on each failure.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay between attempts to reconnect to Discord. A connection
+/// that stays up at least this long resets the backoff.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How long to wait for Discord to become ready before starting over with a
+/// new client, in case it isn't running yet.
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Forwards presence updates from the main loop to Discord until the channel
+/// closes.
+///
+/// The Discord RPC client runs its own thread and blocks on crossbeam while
+/// it waits for replies, so every call into it is made on tokio's blocking
+/// pool. I'd love to write my own async bindings at some point but... one
+/// thing at a time.
+///
+/// If Discord goes away (say, the desktop client restarts), the connection is
+/// torn down and rebuilt with backoff, and the last activity is re-sent once
+/// it's ready again. The same goes for a Discord client that isn't running
+/// when samwise starts. An update that fails to send is tried again a few
+/// times before the connection is given up on. Whether it's connected is kept
+/// up to date in `status`.
+pub async fn rpc_task(
+    config: Config,
+    mut presence_rx: UnboundedReceiver<Option<Activity>>,
+    status: StatusHandle,
+) -> Result<(), RpcError> {
+    let mut last_activity = None;
+    let mut delay = RECONNECT_BASE_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        let disconnected = Arc::new(Notify::new());
+
+        let connected = connect_rpc(
+            &config,
+            &mut presence_rx,
+            &mut last_activity,
+            &disconnected,
+            attempt,
+        )
+        .await;
+
+        let (drpc, _handlers) = match connected {
+            Ok(Some(connected)) => connected,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                tracing::warn!(
+                    discord_state = "waiting",
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    "{}, trying again in {delay:?}",
+                    crate::error_chain(&err)
+                );
+
+                if !wait_to_retry(delay, &mut presence_rx, &mut last_activity).await {
+                    return Ok(());
+                }
+
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                attempt += 1;
+                continue;
+            }
+        };
+
+        attempt = 1;
+
+        let connected_at = Instant::now();
+        status.set_discord_connected(true);
+
+        let result = run_rpc(&drpc, &mut presence_rx, &mut last_activity, &disconnected).await;
+        status.set_discord_connected(false);
+
+        match result {
+            Ok(()) => {
+                tracing::info!(discord_state = "shutdown", "disconnecting from Discord");
+
+                return blocking(move || drpc.shutdown()).await;
+            }
+            Err(err) => {
+                if connected_at.elapsed() >= RECONNECT_MAX_DELAY {
+                    delay = RECONNECT_BASE_DELAY;
+                }
+
+                tracing::warn!(
+                    discord_state = "reconnecting",
+                    delay_ms = delay.as_millis() as u64,
+                    "Discord RPC lost: {}",
+                    crate::error_chain(&err)
+                );
+                metrics::DISCORD_RECONNECTS.inc();
+                systemd::status("lost Discord, reconnecting");
+                let _ = blocking(move || drpc.shutdown()).await;
+
+                if !wait_to_retry(delay, &mut presence_rx, &mut last_activity).await {
+                    return Ok(());
+                }
+
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            }
+        }
+    }
+}
+
+/// Sleeps for `delay` before connecting again, keeping track of the latest
+/// activity in the meantime. Returns `false` if the channel closes first.
+async fn wait_to_retry(
+    delay: Duration,
+    presence_rx: &mut UnboundedReceiver<Option<Activity>>,
+    last_activity: &mut Option<Activity>,
+) -> bool {
+    let sleep = tokio::time::sleep(delay);
+    tokio::pin!(sleep);
+
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return true,
+            activity = presence_rx.recv() => match activity {
+                Some(activity) => *last_activity = activity,
+                None => return false,
+            },
+        }
+    }
+}
+
+/// Runs a call into the Discord client on the blocking pool.
+async fn blocking<T: Send + 'static>(
+    call: impl FnOnce() -> discord_presence::Result<T> + Send + 'static,
+) -> Result<T, RpcError> {
+    tokio::task::spawn_blocking(call)
+        .await
+        .map_err(RpcError::Panicked)?
+        .map_err(RpcError::Client)
+}
+
+/// Creates a Discord client and waits for it to become ready, keeping track
+/// of the latest activity in the meantime. The returned handles keep the
+/// event callbacks registered until they're dropped.
+///
+/// Returns `None` if the channel closes before Discord is ready, since there's
+/// nothing left to show, and fails if it isn't ready within [READY_TIMEOUT].
+async fn connect_rpc(
+    config: &Config,
+    presence_rx: &mut UnboundedReceiver<Option<Activity>>,
+    last_activity: &mut Option<Activity>,
+    disconnected: &Arc<Notify>,
+    attempt: u32,
+) -> Result<Option<(discord_presence::Client, Vec<EventCallbackHandle>)>, RpcError> {
+    let mut drpc = discord_presence::Client::new(config.discord.client);
+    let ready = Arc::new(Notify::new());
+
+    let handlers = vec![
+        drpc.on_ready({
+            let ready = ready.clone();
+            move |_| ready.notify_one()
+        }),
+        drpc.on_error(|ctx| {
+            tracing::warn!(discord_event = "error", event = ?ctx.event, "Discord RPC error");
+        }),
+        drpc.on_connected(|ctx| {
+            tracing::info!(discord_event = "connected", event = ?ctx.event, "Discord RPC connected");
+        }),
+        drpc.on_disconnected({
+            let disconnected = disconnected.clone();
+            move |ctx| {
+                tracing::info!(
+                    discord_event = "disconnected",
+                    event = ?ctx.event,
+                    "Discord RPC disconnected"
+                );
+                disconnected.notify_one();
+            }
+        }),
+    ];
+
+    drpc.start();
+
+    tracing::info!(
+        discord_state = "connecting",
+        attempt,
+        "waiting for Discord RPC"
+    );
+    systemd::status("waiting for Discord");
+
+    let timeout = tokio::time::sleep(READY_TIMEOUT);
+    tokio::pin!(timeout);
+
+    loop {
+        tokio::select! {
+            _ = ready.notified() => break,
+            _ = &mut timeout => {
+                let _ = blocking(move || drpc.shutdown()).await;
+
+                return Err(RpcError::NotReady(READY_TIMEOUT));
+            }
+            activity = presence_rx.recv() => match activity {
+                Some(activity) => *last_activity = activity,
+                None => {
+                    let _ = blocking(move || drpc.shutdown()).await;
+                    return Ok(None);
+                }
+            },
+        }
+    }
+
+    tracing::info!(discord_state = "ready", "Discord RPC is ready");
+    systemd::ready();
+    systemd::status("connected to Discord");
+
+    Ok(Some((drpc, handlers)))
+}
+
+/// Forwards presence updates to Discord until the channel closes, which
+/// returns `Ok`, or the connection is lost, which returns an error. The main
+/// loop clears the activity before closing the channel.
+async fn run_rpc(
+    drpc: &discord_presence::Client,
+    presence_rx: &mut UnboundedReceiver<Option<Activity>>,
+    last_activity: &mut Option<Activity>,
+    disconnected: &Notify,
+) -> Result<(), RpcError> {
+    // catch Discord up on whatever it missed while we were reconnecting
+    if last_activity.is_some() {
+        apply_activity(drpc, presence_rx, last_activity).await?;
+    }
+
+    loop {
+        let activity = tokio::select! {
+            _ = disconnected.notified() => return Err(RpcError::Disconnected),
+            activity = presence_rx.recv() => match activity {
+                Some(activity) => activity,
+                None => return Ok(()),
+            },
+        };
+
+        // setting the same activity again makes it flicker for nothing
+        if activity == *last_activity {
+            tracing::debug!("the Discord activity hasn't changed, not setting it again");
+            continue;
+        }
+
+        *last_activity = activity;
+        apply_activity(drpc, presence_rx, last_activity).await?;
+    }
+}
+
+/// How many times to try setting an activity before giving up on the
+/// connection.
+const SET_ACTIVITY_ATTEMPTS: u32 = 3;
+
+/// How long to wait before trying to set an activity again.
+const SET_ACTIVITY_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Sets the activity to `last_activity`, or clears it, trying again a few
+/// times if it fails in a way that the connection might recover from. Updates
+/// that arrive in the meantime replace `last_activity`, so that it's always
+/// the latest one that's retried.
+async fn apply_activity(
+    drpc: &discord_presence::Client,
+    presence_rx: &mut UnboundedReceiver<Option<Activity>>,
+    last_activity: &mut Option<Activity>,
+) -> Result<(), RpcError> {
+    let mut attempt = 1;
+
+    loop {
+        let mut drpc = drpc.clone();
+
+        let result = match last_activity.clone() {
+            Some(activity) => blocking(move || drpc.set_activity(|_| activity))
+                .await
+                .map(drop),
+            None => blocking(move || drpc.clear_activity()).await.map(drop),
+        };
+
+        let Err(err) = result else {
+            return Ok(());
+        };
+
+        if attempt >= SET_ACTIVITY_ATTEMPTS || !err.is_transient() {
+            return Err(RpcError::Activity {
+                clear: last_activity.is_none(),
+                source: Box::new(err),
+            });
+        }
+
+        tracing::debug!(
+            attempt,
+            "failed to update Discord activity, trying again in \
+             {SET_ACTIVITY_RETRY_DELAY:?}: {}",
+            crate::error_chain(&err)
+        );
+
+        tokio::time::sleep(SET_ACTIVITY_RETRY_DELAY).await;
+
+        // a closed channel is noticed by the caller once this returns
+        while let Ok(activity) = presence_rx.try_recv() {
+            *last_activity = activity;
+        }
+
+        attempt += 1;
+    }
+}
+
+/// An error from the connection to the Discord client. Each of them is
+/// recovered from by reconnecting, and [RpcError::is_transient] ones by just
+/// trying again.
+#[derive(Debug)]
+pub enum RpcError {
+    /// A call into the Discord client failed.
+    Client(DiscordError),
+
+    /// A call into the Discord client panicked.
+    Panicked(JoinError),
+
+    /// Discord didn't become ready in time, like when it isn't running.
+    NotReady(Duration),
+
+    /// Discord closed the connection.
+    Disconnected,
+
+    /// Setting or clearing the activity kept failing.
+    Activity { clear: bool, source: Box<RpcError> },
+}
+
+impl RpcError {
+    /// Whether a failed call into the Discord client is worth trying again on
+    /// the same connection, like a write that timed out, rather than a sign
+    /// that the connection is gone.
+    pub fn is_transient(&self) -> bool {
+        use std::io::ErrorKind;
+
+        match self {
+            RpcError::Client(DiscordError::IoError(err)) => !matches!(
+                err.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::NotConnected
+            ),
+            RpcError::Client(
+                DiscordError::SendMessage(_)
+                | DiscordError::TimeoutError(_)
+                | DiscordError::MPSCTimeout(_),
+            ) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Client(_) => write!(f, "Discord RPC call failed"),
+            RpcError::Panicked(_) => write!(f, "Discord RPC call panicked"),
+            RpcError::NotReady(timeout) => write!(f, "Discord wasn't ready within {timeout:?}"),
+            RpcError::Disconnected => write!(f, "Discord disconnected"),
+            RpcError::Activity { clear, .. } => {
+                let action = if *clear { "clear" } else { "set" };
+                write!(f, "failed to {action} Discord activity")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpcError::Client(err) => Some(err),
+            RpcError::Panicked(err) => Some(err),
+            RpcError::Activity { source, .. } => Some(source.as_ref()),
+            RpcError::NotReady(_) | RpcError::Disconnected => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An [Rpc] whose task has already died with its receiver, after
+    /// `restarts` recent restarts.
+    fn dead_rpc(restarts: usize) -> Rpc {
+        let (presence_tx, presence_rx) = unbounded_channel();
+        drop(presence_rx);
+
+        Rpc {
+            config: crate::config::tests::default_config(),
+            status: StatusHandle::default(),
+            presence_tx,
+            task: Some(tokio::spawn(async { Err(RpcError::Disconnected) })),
+            last_activity: None,
+            restarts: std::iter::repeat_n(Instant::now(), restarts).collect(),
+        }
+    }
+
+    fn activity() -> Option<Activity> {
+        Some(Activity::new().details("fixing the parser"))
+    }
+
+    #[test]
+    fn activity_errors_say_what_caused_them() {
+        let err = RpcError::Activity {
+            clear: true,
+            source: Box::new(RpcError::Disconnected),
+        };
+
+        let source = std::error::Error::source(&err).unwrap();
+        assert!(matches!(
+            source.downcast_ref(),
+            Some(RpcError::Disconnected)
+        ));
+
+        let chain = crate::error_chain(&err);
+        assert_eq!(
+            chain,
+            "failed to clear Discord activity: Discord disconnected"
+        );
+    }
+
+    #[tokio::test]
+    async fn sending_to_a_dead_task_restarts_it() {
+        let mut rpc = dead_rpc(0);
+        rpc.send(activity()).await;
+
+        assert_eq!(rpc.restarts.len(), 1);
+        assert_eq!(rpc.last_activity, activity());
+
+        // it waits a second before connecting, so it's stopped long before
+        rpc.task.take().unwrap().abort();
+    }
+
+    #[tokio::test]
+    async fn sending_after_giving_up_on_discord_does_nothing() {
+        let mut rpc = dead_rpc(MAX_RESTARTS);
+        rpc.send(activity()).await;
+        assert!(rpc.task.is_none());
+
+        rpc.send(None).await;
+        rpc.check().await;
+        rpc.shutdown(Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,207 @@
+//! Setting the Slack custom status to the summary.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    config::{Config, SlackConfig},
+    presence::{PresenceSink, PresenceState, truncate_summary},
+};
+
+/// How long to wait on Slack before giving up on an update.
+const SLACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Slack rejects status texts longer than this many characters.
+pub const MAX_STATUS_LEN: usize = 100;
+
+/// How long to back off when Slack is rate limiting without saying how long
+/// for.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+const PROFILE_SET_URL: &str = "https://slack.com/api/users.profile.set";
+
+/// A custom status: its text and emoji.
+#[derive(Clone, PartialEq, Eq)]
+struct Status {
+    text: String,
+    emoji: String,
+}
+
+/// Sets the Slack custom status to the latest summary.
+///
+/// Updates go through a background task that only ever sends the latest
+/// status, so that a slow or rate-limited Slack doesn't hold up the main loop
+/// or fall behind with stale ones.
+pub struct Slack {
+    status_tx: watch::Sender<Option<Status>>,
+    task: JoinHandle<()>,
+}
+
+impl Slack {
+    pub fn new(config: &SlackConfig) -> anyhow::Result<Self> {
+        let token = config
+            .token()
+            .context("slack.token: set this or SLACK_TOKEN to a Slack user token")?;
+
+        let client = reqwest::Client::builder()
+            .timeout(SLACK_TIMEOUT)
+            .build()
+            .context("failed to create Slack client")?;
+
+        let (status_tx, status_rx) = watch::channel(None);
+        let task = tokio::spawn(slack_task(client, token, status_rx));
+
+        Ok(Self { status_tx, task })
+    }
+
+    /// Sets the status to the summary, or clears it when `presence` is
+    /// `None` or Slack is no longer configured. Repeats of the current status
+    /// aren't sent again.
+    pub fn send(&self, presence: Option<&PresenceState>, config: Option<&SlackConfig>) {
+        let status = presence.zip(config).map(|(presence, config)| {
+            let max_len = config.max_len.unwrap_or(MAX_STATUS_LEN).min(MAX_STATUS_LEN);
+            let mut text = presence.summary.clone();
+            truncate_summary(&mut text, max_len, config.truncate);
+
+            Status {
+                text,
+                emoji: config.emoji.clone(),
+            }
+        });
+
+        self.status_tx.send_if_modified(|current| {
+            if *current == status {
+                return false;
+            }
+
+            *current = status;
+            true
+        });
+    }
+
+    /// Clears the status and waits for that to reach Slack, giving up after
+    /// `timeout`.
+    pub async fn shutdown(self, timeout: Duration) {
+        self.send(None, None);
+
+        // closing the channel tells the task to stop once it's caught up
+        drop(self.status_tx);
+
+        if tokio::time::timeout(timeout, self.task).await.is_err() {
+            tracing::warn!("timed out clearing the Slack status");
+        }
+    }
+}
+
+#[async_trait]
+impl PresenceSink for Slack {
+    fn name(&self) -> &'static str {
+        "slack"
+    }
+
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        self.send(presence, config.slack.as_ref());
+        Ok(())
+    }
+
+    async fn shutdown(self: Box<Self>, timeout: Duration) {
+        Slack::shutdown(*self, timeout).await;
+    }
+}
+
+/// Sends each new status to Slack until the channel closes. Failures are
+/// logged and skipped, except for rate limits, which are waited out before
+/// sending whatever the status is by then.
+async fn slack_task(
+    client: reqwest::Client,
+    token: String,
+    mut status_rx: watch::Receiver<Option<Status>>,
+) {
+    while status_rx.changed().await.is_ok() {
+        loop {
+            let status = status_rx.borrow_and_update().clone();
+
+            match set_status(&client, &token, status.as_ref()).await {
+                Ok(None) => break,
+                Ok(Some(wait)) => {
+                    tracing::warn!("Slack is rate limiting status updates, waiting {wait:?}");
+                    tokio::time::sleep(wait).await;
+                }
+                Err(err) => {
+                    tracing::warn!("failed to set Slack status: {err:#}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sets the custom status with `users.profile.set`, or clears it. Returns
+/// how long to wait before trying again if Slack is rate limiting.
+async fn set_status(
+    client: &reqwest::Client,
+    token: &str,
+    status: Option<&Status>,
+) -> anyhow::Result<Option<Duration>> {
+    let (text, emoji) = status.map_or(("", ""), |status| (&status.text, &status.emoji));
+
+    let body = serde_json::json!({
+        "profile": {
+            "status_text": text,
+            "status_emoji": emoji,
+            "status_expiration": 0,
+        }
+    });
+
+    let response = client
+        .post(PROFILE_SET_URL)
+        .bearer_auth(token)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+
+        return Ok(Some(retry_after));
+    }
+
+    let status = response.status();
+
+    if !status.is_success() {
+        anyhow::bail!("Slack responded with {status}");
+    }
+
+    // Slack answers 200 even when the call fails, with the reason in the body
+    let reply: serde_json::Value = serde_json::from_str(&response.text().await?)
+        .context("Slack's response isn't valid JSON")?;
+
+    if reply["ok"].as_bool() != Some(true) {
+        let error = reply["error"].as_str().unwrap_or("an unknown error");
+
+        match error {
+            "invalid_auth" | "not_authed" | "token_revoked" | "token_expired" => {
+                anyhow::bail!("Slack rejected slack.token ({error})")
+            }
+            "missing_scope" => {
+                anyhow::bail!("slack.token needs the users.profile:write scope")
+            }
+            _ => anyhow::bail!("Slack failed with {error}"),
+        }
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,47 @@
+//! Telling systemd how samwise is doing when it runs as a `Type=notify`
+//! service. Everything here does nothing outside of systemd.
+
+/// Tells systemd that samwise is up and running.
+pub fn ready() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Ready]);
+}
+
+/// Shows `status` in `systemctl status`.
+pub fn status(status: &str) {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Status(status)]);
+
+    #[cfg(not(unix))]
+    let _ = status;
+}
+
+/// Tells systemd that samwise is shutting down.
+pub fn stopping() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+/// Pets the watchdog at half of `WatchdogSec` forever, so that systemd
+/// restarts samwise if the runtime wedges. Never finishes, even when there's
+/// no watchdog.
+pub async fn watchdog() {
+    #[cfg(unix)]
+    if let Some(timeout) = sd_notify::watchdog_enabled() {
+        let mut interval = tokio::time::interval(timeout / 2);
+
+        loop {
+            interval.tick().await;
+            notify(&[sd_notify::NotifyState::Watchdog]);
+        }
+    }
+
+    std::future::pending().await
+}
+
+#[cfg(unix)]
+fn notify(states: &[sd_notify::NotifyState]) {
+    if let Err(err) = sd_notify::notify(states) {
+        tracing::debug!("failed to notify systemd: {err}");
+    }
+}
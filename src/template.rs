@@ -0,0 +1,573 @@
+//! The templates of the config, like the prompt and the presence's lines.
+//!
+//! `{name}` is replaced with a placeholder's value, which filters can change
+//! on the way, like `{branch|lower|truncate:12}`. `{if files > 1}...{end}`
+//! only keeps what's inside when the condition holds, and can have `{elif
+//! ...}` and `{else}` branches. A condition is a placeholder, which holds when
+//! it's neither empty nor `0`, or two values compared with `==`, `!=`, `<`,
+//! `<=`, `>`, or `>=`, which are placeholders, numbers, or quoted text, like
+//! `{if change_type == "fix"}`. Values are compared as numbers when they both
+//! are. `{{` and `}}` stand for literal braces.
+
+use std::cmp::Ordering;
+
+use crate::presence::truncate_chars;
+
+/// Fills in a template with `vars`. The whole template is checked first,
+/// including the branches that aren't taken, so unknown placeholders and
+/// filters, unclosed tags, and unbalanced `{if}`s are always errors.
+pub fn render_template(template: &str, vars: &[(&str, String)]) -> anyhow::Result<String> {
+    let nodes = parse(template)?;
+    check(&nodes, vars)?;
+
+    let mut out = String::with_capacity(template.len());
+    render(&nodes, vars, &mut out);
+    Ok(out)
+}
+
+/// Whether `template` uses `{name}` exactly once, as a plain placeholder
+/// outside of any `{if}` and without filters, like the summary's place in its
+/// line has to be.
+pub fn has_single_placeholder(template: &str, name: &str) -> anyhow::Result<bool> {
+    let nodes = parse(template)?;
+
+    let plain = nodes
+        .iter()
+        .filter(|node| {
+            matches!(node, Node::Var { name: var, filters } if var == name && filters.is_empty())
+        })
+        .count();
+
+    Ok(plain == 1 && count_uses(&nodes, name) == 1)
+}
+
+/// How many times `{name}` is filled in anywhere in `nodes`.
+fn count_uses(nodes: &[Node], name: &str) -> usize {
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(_) => 0,
+            Node::Var { name: var, .. } => usize::from(var == name),
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                let branches: usize = branches
+                    .iter()
+                    .map(|(_, nodes)| count_uses(nodes, name))
+                    .sum();
+
+                branches + count_uses(otherwise, name)
+            }
+        })
+        .sum()
+}
+
+enum Node {
+    Text(String),
+    Var {
+        name: String,
+        filters: Vec<Filter>,
+    },
+    If {
+        branches: Vec<(Condition, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+}
+
+enum Filter {
+    Lower,
+    Upper,
+    Truncate(usize),
+}
+
+enum Operand {
+    Var(String),
+    Literal(String),
+}
+
+struct Condition {
+    left: Operand,
+    compare: Option<(Operator, Operand)>,
+}
+
+#[derive(Clone, Copy)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Operator {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Self::Equal => ordering.is_eq(),
+            Self::NotEqual => ordering.is_ne(),
+            Self::Less => ordering.is_lt(),
+            Self::LessOrEqual => ordering.is_le(),
+            Self::Greater => ordering.is_gt(),
+            Self::GreaterOrEqual => ordering.is_ge(),
+        }
+    }
+}
+
+/// A piece of a template before the `{if}` blocks are put together.
+enum Token {
+    Text(String),
+    Tag(String),
+}
+
+fn tokenize(template: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.next_if_eq(&'{').is_some() => text.push('{'),
+            '}' if chars.next_if_eq(&'}').is_some() => text.push('}'),
+            '{' => {
+                let mut tag = String::new();
+                let mut quoted = false;
+
+                loop {
+                    match chars.next() {
+                        Some('}') if !quoted => break,
+                        Some(c) => {
+                            quoted ^= c == '"';
+                            tag.push(c);
+                        }
+                        None => anyhow::bail!("unclosed placeholder {{{tag}"),
+                    }
+                }
+
+                if !text.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut text)));
+                }
+
+                tokens.push(Token::Tag(tag));
+            }
+            '}' => anyhow::bail!("unmatched }} (write }}}} for a literal brace)"),
+            c => text.push(c),
+        }
+    }
+
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+
+    Ok(tokens)
+}
+
+fn parse(template: &str) -> anyhow::Result<Vec<Node>> {
+    let mut tokens = tokenize(template)?.into_iter();
+    let (nodes, end) = parse_block(&mut tokens)?;
+
+    match end {
+        None => Ok(nodes),
+        Some(tag) => anyhow::bail!("{{{tag}}} without an {{if}} before it"),
+    }
+}
+
+/// Parses nodes up to the next `{elif}`, `{else}`, or `{end}`, which is
+/// returned along with them, or to the end of the template.
+fn parse_block(
+    tokens: &mut impl Iterator<Item = Token>,
+) -> anyhow::Result<(Vec<Node>, Option<String>)> {
+    let mut nodes = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        let tag = match token {
+            Token::Text(text) => {
+                nodes.push(Node::Text(text));
+                continue;
+            }
+            Token::Tag(tag) => tag.trim().to_string(),
+        };
+
+        let keyword = tag.split_whitespace().next().unwrap_or_default();
+
+        match keyword {
+            "elif" | "else" | "end" => return Ok((nodes, Some(tag))),
+            "if" => nodes.push(parse_if(&tag, tokens)?),
+            _ => nodes.push(parse_var(&tag)?),
+        }
+    }
+
+    Ok((nodes, None))
+}
+
+/// Parses the rest of an `{if}` block, whose opening tag is `tag`.
+fn parse_if(tag: &str, tokens: &mut impl Iterator<Item = Token>) -> anyhow::Result<Node> {
+    let mut branches = Vec::new();
+    let mut condition = parse_condition(&tag["if".len()..])?;
+
+    loop {
+        let (nodes, end) = parse_block(tokens)?;
+        branches.push((condition, nodes));
+
+        let Some(end) = end else {
+            anyhow::bail!("{{{tag}}} is missing its {{end}}");
+        };
+
+        match end.split_whitespace().next() {
+            Some("elif") => condition = parse_condition(&end["elif".len()..])?,
+            Some("else") if end == "else" => {
+                let (otherwise, end) = parse_block(tokens)?;
+
+                return match end.as_deref() {
+                    Some("end") => Ok(Node::If {
+                        branches,
+                        otherwise,
+                    }),
+                    Some(end) => anyhow::bail!("{{{end}}} after the {{else}} of {{{tag}}}"),
+                    None => anyhow::bail!("{{{tag}}} is missing its {{end}}"),
+                };
+            }
+            _ if end == "end" => {
+                return Ok(Node::If {
+                    branches,
+                    otherwise: Vec::new(),
+                });
+            }
+            _ => anyhow::bail!("unknown tag {{{end}}}"),
+        }
+    }
+}
+
+fn parse_condition(condition: &str) -> anyhow::Result<Condition> {
+    let condition = condition.trim();
+
+    if condition.is_empty() {
+        anyhow::bail!("{{if}} needs a condition, like {{if files > 1}}");
+    }
+
+    // the longest operators first, so that `>=` isn't taken for `>`
+    const OPERATORS: [(&str, Operator); 6] = [
+        ("==", Operator::Equal),
+        ("!=", Operator::NotEqual),
+        ("<=", Operator::LessOrEqual),
+        (">=", Operator::GreaterOrEqual),
+        ("<", Operator::Less),
+        (">", Operator::Greater),
+    ];
+
+    let split = OPERATORS.iter().find_map(|&(symbol, operator)| {
+        let (left, right) = split_outside_quotes(condition, symbol)?;
+        Some((left, right, operator))
+    });
+
+    let Some((left, right, operator)) = split else {
+        return Ok(Condition {
+            left: parse_operand(condition)?,
+            compare: None,
+        });
+    };
+
+    Ok(Condition {
+        left: parse_operand(left)?,
+        compare: Some((operator, parse_operand(right)?)),
+    })
+}
+
+/// Splits `s` around the first `op` that isn't inside quotes.
+fn split_outside_quotes<'a>(s: &'a str, op: &str) -> Option<(&'a str, &'a str)> {
+    let mut quoted = false;
+
+    for (idx, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && s[idx..].starts_with(op) {
+            return Some((&s[..idx], &s[idx + op.len()..]));
+        }
+    }
+
+    None
+}
+
+fn parse_operand(operand: &str) -> anyhow::Result<Operand> {
+    let operand = operand.trim();
+
+    if let Some(text) = operand
+        .strip_prefix('"')
+        .and_then(|operand| operand.strip_suffix('"'))
+    {
+        return Ok(Operand::Literal(text.to_string()));
+    }
+
+    if operand.parse::<f64>().is_ok() {
+        return Ok(Operand::Literal(operand.to_string()));
+    }
+
+    if operand.is_empty() || operand.contains(char::is_whitespace) || operand.contains('"') {
+        anyhow::bail!("{operand:?} isn't a placeholder, a number, or quoted text");
+    }
+
+    Ok(Operand::Var(operand.to_string()))
+}
+
+fn parse_var(tag: &str) -> anyhow::Result<Node> {
+    let mut parts = tag.split('|').map(str::trim);
+    let name = parts.next().unwrap_or_default().to_string();
+
+    let filters = parts
+        .map(|filter| match filter.split_once(':') {
+            None if filter == "lower" => Ok(Filter::Lower),
+            None if filter == "upper" => Ok(Filter::Upper),
+            Some(("truncate", len)) => match len.trim().parse() {
+                Ok(len) => Ok(Filter::Truncate(len)),
+                Err(_) => anyhow::bail!("truncate:{len} in {{{tag}}}: expected a length"),
+            },
+            _ => anyhow::bail!(
+                "unknown filter {filter} in {{{tag}}} (expected lower, upper, or truncate:<length>)"
+            ),
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(Node::Var { name, filters })
+}
+
+/// Checks that every placeholder in `nodes` is one of `vars`.
+fn check(nodes: &[Node], vars: &[(&str, String)]) -> anyhow::Result<()> {
+    let check_name = |name: &str| {
+        if vars.iter().any(|(var, _)| *var == name) {
+            return Ok(());
+        }
+
+        let known: Vec<_> = vars.iter().map(|(var, _)| *var).collect();
+        anyhow::bail!(
+            "unknown placeholder {{{name}}} (expected one of {}; write {{{{ for a literal brace)",
+            known.join(", ")
+        );
+    };
+
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Var { name, .. } => check_name(name)?,
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, nodes) in branches {
+                    let right = condition.compare.as_ref().map(|(_, right)| right);
+
+                    for operand in std::iter::once(&condition.left).chain(right) {
+                        if let Operand::Var(name) = operand {
+                            check_name(name)?;
+                        }
+                    }
+
+                    check(nodes, vars)?;
+                }
+
+                check(otherwise, vars)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn render(nodes: &[Node], vars: &[(&str, String)], out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var { name, filters } => {
+                let mut value = lookup(vars, name).to_string();
+
+                for filter in filters {
+                    match filter {
+                        Filter::Lower => value = value.to_lowercase(),
+                        Filter::Upper => value = value.to_uppercase(),
+                        Filter::Truncate(len) => truncate_chars(&mut value, *len),
+                    }
+                }
+
+                out.push_str(&value);
+            }
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                let taken = branches
+                    .iter()
+                    .find(|(condition, _)| holds(condition, vars))
+                    .map_or(otherwise, |(_, nodes)| nodes);
+
+                render(taken, vars, out);
+            }
+        }
+    }
+}
+
+fn lookup<'a>(vars: &'a [(&str, String)], name: &str) -> &'a str {
+    vars.iter()
+        .find(|(var, _)| *var == name)
+        .map_or("", |(_, value)| value)
+}
+
+fn holds(condition: &Condition, vars: &[(&str, String)]) -> bool {
+    let value = |operand: &Operand| match operand {
+        Operand::Var(name) => lookup(vars, name).to_string(),
+        Operand::Literal(text) => text.clone(),
+    };
+
+    let left = value(&condition.left);
+
+    let Some((operator, right)) = &condition.compare else {
+        return !left.is_empty() && left != "0";
+    };
+
+    let right = value(right);
+
+    let ordering = match (left.trim().parse::<f64>(), right.trim().parse::<f64>()) {
+        (Ok(left), Ok(right)) => left.partial_cmp(&right).unwrap_or(Ordering::Equal),
+        _ => left.cmp(&right),
+    };
+
+    operator.holds(ordering)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(files: usize) -> Vec<(&'static str, String)> {
+        vec![
+            ("files", files.to_string()),
+            ("change_type", "fix".to_string()),
+            ("branch", "Feature/Parser-Rewrite".to_string()),
+            ("summary", "Fixing a > b".to_string()),
+        ]
+    }
+
+    fn rendered(template: &str, files: usize) -> String {
+        render_template(template, &vars(files)).unwrap()
+    }
+
+    fn error(template: &str) -> String {
+        render_template(template, &vars(1)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(rendered("{{branch}} }}{{", 1), "{branch} }{");
+    }
+
+    #[test]
+    fn unclosed_placeholders_are_errors() {
+        assert!(error("on {branch").contains("unclosed placeholder {branch"));
+    }
+
+    #[test]
+    fn stray_closing_braces_are_errors() {
+        assert!(error("fixing } things").contains("unmatched }"));
+    }
+
+    #[test]
+    fn nested_ifs_take_the_first_branch_that_holds() {
+        let template = concat!(
+            "{if files > 1}",
+            "{if change_type == \"fix\"}fixes{else}changes{end} in {files} files",
+            "{elif files == 1}one file",
+            "{else}nothing",
+            "{end}",
+        );
+
+        assert_eq!(rendered(template, 3), "fixes in 3 files");
+        assert_eq!(rendered(template, 1), "one file");
+        assert_eq!(rendered(template, 0), "nothing");
+    }
+
+    #[test]
+    fn unbalanced_ifs_are_errors() {
+        assert!(error("{if files}one").contains("{if files} is missing its {end}"));
+        assert!(error("{if files}a{else}b").contains("missing its {end}"));
+        assert!(error("done{end}").contains("{end} without an {if}"));
+    }
+
+    #[test]
+    fn elif_after_else_is_an_error() {
+        let err = error("{if files}a{else}b{elif change_type}c{end}");
+        assert!(err.contains("{elif change_type} after the {else} of {if files}"));
+    }
+
+    #[test]
+    fn numbers_are_compared_as_numbers() {
+        // "10" < "9" as text
+        assert_eq!(rendered("{if files > 9}many{end}", 10), "many");
+        assert_eq!(rendered("{if files >= 10}many{else}few{end}", 9), "few");
+    }
+
+    #[test]
+    fn text_is_compared_as_text() {
+        assert_eq!(rendered("{if change_type == \"fix\"}yes{end}", 1), "yes");
+        assert_eq!(rendered("{if change_type != \"fix\"}yes{end}", 1), "");
+        assert_eq!(rendered("{if branch < \"G\"}before{end}", 1), "before");
+    }
+
+    #[test]
+    fn operators_inside_quotes_arent_split_on() {
+        let template = "{if summary == \"Fixing a > b\"}same{else}different{end}";
+        assert_eq!(rendered(template, 1), "same");
+
+        // the quoted `==` isn't taken for the operator
+        let template = "{if change_type != \"a == b\"}yes{end}";
+        assert_eq!(rendered(template, 1), "yes");
+
+        assert_eq!(rendered("{if branch != \"}\"}yes{end}", 1), "yes");
+    }
+
+    #[test]
+    fn filters_chain_in_order() {
+        assert_eq!(rendered("{branch|lower}", 1), "feature/parser-rewrite");
+        assert_eq!(rendered("{branch|lower|truncate:8}", 1), "feature…");
+        assert_eq!(rendered("{branch|truncate:8|upper}", 1), "FEATURE…");
+    }
+
+    #[test]
+    fn unknown_filters_are_errors() {
+        assert!(error("{branch|title}").contains("unknown filter title"));
+        assert!(error("{branch|truncate:few}").contains("expected a length"));
+    }
+
+    #[test]
+    fn branches_not_taken_are_checked_too() {
+        let err = error("{if files == 0}{branch|title}{end}");
+        assert!(err.contains("unknown filter title"));
+
+        let err = error("{if files == 0}{else}{elif files}{nope}{end}");
+        assert!(err.contains("after the {else}"));
+
+        let err = error("{if files == 0}{nope}{end}");
+        assert!(err.contains("unknown placeholder {nope}"));
+
+        let err = error("{if nope}{files}{end}");
+        assert!(err.contains("unknown placeholder {nope}"));
+    }
+
+    #[test]
+    fn single_placeholder() {
+        assert!(has_single_placeholder("{summary}", "summary").unwrap());
+        assert!(has_single_placeholder("{branch}: {summary}", "summary").unwrap());
+        assert!(!has_single_placeholder("{branch}", "summary").unwrap());
+        assert!(!has_single_placeholder("{summary} {summary}", "summary").unwrap());
+    }
+
+    #[test]
+    fn single_placeholder_is_plain_and_outside_ifs() {
+        let inside = "{if files}{summary}{end}";
+        assert!(!has_single_placeholder(inside, "summary").unwrap());
+
+        let twice = "{summary}{if files} ({summary}){end}";
+        assert!(!has_single_placeholder(twice, "summary").unwrap());
+
+        assert!(!has_single_placeholder("{summary|upper}", "summary").unwrap());
+        assert!(has_single_placeholder("{summary}{{", "summary").unwrap());
+        assert!(has_single_placeholder("{summary", "summary").is_err());
+    }
+}
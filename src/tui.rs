@@ -0,0 +1,233 @@
+//! The live status view shown in the terminal with `--tui`.
+
+use std::{
+    collections::VecDeque,
+    io::{IsTerminal, Write},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use ratatui::{
+    Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Paragraph, Wrap},
+};
+use tokio::sync::Notify;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::http::{Status, StatusHandle};
+
+/// How many log lines are kept for the log pane.
+const LOG_LINES: usize = 200;
+
+/// How often key presses are checked for.
+const INPUT_POLL: Duration = Duration::from_millis(100);
+
+/// Whether the TUI can be shown, which needs a terminal to draw in.
+pub fn is_supported() -> bool {
+    std::io::stdout().is_terminal()
+}
+
+/// The latest log lines, written by a [tracing_subscriber::fmt] layer in
+/// place of stderr, which would scribble over the TUI.
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    changed: Arc<Notify>,
+}
+
+impl LogBuffer {
+    fn lines(&self) -> Vec<String> {
+        self.lines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn push(&self, text: &str) {
+        let mut lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            if lines.len() == LOG_LINES {
+                lines.pop_front();
+            }
+
+            lines.push_back(line.to_string());
+        }
+
+        drop(lines);
+        self.changed.notify_one();
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogWriter {
+            buffer: self.clone(),
+            bytes: Vec::new(),
+        }
+    }
+}
+
+/// Collects one log event and adds it to the [LogBuffer] when dropped.
+pub struct LogWriter {
+    buffer: LogBuffer,
+    bytes: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.buffer.push(&String::from_utf8_lossy(&self.bytes));
+    }
+}
+
+/// Takes over the terminal and draws `status` and `logs` whenever either
+/// changes, until `q`, Esc, or Ctrl+C is pressed. The terminal is put back
+/// the way it was on the way out.
+pub async fn run(status: StatusHandle, logs: LogBuffer) -> anyhow::Result<()> {
+    let mut terminal = ratatui::try_init().context("failed to set up the terminal")?;
+    let result = draw_loop(&mut terminal, status, logs).await;
+    ratatui::restore();
+    result
+}
+
+async fn draw_loop(
+    terminal: &mut ratatui::DefaultTerminal,
+    status: StatusHandle,
+    logs: LogBuffer,
+) -> anyhow::Result<()> {
+    let mut updates = status.subscribe();
+    let mut input = tokio::time::interval(INPUT_POLL);
+
+    loop {
+        let current = updates.borrow_and_update().clone();
+        let lines = logs.lines();
+
+        terminal
+            .draw(|frame| draw(frame, &current, &lines))
+            .context("failed to draw the TUI")?;
+
+        tokio::select! {
+            _ = updates.changed() => {}
+            _ = logs.changed.notified() => {}
+            _ = input.tick() => {
+                if quit_pressed()? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Reads the key presses waiting, if any, and checks for one that quits.
+fn quit_pressed() -> anyhow::Result<bool> {
+    while event::poll(Duration::ZERO).context("failed to read terminal input")? {
+        let Event::Key(key) = event::read().context("failed to read terminal input")? else {
+            continue;
+        };
+
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(true),
+            _ => {}
+        }
+    }
+
+    Ok(false)
+}
+
+fn draw(frame: &mut Frame, status: &Status, logs: &[String]) {
+    let [status_area, logs_area] =
+        Layout::vertical([Constraint::Length(9), Constraint::Min(3)]).areas(frame.area());
+
+    let field = |name: &'static str, value: Option<&str>| {
+        Line::from(vec![
+            Span::styled(format!("{name:>12}  "), Style::new().dim()),
+            match value {
+                Some(value) => Span::raw(value.to_string()),
+                None => Span::styled("-", Style::new().dim()),
+            },
+        ])
+    };
+
+    let discord = if status.discord_connected {
+        Span::styled("connected", Style::new().fg(Color::Green))
+    } else {
+        Span::styled("not connected", Style::new().fg(Color::Yellow))
+    };
+
+    let last_prompt = status
+        .last_prompt_model
+        .as_ref()
+        .zip(status.last_prompt_ms)
+        .map(|(model, ms)| format!("{model} in {ms}ms"));
+
+    let tokens = &status.tokens_today;
+    let tokens_today = (tokens.total_tokens > 0).then(|| {
+        format!(
+            "{} ({} in, {} out)",
+            tokens.total_tokens, tokens.input_tokens, tokens.output_tokens
+        )
+    });
+
+    let mut lines = vec![
+        field("summary", status.summary.as_deref()).bold(),
+        field("branch", status.branch.as_deref()),
+        field("changes", status.diff_stat.as_deref()),
+        field("last update", status.last_update.as_deref()),
+        field("last prompt", last_prompt.as_deref()),
+        field("tokens today", tokens_today.as_deref()),
+        Line::from(vec![
+            Span::styled(format!("{:>12}  ", "discord"), Style::new().dim()),
+            discord,
+        ]),
+    ];
+
+    if let Some(error) = &status.last_error {
+        lines.push(field("last error", Some(error)).fg(Color::Red));
+    }
+
+    let title = " samwise · q to quit ";
+
+    frame.render_widget(
+        Paragraph::new(lines)
+            .block(Block::bordered().title(title))
+            .wrap(Wrap { trim: true }),
+        status_area,
+    );
+
+    // keep the newest lines in view
+    let visible = logs_area.height.saturating_sub(2) as usize;
+    let logs: Vec<Line> = logs[logs.len().saturating_sub(visible)..]
+        .iter()
+        .map(|line| Line::raw(line.as_str()))
+        .collect();
+
+    frame.render_widget(
+        Paragraph::new(logs).block(Block::bordered().title(" log ")),
+        logs_area,
+    );
+}
@@ -0,0 +1,151 @@
+//! How many tokens the models used today, for keeping an eye on what hosted
+//! providers cost and stopping at [AgentConfig::daily_token_budget].
+//!
+//! The totals are kept in samwise's cache directory, so that restarting
+//! doesn't start the day over.
+//!
+//! [AgentConfig::daily_token_budget]: crate::config::AgentConfig::daily_token_budget
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+/// The tokens used on one day, in local time.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DailyUsage {
+    /// The day these are for, like `2026-10-14`.
+    pub date: String,
+
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+
+    /// What the providers reported in all, which some give without saying
+    /// how much was input and output.
+    pub total_tokens: u64,
+
+    /// The total tokens used by each model.
+    pub models: BTreeMap<String, u64>,
+
+    /// Whether it's been warned that the budget ran out today.
+    #[serde(skip)]
+    warned: bool,
+}
+
+/// Today's usage, once it's been loaded.
+static TODAY: Mutex<Option<DailyUsage>> = Mutex::new(None);
+
+/// Counts the tokens `model` used for one prompt.
+pub fn record(model: &str, usage: rig::completion::Usage) {
+    let total = match usage.total_tokens {
+        0 => usage.input_tokens + usage.output_tokens,
+        total => total,
+    };
+
+    let today = with_today(|today| {
+        today.input_tokens += usage.input_tokens;
+        today.output_tokens += usage.output_tokens;
+        today.total_tokens += total;
+        *today.models.entry(model.to_string()).or_default() += total;
+        today.clone()
+    });
+
+    tracing::debug!(
+        model,
+        input_tokens = usage.input_tokens,
+        output_tokens = usage.output_tokens,
+        tokens_today = today.total_tokens,
+        "counted the prompt's tokens"
+    );
+
+    save(&today);
+}
+
+/// The tokens used so far today.
+pub fn today() -> DailyUsage {
+    with_today(|today| today.clone())
+}
+
+/// Whether `budget` has been used up today, warning the first time it is.
+pub fn over_budget(budget: u64) -> bool {
+    with_today(|today| {
+        let over = today.total_tokens >= budget;
+
+        if over && !today.warned {
+            tracing::warn!(
+                "used {} of the daily budget of {budget} tokens, so the model won't be asked \
+                 again until tomorrow",
+                today.total_tokens
+            );
+
+            today.warned = true;
+        }
+
+        over
+    })
+}
+
+/// How long until the next day starts, and with it a fresh budget.
+pub fn until_tomorrow() -> Duration {
+    let now = Local::now();
+
+    now.date_naive()
+        .succ_opt()
+        .map(|tomorrow| tomorrow.and_time(NaiveTime::MIN))
+        .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+        .and_then(|midnight| (midnight - now).to_std().ok())
+        .unwrap_or(Duration::from_secs(60 * 60))
+}
+
+/// Runs `f` on today's usage, loading it first if it hasn't been and
+/// starting over once the day changes.
+fn with_today<T>(f: impl FnOnce(&mut DailyUsage) -> T) -> T {
+    let date = Local::now().date_naive().to_string();
+    let mut today = TODAY.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let today = today.get_or_insert_with(|| load().unwrap_or_default());
+
+    if today.date != date {
+        *today = DailyUsage {
+            date,
+            ..Default::default()
+        };
+    }
+
+    f(today)
+}
+
+/// Where the usage is kept between runs.
+fn usage_file() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("samwise").join("usage.json"))
+}
+
+fn load() -> Option<DailyUsage> {
+    let json = std::fs::read_to_string(usage_file()?).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+/// Saves the usage. Failures are only logged, like
+/// [save_presence](crate::presence::save_presence).
+fn save(usage: &DailyUsage) {
+    let Some(path) = usage_file() else {
+        return;
+    };
+
+    let result = serde_json::to_string(usage)
+        .map_err(std::io::Error::other)
+        .and_then(|json| {
+            path.parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(&path, json))
+        });
+
+    if let Err(err) = result {
+        tracing::warn!("failed to save token usage to {}: {err}", path.display());
+    }
+}
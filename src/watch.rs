@@ -0,0 +1,144 @@
+//! Watching repository work trees for changes.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+use crate::git::{get_ignored, get_toplevel};
+
+/// How long the work tree has to settle after a change before the diff is
+/// checked, so that a save or checkout touching many files is one update.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The most paths passed to a single `git check-ignore`.
+const IGNORE_CHECK_BATCH: usize = 256;
+
+/// Files in `.git` whose changes can change the diff: staging changes the
+/// index, and committing or switching branches moves `HEAD`.
+const GIT_FILES: &[&str] = &["index", "HEAD"];
+
+/// Watches the work trees of the configured repositories.
+pub struct RepoWatcher {
+    toplevels: Vec<PathBuf>,
+    events: UnboundedReceiver<PathBuf>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl RepoWatcher {
+    pub async fn new(repos: &[PathBuf]) -> anyhow::Result<Self> {
+        use notify::Watcher;
+
+        let (tx, events) = unbounded_channel();
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+
+                if event.kind.is_access() {
+                    return;
+                }
+
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            })
+            .context("failed to create file watcher")?;
+
+        let mut toplevels = Vec::with_capacity(repos.len());
+
+        for repo in repos {
+            let toplevel = get_toplevel(repo)
+                .await
+                .with_context(|| format!("failed to find the work tree of {}", repo.display()))?;
+
+            watcher
+                .watch(&toplevel, notify::RecursiveMode::Recursive)
+                .with_context(|| format!("failed to watch {}", toplevel.display()))?;
+
+            toplevels.push(toplevel);
+        }
+
+        Ok(Self {
+            toplevels,
+            events,
+            _watcher: watcher,
+        })
+    }
+
+    /// Waits until a file that could change one of the diffs has changed and
+    /// the work tree has settled. Changes to files git ignores don't count.
+    pub async fn changed(&mut self) {
+        loop {
+            let Some(first) = self.events.recv().await else {
+                // the watcher stopped, so nothing will ever change
+                return std::future::pending().await;
+            };
+
+            let mut paths = BTreeSet::from([first]);
+
+            while let Ok(Some(path)) =
+                tokio::time::timeout(WATCH_DEBOUNCE, self.events.recv()).await
+            {
+                paths.insert(path);
+            }
+
+            if self.any_relevant(paths).await {
+                return;
+            }
+        }
+    }
+
+    /// Checks whether any of `paths` is in a work tree and not ignored.
+    async fn any_relevant(&self, paths: BTreeSet<PathBuf>) -> bool {
+        for toplevel in &self.toplevels {
+            let mut candidates = Vec::new();
+
+            for path in &paths {
+                let Ok(relative) = path.strip_prefix(toplevel) else {
+                    continue;
+                };
+
+                if let Ok(git_file) = relative.strip_prefix(".git") {
+                    if GIT_FILES.iter().any(|name| git_file == Path::new(name)) {
+                        return true;
+                    }
+
+                    continue;
+                }
+
+                // jj writes its bookkeeping here every time it snapshots the
+                // working copy, which would otherwise wake the loop forever
+                if relative.starts_with(".jj") {
+                    continue;
+                }
+
+                // git can't be asked about paths it can't represent
+                let Some(path) = path.to_str() else {
+                    return true;
+                };
+
+                candidates.push(path);
+            }
+
+            for batch in candidates.chunks(IGNORE_CHECK_BATCH) {
+                match get_ignored(toplevel, batch).await {
+                    Ok(ignored) if ignored.len() < batch.len() => return true,
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!("failed to check ignored files: {err}");
+                        return true;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+}
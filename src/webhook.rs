@@ -0,0 +1,152 @@
+//! Posting summaries to an HTTP endpoint.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+use crate::{
+    agent::prompt_vars,
+    config::{Config, WebhookConfig},
+    presence::{PresenceSink, PresenceState},
+    template::render_template,
+};
+
+/// How long to wait on the endpoint before giving up on an update.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts presence updates to the configured webhook.
+pub struct Webhook {
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new() -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .context("failed to create webhook client")?;
+
+        Ok(Self { client })
+    }
+
+    /// Posts `presence` in the background so that a slow endpoint doesn't
+    /// hold up the main loop. Failures to post are logged and otherwise
+    /// ignored.
+    pub fn send(&self, presence: &PresenceState, config: &WebhookConfig) -> anyhow::Result<()> {
+        let body = webhook_body(presence, config).context("failed to build webhook body")?;
+
+        let mut request = self
+            .client
+            .post(&config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+
+        for (name, value) in &config.headers {
+            request = request.header(name, value);
+        }
+
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => {
+                    tracing::warn!("webhook responded with {}", response.status());
+                }
+                Err(err) => tracing::warn!("failed to post to webhook: {err}"),
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PresenceSink for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn update(
+        &mut self,
+        presence: Option<&PresenceState>,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        // clears are sent every tick there are no changes, so only post
+        // actual summaries
+        match (presence, &config.webhook) {
+            (Some(presence), Some(webhook_config)) => self.send(
+                &presence.truncated(webhook_config.max_len, webhook_config.truncate),
+                webhook_config,
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The placeholders available in the webhook body.
+pub fn webhook_vars(presence: &PresenceState) -> Vec<(&'static str, String)> {
+    let metadata = presence.metadata.clone().unwrap_or_default();
+    let mut vars = prompt_vars(&metadata);
+
+    vars.extend([
+        ("summary", presence.summary.clone()),
+        ("model", presence.model.clone()),
+        (
+            "timestamp",
+            humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        ),
+    ]);
+
+    vars
+}
+
+/// Builds the JSON posted for `presence`. Without a body template, every
+/// placeholder is sent as a field, keeping numbers as numbers.
+pub fn webhook_body(presence: &PresenceState, config: &WebhookConfig) -> anyhow::Result<String> {
+    let vars = webhook_vars(presence);
+
+    let body = match &config.body {
+        Some(template) => render_value(&toml::Value::Table(template.clone()), &vars)?,
+        None => {
+            let metadata = presence.metadata.clone().unwrap_or_default();
+
+            serde_json::json!({
+                "summary": presence.summary,
+                "branch": metadata.branch,
+                "repo": metadata.repo,
+                "files": metadata.files,
+                "insertions": metadata.insertions,
+                "deletions": metadata.deletions,
+                "change_type": metadata.change_type,
+                "model": presence.model,
+                "timestamp": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            })
+        }
+    };
+
+    serde_json::to_string(&body).context("failed to serialize webhook body")
+}
+
+/// Converts a body template to JSON, filling in the placeholders of every
+/// string in it.
+fn render_value(
+    template: &toml::Value,
+    vars: &[(&str, String)],
+) -> anyhow::Result<serde_json::Value> {
+    Ok(match template {
+        toml::Value::String(template) => render_template(template, vars)?.into(),
+        toml::Value::Integer(value) => (*value).into(),
+        toml::Value::Float(value) => (*value).into(),
+        toml::Value::Boolean(value) => (*value).into(),
+        toml::Value::Datetime(value) => value.to_string().into(),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| render_value(value, vars))
+            .collect::<anyhow::Result<_>>()?,
+        toml::Value::Table(table) => table
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), render_value(value, vars)?)))
+            .collect::<anyhow::Result<serde_json::Map<_, _>>>()?
+            .into(),
+    })
+}